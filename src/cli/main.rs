@@ -1,16 +1,122 @@
-use std::time::Instant;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 
 use coresight_rs::dap_access::DAPAccess;
 use coresight_rs::access_port::AccessPortError;
 use coresight_rs::memory_interface::MemoryInterface;
 use probe_rs::debug_probe::DebugProbe;
+use probe_rs::protocol::WireProtocol;
 
 use structopt::StructOpt;
 
+use stlink::cortex_m::Core;
+use stlink::itm::{ItmDecoder, TracePacket};
+use stlink::stm32::{RdpLevel, Stm32Device, Stm32Family, Stm32Flash, Stm32FlashError};
+use stlink::stm8::{Stm8Flash, Stm8FlashError};
+use stlink::{
+    BacktraceError, DefmtDecoder, DefmtError, DefmtTable, ExceptionNames, FaultReport,
+    FlashAlgorithm, FlashAlgorithmError, FlashLoader, FlashReport, FrameError, FreeRtos, GdbError,
+    GdbServer, Image, ImageError, ImageFormat, LogFrame, MemoryRegion, Profile, RegionKind,
+    RtosError, Rtt, RttError, Segment, Semihosting, SemihostingOutcome, Swim, SwvReader,
+    SymbolError, Symbols, Timeline, TraceConfig, Unwinder,
+};
+
 fn parse_hex(src: &str) -> Result<u32, std::num::ParseIntError> {
     u32::from_str_radix(src, 16)
 }
 
+fn parse_option_byte(src: &str) -> Result<(u32, u8), &'static str> {
+    let mut parts = src.splitn(2, '=');
+    let addr = parts
+        .next()
+        .and_then(|addr| u32::from_str_radix(addr, 16).ok());
+    let value = parts
+        .next()
+        .and_then(|value| u8::from_str_radix(value, 16).ok());
+    match (addr, value) {
+        (Some(addr), Some(value)) => Ok((addr, value)),
+        _ => Err("Option bytes have to be given as <address>=<value> in hexadecimal."),
+    }
+}
+
+fn parse_port_output(src: &str) -> Result<(u8, PathBuf), &'static str> {
+    let mut parts = src.splitn(2, '=');
+    let port = parts.next().and_then(|port| port.parse().ok());
+    let path = parts.next().map(PathBuf::from);
+    match (port, path) {
+        (Some(port), Some(path)) if port < 32 => Ok((port, path)),
+        _ => Err("Outputs have to be given as <port>=<path> with a port between 0 and 31."),
+    }
+}
+
+fn parse_region(src: &str) -> Result<Vec<u32>, &'static str> {
+    src.split(':')
+        .map(|part| u32::from_str_radix(part, 16))
+        .collect::<Result<Vec<_>, _>>()
+        .or_else(|_| Err("Regions have to be given in hexadecimal."))
+}
+
+fn parse_flash_region(src: &str) -> Result<MemoryRegion, &'static str> {
+    match parse_region(src)?[..] {
+        [start, length] => Ok(MemoryRegion {
+            kind: RegionKind::Flash,
+            start,
+            length,
+        }),
+        _ => Err("Flash regions have to be given as <start>:<length>."),
+    }
+}
+
+fn parse_ram_region(src: &str) -> Result<MemoryRegion, &'static str> {
+    match parse_region(src)?[..] {
+        [start, length] => Ok(MemoryRegion {
+            kind: RegionKind::Ram,
+            start,
+            length,
+        }),
+        _ => Err("RAM regions have to be given as <start>:<length>."),
+    }
+}
+
+fn parse_image_format(src: &str) -> Result<ImageFormat, &'static str> {
+    match src {
+        "elf" => Ok(ImageFormat::Elf),
+        "hex" => Ok(ImageFormat::IntelHex),
+        "srec" => Ok(ImageFormat::SRecord),
+        "bin" => Ok(ImageFormat::Binary),
+        _ => Err("The format has to be one of elf, hex, srec or bin."),
+    }
+}
+
+fn parse_option_field(src: &str) -> Result<(String, u32), &'static str> {
+    let mut parts = src.splitn(2, '=');
+    let name = parts.next().unwrap_or("");
+    let value = parts.next().and_then(|value| {
+        if value.starts_with("0x") {
+            u32::from_str_radix(&value[2..], 16).ok()
+        } else {
+            value.parse().ok()
+        }
+    });
+    match value {
+        Some(value) if !name.is_empty() => Ok((name.to_string(), value)),
+        _ => {
+            Err("Option fields have to be given as <name>=<value> in decimal or with a 0x prefix.")
+        }
+    }
+}
+
+fn parse_protocol(src: &str) -> Result<WireProtocol, &'static str> {
+    match src {
+        "swd" => Ok(WireProtocol::Swd),
+        "jtag" => Ok(WireProtocol::Jtag),
+        _ => Err("The protocol has to be either swd or jtag."),
+    }
+}
+
 #[derive(StructOpt)]
 #[structopt(
     name = "ST-Link CLI",
@@ -26,6 +132,13 @@ enum CLI {
     Info {
         /// The number associated with the ST-Link to use
         n: u8,
+        /// The wire protocol to use (swd or jtag)
+        #[structopt(
+            long = "protocol",
+            default_value = "swd",
+            parse(try_from_str = "parse_protocol")
+        )]
+        protocol: WireProtocol,
     },
     /// Resets the target attached to the selected ST-Link
     #[structopt(name = "reset")]
@@ -45,17 +158,400 @@ enum CLI {
         loc: u32,
         /// The amount of memory (in words) to dump
         words: u32,
+        /// The wire protocol to use (swd or jtag)
+        #[structopt(
+            long = "protocol",
+            default_value = "swd",
+            parse(try_from_str = "parse_protocol")
+        )]
+        protocol: WireProtocol,
+    },
+    /// Program a binary image into the non-volatile memory of the attached target
+    #[structopt(name = "flash")]
+    Flash {
+        /// The number associated with the ST-Link to use
+        n: u8,
+        /// Program an STM8 target over SWIM
+        #[structopt(long = "swim")]
+        swim: bool,
+        /// The name of the target device (e.g. STM8S103F3 or STM32F407xG)
+        #[structopt(long = "device")]
+        device: Option<String>,
+        /// Program the data EEPROM instead of the program memory
+        #[structopt(long = "eeprom")]
+        eeprom: bool,
+        /// The address to program the image to (in hexadecimal without 0x prefix). Defaults to the start of the memory
+        #[structopt(long = "address", parse(try_from_str = "parse_hex"))]
+        address: Option<u32>,
+        /// An option byte to write, given as <address>=<value> (in hexadecimal without 0x prefix)
+        #[structopt(long = "option-byte", parse(try_from_str = "parse_option_byte"))]
+        option_bytes: Vec<(u32, u8)>,
+        /// Enable the readout protection after programming
+        #[structopt(long = "rop")]
+        rop: bool,
+        /// Program through a CMSIS-Pack flash algorithm (.FLM) instead of the built-in STM32 drivers
+        #[structopt(long = "algorithm", parse(from_os_str))]
+        algorithm: Option<PathBuf>,
+        /// The target RAM to run the flash algorithm in as <start>:<length> in hexadecimal
+        #[structopt(
+            long = "ram",
+            default_value = "20000000:4000",
+            parse(try_from_str = "parse_ram_region")
+        )]
+        ram: MemoryRegion,
+        /// Compare the flash through a CRC computed on the target at the start of --ram (built-in STM32 drivers only)
+        #[structopt(long = "crc")]
+        crc: bool,
+        /// The raw binary image to program
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+    },
+    /// Capture SWV trace data and write the ITM stimulus ports to stdout or files
+    #[structopt(name = "trace")]
+    Trace {
+        /// The number associated with the ST-Link to use
+        n: u8,
+        /// The clock feeding the TPIU of the target in Hz
+        clock: u32,
+        #[structopt(flatten)]
+        options: TraceOptions,
+    },
+    /// Sample the PC of the running target and report the hottest functions
+    #[structopt(name = "profile")]
+    Profile {
+        /// The number associated with the ST-Link to use
+        n: u8,
+        #[structopt(flatten)]
+        options: ProfileOptions,
+    },
+    /// Record the exception trace of the target as a Chrome Trace Event JSON timeline
+    #[structopt(name = "timeline")]
+    Timeline {
+        /// The number associated with the ST-Link to use
+        n: u8,
+        /// The clock feeding the TPIU of the target in Hz
+        clock: u32,
+        /// The file to write the timeline to
+        #[structopt(parse(from_os_str))]
+        output: PathBuf,
+        /// The SWO baud rate
+        #[structopt(long = "baud", default_value = "2000000")]
+        baud: u32,
+        /// The number of seconds to record for
+        #[structopt(long = "duration", default_value = "1")]
+        duration: u64,
+        /// The firmware ELF whose vector table names the interrupts
+        #[structopt(long = "elf", parse(from_os_str))]
+        elf: Option<PathBuf>,
+        /// A CMSIS-SVD file naming the interrupts
+        #[structopt(long = "svd", parse(from_os_str))]
+        svd: Option<PathBuf>,
+    },
+    /// Stream RTT channel 0 of the target to the terminal and forward stdin to it
+    #[structopt(name = "rtt")]
+    Rtt {
+        /// The number associated with the ST-Link to use
+        n: u8,
+        /// The firmware ELF to take the address of the _SEGGER_RTT control block from
+        #[structopt(long = "elf", parse(from_os_str))]
+        elf: Option<PathBuf>,
+        /// The start of the RAM to scan for the control block (in hexadecimal without 0x prefix)
+        #[structopt(
+            long = "scan-start",
+            default_value = "20000000",
+            parse(try_from_str = "parse_hex")
+        )]
+        scan_start: u32,
+        /// The number of bytes of RAM to scan for the control block (in hexadecimal without 0x prefix)
+        #[structopt(
+            long = "scan-size",
+            default_value = "10000",
+            parse(try_from_str = "parse_hex")
+        )]
+        scan_size: u32,
+        /// Decode channel 0 as defmt logs using the table of the ELF given with --elf
+        #[structopt(long = "defmt")]
+        defmt: bool,
+    },
+    /// Run the target and service its semihosting requests until it exits
+    #[structopt(name = "run")]
+    Run {
+        /// The number associated with the ST-Link to use
+        n: u8,
+        /// The directory the target may open files in
+        #[structopt(long = "root", default_value = ".", parse(from_os_str))]
+        root: PathBuf,
+        /// Reset the target before running it
+        #[structopt(long = "reset")]
+        reset: bool,
+    },
+    /// Serve the GDB remote protocol on a TCP port
+    #[structopt(name = "gdb")]
+    Gdb {
+        /// The number associated with the ST-Link to use
+        n: u8,
+        /// The TCP port to listen on
+        #[structopt(long = "port", default_value = "1337")]
+        port: u16,
+        /// A flash region for the memory map as <start>:<length> in hexadecimal, GDB can't write it
+        #[structopt(long = "flash", parse(try_from_str = "parse_flash_region"))]
+        flash: Vec<MemoryRegion>,
+        /// A RAM region for the memory map as <start>:<length> in hexadecimal
+        #[structopt(long = "ram", parse(try_from_str = "parse_ram_region"))]
+        ram: Vec<MemoryRegion>,
+        /// The access port of a core to debug, each core becomes a GDB thread
+        #[structopt(long = "ap")]
+        aps: Vec<u8>,
+        /// The firmware ELF, whose FreeRTOS tasks become GDB threads
+        #[structopt(long = "elf", parse(from_os_str))]
+        elf: Option<PathBuf>,
+    },
+    /// List the FreeRTOS tasks of the halted target
+    #[structopt(name = "tasks")]
+    Tasks {
+        /// The number associated with the ST-Link to use
+        n: u8,
+        /// The firmware ELF to find the kernel variables in
+        #[structopt(long = "elf", parse(from_os_str))]
+        elf: PathBuf,
+    },
+    /// Load an ELF, Intel HEX, S-record or binary image into the flash and RAM of the target
+    #[structopt(name = "load")]
+    Load {
+        /// The number associated with the ST-Link to use
+        n: u8,
+        #[structopt(flatten)]
+        options: LoadOptions,
+        /// The image to load
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+    },
+    /// Show or change the option bytes of an STM32 target
+    #[structopt(name = "option-bytes")]
+    OptionBytes {
+        /// The number associated with the ST-Link to use
+        n: u8,
+        /// The name of the target device (e.g. STM32F407xG)
+        #[structopt(long = "device")]
+        device: String,
+        #[structopt(subcommand)]
+        command: OptionBytesCommand,
+    },
+    /// Explain a fault from the fault status registers and the stacked exception frame
+    #[structopt(name = "fault")]
+    Fault {
+        /// The number associated with the ST-Link to use
+        n: u8,
+        /// The firmware ELF to symbolize the stacked PC and LR with
+        #[structopt(long = "elf", parse(from_os_str))]
+        elf: Option<PathBuf>,
+    },
+    /// Print the call stack of the halted target
+    #[structopt(name = "backtrace")]
+    Backtrace {
+        /// The number associated with the ST-Link to use
+        n: u8,
+        /// The firmware ELF with the call frame information to unwind with
+        #[structopt(long = "elf", parse(from_os_str))]
+        elf: PathBuf,
+    },
+    /// Remove the readout protection of a locked STM32, which mass erases its flash
+    #[structopt(name = "recover")]
+    Recover {
+        /// The number associated with the ST-Link to use
+        n: u8,
+        /// The name of the target device (e.g. STM32F407xG)
+        #[structopt(long = "device")]
+        device: String,
+        /// Do not ask for confirmation before erasing
+        #[structopt(long = "yes")]
+        yes: bool,
+    },
+}
+
+#[derive(StructOpt)]
+enum OptionBytesCommand {
+    /// Print the decoded option bytes
+    #[structopt(name = "show")]
+    Show {},
+    /// Change option fields and program them
+    #[structopt(name = "set")]
+    Set {
+        /// The fields to change as <name>=<value>, e.g. RDP=0xaa
+        #[structopt(parse(try_from_str = "parse_option_field"))]
+        fields: Vec<(String, u32)>,
+        /// Only print the changes
+        #[structopt(long = "dry-run")]
+        dry_run: bool,
+        /// Do not ask for confirmation before programming
+        #[structopt(long = "yes")]
+        yes: bool,
+        /// Allow readout protection level 2, which disables debugging for good
+        #[structopt(long = "permanent")]
+        permanent: bool,
     },
 }
 
+// The options of the trace command.
+#[derive(StructOpt)]
+struct TraceOptions {
+    /// The SWO baud rate
+    #[structopt(long = "baud", default_value = "2000000")]
+    baud: u32,
+    /// The stimulus ports to enable as a bitmask (in hexadecimal without 0x prefix)
+    #[structopt(
+        long = "ports",
+        default_value = "ffffffff",
+        parse(try_from_str = "parse_hex")
+    )]
+    ports: u32,
+    /// Write a stimulus port to a file instead of stdout, given as <port>=<path>
+    #[structopt(long = "output", parse(try_from_str = "parse_port_output"))]
+    outputs: Vec<(u8, PathBuf)>,
+    /// Stop capturing after the given number of seconds
+    #[structopt(long = "duration")]
+    duration: Option<u64>,
+    /// Decode the defmt logs of the given firmware ELF arriving on the defmt port
+    #[structopt(long = "defmt", parse(from_os_str))]
+    defmt: Option<PathBuf>,
+    /// The stimulus port carrying the defmt logs
+    #[structopt(long = "defmt-port", default_value = "0")]
+    defmt_port: u8,
+}
+
+// The options of the profile command.
+#[derive(StructOpt)]
+struct ProfileOptions {
+    /// The firmware ELF used to map samples to functions
+    #[structopt(long = "elf", parse(from_os_str))]
+    elf: Option<PathBuf>,
+    /// The number of seconds to collect samples for
+    #[structopt(long = "duration", default_value = "5")]
+    duration: u64,
+    /// Collect DWT PC samples over SWV instead of polling DWT_PCSR, given the trace clock of the target in Hz
+    #[structopt(long = "swv")]
+    swv: Option<u32>,
+    /// The SWO baud rate
+    #[structopt(long = "baud", default_value = "2000000")]
+    baud: u32,
+    /// The number of functions to report
+    #[structopt(long = "top", default_value = "20")]
+    top: usize,
+    /// Write the samples as collapsed stacks to this file
+    #[structopt(long = "collapsed", parse(from_os_str))]
+    collapsed: Option<PathBuf>,
+    /// Write a flamegraph SVG of the samples to this file
+    #[structopt(long = "flamegraph", parse(from_os_str))]
+    flamegraph: Option<PathBuf>,
+}
+
+// The options of the load command.
+#[derive(StructOpt)]
+struct LoadOptions {
+    /// The image format (elf, hex, srec or bin). Detected from the contents by default
+    #[structopt(long = "format", parse(try_from_str = "parse_image_format"))]
+    format: Option<ImageFormat>,
+    /// The address of a raw binary image (in hexadecimal without 0x prefix)
+    #[structopt(long = "base", parse(try_from_str = "parse_hex"))]
+    base: Option<u32>,
+    /// The name of the target device whose flash is programmed (e.g. STM32F407xG)
+    #[structopt(long = "device")]
+    device: Option<String>,
+    /// Program the flash through a CMSIS-Pack flash algorithm (.FLM)
+    #[structopt(long = "algorithm", parse(from_os_str))]
+    algorithm: Option<PathBuf>,
+    /// The target RAM to run the flash algorithm in, which segments may also be loaded to, as <start>:<length> in hexadecimal
+    #[structopt(
+        long = "ram",
+        default_value = "20000000:4000",
+        parse(try_from_str = "parse_ram_region")
+    )]
+    ram: MemoryRegion,
+    /// Compare the flash through a CRC computed on the target at the start of --ram (built-in STM32 drivers only)
+    #[structopt(long = "crc")]
+    crc: bool,
+}
+
 fn main() {
     let matches = CLI::from_args();
 
     match matches {
         CLI::List {} => list_connected_devices(),
-        CLI::Info { n } => show_info_of_device(n).unwrap(),
+        CLI::Info { n, protocol } => show_info_of_device(n, protocol).unwrap(),
         CLI::Reset { n, assert } => reset_target_of_device(n, assert).unwrap(),
-        CLI::Dump { n, loc, words } => dump_memory(n, loc, words).unwrap(),
+        CLI::Dump {
+            n,
+            loc,
+            words,
+            protocol,
+        } => dump_memory(n, loc, words, protocol).unwrap(),
+        CLI::Flash {
+            n,
+            swim,
+            device,
+            eeprom,
+            address,
+            option_bytes,
+            rop,
+            algorithm,
+            ram,
+            crc,
+            path,
+        } => {
+            if swim {
+                flash_stm8(n, device, eeprom, address, option_bytes, rop, path).unwrap()
+            } else if eeprom || rop || !option_bytes.is_empty() {
+                println!("--eeprom, --option-byte and --rop are only supported for STM8 targets.");
+            } else if let Some(algorithm) = algorithm {
+                flash_with_algorithm(n, algorithm, ram, address, path).unwrap()
+            } else {
+                flash_stm32(n, device, address, ram, crc, path).unwrap()
+            }
+        }
+        CLI::Trace { n, clock, options } => trace_target(n, clock, options).unwrap(),
+        CLI::Profile { n, options } => profile_target(n, options).unwrap(),
+        CLI::Timeline {
+            n,
+            clock,
+            output,
+            baud,
+            duration,
+            elf,
+            svd,
+        } => record_timeline(n, clock, output, baud, duration, elf, svd).unwrap(),
+        CLI::Rtt {
+            n,
+            elf,
+            scan_start,
+            scan_size,
+            defmt,
+        } => stream_rtt(n, elf, scan_start, scan_size, defmt).unwrap(),
+        CLI::Run { n, root, reset } => {
+            let code = run_semihosting(n, root, reset).unwrap();
+            std::process::exit(code);
+        }
+        CLI::Gdb {
+            n,
+            port,
+            flash,
+            ram,
+            aps,
+            elf,
+        } => serve_gdb(n, port, flash, ram, aps, elf).unwrap(),
+        CLI::Load { n, options, path } => load_image(n, options, path).unwrap(),
+        CLI::OptionBytes { n, device, command } => match command {
+            OptionBytesCommand::Show {} => show_option_bytes(n, device).unwrap(),
+            OptionBytesCommand::Set {
+                fields,
+                dry_run,
+                yes,
+                permanent,
+            } => set_option_bytes(n, device, fields, dry_run, yes, permanent).unwrap(),
+        },
+        CLI::Fault { n, elf } => analyze_fault(n, elf).unwrap(),
+        CLI::Backtrace { n, elf } => print_backtrace(n, elf).unwrap(),
+        CLI::Recover { n, device, yes } => recover(n, device, yes).unwrap(),
+        CLI::Tasks { n, elf } => list_tasks(n, elf).unwrap(),
     }
 }
 
@@ -86,15 +582,32 @@ enum Error {
     DeviceNotFound,
     STLinkError(stlink::STLinkError),
     AccessPortError(AccessPortError),
+    Stm8FlashError(Stm8FlashError),
+    Stm32FlashError(Stm32FlashError),
+    FlashAlgorithmError(FlashAlgorithmError),
+    ImageError(ImageError),
+    SymbolError(SymbolError),
+    RttError(RttError),
+    DefmtError(DefmtError),
+    GdbError(GdbError),
+    RtosError(RtosError),
+    BacktraceError(BacktraceError),
+    IO(std::io::Error),
     Custom(&'static str),
 }
 
-fn show_info_of_device(n: u8) -> Result<(), Error> {
-    let mut context = libusb::Context::new().or_else(|e| {
+fn open_context() -> Result<libusb::Context, Error> {
+    libusb::Context::new().or_else(|e| {
         println!("Failed to open an USB context.");
         Err(Error::USB(e))
-    })?;
-    let mut connected_devices = stlink::get_all_plugged_devices(&mut context).or_else(|e| {
+    })
+}
+
+fn get_device<'a>(
+    context: &'a libusb::Context,
+    n: u8,
+) -> Result<stlink::STLinkUSBDevice<'a>, Error> {
+    let mut connected_devices = stlink::get_all_plugged_devices(context).or_else(|e| {
         println!("Failed to fetch plugged USB devices.");
         Err(Error::USB(e))
     })?;
@@ -102,9 +615,13 @@ fn show_info_of_device(n: u8) -> Result<(), Error> {
         println!("The device with the given number was not found.");
         Err(Error::DeviceNotFound)
     } else {
-        Ok(())
-    }?;
-    let usb_device = connected_devices.remove(n as usize);
+        Ok(connected_devices.remove(n as usize))
+    }
+}
+
+fn show_info_of_device(n: u8, protocol: WireProtocol) -> Result<(), Error> {
+    let context = open_context()?;
+    let usb_device = get_device(&context, n)?;
     let mut st_link = stlink::STLink::new(usb_device);
     st_link.open().or_else(|e| Err(Error::STLinkError(e)))?;
 
@@ -118,25 +635,34 @@ fn show_info_of_device(n: u8) -> Result<(), Error> {
     println!("JTAG Version: {:?}", version.1);
     println!("Target Voltage: {:?}", vtg);
 
+    // TARGETID only exists on a SW-DP.
+    let has_target_id = match protocol {
+        WireProtocol::Swd => true,
+        WireProtocol::Jtag => false,
+    };
+
     st_link
-        .attach(probe_rs::protocol::WireProtocol::Swd)
-        .or_else(|e| Err(Error::STLinkError(e)))?;
-    st_link
-        .write_register(0xFFFF, 0x2, 0x2)
+        .attach(protocol)
         .or_else(|e| Err(Error::STLinkError(e)))?;
 
-    let target_info = st_link
-        .read_register(0xFFFF, 0x4)
-        .or_else(|e| Err(Error::STLinkError(e)))?;
-    let target_info = parse_target_id(target_info);
-    println!("Target Identification Register (TARGETID):");
-    println!(
-        "\tRevision = {}, Part Number = {}, Designer = {}",
-        target_info.0, target_info.3, target_info.2
-    );
+    if has_target_id {
+        st_link
+            .write_register(0xFFFF, 0x2, 0x2)
+            .or_else(|e| Err(Error::STLinkError(e)))?;
+
+        let target_info = st_link
+            .read_register(0xFFFF, 0x4)
+            .or_else(|e| Err(Error::STLinkError(e)))?;
+        let target_info = parse_target_id(target_info);
+        println!("Target Identification Register (TARGETID):");
+        println!(
+            "\tRevision = {}, Part Number = {}, Designer = {}",
+            target_info.0, target_info.3, target_info.2
+        );
+    }
 
     let target_info = st_link
-        .read_register(0xFFFF, 0x0)
+        .read_idcode()
         .or_else(|e| Err(Error::STLinkError(e)))?;
     let target_info = parse_target_id(target_info);
     println!("Identification Code Register (IDCODE):");
@@ -152,6 +678,65 @@ fn show_info_of_device(n: u8) -> Result<(), Error> {
         target_info.1,
         target_info.2
     );
+
+    let chip_info =
+        stlink::stm32::identify(&mut st_link, 0).or_else(|e| Err(Error::STLinkError(e)))?;
+    if let Some(chip_info) = chip_info {
+        println!(
+            "STM32 Identification (DBGMCU_IDCODE = 0x{:08x}):",
+            chip_info.idcode
+        );
+        println!(
+            "\tPart = {},\n\tRevision = {} (DEV_ID = 0x{:03x}, REV_ID = 0x{:04x})",
+            chip_info.part.unwrap_or("Unknown"),
+            chip_info.revision.unwrap_or("Unknown"),
+            chip_info.dev_id,
+            chip_info.rev_id
+        );
+        if let Some(flash_size) = chip_info.flash_size {
+            println!("\tFlash Size = {} kB", flash_size);
+        }
+        if let Some(unique_id) = chip_info.unique_id {
+            println!(
+                "\tUnique ID = {:08x}{:08x}{:08x}",
+                unique_id[2], unique_id[1], unique_id[0]
+            );
+        }
+    }
+
+    let core_info = Core::new(0)
+        .info(&mut st_link)
+        .or_else(|e| Err(Error::STLinkError(e)))?;
+    println!("Core (CPUID = 0x{:08x}):", core_info.cpuid);
+    println!(
+        "\tType = {} r{}p{},\n\tArchitecture = {}",
+        core_info.core_type, core_info.revision, core_info.patch, core_info.architecture
+    );
+    match core_info.fpu {
+        Some(fpu) => println!(
+            "\tFPU = {} precision{}",
+            if fpu.double_precision {
+                "double"
+            } else {
+                "single"
+            },
+            if fpu.half_precision {
+                ", half precision conversion"
+            } else {
+                ""
+            }
+        ),
+        None => println!("\tFPU = None"),
+    }
+    println!(
+        "\tTrustZone = {},\n\tMPU Regions = {}",
+        if core_info.trustzone { "Yes" } else { "No" },
+        core_info.mpu_regions
+    );
+    println!(
+        "\tBreakpoints = {},\n\tWatchpoints = {}",
+        core_info.breakpoints, core_info.watchpoints
+    );
     st_link.close().or_else(|e| Err(Error::STLinkError(e)))?;
     if target_info.3 != 1
         || !(target_info.0 == 0x3 || target_info.0 == 0x4)
@@ -175,7 +760,7 @@ fn parse_target_id(value: u32) -> (u8, u16, u16, u8) {
     )
 }
 
-fn dump_memory(n: u8, loc: u32, words: u32) -> Result<(), Error> {
+fn dump_memory(n: u8, loc: u32, words: u32, protocol: WireProtocol) -> Result<(), Error> {
     const CSW_SIZE32: u32 = 0x00000002;
     const CSW_SADDRINC: u32 = 0x00000010;
     const CSW_DBGSTAT: u32 = 0x00000040;
@@ -185,26 +770,13 @@ fn dump_memory(n: u8, loc: u32, words: u32) -> Result<(), Error> {
 
     const CSW_VALUE: u32 = (CSW_RESERVED | CSW_MSTRDBG | CSW_HPROT | CSW_DBGSTAT | CSW_SADDRINC);
 
-    let mut context = libusb::Context::new().or_else(|e| {
-        println!("Failed to open an USB context.");
-        Err(Error::USB(e))
-    })?;
-    let mut connected_devices = stlink::get_all_plugged_devices(&mut context).or_else(|e| {
-        println!("Failed to fetch plugged USB devices.");
-        Err(Error::USB(e))
-    })?;
-    if connected_devices.len() <= n as usize {
-        println!("The device with the given number was not found.");
-        Err(Error::DeviceNotFound)
-    } else {
-        Ok(())
-    }?;
-    let usb_device = connected_devices.remove(n as usize);
+    let context = open_context()?;
+    let usb_device = get_device(&context, n)?;
     let mut st_link = stlink::STLink::new(usb_device);
     st_link.open().or_else(|e| Err(Error::STLinkError(e)))?;
 
     st_link
-        .attach(probe_rs::protocol::WireProtocol::Swd)
+        .attach(protocol)
         .or_else(|e| Err(Error::STLinkError(e)))?;
 
     st_link
@@ -233,21 +805,8 @@ fn dump_memory(n: u8, loc: u32, words: u32) -> Result<(), Error> {
 }
 
 fn reset_target_of_device(n: u8, assert: Option<bool>) -> Result<(), Error> {
-    let mut context = libusb::Context::new().or_else(|e| {
-        println!("Failed to open an USB context.");
-        Err(Error::USB(e))
-    })?;
-    let mut connected_devices = stlink::get_all_plugged_devices(&mut context).or_else(|e| {
-        println!("Failed to fetch plugged USB devices.");
-        Err(Error::USB(e))
-    })?;
-    if connected_devices.len() <= n as usize {
-        println!("The device with the given number was not found.");
-        Err(Error::DeviceNotFound)
-    } else {
-        Ok(())
-    }?;
-    let usb_device = connected_devices.remove(n as usize);
+    let context = open_context()?;
+    let usb_device = get_device(&context, n)?;
     let mut st_link = stlink::STLink::new(usb_device);
     st_link.open().or_else(|e| Err(Error::STLinkError(e)))?;
 
@@ -273,3 +832,1235 @@ fn reset_target_of_device(n: u8, assert: Option<bool>) -> Result<(), Error> {
     st_link.close().or_else(|e| Err(Error::STLinkError(e)))?;
     Ok(())
 }
+
+fn flash_stm8(
+    n: u8,
+    device: Option<String>,
+    eeprom: bool,
+    address: Option<u32>,
+    option_bytes: Vec<(u32, u8)>,
+    rop: bool,
+    path: PathBuf,
+) -> Result<(), Error> {
+    let device = device.ok_or_else(|| {
+        println!("The target device has to be given with --device.");
+        Error::Custom("No target device given.")
+    })?;
+    let device = stlink::stm8::find_device(&device).ok_or_else(|| {
+        println!("The device {} is not known.", device);
+        Error::Custom("Unknown target device.")
+    })?;
+    let image = std::fs::read(&path).or_else(|e| Err(Error::IO(e)))?;
+
+    let context = open_context()?;
+    let usb_device = get_device(&context, n)?;
+    let mut swim = Swim::new(usb_device);
+    swim.open().or_else(|e| Err(Error::STLinkError(e)))?;
+    swim.attach().or_else(|e| Err(Error::STLinkError(e)))?;
+
+    {
+        let mut flash = Stm8Flash::new(&mut swim, device);
+        let instant = Instant::now();
+        if eeprom {
+            let address = address.unwrap_or(device.eeprom_start);
+            flash
+                .program_eeprom(address, &image)
+                .or_else(|e| Err(Error::Stm8FlashError(e)))?;
+        } else {
+            let address = address.unwrap_or(device.flash_start);
+            flash
+                .program_flash(address, &image)
+                .or_else(|e| Err(Error::Stm8FlashError(e)))?;
+        }
+        println!(
+            "Programmed and verified {} bytes in {:?}",
+            image.len(),
+            instant.elapsed()
+        );
+
+        for (address, value) in option_bytes {
+            flash
+                .write_option_byte(address, value)
+                .or_else(|e| Err(Error::Stm8FlashError(e)))?;
+            println!("Option byte 0x{:04x} set to 0x{:02x}", address, value);
+        }
+
+        if rop {
+            flash
+                .set_readout_protection(true)
+                .or_else(|e| Err(Error::Stm8FlashError(e)))?;
+            println!("Readout protection enabled.");
+        }
+
+        flash.lock().or_else(|e| Err(Error::Stm8FlashError(e)))?;
+    }
+
+    swim.generate_reset()
+        .or_else(|e| Err(Error::STLinkError(e)))?;
+    swim.close().or_else(|e| Err(Error::STLinkError(e)))?;
+    Ok(())
+}
+
+fn flash_stm32(
+    n: u8,
+    device: Option<String>,
+    address: Option<u32>,
+    ram: MemoryRegion,
+    crc: bool,
+    path: PathBuf,
+) -> Result<(), Error> {
+    let device = device.ok_or_else(|| {
+        println!("The target device has to be given with --device.");
+        Error::Custom("No target device given.")
+    })?;
+    let device = find_stm32_device(&device)?;
+    let image = std::fs::read(&path).or_else(|e| Err(Error::IO(e)))?;
+
+    let context = open_context()?;
+    let usb_device = get_device(&context, n)?;
+    let mut st_link = stlink::STLink::new(usb_device);
+    st_link.open().or_else(|e| Err(Error::STLinkError(e)))?;
+    st_link
+        .attach(WireProtocol::Swd)
+        .or_else(|e| Err(Error::STLinkError(e)))?;
+
+    // Keep the firmware from accessing the flash while it is programmed.
+    let core = Core::new(0);
+    core.halt(&mut st_link)
+        .or_else(|e| Err(Error::STLinkError(e)))?;
+
+    {
+        let mut flash = Stm32Flash::new(&mut st_link, core.apsel, device);
+        if crc {
+            flash
+                .use_crc_stub(ram.start)
+                .or_else(|e| Err(Error::Stm32FlashError(e)))?;
+        }
+        let instant = Instant::now();
+        let segment = Segment {
+            address: address.unwrap_or(device.flash_start),
+            data: image,
+        };
+        let report = flash
+            .program_segments(&[segment])
+            .or_else(|e| Err(Error::Stm32FlashError(e)))?;
+        if report.sectors_written == 0 {
+            println!("The flash already holds the image, nothing was written.");
+        } else {
+            println!(
+                "Programmed and verified the image in {:?}",
+                instant.elapsed()
+            );
+        }
+        print_flash_report(report);
+        flash.lock().or_else(|e| Err(Error::Stm32FlashError(e)))?;
+    }
+
+    st_link
+        .target_reset()
+        .or_else(|e| Err(Error::STLinkError(e)))?;
+    st_link.close().or_else(|e| Err(Error::STLinkError(e)))?;
+    Ok(())
+}
+
+fn flash_with_algorithm(
+    n: u8,
+    algorithm: PathBuf,
+    ram: MemoryRegion,
+    address: Option<u32>,
+    path: PathBuf,
+) -> Result<(), Error> {
+    let algorithm =
+        FlashAlgorithm::load(algorithm).or_else(|e| Err(Error::FlashAlgorithmError(e)))?;
+    let image = std::fs::read(&path).or_else(|e| Err(Error::IO(e)))?;
+
+    let context = open_context()?;
+    let usb_device = get_device(&context, n)?;
+    let mut st_link = stlink::STLink::new(usb_device);
+    st_link.open().or_else(|e| Err(Error::STLinkError(e)))?;
+    st_link
+        .attach(WireProtocol::Swd)
+        .or_else(|e| Err(Error::STLinkError(e)))?;
+
+    // Run the algorithm on a core which did not set up any interrupts yet.
+    let core = Core::new(0);
+    core.reset_and_halt(&mut st_link)
+        .or_else(|e| Err(Error::STLinkError(e)))?;
+
+    {
+        let mut loader = FlashLoader::new(&mut st_link, core, &algorithm, ram.start, ram.length)
+            .or_else(|e| Err(Error::FlashAlgorithmError(e)))?;
+        let instant = Instant::now();
+        let segment = Segment {
+            address: address.unwrap_or(algorithm.flash_start),
+            data: image,
+        };
+        let report = loader
+            .program_segments(&[segment])
+            .or_else(|e| Err(Error::FlashAlgorithmError(e)))?;
+        if report.sectors_written == 0 {
+            println!("The flash already holds the image, nothing was written.");
+        } else {
+            println!(
+                "Programmed the image with {} in {:?}",
+                algorithm.name,
+                instant.elapsed()
+            );
+        }
+        print_flash_report(report);
+    }
+
+    st_link
+        .target_reset()
+        .or_else(|e| Err(Error::STLinkError(e)))?;
+    st_link.close().or_else(|e| Err(Error::STLinkError(e)))?;
+    Ok(())
+}
+
+fn load_image(n: u8, options: LoadOptions, path: PathBuf) -> Result<(), Error> {
+    let LoadOptions {
+        format,
+        base,
+        device,
+        algorithm,
+        ram,
+        crc,
+    } = options;
+    let image = Image::load(&path, format, base).or_else(|e| Err(Error::ImageError(e)))?;
+    let algorithm = match algorithm {
+        Some(algorithm) => {
+            Some(FlashAlgorithm::load(algorithm).or_else(|e| Err(Error::FlashAlgorithmError(e)))?)
+        }
+        None => None,
+    };
+    let device = match device {
+        Some(device) => Some(find_stm32_device(&device)?),
+        None => None,
+    };
+    let flash = match (&algorithm, device) {
+        (Some(algorithm), _) => Some((algorithm.flash_start, algorithm.flash_size)),
+        (None, Some(device)) => Some((device.flash_start, device.flash_size)),
+        (None, None) => None,
+    };
+
+    // Plain writes do not program flash or option bytes, so the other segments are only
+    // downloaded into the SRAM region of the memory map or the given RAM.
+    let in_ram = |segment: &Segment| {
+        let within = |start: u32, length: u32| {
+            segment.address >= start
+                && u64::from(segment.end()) <= u64::from(start) + u64::from(length)
+        };
+        within(0x2000_0000, 0x2000_0000) || within(ram.start, ram.length)
+    };
+
+    // Split the image into the segments to flash and the ones to download.
+    let mut flash_segments = vec![];
+    let mut ram_segments = vec![];
+    for segment in image.segments {
+        match flash {
+            Some((start, size)) if segment.address >= start && segment.address < start + size => {
+                if segment.end() > start + size {
+                    println!(
+                        "The segment at 0x{:08x} exceeds the flash.",
+                        segment.address
+                    );
+                    return Err(Error::Custom("Segment exceeds the flash."));
+                }
+                flash_segments.push(segment);
+            }
+            _ if in_ram(&segment) => ram_segments.push(segment),
+            _ => {
+                println!(
+                    "The segment at 0x{:08x} is neither in the flash nor in RAM.",
+                    segment.address
+                );
+                if flash.is_none() {
+                    println!("Give --device or --algorithm to program the flash.");
+                }
+                return Err(Error::Custom("Segment outside of flash and RAM."));
+            }
+        }
+    }
+
+    let context = open_context()?;
+    let usb_device = get_device(&context, n)?;
+    let mut st_link = stlink::STLink::new(usb_device);
+    st_link.open().or_else(|e| Err(Error::STLinkError(e)))?;
+    st_link
+        .attach(WireProtocol::Swd)
+        .or_else(|e| Err(Error::STLinkError(e)))?;
+    let core = Core::new(0);
+    core.reset_and_halt(&mut st_link)
+        .or_else(|e| Err(Error::STLinkError(e)))?;
+
+    let instant = Instant::now();
+    let mut report = None;
+    if !flash_segments.is_empty() {
+        if let Some(algorithm) = &algorithm {
+            let mut loader = FlashLoader::new(&mut st_link, core, algorithm, ram.start, ram.length)
+                .or_else(|e| Err(Error::FlashAlgorithmError(e)))?;
+            report = Some(
+                loader
+                    .program_segments(&flash_segments)
+                    .or_else(|e| Err(Error::FlashAlgorithmError(e)))?,
+            );
+        } else if let Some(device) = device {
+            let mut flash = Stm32Flash::new(&mut st_link, core.apsel, device);
+            if crc {
+                flash
+                    .use_crc_stub(ram.start)
+                    .or_else(|e| Err(Error::Stm32FlashError(e)))?;
+            }
+            report = Some(
+                flash
+                    .program_segments(&flash_segments)
+                    .or_else(|e| Err(Error::Stm32FlashError(e)))?,
+            );
+            flash.lock().or_else(|e| Err(Error::Stm32FlashError(e)))?;
+        }
+    }
+    for segment in &ram_segments {
+        write_segment(&mut st_link, core, segment)?;
+    }
+    let size: usize = flash_segments
+        .iter()
+        .chain(ram_segments.iter())
+        .map(|segment| segment.data.len())
+        .sum();
+    println!(
+        "Loaded {} bytes in {} flash and {} RAM segments in {:?}",
+        size,
+        flash_segments.len(),
+        ram_segments.len(),
+        instant.elapsed()
+    );
+    if let Some(report) = report {
+        print_flash_report(report);
+    }
+
+    if !flash_segments.is_empty() {
+        st_link
+            .target_reset()
+            .or_else(|e| Err(Error::STLinkError(e)))?;
+    } else if let Some(entry) = image.entry {
+        println!("The core is halted, the entry point is at 0x{:08x}.", entry);
+    }
+    st_link.close().or_else(|e| Err(Error::STLinkError(e)))?;
+    Ok(())
+}
+
+fn find_stm32_device(device: &str) -> Result<&'static Stm32Device, Error> {
+    stlink::stm32::find_device(device).ok_or_else(|| {
+        println!("The device {} is not known.", device);
+        Error::Custom("Unknown target device.")
+    })
+}
+
+fn show_option_bytes(n: u8, device: String) -> Result<(), Error> {
+    let device = find_stm32_device(&device)?;
+    let context = open_context()?;
+    let usb_device = get_device(&context, n)?;
+    let mut st_link = stlink::STLink::new(usb_device);
+    st_link.open().or_else(|e| Err(Error::STLinkError(e)))?;
+    st_link
+        .attach(WireProtocol::Swd)
+        .or_else(|e| Err(Error::STLinkError(e)))?;
+
+    let options = Stm32Flash::new(&mut st_link, 0, device)
+        .read_option_bytes()
+        .or_else(|e| Err(Error::Stm32FlashError(e)))?;
+    for (name, value) in options.registers().iter().zip(&options.values) {
+        println!("{:<18} 0x{:08x}", name, value);
+    }
+    println!();
+    for (name, value) in options.fields() {
+        println!("{:<18} 0x{:x}", name, value);
+    }
+    println!("\nReadout protection: {:?}", options.rdp_level());
+
+    st_link.close().or_else(|e| Err(Error::STLinkError(e)))?;
+    Ok(())
+}
+
+fn set_option_bytes(
+    n: u8,
+    device: String,
+    fields: Vec<(String, u32)>,
+    dry_run: bool,
+    yes: bool,
+    permanent: bool,
+) -> Result<(), Error> {
+    let device = find_stm32_device(&device)?;
+    let context = open_context()?;
+    let usb_device = get_device(&context, n)?;
+    let mut st_link = stlink::STLink::new(usb_device);
+    st_link.open().or_else(|e| Err(Error::STLinkError(e)))?;
+    st_link
+        .attach(WireProtocol::Swd)
+        .or_else(|e| Err(Error::STLinkError(e)))?;
+
+    let written = program_option_fields(&mut st_link, device, fields, dry_run, yes, permanent);
+    if let Ok(true) = written {
+        st_link
+            .target_reset()
+            .or_else(|e| Err(Error::STLinkError(e)))?;
+    }
+    st_link.close().or_else(|e| Err(Error::STLinkError(e)))?;
+    written.map(|_| ())
+}
+
+/// Applies the fields to the option bytes and programs them once confirmed.
+/// Returns whether they were written.
+fn program_option_fields(
+    st_link: &mut stlink::STLink,
+    device: &'static Stm32Device,
+    fields: Vec<(String, u32)>,
+    dry_run: bool,
+    yes: bool,
+    permanent: bool,
+) -> Result<bool, Error> {
+    let mut flash = Stm32Flash::new(st_link, 0, device);
+    let current = flash
+        .read_option_bytes()
+        .or_else(|e| Err(Error::Stm32FlashError(e)))?;
+    let mut options = current.clone();
+    for (name, value) in fields {
+        options
+            .set(&name, value)
+            .or_else(|e| Err(Error::Stm32FlashError(e)))?;
+    }
+
+    let diff = current.diff(&options);
+    if diff.is_empty() {
+        println!("The option bytes already hold these values.");
+        return Ok(false);
+    }
+    for (name, old, new) in diff {
+        println!("{:<18} 0x{:x} -> 0x{:x}", name, old, new);
+    }
+    if dry_run {
+        return Ok(false);
+    }
+
+    match (current.rdp_level(), options.rdp_level()) {
+        (RdpLevel::Level2, _) => (),
+        (_, RdpLevel::Level2) if !permanent => {
+            println!(
+                "Readout protection level 2 disables debugging for good and cannot be undone."
+            );
+            println!("Add --permanent to select it anyway.");
+            return Err(Error::Custom("Level 2 requires --permanent."));
+        }
+        (_, RdpLevel::Level2) => {
+            println!("Readout protection level 2 disables debugging for good.")
+        }
+        (RdpLevel::Level1, RdpLevel::Level0) => println!(
+            "Regressing to level 0 erases all {} kB of flash of the {}.",
+            device.flash_size / 1024,
+            device.name
+        ),
+        _ => (),
+    }
+    if !yes && !confirm()? {
+        println!("Aborted.");
+        return Ok(false);
+    }
+
+    flash
+        .write_option_bytes(&options)
+        .or_else(|e| Err(Error::Stm32FlashError(e)))?;
+    flash
+        .reload_option_bytes()
+        .or_else(|e| Err(Error::Stm32FlashError(e)))?;
+    println!("The option bytes were written.");
+    Ok(true)
+}
+
+/// Asks the user to type 'yes' to continue.
+fn confirm() -> Result<bool, Error> {
+    print!("Type 'yes' to continue: ");
+    std::io::stdout().flush().or_else(|e| Err(Error::IO(e)))?;
+    let mut answer = String::new();
+    std::io::stdin()
+        .read_line(&mut answer)
+        .or_else(|e| Err(Error::IO(e)))?;
+    Ok(answer.trim() == "yes")
+}
+
+/// Attaches with nRESET asserted and halts the core as it leaves reset,
+/// before the firmware can disable the debug pins or enter a low power mode.
+fn attach_under_reset(st_link: &mut stlink::STLink, core: Core) -> Result<(), Error> {
+    st_link
+        .drive_nreset(true)
+        .or_else(|e| Err(Error::STLinkError(e)))?;
+    st_link
+        .attach(WireProtocol::Swd)
+        .or_else(|e| Err(Error::STLinkError(e)))?;
+    // The halt request is latched while the core is held in reset.
+    let halted = core.halt(st_link);
+    st_link
+        .drive_nreset(false)
+        .or_else(|e| Err(Error::STLinkError(e)))?;
+    match halted {
+        Ok(()) => Ok(()),
+        Err(stlink::STLinkError::CoreTimeout) => core
+            .wait_for_halt(st_link, Duration::from_millis(100))
+            .or_else(|e| Err(Error::STLinkError(e))),
+        Err(e) => Err(Error::STLinkError(e)),
+    }
+}
+
+fn recover(n: u8, device: String, yes: bool) -> Result<(), Error> {
+    let device = find_stm32_device(&device)?;
+    let context = open_context()?;
+    let usb_device = get_device(&context, n)?;
+    let mut st_link = stlink::STLink::new(usb_device);
+    st_link.open().or_else(|e| Err(Error::STLinkError(e)))?;
+
+    let core = Core::new(0);
+    if let Err(e) = attach_under_reset(&mut st_link, core) {
+        println!("The target does not answer on SWD.");
+        println!(
+            "Either it is not connected or readout protection level 2 disabled debugging for good."
+        );
+        return Err(e);
+    }
+
+    let protection = Stm32Flash::new(&mut st_link, core.apsel, device)
+        .readout_protection()
+        .or_else(|e| Err(Error::Stm32FlashError(e)))?;
+    match protection.configured {
+        Some(level) => println!("The option bytes select readout protection {:?}.", level),
+        None => println!("The option bytes could not be read."),
+    }
+    if protection.flash_faults {
+        println!("Reading the flash at 0x{:08x} fails.", device.flash_start);
+    } else {
+        println!("The flash at 0x{:08x} can be read.", device.flash_start);
+    }
+
+    match protection.level() {
+        RdpLevel::Level0 => {
+            println!("The target is not protected, there is nothing to recover.");
+            st_link.close().or_else(|e| Err(Error::STLinkError(e)))?;
+            return Ok(());
+        }
+        RdpLevel::Level2 => {
+            println!("Readout protection level 2 cannot be removed.");
+            st_link.close().or_else(|e| Err(Error::STLinkError(e)))?;
+            return Err(Error::Stm32FlashError(
+                Stm32FlashError::PermanentlyProtected,
+            ));
+        }
+        RdpLevel::Level1 => (),
+    }
+
+    println!(
+        "Regressing to level 0 erases all {} kB of flash of the {}.",
+        device.flash_size / 1024,
+        device.name
+    );
+    if !yes && !confirm()? {
+        println!("Aborted.");
+        st_link.close().or_else(|e| Err(Error::STLinkError(e)))?;
+        return Ok(());
+    }
+
+    Stm32Flash::new(&mut st_link, core.apsel, device)
+        .regress_readout_protection()
+        .or_else(|e| Err(Error::Stm32FlashError(e)))?;
+    println!("Erasing, this can take a minute.");
+
+    // The target resets or needs a power cycle to load the new option bytes.
+    // Reconnect until the flash can be read again.
+    let instant = Instant::now();
+    let mut hinted = false;
+    loop {
+        std::thread::sleep(Duration::from_millis(500));
+        let recovered = attach_under_reset(&mut st_link, core).is_ok()
+            && Stm32Flash::new(&mut st_link, core.apsel, device)
+                .readout_protection()
+                .map(|protection| protection.level() == RdpLevel::Level0)
+                .unwrap_or(false);
+        if recovered {
+            break;
+        }
+        if !hinted && instant.elapsed() > Duration::from_secs(10) {
+            println!("Power cycle the target if it does not come back on its own.");
+            hinted = true;
+        }
+        if instant.elapsed() > Duration::from_secs(120) {
+            println!("The target did not come back with readout protection level 0.");
+            return Err(Error::Custom("Recovery timed out."));
+        }
+    }
+    println!("Recovered the target in {:?}.", instant.elapsed());
+
+    st_link
+        .target_reset()
+        .or_else(|e| Err(Error::STLinkError(e)))?;
+    st_link.close().or_else(|e| Err(Error::STLinkError(e)))?;
+    Ok(())
+}
+
+fn print_flash_report(report: FlashReport) {
+    println!(
+        "{} sectors written, {} sectors unchanged.",
+        report.sectors_written, report.sectors_skipped
+    );
+}
+
+/// Downloads a segment into RAM, with word accesses where the alignment allows it.
+fn write_segment(st_link: &mut stlink::STLink, core: Core, segment: &Segment) -> Result<(), Error> {
+    if segment.address % 4 == 0 && segment.data.len() % 4 == 0 {
+        st_link.write_mem32(segment.address, segment.data.clone(), core.apsel)
+    } else {
+        st_link.write_mem8(segment.address, segment.data.clone(), core.apsel)
+    }
+    .or_else(|e| Err(Error::STLinkError(e)))
+}
+
+/// Creates a trace configuration which enables the trace pins if the target is an STM32.
+fn trace_config(st_link: &mut stlink::STLink, clock: u32, baud: u32) -> Result<TraceConfig, Error> {
+    let mut config = TraceConfig::new(clock, baud);
+    config.dbgmcu_cr = stlink::stm32::identify(st_link, 0)
+        .or_else(|e| Err(Error::STLinkError(e)))?
+        .and_then(|info| info.family)
+        .map(Stm32Family::dbgmcu_cr);
+    Ok(config)
+}
+
+/// Starts the SWV reception and tells when the trace clock cannot generate `baud` exactly.
+fn start_swv(st_link: &mut stlink::STLink, baud: u32, clock: u32) -> Result<(), Error> {
+    let actual = st_link
+        .start_swv(baud, clock)
+        .or_else(|e| Err(Error::STLinkError(e)))?;
+    if actual != baud {
+        eprintln!(
+            "SWO runs at {} baud, the closest rate to {} baud.",
+            actual, baud
+        );
+    }
+    Ok(())
+}
+
+fn trace_target(n: u8, clock: u32, options: TraceOptions) -> Result<(), Error> {
+    let TraceOptions {
+        baud,
+        ports,
+        outputs,
+        duration,
+        defmt,
+        defmt_port,
+    } = options;
+    let mut files = HashMap::new();
+    for (port, path) in outputs {
+        files.insert(port, File::create(path).or_else(|e| Err(Error::IO(e)))?);
+    }
+    let table = match defmt {
+        Some(path) => Some(DefmtTable::load(path).or_else(|e| Err(Error::DefmtError(e)))?),
+        None => None,
+    };
+    let mut defmt_decoder = table.as_ref().map(DefmtDecoder::new);
+
+    let context = open_context()?;
+    let usb_device = get_device(&context, n)?;
+    let mut st_link = stlink::STLink::new(usb_device);
+    st_link.open().or_else(|e| Err(Error::STLinkError(e)))?;
+    st_link
+        .attach(WireProtocol::Swd)
+        .or_else(|e| Err(Error::STLinkError(e)))?;
+
+    let mut config = trace_config(&mut st_link, clock, baud)?;
+    config.stimulus_ports = ports;
+    stlink::configure_trace(&mut st_link, 0, &config).or_else(|e| Err(Error::STLinkError(e)))?;
+    start_swv(&mut st_link, baud, clock)?;
+
+    let mut reader = SwvReader::new(1 << 20);
+    let mut decoder = ItmDecoder::new();
+    let stdout = std::io::stdout();
+    let mut stdout = stdout.lock();
+    let instant = Instant::now();
+
+    while duration.map_or(true, |duration| {
+        instant.elapsed() < Duration::from_secs(duration)
+    }) {
+        let received = reader
+            .poll(&mut st_link)
+            .or_else(|e| Err(Error::STLinkError(e)))?;
+        if let Some(overflow) = reader.take_overflow() {
+            eprintln!("SWV data was lost: {:?}", overflow);
+        }
+        if received == 0 {
+            std::thread::sleep(Duration::from_millis(1));
+            continue;
+        }
+
+        for packet in decoder.feed(&reader.drain()) {
+            match packet {
+                TracePacket::Instrumentation { port, payload }
+                    if port == defmt_port && defmt_decoder.is_some() =>
+                {
+                    if let Some(decoder) = defmt_decoder.as_mut() {
+                        print_log_frames(&mut stdout, decoder.feed(&payload))?;
+                    }
+                }
+                TracePacket::Instrumentation { port, payload } => {
+                    let result = match files.get_mut(&port) {
+                        Some(file) => file.write_all(&payload),
+                        None => stdout.write_all(&payload),
+                    };
+                    result.or_else(|e| Err(Error::IO(e)))?;
+                }
+                TracePacket::Overflow => eprintln!("The ITM FIFO of the target overflowed."),
+                _ => (),
+            }
+        }
+        stdout.flush().or_else(|e| Err(Error::IO(e)))?;
+    }
+
+    st_link.stop_swv().or_else(|e| Err(Error::STLinkError(e)))?;
+    st_link.close().or_else(|e| Err(Error::STLinkError(e)))?;
+    Ok(())
+}
+
+fn profile_target(n: u8, options: ProfileOptions) -> Result<(), Error> {
+    let ProfileOptions {
+        elf,
+        duration,
+        swv,
+        baud,
+        top,
+        collapsed,
+        flamegraph,
+    } = options;
+    // A PC sample needs 50 bits on the wire, so slower rates can't carry any.
+    if swv.is_some() && baud < 50 {
+        return Err(Error::Custom("The SWO baud rate has to be at least 50."));
+    }
+    let symbols = match elf {
+        Some(path) => Some(Symbols::load(path).or_else(|e| Err(Error::SymbolError(e)))?),
+        None => None,
+    };
+
+    let context = open_context()?;
+    let usb_device = get_device(&context, n)?;
+    let mut st_link = stlink::STLink::new(usb_device);
+    st_link.open().or_else(|e| Err(Error::STLinkError(e)))?;
+    st_link
+        .attach(WireProtocol::Swd)
+        .or_else(|e| Err(Error::STLinkError(e)))?;
+
+    let mut profile = Profile::new();
+    let duration = Duration::from_secs(duration);
+    println!("Sampling for {:?}.", duration);
+
+    if let Some(clock) = swv {
+        let mut config = trace_config(&mut st_link, clock, baud)?;
+        config.stimulus_ports = 0;
+        // Sample as often as the SWO bandwidth allows: a PC sample packet is 5 bytes, 50 bits on the wire.
+        config.pc_sampling = Some(u32::max(clock / (baud / 50), 64));
+        stlink::configure_trace(&mut st_link, 0, &config)
+            .or_else(|e| Err(Error::STLinkError(e)))?;
+        start_swv(&mut st_link, baud, clock)?;
+
+        let mut reader = SwvReader::new(1 << 20);
+        let mut decoder = ItmDecoder::new();
+        let instant = Instant::now();
+        while instant.elapsed() < duration {
+            reader
+                .poll(&mut st_link)
+                .or_else(|e| Err(Error::STLinkError(e)))?;
+            profile.add_packets(&decoder.feed(&reader.drain()));
+        }
+        if let Some(overflow) = reader.take_overflow() {
+            eprintln!("SWV data was lost: {:?}", overflow);
+        }
+        st_link.stop_swv().or_else(|e| Err(Error::STLinkError(e)))?;
+    } else {
+        stlink::sample_pcsr(&mut st_link, 0, &mut profile, duration)
+            .or_else(|e| Err(Error::STLinkError(e)))?;
+    }
+    st_link.close().or_else(|e| Err(Error::STLinkError(e)))?;
+
+    println!("Collected {} samples.", profile.total());
+    println!("{:>4}  {:>7}  {:>8}  Function", "Rank", "Share", "Samples");
+    for (rank, (name, count)) in profile
+        .hot_functions(symbols.as_ref())
+        .iter()
+        .take(top)
+        .enumerate()
+    {
+        println!(
+            "{:>4}  {:>6.2}%  {:>8}  {}",
+            rank + 1,
+            100.0 * *count as f64 / profile.total() as f64,
+            count,
+            name
+        );
+    }
+
+    if let Some(path) = collapsed {
+        let mut file = File::create(path).or_else(|e| Err(Error::IO(e)))?;
+        profile
+            .write_collapsed(symbols.as_ref(), &mut file)
+            .or_else(|e| Err(Error::IO(e)))?;
+    }
+    if let Some(path) = flamegraph {
+        let mut file = File::create(path).or_else(|e| Err(Error::IO(e)))?;
+        profile
+            .write_flamegraph(symbols.as_ref(), &mut file)
+            .or_else(|e| Err(Error::IO(e)))?;
+    }
+    Ok(())
+}
+
+fn record_timeline(
+    n: u8,
+    clock: u32,
+    output: PathBuf,
+    baud: u32,
+    duration: u64,
+    elf: Option<PathBuf>,
+    svd: Option<PathBuf>,
+) -> Result<(), Error> {
+    let names = if let Some(path) = svd {
+        ExceptionNames::from_svd(path).or_else(|e| Err(Error::SymbolError(e)))?
+    } else if let Some(path) = elf {
+        let data = std::fs::read(path).or_else(|e| Err(Error::IO(e)))?;
+        let symbols = Symbols::from_elf(&data).or_else(|e| Err(Error::SymbolError(e)))?;
+        ExceptionNames::from_elf(&data, &symbols).or_else(|e| Err(Error::SymbolError(e)))?
+    } else {
+        ExceptionNames::new()
+    };
+
+    let context = open_context()?;
+    let usb_device = get_device(&context, n)?;
+    let mut st_link = stlink::STLink::new(usb_device);
+    st_link.open().or_else(|e| Err(Error::STLinkError(e)))?;
+    st_link
+        .attach(WireProtocol::Swd)
+        .or_else(|e| Err(Error::STLinkError(e)))?;
+
+    let mut config = trace_config(&mut st_link, clock, baud)?;
+    config.stimulus_ports = 0;
+    config.timestamps = true;
+    config.exception_trace = true;
+    stlink::configure_trace(&mut st_link, 0, &config).or_else(|e| Err(Error::STLinkError(e)))?;
+    start_swv(&mut st_link, baud, clock)?;
+
+    let mut reader = SwvReader::new(1 << 20);
+    let mut decoder = ItmDecoder::new();
+    let mut timeline = Timeline::new();
+    let duration = Duration::from_secs(duration);
+    let instant = Instant::now();
+    while instant.elapsed() < duration {
+        reader
+            .poll(&mut st_link)
+            .or_else(|e| Err(Error::STLinkError(e)))?;
+        timeline.add_packets(&decoder.feed(&reader.drain()));
+    }
+    if let Some(overflow) = reader.take_overflow() {
+        eprintln!("SWV data was lost: {:?}", overflow);
+    }
+    st_link.stop_swv().or_else(|e| Err(Error::STLinkError(e)))?;
+    st_link.close().or_else(|e| Err(Error::STLinkError(e)))?;
+
+    let mut file = File::create(&output).or_else(|e| Err(Error::IO(e)))?;
+    timeline
+        .write_chrome_trace(&names, clock, &mut file)
+        .or_else(|e| Err(Error::IO(e)))?;
+    println!(
+        "Wrote {} exception events to {}.",
+        timeline.events().len(),
+        output.display()
+    );
+    Ok(())
+}
+
+fn stream_rtt(
+    n: u8,
+    elf: Option<PathBuf>,
+    scan_start: u32,
+    scan_size: u32,
+    defmt: bool,
+) -> Result<(), Error> {
+    let table = match (&elf, defmt) {
+        (Some(path), true) => Some(DefmtTable::load(path).or_else(|e| Err(Error::DefmtError(e)))?),
+        (None, true) => {
+            println!("Decoding defmt logs requires the firmware ELF (--elf).");
+            return Err(Error::Custom("No ELF to read the defmt table from."));
+        }
+        _ => None,
+    };
+    let mut defmt_decoder = table.as_ref().map(DefmtDecoder::new);
+
+    let address = match elf {
+        Some(path) => {
+            let symbols = Symbols::load(path).or_else(|e| Err(Error::SymbolError(e)))?;
+            let symbol = symbols.get("_SEGGER_RTT").ok_or_else(|| {
+                println!("The ELF has no _SEGGER_RTT symbol.");
+                Error::Custom("No RTT control block symbol.")
+            })?;
+            Some(symbol.address)
+        }
+        None => None,
+    };
+
+    let context = open_context()?;
+    let usb_device = get_device(&context, n)?;
+    let mut st_link = stlink::STLink::new(usb_device);
+    st_link.open().or_else(|e| Err(Error::STLinkError(e)))?;
+    st_link
+        .attach(WireProtocol::Swd)
+        .or_else(|e| Err(Error::STLinkError(e)))?;
+
+    let rtt = match address {
+        Some(address) => Rtt::attach(&mut st_link, 0, address),
+        None => Rtt::scan(&mut st_link, 0, scan_start, scan_size),
+    }
+    .or_else(|e| Err(Error::RttError(e)))?;
+    eprintln!("Found the RTT control block at 0x{:08x}.", rtt.address);
+    for channel in &rtt.up_channels {
+        eprintln!(
+            "Up channel {}: {} ({} bytes)",
+            channel.number,
+            channel.name.as_ref().map_or("<unnamed>", String::as_str),
+            channel.size
+        );
+    }
+
+    // Stdin blocks, so it is read on its own thread.
+    let (sender, receiver) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let stdin = std::io::stdin();
+        let mut line = String::new();
+        while let Ok(count) = stdin.read_line(&mut line) {
+            if count == 0 || sender.send(line.clone().into_bytes()).is_err() {
+                break;
+            }
+            line.clear();
+        }
+    });
+
+    let stdout = std::io::stdout();
+    let mut stdout = stdout.lock();
+    let mut input = vec![];
+    loop {
+        let data = rtt
+            .read(&mut st_link, 0)
+            .or_else(|e| Err(Error::RttError(e)))?;
+        if !data.is_empty() {
+            match defmt_decoder.as_mut() {
+                Some(decoder) => print_log_frames(&mut stdout, decoder.feed(&data))?,
+                None => stdout.write_all(&data).or_else(|e| Err(Error::IO(e)))?,
+            }
+            stdout.flush().or_else(|e| Err(Error::IO(e)))?;
+        }
+
+        input.extend(receiver.try_iter().flatten());
+        if !input.is_empty() && !rtt.down_channels.is_empty() {
+            let written = rtt
+                .write(&mut st_link, 0, &input)
+                .or_else(|e| Err(Error::RttError(e)))?;
+            input.drain(..written);
+        }
+
+        if data.is_empty() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+fn run_semihosting(n: u8, root: PathBuf, reset: bool) -> Result<i32, Error> {
+    let context = open_context()?;
+    let usb_device = get_device(&context, n)?;
+    let mut st_link = stlink::STLink::new(usb_device);
+    st_link.open().or_else(|e| Err(Error::STLinkError(e)))?;
+    st_link
+        .attach(WireProtocol::Swd)
+        .or_else(|e| Err(Error::STLinkError(e)))?;
+
+    let core = Core::new(0);
+    if reset {
+        core.reset_and_halt(&mut st_link)
+            .or_else(|e| Err(Error::STLinkError(e)))?;
+    }
+    core.run(&mut st_link)
+        .or_else(|e| Err(Error::STLinkError(e)))?;
+
+    let mut semihosting = Semihosting::new(root);
+    let code = loop {
+        if !core
+            .is_halted(&mut st_link)
+            .or_else(|e| Err(Error::STLinkError(e)))?
+        {
+            std::thread::sleep(Duration::from_millis(1));
+            continue;
+        }
+        match semihosting
+            .handle(&mut st_link, &core)
+            .or_else(|e| Err(Error::STLinkError(e)))?
+        {
+            SemihostingOutcome::Resumed => (),
+            SemihostingOutcome::Exited(code) => break code,
+            SemihostingOutcome::Halted(reason) => {
+                let pc = core
+                    .read_register(&mut st_link, stlink::cortex_m::registers::PC)
+                    .or_else(|e| Err(Error::STLinkError(e)))?;
+                println!("The target halted at 0x{:08x} ({:?}).", pc, reason);
+                break 1;
+            }
+        }
+    };
+
+    st_link.close().or_else(|e| Err(Error::STLinkError(e)))?;
+    Ok(code)
+}
+
+fn serve_gdb(
+    n: u8,
+    port: u16,
+    flash: Vec<MemoryRegion>,
+    ram: Vec<MemoryRegion>,
+    aps: Vec<u8>,
+    elf: Option<PathBuf>,
+) -> Result<(), Error> {
+    let context = open_context()?;
+    let usb_device = get_device(&context, n)?;
+    let mut st_link = stlink::STLink::new(usb_device);
+    st_link.open().or_else(|e| Err(Error::STLinkError(e)))?;
+    st_link
+        .attach(WireProtocol::Swd)
+        .or_else(|e| Err(Error::STLinkError(e)))?;
+
+    let aps = if aps.is_empty() { vec![0] } else { aps };
+    for &ap in &aps {
+        // The first AP is opened when attaching.
+        if ap != 0 {
+            st_link
+                .open_ap(ap)
+                .or_else(|e| Err(Error::STLinkError(e)))?;
+        }
+    }
+    let cores = aps.into_iter().map(Core::new).collect();
+
+    let listener =
+        std::net::TcpListener::bind(("127.0.0.1", port)).or_else(|e| Err(Error::IO(e)))?;
+    println!("Waiting for GDB on port {}.", port);
+
+    let regions = flash.into_iter().chain(ram).collect();
+    let mut server = GdbServer::new(&mut st_link, cores, regions);
+    if let Some(path) = elf {
+        let symbols = Symbols::load(path).or_else(|e| Err(Error::SymbolError(e)))?;
+        match FreeRtos::new(&symbols) {
+            Ok(rtos) => server.set_rtos(rtos),
+            Err(e) => println!("No FreeRTOS awareness: {:?}", e),
+        }
+    }
+    // Serve one session after the other until interrupted.
+    for stream in listener.incoming() {
+        let stream = stream.or_else(|e| Err(Error::IO(e)))?;
+        println!("GDB connected from {:?}.", stream.peer_addr());
+        match server.serve(stream) {
+            Ok(()) => println!("GDB detached."),
+            Err(GdbError::IO(e)) => println!("GDB disconnected: {}", e),
+            Err(e) => return Err(Error::GdbError(e)),
+        }
+    }
+    Ok(())
+}
+
+fn list_tasks(n: u8, elf: PathBuf) -> Result<(), Error> {
+    let symbols = Symbols::load(elf).or_else(|e| Err(Error::SymbolError(e)))?;
+    let rtos = FreeRtos::new(&symbols).or_else(|e| Err(Error::RtosError(e)))?;
+
+    let context = open_context()?;
+    let usb_device = get_device(&context, n)?;
+    let mut st_link = stlink::STLink::new(usb_device);
+    st_link.open().or_else(|e| Err(Error::STLinkError(e)))?;
+    st_link
+        .attach(WireProtocol::Swd)
+        .or_else(|e| Err(Error::STLinkError(e)))?;
+
+    // The lists are only consistent while the core is halted.
+    let core = Core::new(0);
+    let was_running = !core
+        .is_halted(&mut st_link)
+        .or_else(|e| Err(Error::STLinkError(e)))?;
+    core.halt(&mut st_link)
+        .or_else(|e| Err(Error::STLinkError(e)))?;
+    let tasks = rtos.tasks(&mut st_link, core.apsel);
+    let pc = core
+        .read_register(&mut st_link, stlink::cortex_m::registers::PC)
+        .or_else(|e| Err(Error::STLinkError(e)))?;
+    if was_running {
+        core.run(&mut st_link)
+            .or_else(|e| Err(Error::STLinkError(e)))?;
+    }
+    let tasks = tasks.or_else(|e| Err(Error::RtosError(e)))?;
+
+    println!(
+        "{:<16} {:<10} {:>8} {:>10}  LOCATION",
+        "NAME", "STATE", "PRIORITY", "STACK FREE"
+    );
+    for task in tasks {
+        let pc = task.registers.map(|registers| registers[15]).unwrap_or(pc);
+        println!(
+            "{:<16} {:<10} {:>8} {:>10}  {}",
+            task.name,
+            format!("{:?}", task.state),
+            task.priority,
+            task.stack_high_water_mark,
+            symbols.describe(pc)
+        );
+    }
+
+    st_link.close().or_else(|e| Err(Error::STLinkError(e)))?;
+    Ok(())
+}
+
+fn analyze_fault(n: u8, elf: Option<PathBuf>) -> Result<(), Error> {
+    let symbols = match elf {
+        Some(elf) => Some(Symbols::load(elf).or_else(|e| Err(Error::SymbolError(e)))?),
+        None => None,
+    };
+    let describe = |address: u32| match &symbols {
+        Some(symbols) => format!("0x{:08x} {}", address, symbols.describe(address)),
+        None => format!("0x{:08x}", address),
+    };
+
+    let context = open_context()?;
+    let usb_device = get_device(&context, n)?;
+    let mut st_link = stlink::STLink::new(usb_device);
+    st_link.open().or_else(|e| Err(Error::STLinkError(e)))?;
+    st_link
+        .attach(WireProtocol::Swd)
+        .or_else(|e| Err(Error::STLinkError(e)))?;
+
+    let core = Core::new(0);
+    if !core
+        .is_halted(&mut st_link)
+        .or_else(|e| Err(Error::STLinkError(e)))?
+    {
+        println!("Halting the running core.");
+        core.halt(&mut st_link)
+            .or_else(|e| Err(Error::STLinkError(e)))?;
+    }
+    let report = FaultReport::read(&mut st_link, core).or_else(|e| Err(Error::STLinkError(e)))?;
+    st_link.close().or_else(|e| Err(Error::STLinkError(e)))?;
+
+    if report.exception == 0 {
+        println!("The core is in thread mode.");
+    } else {
+        println!(
+            "The core is handling {} (exception {}).",
+            ExceptionNames::new().name(report.exception),
+            report.exception
+        );
+    }
+    if report.lockup {
+        println!("The core is locked up after a fault in the HardFault or NMI handler.");
+    }
+
+    println!();
+    let registers = [
+        ("CFSR", report.cfsr),
+        ("HFSR", report.hfsr),
+        ("DFSR", Some(report.dfsr)),
+        ("SFSR", report.sfsr),
+        ("MMFAR", report.mmfar),
+        ("BFAR", report.bfar),
+        ("SFAR", report.sfar),
+    ];
+    for (name, value) in registers.iter() {
+        if let Some(value) = value {
+            println!("{:<6} = 0x{:08x}", name, value);
+        }
+    }
+    for (register, name, description) in report.flags() {
+        println!("  {:<5} {:<12} {}", register, name, description);
+    }
+
+    println!();
+    match report.frame {
+        Some(frame) => {
+            println!(
+                "Exception frame on the {} stack at 0x{:08x} (EXC_RETURN = 0x{:08x}):",
+                if frame.process_stack {
+                    "process"
+                } else {
+                    "main"
+                },
+                frame.address,
+                frame.exc_return
+            );
+            let names = ["R0", "R1", "R2", "R3", "R12"];
+            for (name, value) in names.iter().zip(&frame.registers) {
+                println!("  {:<4} = 0x{:08x}", name, value);
+            }
+            println!("  {:<4} = {}", "LR", describe(frame.lr()));
+            println!("  {:<4} = {}", "PC", describe(frame.pc()));
+            println!("  {:<4} = 0x{:08x}", "xPSR", frame.xpsr());
+        }
+        None => println!("No exception frame found, LR does not hold an EXC_RETURN value."),
+    }
+    Ok(())
+}
+
+fn print_backtrace(n: u8, elf: PathBuf) -> Result<(), Error> {
+    let symbols = Symbols::load(&elf).or_else(|e| Err(Error::SymbolError(e)))?;
+    let unwinder = Unwinder::load(&elf).or_else(|e| Err(Error::BacktraceError(e)))?;
+
+    let context = open_context()?;
+    let usb_device = get_device(&context, n)?;
+    let mut st_link = stlink::STLink::new(usb_device);
+    st_link.open().or_else(|e| Err(Error::STLinkError(e)))?;
+    st_link
+        .attach(WireProtocol::Swd)
+        .or_else(|e| Err(Error::STLinkError(e)))?;
+
+    let core = Core::new(0);
+    let was_running = !core
+        .is_halted(&mut st_link)
+        .or_else(|e| Err(Error::STLinkError(e)))?;
+    core.halt(&mut st_link)
+        .or_else(|e| Err(Error::STLinkError(e)))?;
+    let frames = unwinder.backtrace(&mut st_link, core);
+    if was_running {
+        core.run(&mut st_link)
+            .or_else(|e| Err(Error::STLinkError(e)))?;
+    }
+    let frames = frames.or_else(|e| Err(Error::BacktraceError(e)))?;
+
+    for (i, frame) in frames.iter().enumerate() {
+        if frame.exception {
+            println!("      <exception entry>");
+        }
+        println!(
+            "{:>3}: 0x{:08x} {}",
+            i,
+            frame.pc,
+            symbols.describe(frame.pc)
+        );
+        if let Some(location) = &frame.location {
+            println!("           at {}:{}", location.file, location.line);
+        }
+    }
+
+    st_link.close().or_else(|e| Err(Error::STLinkError(e)))?;
+    Ok(())
+}
+
+fn print_log_frames<W: Write>(
+    w: &mut W,
+    frames: Vec<Result<LogFrame, FrameError>>,
+) -> Result<(), Error> {
+    for frame in frames {
+        match frame {
+            Ok(frame) => writeln!(w, "{}", frame).or_else(|e| Err(Error::IO(e)))?,
+            Err(e) => eprintln!("Failed to decode a defmt frame: {:?}", e),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,360 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use gimli::{
+    BaseAddresses, CfaRule, DebugFrame, EndianSlice, Register, RegisterRule, RunTimeEndian,
+    UninitializedUnwindContext, UnwindSection,
+};
+use goblin::elf::section_header::SHT_NOBITS;
+use goblin::elf::Elf;
+
+use crate::cortex_m::{registers, Core};
+use crate::defmt::{file_name, Location};
+use crate::memory::MemoryAccess;
+use crate::stlink::{AccessPort, STLinkError};
+
+/// Sanity limit for the number of frames, to not follow a corrupted stack forever.
+const MAX_FRAMES: usize = 64;
+
+/// The DWARF numbers of the registers are the ones of the core.
+const SP: usize = 13;
+const LR: usize = 14;
+const PC: usize = 15;
+
+/// EXC_RETURN values have all of the upper byte set.
+const EXC_RETURN_PREFIX: u32 = 0xff00_0000;
+/// The value of LR out of reset, which marks the end of the stack.
+const LR_RESET: u32 = 0xffff_ffff;
+/// EXC_RETURN bit which is set if the frame is on the process stack.
+const EXC_RETURN_SPSEL: u32 = 1 << 2;
+/// EXC_RETURN bit which is cleared if the exception frame includes the FPU registers.
+const EXC_RETURN_STANDARD_FRAME: u32 = 1 << 4;
+/// xPSR bit which is set if the exception entry aligned the stack by an extra word.
+const XPSR_STACK_ALIGNED: u32 = 1 << 9;
+
+#[derive(Debug)]
+pub enum BacktraceError {
+    IO(std::io::Error),
+    Elf(goblin::error::Error),
+    Dwarf(gimli::Error),
+    STLink(STLinkError),
+    /// A section reaches past the end of the ELF file.
+    TruncatedSection,
+}
+
+impl From<STLinkError> for BacktraceError {
+    fn from(e: STLinkError) -> Self {
+        BacktraceError::STLink(e)
+    }
+}
+
+impl From<gimli::Error> for BacktraceError {
+    fn from(e: gimli::Error) -> Self {
+        BacktraceError::Dwarf(e)
+    }
+}
+
+/// A frame of the call stack.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StackFrame {
+    /// The current PC in the innermost frame and the return address in all others.
+    pub pc: u32,
+    /// Whether the frame was interrupted by an exception, rather than calling the frame above.
+    pub exception: bool,
+    pub location: Option<Location>,
+}
+
+/// Unwinds the stack of a halted core with the call frame information of its firmware.
+pub struct Unwinder {
+    debug_frame: Vec<u8>,
+    endian: RunTimeEndian,
+    /// The rows of the line tables sorted by address, with `None` ending a sequence.
+    lines: Vec<(u32, Option<Location>)>,
+}
+
+impl Unwinder {
+    /// Reads the `.debug_frame` and line tables of the ELF at `path`.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, BacktraceError> {
+        let data = std::fs::read(path).or_else(|e| Err(BacktraceError::IO(e)))?;
+        Self::from_elf(&data)
+    }
+
+    pub fn from_elf(data: &[u8]) -> Result<Self, BacktraceError> {
+        let elf = Elf::parse(data).or_else(|e| Err(BacktraceError::Elf(e)))?;
+        let endian = if elf.little_endian {
+            RunTimeEndian::Little
+        } else {
+            RunTimeEndian::Big
+        };
+        let load_section =
+            |id: gimli::SectionId| -> Result<EndianSlice<RunTimeEndian>, BacktraceError> {
+                let section = elf.section_headers.iter().find(|header| {
+                    match elf.shdr_strtab.get(header.sh_name) {
+                        Some(Ok(name)) => name == id.name(),
+                        _ => false,
+                    }
+                });
+                let bytes = match section {
+                    Some(header) if header.sh_type != SHT_NOBITS => {
+                        let start = header.sh_offset as usize;
+                        start
+                            .checked_add(header.sh_size as usize)
+                            .and_then(|end| data.get(start..end))
+                            .ok_or(BacktraceError::TruncatedSection)?
+                    }
+                    _ => &[],
+                };
+                Ok(EndianSlice::new(bytes, endian))
+            };
+        let load_supplementary = |_| Ok(EndianSlice::new(&[], endian));
+        let dwarf = gimli::Dwarf::load(load_section, load_supplementary)?;
+
+        let mut lines = vec![];
+        let mut units = dwarf.units();
+        while let Some(header) = units.next()? {
+            let unit = dwarf.unit(header)?;
+            let program = match &unit.line_program {
+                Some(program) => program.clone(),
+                None => continue,
+            };
+            let mut files = HashMap::new();
+            let mut rows = program.rows();
+            while let Some((_, row)) = rows.next_row()? {
+                let address = row.address() as u32;
+                if row.end_sequence() {
+                    lines.push((address, None));
+                    continue;
+                }
+                let file = match files.get(&row.file_index()) {
+                    Some(file) => file,
+                    None => files.entry(row.file_index()).or_insert(file_name(
+                        &dwarf,
+                        &unit,
+                        row.file_index(),
+                    )?),
+                };
+                let location = match (file, row.line()) {
+                    (Some(file), Some(line)) => Some(Location {
+                        file: file.clone(),
+                        line,
+                    }),
+                    _ => None,
+                };
+                lines.push((address, location));
+            }
+        }
+        // A sequence may start where another one ends.
+        lines.sort_by_key(|(address, location)| (*address, location.is_some()));
+
+        Ok(Self {
+            debug_frame: load_section(gimli::SectionId::DebugFrame)?.slice().to_vec(),
+            endian,
+            lines,
+        })
+    }
+
+    /// Returns the source location of the instruction at `address`.
+    pub fn location(&self, address: u32) -> Option<&Location> {
+        let index = match self.lines.binary_search_by(|&(row, _)| {
+            if row <= address {
+                std::cmp::Ordering::Less
+            } else {
+                std::cmp::Ordering::Greater
+            }
+        }) {
+            Ok(index) | Err(index) => index,
+        };
+        match index {
+            0 => None,
+            index => self.lines[index - 1].1.as_ref(),
+        }
+    }
+
+    /// Unwinds the stack of `core`, which has to be halted.
+    pub fn backtrace<M: MemoryAccess>(
+        &self,
+        mem: &mut M,
+        core: Core,
+    ) -> Result<Vec<StackFrame>, BacktraceError> {
+        let mut values = [0; 16];
+        for (register, value) in values.iter_mut().enumerate() {
+            *value = core.read_register(mem, register as u16)?;
+        }
+        let psp = core.read_register(mem, registers::PSP)?;
+        self.unwind(mem, core.apsel, values, psp)
+    }
+
+    /// Unwinds the stack from the given values of R0 to R15 and the process stack pointer.
+    pub fn unwind<M: MemoryAccess>(
+        &self,
+        mem: &mut M,
+        apsel: AccessPort,
+        values: [u32; 16],
+        psp: u32,
+    ) -> Result<Vec<StackFrame>, BacktraceError> {
+        let mut debug_frame = DebugFrame::new(&self.debug_frame, self.endian);
+        debug_frame.set_address_size(4);
+        let bases = BaseAddresses::default();
+        let mut ctx = UninitializedUnwindContext::new();
+
+        let mut registers: Vec<Option<u32>> = values.iter().map(|&value| Some(value)).collect();
+        let mut frames: Vec<StackFrame> = vec![];
+        // Return addresses point behind the call, which may be the last instruction of a function.
+        let mut return_address = false;
+        let mut exception = false;
+        while frames.len() < MAX_FRAMES {
+            let pc = match registers[PC] {
+                Some(pc) => pc & !1,
+                None => break,
+            };
+            let lookup = if return_address {
+                pc.wrapping_sub(1)
+            } else {
+                pc
+            };
+            frames.push(StackFrame {
+                pc,
+                exception,
+                location: self.location(lookup).cloned(),
+            });
+
+            let row = match debug_frame.unwind_info_for_address(
+                &bases,
+                &mut ctx,
+                u64::from(lookup),
+                DebugFrame::cie_from_offset,
+            ) {
+                Ok(row) => row,
+                Err(gimli::Error::NoUnwindInfoForAddress) => break,
+                Err(e) => return Err(e.into()),
+            };
+            let cfa = match row.cfa() {
+                CfaRule::RegisterAndOffset { register, offset } => {
+                    match registers.get(register.0 as usize).and_then(|&value| value) {
+                        Some(value) => (i64::from(value) + offset) as u32,
+                        None => break,
+                    }
+                }
+                CfaRule::Expression(_) => break,
+            };
+
+            let mut caller = registers.clone();
+            for (number, value) in caller.iter_mut().enumerate() {
+                *value = match row.register(Register(number as u16)) {
+                    RegisterRule::Undefined | RegisterRule::SameValue => *value,
+                    RegisterRule::Offset(offset) => {
+                        Some(mem.read_word32((i64::from(cfa) + offset) as u32, apsel)?)
+                    }
+                    RegisterRule::ValOffset(offset) => Some((i64::from(cfa) + offset) as u32),
+                    RegisterRule::Register(register) => {
+                        registers.get(register.0 as usize).and_then(|&value| value)
+                    }
+                    _ => None,
+                };
+            }
+            caller[SP] = Some(cfa);
+            // The call overwrote LR, so it is only known if the caller saved it.
+            caller[PC] = caller[LR].take();
+
+            let return_to = match caller[PC] {
+                Some(LR_RESET) | None => break,
+                Some(address) => address,
+            };
+            if return_to & EXC_RETURN_PREFIX == EXC_RETURN_PREFIX {
+                // The handler returns to the context it interrupted, which the core stacked.
+                let frame = if return_to & EXC_RETURN_SPSEL != 0 {
+                    psp
+                } else {
+                    cfa
+                };
+                let data = mem.read_mem32(frame, 8 * 4, apsel)?;
+                let words: Vec<u32> = data
+                    .chunks(4)
+                    .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+                    .collect();
+                for (&register, &value) in [0, 1, 2, 3, 12, LR, PC].iter().zip(&words) {
+                    caller[register] = Some(value);
+                }
+                for register in 4..12 {
+                    caller[register] = caller[register].or(registers[register]);
+                }
+                let mut sp = frame + 8 * 4;
+                if return_to & EXC_RETURN_STANDARD_FRAME == 0 {
+                    // S0 to S15, FPSCR and a reserved word.
+                    sp += 18 * 4;
+                }
+                if words[7] & XPSR_STACK_ALIGNED != 0 {
+                    sp += 4;
+                }
+                caller[SP] = Some(sp);
+                return_address = false;
+                exception = true;
+            } else {
+                return_address = true;
+                exception = false;
+            }
+
+            // A frame which does not move on would be unwound forever.
+            if caller[SP] == registers[SP] && caller[PC] == registers[PC] {
+                break;
+            }
+            registers = caller;
+        }
+        Ok(frames)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::mock::Memory;
+
+    /// A `.debug_frame` with one FDE for 0x0800_0100..0x0800_0120,
+    /// which starts with `push {r7, lr}`.
+    #[rustfmt::skip]
+    const DEBUG_FRAME: [u8; 40] = [
+        // CIE: version 1, code alignment 2, data alignment -4, return address in r14,
+        // CFA = r13.
+        0x0c, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff, 0x01, 0x00, 0x02, 0x7c,
+        0x0e, 0x0c, 0x0d, 0x00,
+        // FDE: advance by 2, CFA = r13 + 8, r14 at CFA - 4, r7 at CFA - 8.
+        0x14, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x08,
+        0x20, 0x00, 0x00, 0x00, 0x41, 0x0e, 0x08, 0x8e, 0x01, 0x87, 0x02, 0x00,
+    ];
+
+    #[test]
+    fn unwinds_calls_and_exceptions() {
+        let unwinder = Unwinder {
+            debug_frame: DEBUG_FRAME.to_vec(),
+            endian: RunTimeEndian::Little,
+            lines: vec![
+                (
+                    0x0800_0100,
+                    Some(Location {
+                        file: "main.rs".to_owned(),
+                        line: 7,
+                    }),
+                ),
+                (0x0800_0120, None),
+            ],
+        };
+        let mut values = [0; 16];
+        values[SP] = 0x2000_0f00;
+        values[PC] = 0x0800_0110;
+
+        // Called from 0x0800_0200.
+        let mut ram = Memory::new();
+        ram.set_word(0x2000_0f04, 0x0800_0205);
+        let frames = unwinder.unwind(&mut ram, 0, values, 0).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].location.as_ref().map(|l| l.line), Some(7));
+        assert_eq!((frames[1].pc, frames[1].exception), (0x0800_0204, false));
+
+        // Entered as a handler of an exception which interrupted 0x0800_0300.
+        ram.set_word(0x2000_0f04, 0xffff_fff9);
+        ram.set_word(0x2000_0f08 + 6 * 4, 0x0800_0300);
+        let frames = unwinder.unwind(&mut ram, 0, values, 0).unwrap();
+        assert_eq!((frames[1].pc, frames[1].exception), (0x0800_0300, true));
+        assert_eq!(frames[1].location, None);
+    }
+}
@@ -18,6 +18,25 @@ pub mod commands {
     pub const DFU_EXIT: u8 = 0x07;
     pub const SWIM_EXIT: u8 = 0x01;
 
+    // SWIM commands.
+    pub const SWIM_ENTER: u8 = 0x00;
+    pub const SWIM_READ_CAP: u8 = 0x02;
+    pub const SWIM_SPEED: u8 = 0x03;
+    pub const SWIM_ENTER_SEQ: u8 = 0x04;
+    pub const SWIM_GEN_RST: u8 = 0x05;
+    pub const SWIM_RESET: u8 = 0x06;
+    pub const SWIM_ASSERT_RESET: u8 = 0x07;
+    pub const SWIM_DEASSERT_RESET: u8 = 0x08;
+    pub const SWIM_READSTATUS: u8 = 0x09;
+    pub const SWIM_WRITEMEM: u8 = 0x0a;
+    pub const SWIM_READMEM: u8 = 0x0b;
+    pub const SWIM_READBUF: u8 = 0x0c;
+    pub const SWIM_READ_BUFFERSIZE: u8 = 0x0d;
+
+    // Status bytes returned by SWIM_READSTATUS.
+    pub const SWIM_OK: u8 = 0x00;
+    pub const SWIM_BUSY: u8 = 0x01;
+
     // JTAG commands.
     pub const JTAG_READMEM_32BIT: u8 = 0x07;
     pub const JTAG_WRITEMEM_32BIT: u8 = 0x08;
@@ -25,6 +44,7 @@ pub mod commands {
     pub const JTAG_WRITEMEM_8BIT: u8 = 0x0d;
     pub const JTAG_EXIT: u8 = 0x21;
     pub const JTAG_ENTER2: u8 = 0x30;
+    pub const JTAG_READ_IDCODES: u8 = 0x31;
     pub const JTAG_GETLASTRWSTATUS2: u8 = 0x3e; // From V2J15
     pub const JTAG_DRIVE_NRST: u8 = 0x3c;
     pub const SWV_START_TRACE_RECEPTION: u8 = 0x40;
@@ -43,7 +63,7 @@ pub mod commands {
     
     // Parameters for JTAG_ENTER2.
     pub const JTAG_ENTER_SWD: u8 = 0xa3;
-    pub const JTAG_ENTER_JTAG_NO_CORE_RESET: u8 = 0xa3;
+    pub const JTAG_ENTER_JTAG_NO_CORE_RESET: u8 = 0xa4;
 
     // Parameters for JTAG_DRIVE_NRST.
     pub const JTAG_DRIVE_NRST_LOW: u8 = 0x00;
@@ -0,0 +1,641 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::memory::MemoryAccess;
+use crate::stlink::{AccessPort, STLinkError};
+
+/// Numbers of the core registers as used by DCRSR.
+pub mod registers {
+    pub const R0: u16 = 0;
+    pub const R1: u16 = 1;
+    pub const R2: u16 = 2;
+    pub const R3: u16 = 3;
+    pub const R7: u16 = 7;
+    pub const SP: u16 = 13;
+    pub const LR: u16 = 14;
+    pub const PC: u16 = 15;
+    pub const XPSR: u16 = 16;
+    pub const MSP: u16 = 17;
+    pub const PSP: u16 = 18;
+    /// CONTROL, FAULTMASK, BASEPRI and PRIMASK packed into one word.
+    pub const SPECIAL: u16 = 20;
+}
+
+pub(crate) const DFSR: u32 = 0xe000_ed30;
+const DFSR_HALTED: u32 = 1 << 0;
+pub(crate) const DFSR_BKPT: u32 = 1 << 1;
+const DFSR_DWTTRAP: u32 = 1 << 2;
+const DFSR_VCATCH: u32 = 1 << 3;
+const DFSR_EXTERNAL: u32 = 1 << 4;
+
+const DHCSR: u32 = 0xe000_edf0;
+const DHCSR_DBGKEY: u32 = 0xa05f << 16;
+const DHCSR_C_DEBUGEN: u32 = 1 << 0;
+const DHCSR_C_HALT: u32 = 1 << 1;
+const DHCSR_C_STEP: u32 = 1 << 2;
+const DHCSR_C_MASKINTS: u32 = 1 << 3;
+const DHCSR_S_REGRDY: u32 = 1 << 16;
+const DHCSR_S_HALT: u32 = 1 << 17;
+const DHCSR_S_LOCKUP: u32 = 1 << 19;
+/// Set if the core was reset since the last read of DHCSR.
+const DHCSR_S_RESET_ST: u32 = 1 << 25;
+
+const DCRSR: u32 = 0xe000_edf4;
+const DCRSR_REGWNR: u32 = 1 << 16;
+const DCRDR: u32 = 0xe000_edf8;
+
+pub(crate) const DEMCR: u32 = 0xe000_edfc;
+const DEMCR_VC_CORERESET: u32 = 1 << 0;
+/// Enables the DWT and ITM.
+pub(crate) const DEMCR_TRCENA: u32 = 1 << 24;
+
+const AIRCR: u32 = 0xe000_ed0c;
+const AIRCR_VECTKEY: u32 = 0x05fa << 16;
+const AIRCR_SYSRESETREQ: u32 = 1 << 2;
+
+pub(crate) const CPUID: u32 = 0xe000_ed00;
+/// Only implemented with the CPUID scheme, not on ARMv6-M.
+const ID_PFR0: u32 = 0xe000_ed40;
+const MPU_TYPE: u32 = 0xe000_ed90;
+const MVFR0: u32 = 0xe000_ef40;
+const MVFR1: u32 = 0xe000_ef44;
+/// Only implemented on ARMv8-M.
+const DAUTHSTATUS: u32 = 0xe000_efb8;
+const FP_CTRL: u32 = 0xe000_2000;
+const DWT_CTRL: u32 = 0xe000_1000;
+
+/// How long to wait for the core to halt after a request.
+const HALT_TIMEOUT: Duration = Duration::from_millis(500);
+/// How long to wait for a core register transfer.
+const REGISTER_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Why the core entered debug state, as reported by DFSR.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HaltReason {
+    /// A `BKPT` instruction or a FPB breakpoint.
+    Breakpoint,
+    /// A DWT watchpoint.
+    Watchpoint,
+    VectorCatch,
+    /// The external debug request signal.
+    External,
+    /// A halt request or the end of a single step.
+    Request,
+    Unknown,
+}
+
+/// The core implementation, as given by the part number of CPUID.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CoreType {
+    M0,
+    M0Plus,
+    M3,
+    M4,
+    M7,
+    M23,
+    M33,
+    M55,
+    Unknown(u16),
+}
+
+impl CoreType {
+    fn from_part_number(part_number: u16) -> Self {
+        match part_number {
+            0xc20 => CoreType::M0,
+            0xc60 => CoreType::M0Plus,
+            0xc23 => CoreType::M3,
+            0xc24 => CoreType::M4,
+            0xc27 => CoreType::M7,
+            0xd20 => CoreType::M23,
+            0xd21 => CoreType::M33,
+            0xd22 => CoreType::M55,
+            _ => CoreType::Unknown(part_number),
+        }
+    }
+
+    pub fn architecture(self) -> Architecture {
+        match self {
+            CoreType::M0 | CoreType::M0Plus => Architecture::V6M,
+            CoreType::M3 | CoreType::M4 | CoreType::M7 => Architecture::V7M,
+            CoreType::M23 => Architecture::V8MBaseline,
+            CoreType::M33 => Architecture::V8MMainline,
+            CoreType::M55 => Architecture::V81MMainline,
+            CoreType::Unknown(_) => Architecture::Unknown,
+        }
+    }
+}
+
+impl fmt::Display for CoreType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            CoreType::M0 => "Cortex-M0",
+            CoreType::M0Plus => "Cortex-M0+",
+            CoreType::M3 => "Cortex-M3",
+            CoreType::M4 => "Cortex-M4",
+            CoreType::M7 => "Cortex-M7",
+            CoreType::M23 => "Cortex-M23",
+            CoreType::M33 => "Cortex-M33",
+            CoreType::M55 => "Cortex-M55",
+            CoreType::Unknown(part_number) => {
+                return write!(f, "Unknown core (part number 0x{:03x})", part_number)
+            }
+        };
+        f.pad(name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Architecture {
+    V6M,
+    V7M,
+    V8MBaseline,
+    V8MMainline,
+    V81MMainline,
+    Unknown,
+}
+
+impl Architecture {
+    /// Whether the architecture has the Main Extension, which brings the CPUID scheme
+    /// and FPU feature registers.
+    pub(crate) fn is_mainline(self) -> bool {
+        matches!(
+            self,
+            Architecture::V7M | Architecture::V8MMainline | Architecture::V81MMainline
+        )
+    }
+}
+
+impl fmt::Display for Architecture {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Architecture::V6M => "ARMv6-M",
+            Architecture::V7M => "ARMv7-M",
+            Architecture::V8MBaseline => "ARMv8-M Baseline",
+            Architecture::V8MMainline => "ARMv8-M Mainline",
+            Architecture::V81MMainline => "ARMv8.1-M Mainline",
+            Architecture::Unknown => "Unknown",
+        };
+        f.pad(name)
+    }
+}
+
+/// The floating point unit, as described by MVFR0 and MVFR1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fpu {
+    pub double_precision: bool,
+    /// Whether it converts between half and single precision.
+    pub half_precision: bool,
+}
+
+/// What a core is and which debug and system features it implements.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CoreInfo {
+    pub cpuid: u32,
+    pub core_type: CoreType,
+    /// The N of the rNpM revision.
+    pub revision: u8,
+    /// The M of the rNpM revision.
+    pub patch: u8,
+    pub architecture: Architecture,
+    pub fpu: Option<Fpu>,
+    pub trustzone: bool,
+    /// The number of MPU regions, zero without an MPU.
+    pub mpu_regions: u8,
+    /// The number of FPB instruction comparators.
+    pub breakpoints: u8,
+    /// The number of DWT comparators.
+    pub watchpoints: u8,
+}
+
+/// A Cortex-M core behind the MEM-AP `apsel`.
+#[derive(Debug, Clone, Copy)]
+pub struct Core {
+    pub apsel: AccessPort,
+}
+
+impl Core {
+    pub fn new(apsel: AccessPort) -> Self {
+        Self { apsel }
+    }
+
+    pub fn is_halted<M: MemoryAccess>(&self, mem: &mut M) -> Result<bool, STLinkError> {
+        Ok(mem.read_word32(DHCSR, self.apsel)? & DHCSR_S_HALT != 0)
+    }
+
+    /// Whether the core is locked up after a fault it could not handle.
+    pub fn is_locked_up<M: MemoryAccess>(&self, mem: &mut M) -> Result<bool, STLinkError> {
+        Ok(mem.read_word32(DHCSR, self.apsel)? & DHCSR_S_LOCKUP != 0)
+    }
+
+    /// Halts the core and waits until it entered debug state.
+    pub fn halt<M: MemoryAccess>(&self, mem: &mut M) -> Result<(), STLinkError> {
+        mem.write_word32(
+            DHCSR,
+            DHCSR_DBGKEY | DHCSR_C_DEBUGEN | DHCSR_C_HALT,
+            self.apsel,
+        )?;
+        self.wait_for_halt(mem, HALT_TIMEOUT)
+    }
+
+    /// Lets the core run.
+    pub fn run<M: MemoryAccess>(&self, mem: &mut M) -> Result<(), STLinkError> {
+        mem.write_word32(DHCSR, DHCSR_DBGKEY | DHCSR_C_DEBUGEN, self.apsel)
+    }
+
+    /// Executes a single instruction with interrupts masked.
+    pub fn step<M: MemoryAccess>(&self, mem: &mut M) -> Result<(), STLinkError> {
+        // C_MASKINTS may only change while the core is halted.
+        mem.write_word32(
+            DHCSR,
+            DHCSR_DBGKEY | DHCSR_C_DEBUGEN | DHCSR_C_HALT | DHCSR_C_MASKINTS,
+            self.apsel,
+        )?;
+        mem.write_word32(
+            DHCSR,
+            DHCSR_DBGKEY | DHCSR_C_DEBUGEN | DHCSR_C_STEP | DHCSR_C_MASKINTS,
+            self.apsel,
+        )?;
+        self.wait_for_halt(mem, HALT_TIMEOUT)?;
+        mem.write_word32(
+            DHCSR,
+            DHCSR_DBGKEY | DHCSR_C_DEBUGEN | DHCSR_C_HALT,
+            self.apsel,
+        )
+    }
+
+    pub fn wait_for_halt<M: MemoryAccess>(
+        &self,
+        mem: &mut M,
+        timeout: Duration,
+    ) -> Result<(), STLinkError> {
+        let instant = Instant::now();
+        while !self.is_halted(mem)? {
+            if instant.elapsed() > timeout {
+                return Err(STLinkError::CoreTimeout);
+            }
+        }
+        Ok(())
+    }
+
+    /// Resets the whole system and halts the core before it executes the first instruction.
+    pub fn reset_and_halt<M: MemoryAccess>(&self, mem: &mut M) -> Result<(), STLinkError> {
+        // The vector catch needs halting debug, which is enabled without touching C_HALT.
+        // Reading DHCSR also clears a stale S_RESET_ST.
+        if mem.read_word32(DHCSR, self.apsel)? & DHCSR_C_DEBUGEN == 0 {
+            mem.write_word32(DHCSR, DHCSR_DBGKEY | DHCSR_C_DEBUGEN, self.apsel)?;
+        }
+        let demcr = mem.read_word32(DEMCR, self.apsel)?;
+        mem.write_word32(DEMCR, demcr | DEMCR_VC_CORERESET, self.apsel)?;
+        mem.write_word32(AIRCR, AIRCR_VECTKEY | AIRCR_SYSRESETREQ, self.apsel)?;
+
+        // A core halted before the request still reports S_HALT until the reset takes
+        // effect, so only trust S_HALT once the reset was seen.
+        // The debug port may not answer while the reset is in progress.
+        let instant = Instant::now();
+        let mut reset = false;
+        loop {
+            match mem.read_word32(DHCSR, self.apsel) {
+                Ok(dhcsr) => {
+                    reset |= dhcsr & DHCSR_S_RESET_ST != 0;
+                    if reset && dhcsr & DHCSR_S_HALT != 0 {
+                        break;
+                    }
+                }
+                Err(STLinkError::TransferFault(..)) => (),
+                Err(e) => return Err(e),
+            }
+            if instant.elapsed() > HALT_TIMEOUT {
+                return Err(STLinkError::CoreTimeout);
+            }
+        }
+        mem.write_word32(DEMCR, demcr & !DEMCR_VC_CORERESET, self.apsel)
+    }
+
+    /// Reads and clears the reason of the last halt.
+    pub fn halt_reason<M: MemoryAccess>(&self, mem: &mut M) -> Result<HaltReason, STLinkError> {
+        let dfsr = mem.read_word32(DFSR, self.apsel)?;
+        // The flags are cleared by writing ones.
+        mem.write_word32(DFSR, dfsr, self.apsel)?;
+        Ok(if dfsr & DFSR_BKPT != 0 {
+            HaltReason::Breakpoint
+        } else if dfsr & DFSR_DWTTRAP != 0 {
+            HaltReason::Watchpoint
+        } else if dfsr & DFSR_VCATCH != 0 {
+            HaltReason::VectorCatch
+        } else if dfsr & DFSR_EXTERNAL != 0 {
+            HaltReason::External
+        } else if dfsr & DFSR_HALTED != 0 {
+            HaltReason::Request
+        } else {
+            HaltReason::Unknown
+        })
+    }
+
+    /// Identifies the core from its system control and debug registers.
+    /// The core does not have to be halted.
+    pub fn info<M: MemoryAccess>(&self, mem: &mut M) -> Result<CoreInfo, STLinkError> {
+        let cpuid = mem.read_word32(CPUID, self.apsel)?;
+        let core_type = CoreType::from_part_number((cpuid >> 4 & 0xfff) as u16);
+        let architecture = match core_type.architecture() {
+            // ARMv6-M is the only architecture with its own CPUID.ARCHITECTURE.
+            Architecture::Unknown if cpuid >> 16 & 0xf == 0xc => Architecture::V6M,
+            architecture => architecture,
+        };
+
+        // Parts this does not know use the CPUID scheme, where ID_PFR0.State1 tells whether the
+        // instruction set includes Thumb-2 and so the Main Extension.
+        let mainline = match architecture {
+            Architecture::Unknown if cpuid >> 16 & 0xf == 0xf => {
+                mem.read_word32(ID_PFR0, self.apsel)? >> 4 & 0xf == 0x3
+            }
+            architecture => architecture.is_mainline(),
+        };
+
+        let fpu = if mainline {
+            let mvfr0 = mem.read_word32(MVFR0, self.apsel)?;
+            let mvfr1 = mem.read_word32(MVFR1, self.apsel)?;
+            if mvfr0 >> 4 & 0xf != 0 {
+                Some(Fpu {
+                    double_precision: mvfr0 >> 8 & 0xf != 0,
+                    half_precision: mvfr1 >> 24 & 0xf != 0,
+                })
+            } else {
+                None
+            }
+        } else {
+            None
+        };
+
+        // The secure debug fields of DAUTHSTATUS read as zero without the Security Extension.
+        let trustzone = match architecture {
+            Architecture::V8MBaseline | Architecture::V8MMainline | Architecture::V81MMainline => {
+                mem.read_word32(DAUTHSTATUS, self.apsel)? & 0xf0 != 0
+            }
+            _ => false,
+        };
+
+        let mpu_regions = (mem.read_word32(MPU_TYPE, self.apsel)? >> 8) as u8;
+        let fp_ctrl = mem.read_word32(FP_CTRL, self.apsel)?;
+        let breakpoints = ((fp_ctrl >> 8) & 0x70 | (fp_ctrl >> 4) & 0xf) as u8;
+
+        // The DWT cannot be read while TRCENA is clear.
+        let demcr = mem.read_word32(DEMCR, self.apsel)?;
+        mem.write_word32(DEMCR, demcr | DEMCR_TRCENA, self.apsel)?;
+        let watchpoints = (mem.read_word32(DWT_CTRL, self.apsel)? >> 28) as u8;
+        mem.write_word32(DEMCR, demcr, self.apsel)?;
+
+        Ok(CoreInfo {
+            cpuid,
+            core_type,
+            revision: (cpuid >> 20 & 0xf) as u8,
+            patch: (cpuid & 0xf) as u8,
+            architecture,
+            fpu,
+            trustzone,
+            mpu_regions,
+            breakpoints,
+            watchpoints,
+        })
+    }
+
+    /// Reads a core register. The core has to be halted.
+    pub fn read_register<M: MemoryAccess>(
+        &self,
+        mem: &mut M,
+        register: u16,
+    ) -> Result<u32, STLinkError> {
+        mem.write_word32(DCRSR, u32::from(register), self.apsel)?;
+        self.wait_for_register(mem)?;
+        mem.read_word32(DCRDR, self.apsel)
+    }
+
+    /// Writes a core register. The core has to be halted.
+    pub fn write_register<M: MemoryAccess>(
+        &self,
+        mem: &mut M,
+        register: u16,
+        value: u32,
+    ) -> Result<(), STLinkError> {
+        mem.write_word32(DCRDR, value, self.apsel)?;
+        mem.write_word32(DCRSR, DCRSR_REGWNR | u32::from(register), self.apsel)?;
+        self.wait_for_register(mem)
+    }
+
+    fn wait_for_register<M: MemoryAccess>(&self, mem: &mut M) -> Result<(), STLinkError> {
+        let instant = Instant::now();
+        while mem.read_word32(DHCSR, self.apsel)? & DHCSR_S_REGRDY == 0 {
+            if instant.elapsed() > REGISTER_TIMEOUT {
+                return Err(STLinkError::CoreTimeout);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A simulated core for the tests of the debug helpers.
+#[cfg(test)]
+pub(crate) mod mock {
+    use super::*;
+    use crate::memory::mock::Memory;
+
+    /// Runs the code of the target on its memory and core registers.
+    pub type Firmware = Box<dyn FnMut(&mut Memory, &mut [u32; 32])>;
+
+    /// A core which executes `firmware` in one go whenever it was resumed and is polled for the halt.
+    pub struct Target {
+        pub memory: Memory,
+        /// The core registers by their DCRSR selector.
+        pub registers: [u32; 32],
+        pub firmware: Firmware,
+        /// How often the core was resumed, single steps included.
+        pub resumed: usize,
+        running: bool,
+    }
+
+    impl Target {
+        pub fn new() -> Self {
+            Self {
+                memory: Memory::new(),
+                registers: [0; 32],
+                firmware: Box::new(|_, _| ()),
+                resumed: 0,
+                running: false,
+            }
+        }
+    }
+
+    impl MemoryAccess for Target {
+        fn read_mem32(
+            &mut self,
+            addr: u32,
+            size: u32,
+            apsel: AccessPort,
+        ) -> Result<Vec<u8>, STLinkError> {
+            if addr == DHCSR {
+                if self.running {
+                    self.running = false;
+                    (self.firmware)(&mut self.memory, &mut self.registers);
+                }
+                let dhcsr = DHCSR_S_HALT | DHCSR_S_REGRDY | DHCSR_C_DEBUGEN;
+                return Ok(dhcsr.to_le_bytes().to_vec());
+            }
+            self.memory.read_mem32(addr, size, apsel)
+        }
+
+        fn write_mem32(
+            &mut self,
+            addr: u32,
+            data: Vec<u8>,
+            apsel: AccessPort,
+        ) -> Result<(), STLinkError> {
+            let value = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+            let register = value as usize & 0x1f;
+            match addr {
+                DHCSR => {
+                    self.running = value & DHCSR_C_HALT == 0;
+                    if self.running {
+                        self.resumed += 1;
+                    }
+                }
+                // The flags of DFSR are cleared by writing ones.
+                DFSR => {
+                    let dfsr = self.memory.word(DFSR);
+                    self.memory.set_word(DFSR, dfsr & !value);
+                }
+                DCRSR if value & DCRSR_REGWNR != 0 => {
+                    self.registers[register] = self.memory.word(DCRDR)
+                }
+                DCRSR => self.memory.set_word(DCRDR, self.registers[register]),
+                _ => self.memory.write_mem32(addr, data, apsel)?,
+            }
+            Ok(())
+        }
+
+        fn read_mem16(
+            &mut self,
+            addr: u32,
+            size: u32,
+            apsel: AccessPort,
+        ) -> Result<Vec<u8>, STLinkError> {
+            self.memory.read_mem16(addr, size, apsel)
+        }
+
+        fn write_mem16(
+            &mut self,
+            addr: u32,
+            data: Vec<u8>,
+            apsel: AccessPort,
+        ) -> Result<(), STLinkError> {
+            self.memory.write_mem16(addr, data, apsel)
+        }
+
+        fn read_mem8(
+            &mut self,
+            addr: u32,
+            size: u32,
+            apsel: AccessPort,
+        ) -> Result<Vec<u8>, STLinkError> {
+            self.memory.read_mem8(addr, size, apsel)
+        }
+
+        fn write_mem8(
+            &mut self,
+            addr: u32,
+            data: Vec<u8>,
+            apsel: AccessPort,
+        ) -> Result<(), STLinkError> {
+            self.memory.write_mem8(addr, data, apsel)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::mock::Memory;
+
+    fn info(cpuid: u32, setup: impl Fn(&mut Memory)) -> CoreInfo {
+        let mut mem = Memory::new();
+        mem.set_word(CPUID, cpuid);
+        setup(&mut mem);
+        Core::new(0).info(&mut mem).unwrap()
+    }
+
+    #[test]
+    fn identifies_cores() {
+        let m0plus = info(0x410c_c601, |_| ());
+        assert_eq!(m0plus.core_type, CoreType::M0Plus);
+        assert_eq!(m0plus.architecture, Architecture::V6M);
+        assert_eq!((m0plus.revision, m0plus.patch), (0, 1));
+        assert_eq!(m0plus.fpu, None);
+
+        let m7 = info(0x411f_c272, |_| ());
+        assert_eq!(m7.core_type, CoreType::M7);
+        assert_eq!(m7.architecture, Architecture::V7M);
+        assert_eq!((m7.revision, m7.patch), (1, 2));
+
+        let unknown = info(0x410c_c991, |_| ());
+        assert_eq!(unknown.core_type, CoreType::Unknown(0xc99));
+        assert_eq!(unknown.architecture, Architecture::V6M);
+    }
+
+    #[test]
+    fn decodes_features() {
+        let m4 = info(0x410f_c241, |mem| {
+            mem.set_word(MVFR0, 0x1011_0021);
+            mem.set_word(MVFR1, 0x1100_0011);
+            mem.set_word(MPU_TYPE, 8 << 8);
+            mem.set_word(FP_CTRL, 0x6 << 4);
+            mem.set_word(DWT_CTRL, 4 << 28);
+        });
+        let fpu = Fpu {
+            double_precision: false,
+            half_precision: true,
+        };
+        assert_eq!(m4.fpu, Some(fpu));
+        assert_eq!(m4.mpu_regions, 8);
+        assert_eq!(m4.breakpoints, 6);
+        assert_eq!(m4.watchpoints, 4);
+        assert!(!m4.trustzone);
+
+        let m33 = info(0x410f_d214, |mem| {
+            mem.set_word(MVFR0, 0x1011_0221);
+            mem.set_word(DAUTHSTATUS, 0xff);
+            mem.set_word(FP_CTRL, 0x1 << 12);
+        });
+        assert_eq!(m33.fpu.map(|fpu| fpu.double_precision), Some(true));
+        assert!(m33.trustzone);
+        assert_eq!(m33.breakpoints, 0x10);
+
+        // ARMv6-M has no MVFR0, whatever it reads as.
+        let m0 = info(0x410c_c200, |mem| mem.set_word(MVFR0, 0x21));
+        assert_eq!(m0.fpu, None);
+    }
+
+    #[test]
+    fn restores_demcr() {
+        let mut mem = Memory::new();
+        mem.set_word(CPUID, 0x410f_c241);
+        Core::new(0).info(&mut mem).unwrap();
+        // TRCENA is only set while the DWT is read.
+        assert_eq!(mem.word(DEMCR), 0);
+        assert!(mem.writes.contains(&(32, DEMCR, 4)));
+    }
+
+    #[test]
+    fn reads_id_pfr0_of_unknown_parts() {
+        let with_fpu = |mem: &mut Memory| mem.set_word(MVFR0, 0x21);
+        let baseline = info(0x410f_c991, |mem| {
+            with_fpu(mem);
+            mem.set_word(ID_PFR0, 0x10);
+        });
+        assert_eq!(baseline.fpu, None);
+
+        let mainline = info(0x410f_c991, |mem| {
+            with_fpu(mem);
+            mem.set_word(ID_PFR0, 0x30);
+        });
+        assert_eq!(mainline.architecture, Architecture::Unknown);
+        assert!(mainline.fpu.is_some());
+    }
+}
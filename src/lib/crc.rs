@@ -0,0 +1,167 @@
+use std::time::Duration;
+
+use crate::cortex_m::{registers, Core};
+use crate::memory::MemoryAccess;
+use crate::stlink::STLinkError;
+
+const POLYNOMIAL: u32 = 0x04c1_1db7;
+
+/// The Thumb routine computing the CRC of `R1` bytes at `R0`.
+/// `R2` holds the base of a CRC peripheral or zero to compute it in software.
+/// It runs on ARMv6-M and needs no stack.
+#[rustfmt::skip]
+const ROUTINE: [u16; 32] = [
+    0xbe00, //         bkpt #0
+    0xbe00, //         bkpt #0
+    0x1841, // entry:  adds r1, r0, r1
+    0x2a00, //         cmp r2, #0
+    0xd008, //         beq software
+    0x2301, //         movs r3, #1
+    0x6093, //         str r3, [r2, #8]      @ CR = RESET
+    0x4288, // hw:     cmp r0, r1
+    0xd202, //         bhs hw_done
+    0xc808, //         ldm r0!, {r3}
+    0x6013, //         str r3, [r2, #0]      @ DR = word
+    0xe7fa, //         b hw
+    0x6810, // hw_done: ldr r0, [r2, #0]
+    0x4770, //         bx lr
+    0x2200, // software: movs r2, #0
+    0x43d2, //         mvns r2, r2
+    0x4c06, //         ldr r4, polynomial
+    0x4288, // word:   cmp r0, r1
+    0xd208, //         bhs done
+    0xc808, //         ldm r0!, {r3}
+    0x405a, //         eors r2, r3
+    0x2520, //         movs r5, #32
+    0x0052, // bit:    lsls r2, r2, #1
+    0xd300, //         bcc skip
+    0x4062, //         eors r2, r4
+    0x3d01, // skip:   subs r5, #1
+    0xd1fa, //         bne bit
+    0xe7f4, //         b word
+    0x0010, // done:   movs r0, r2
+    0x4770, //         bx lr
+    POLYNOMIAL as u16,
+    (POLYNOMIAL >> 16) as u16,
+];
+
+/// The offset of the entry point behind the breakpoints the routine returns to.
+const ENTRY: u32 = 4;
+
+/// The EPSR T bit, which has to be set to execute Thumb code.
+const XPSR_THUMB: u32 = 1 << 24;
+
+/// Computes the CRC the STM32 CRC peripheral produces for `data`:
+/// CRC-32/MPEG-2 over little endian words, most significant bit first.
+/// The length of `data` has to be a multiple of four.
+pub fn crc32(data: &[u8]) -> u32 {
+    data.chunks(4).fold(0xffff_ffff, |crc, word| {
+        let word = word
+            .iter()
+            .rev()
+            .fold(0u32, |value, &byte| value << 8 | u32::from(byte));
+        (0..32).fold(crc ^ word, |crc, _| {
+            if crc & 0x8000_0000 != 0 {
+                crc << 1 ^ POLYNOMIAL
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+/// A CRC peripheral and the clock it needs.
+#[derive(Debug, Clone, Copy)]
+pub struct CrcUnit {
+    pub base: u32,
+    /// The RCC register and bit enabling the clock of the peripheral.
+    pub clock_enable: (u32, u32),
+    /// Whether the polynomial and initial value are configurable and have to be reset.
+    pub programmable: bool,
+}
+
+impl CrcUnit {
+    const INIT: u32 = 0x10;
+    const POL: u32 = 0x14;
+}
+
+/// Computes CRCs of target memory on the target, which is much faster than reading it back.
+/// The core has to be halted.
+#[derive(Debug, Clone, Copy)]
+pub struct CrcStub {
+    core: Core,
+    address: u32,
+    unit: Option<CrcUnit>,
+}
+
+impl CrcStub {
+    /// The number of bytes of RAM the routine occupies.
+    pub const SIZE: u32 = ROUTINE.len() as u32 * 2;
+
+    /// Downloads the routine to `address` in RAM and prepares the CRC peripheral, if given.
+    pub fn load<M: MemoryAccess>(
+        mem: &mut M,
+        core: Core,
+        address: u32,
+        unit: Option<CrcUnit>,
+    ) -> Result<Self, STLinkError> {
+        let code = ROUTINE
+            .iter()
+            .flat_map(|&halfword| vec![halfword as u8, (halfword >> 8) as u8])
+            .collect();
+        mem.write_mem32(address, code, core.apsel)?;
+        if let Some(unit) = unit {
+            let (register, bit) = unit.clock_enable;
+            let enabled = mem.read_word32(register, core.apsel)?;
+            mem.write_word32(register, enabled | bit, core.apsel)?;
+            if unit.programmable {
+                mem.write_word32(unit.base + CrcUnit::INIT, 0xffff_ffff, core.apsel)?;
+                mem.write_word32(unit.base + CrcUnit::POL, POLYNOMIAL, core.apsel)?;
+            }
+        }
+        Ok(Self {
+            core,
+            address,
+            unit,
+        })
+    }
+
+    /// Returns the CRC of the `size` bytes at `addr`, which both have to be word aligned.
+    pub fn crc<M: MemoryAccess>(
+        &self,
+        mem: &mut M,
+        addr: u32,
+        size: u32,
+    ) -> Result<u32, STLinkError> {
+        if addr % 4 != 0 || size % 4 != 0 {
+            return Err(STLinkError::DataAlignmentError);
+        }
+        let core = self.core;
+        let base = self.unit.map(|unit| unit.base).unwrap_or(0);
+        core.write_register(mem, registers::R0, addr)?;
+        core.write_register(mem, registers::R1, size)?;
+        core.write_register(mem, registers::R2, base)?;
+        core.write_register(mem, registers::LR, self.address | 1)?;
+        core.write_register(mem, registers::PC, self.address + ENTRY)?;
+        core.write_register(mem, registers::XPSR, XPSR_THUMB)?;
+        core.run(mem)?;
+        // The software CRC manages about 50 kB/s on a core running from a slow reset clock.
+        let timeout = Duration::from_millis(100 + u64::from(size) / 50);
+        if let Err(e) = core.wait_for_halt(mem, timeout) {
+            core.halt(mem).ok();
+            return Err(e);
+        }
+        core.read_register(mem, registers::R0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_the_crc_peripheral() {
+        assert_eq!(crc32(&[0x78, 0x56, 0x34, 0x12]), 0xdf8a_8a2b);
+        assert_eq!(crc32(&[]), 0xffff_ffff);
+    }
+}
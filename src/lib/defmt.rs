@@ -0,0 +1,1031 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use gimli::{AttributeValue, EndianSlice, RunTimeEndian};
+use goblin::elf::section_header::SHT_NOBITS;
+use goblin::elf::Elf;
+
+/// The wire format version this decoder understands, which is the one of defmt 0.3.
+const SUPPORTED_VERSION: &str = "4";
+
+#[derive(Debug)]
+pub enum DefmtError {
+    IO(std::io::Error),
+    Elf(goblin::error::Error),
+    Dwarf(gimli::Error),
+    /// The ELF has no `.defmt` section.
+    NoDefmtTable,
+    UnsupportedVersion(String),
+    /// A section reaches past the end of the ELF file.
+    TruncatedSection,
+}
+
+impl From<gimli::Error> for DefmtError {
+    fn from(e: gimli::Error) -> Self {
+        DefmtError::Dwarf(e)
+    }
+}
+
+/// Reasons a single frame could not be decoded.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FrameError {
+    /// The frame is not valid rzCOBS or contains invalid values.
+    Corrupted,
+    /// The frame ended before all of its arguments were read.
+    Truncated,
+    /// The frame references an index which is not in the table.
+    UnknownIndex(u16),
+    /// The format string of an entry could not be parsed.
+    MalformedFormat(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum Level {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl Level {
+    fn from_tag(tag: &str) -> Option<Self> {
+        match tag {
+            "defmt_trace" => Some(Level::Trace),
+            "defmt_debug" => Some(Level::Debug),
+            "defmt_info" => Some(Level::Info),
+            "defmt_warn" => Some(Level::Warn),
+            "defmt_error" => Some(Level::Error),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Level::Trace => "TRACE",
+            Level::Debug => "DEBUG",
+            Level::Info => "INFO",
+            Level::Warn => "WARN",
+            Level::Error => "ERROR",
+        };
+        f.pad(name)
+    }
+}
+
+/// The source location of a log statement.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    pub file: String,
+    pub line: u64,
+}
+
+/// A decoded log message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogFrame {
+    /// `None` for `println!` messages.
+    pub level: Option<Level>,
+    pub timestamp: Option<String>,
+    pub message: String,
+    pub location: Option<Location>,
+}
+
+impl fmt::Display for LogFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(timestamp) = &self.timestamp {
+            write!(f, "{} ", timestamp)?;
+        }
+        if let Some(level) = self.level {
+            write!(f, "{:<5} ", level)?;
+        }
+        write!(f, "{}", self.message)?;
+        if let Some(location) = &self.location {
+            write!(f, "\n└─ {}:{}", location.file, location.line)?;
+        }
+        Ok(())
+    }
+}
+
+/// How frames are delimited on the wire.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    /// Frames follow each other without any framing.
+    Raw,
+    /// Every frame is rzCOBS encoded and terminated by a zero byte.
+    Rzcobs,
+}
+
+#[derive(Debug)]
+struct Entry {
+    tag: String,
+    format: String,
+}
+
+/// The interned format strings of a firmware, read from its `.defmt` section.
+pub struct DefmtTable {
+    entries: HashMap<u16, Entry>,
+    /// The format string of the timestamp, if the firmware defines one.
+    timestamp: Option<String>,
+    encoding: Encoding,
+    locations: HashMap<u16, Location>,
+}
+
+impl DefmtTable {
+    /// Reads the table of the ELF at `path`.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, DefmtError> {
+        let data = std::fs::read(path).or_else(|e| Err(DefmtError::IO(e)))?;
+        Self::from_elf(&data)
+    }
+
+    /// Reads the table from the contents of an ELF file.
+    pub fn from_elf(data: &[u8]) -> Result<Self, DefmtError> {
+        let elf = Elf::parse(data).or_else(|e| Err(DefmtError::Elf(e)))?;
+        let section = elf
+            .section_headers
+            .iter()
+            .position(|header| match elf.shdr_strtab.get(header.sh_name) {
+                Some(Ok(name)) => name == ".defmt",
+                _ => false,
+            })
+            .ok_or(DefmtError::NoDefmtTable)?;
+
+        let mut entries = HashMap::new();
+        let mut timestamp = None;
+        let mut encoding = Encoding::Rzcobs;
+        for symbol in elf.syms.iter() {
+            let name = match elf.strtab.get(symbol.st_name) {
+                Some(Ok(name)) => name,
+                _ => continue,
+            };
+            // The version and encoding are stored in the names of marker symbols.
+            if name.starts_with("_defmt_version_ = ") {
+                let version = &name["_defmt_version_ = ".len()..];
+                if version != SUPPORTED_VERSION {
+                    return Err(DefmtError::UnsupportedVersion(version.to_owned()));
+                }
+                continue;
+            }
+            if name.starts_with("_defmt_encoding_ = ") {
+                if &name["_defmt_encoding_ = ".len()..] == "raw" {
+                    encoding = Encoding::Raw;
+                }
+                continue;
+            }
+            if symbol.st_shndx != section {
+                continue;
+            }
+
+            // The names of the table entries are JSON objects.
+            let mut fields = match parse_json_object(name) {
+                Some(fields) => fields,
+                None => continue,
+            };
+            let (tag, format) = match (fields.remove("tag"), fields.remove("data")) {
+                (Some(tag), Some(format)) => (tag, format),
+                _ => continue,
+            };
+            if tag == "defmt_timestamp" {
+                timestamp = Some(format);
+            } else {
+                entries.insert(symbol.st_value as u16, Entry { tag, format });
+            }
+        }
+
+        let header = &elf.section_headers[section];
+        let locations = read_locations(&elf, data, header.sh_addr, header.sh_size)?;
+
+        Ok(Self {
+            entries,
+            timestamp,
+            encoding,
+            locations,
+        })
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    /// Decodes one unframed frame at the start of `data`.
+    /// Returns the frame and the number of bytes it occupied.
+    pub fn decode(&self, data: &[u8]) -> Result<(LogFrame, usize), FrameError> {
+        let mut reader = Reader { data, position: 0 };
+        let index = reader.u16()?;
+        let entry = self
+            .entries
+            .get(&index)
+            .ok_or(FrameError::UnknownIndex(index))?;
+        let level = Level::from_tag(&entry.tag);
+        // Anything but a log statement at the start of a frame means the stream is garbage.
+        if level.is_none() && entry.tag != "defmt_println" {
+            return Err(FrameError::UnknownIndex(index));
+        }
+
+        let timestamp = match &self.timestamp {
+            Some(format) => Some(self.format(format, &mut reader)?),
+            None => None,
+        };
+        let message = self.format(&entry.format, &mut reader)?;
+
+        let frame = LogFrame {
+            level,
+            timestamp,
+            message,
+            location: self.locations.get(&index).cloned(),
+        };
+        Ok((frame, reader.position))
+    }
+
+    /// Formats the arguments following in `reader` according to a format string.
+    fn format(&self, format: &str, reader: &mut Reader) -> Result<String, FrameError> {
+        let fragments =
+            parse_format(format).ok_or_else(|| FrameError::MalformedFormat(format.to_owned()))?;
+        let parameters = fragments
+            .iter()
+            .filter_map(|fragment| match fragment {
+                Fragment::Parameter(parameter) => Some(parameter),
+                Fragment::Literal(_) => None,
+            })
+            .collect::<Vec<_>>();
+
+        // Arguments are sent in the order of their index, every argument once.
+        let count = parameters.iter().map(|p| p.index + 1).max().unwrap_or(0);
+        let mut values = vec![];
+        for index in 0..count {
+            let uses = parameters
+                .iter()
+                .filter(|p| p.index == index)
+                .collect::<Vec<_>>();
+            let value = match uses.first().map(|p| &p.ty) {
+                Some(Type::BitField(..)) => {
+                    // All bitfields of an argument share the bytes spanning their ranges.
+                    let (start, end) = uses.iter().fold((128, 0), |(start, end), p| match p.ty {
+                        Type::BitField(s, e) => (u8::min(start, s), u8::max(end, e)),
+                        _ => (start, end),
+                    });
+                    let lowest_byte = start / 8;
+                    let size = match (end - 1) / 8 - lowest_byte + 1 {
+                        1 => 1,
+                        2 => 2,
+                        3..=4 => 4,
+                        5..=8 => 8,
+                        _ => 16,
+                    };
+                    Value::Unsigned(reader.uint(size)? << (lowest_byte * 8))
+                }
+                Some(ty) => self.read_value(ty, reader)?,
+                None => return Err(FrameError::MalformedFormat(format.to_owned())),
+            };
+            values.push(value);
+        }
+
+        let mut output = String::new();
+        for fragment in &fragments {
+            match fragment {
+                Fragment::Literal(literal) => output.push_str(literal),
+                Fragment::Parameter(parameter) => {
+                    let value = &values[parameter.index];
+                    match parameter.ty {
+                        Type::BitField(start, end) => {
+                            let value = match value {
+                                Value::Unsigned(value) => value >> start,
+                                _ => 0,
+                            };
+                            let width = u32::from(end - start);
+                            let mask = 1u128.checked_shl(width).map_or(!0, |bit| bit - 1);
+                            output
+                                .push_str(&render(&Value::Unsigned(value & mask), &parameter.hint));
+                        }
+                        _ => output.push_str(&render(value, &parameter.hint)),
+                    }
+                }
+            }
+        }
+        Ok(output)
+    }
+
+    fn read_value(&self, ty: &Type, reader: &mut Reader) -> Result<Value, FrameError> {
+        Ok(match ty {
+            Type::U8 => Value::Unsigned(reader.uint(1)?),
+            Type::U16 => Value::Unsigned(reader.uint(2)?),
+            Type::U32 | Type::Usize => Value::Unsigned(reader.uint(4)?),
+            Type::U64 => Value::Unsigned(reader.uint(8)?),
+            Type::U128 => Value::Unsigned(reader.uint(16)?),
+            Type::I8 => Value::Signed(reader.int(1)?),
+            Type::I16 => Value::Signed(reader.int(2)?),
+            Type::I32 | Type::Isize => Value::Signed(reader.int(4)?),
+            Type::I64 => Value::Signed(reader.int(8)?),
+            Type::I128 => Value::Signed(reader.int(16)?),
+            Type::F32 => Value::F32(f32::from_bits(reader.uint(4)? as u32)),
+            Type::F64 => Value::F64(f64::from_bits(reader.uint(8)? as u64)),
+            Type::Bool => Value::Bool(reader.uint(1)? != 0),
+            Type::Char => Value::Char(
+                std::char::from_u32(reader.uint(4)? as u32).ok_or(FrameError::Corrupted)?,
+            ),
+            Type::Str => {
+                let length = reader.uint(4)? as usize;
+                Value::Str(String::from_utf8_lossy(reader.bytes(length)?).into_owned())
+            }
+            Type::IStr => {
+                let index = reader.u16()?;
+                let entry = self
+                    .entries
+                    .get(&index)
+                    .ok_or(FrameError::UnknownIndex(index))?;
+                Value::Str(entry.format.clone())
+            }
+            Type::U8Slice => {
+                let length = reader.uint(4)? as usize;
+                Value::Bytes(reader.bytes(length)?.to_vec())
+            }
+            Type::U8Array(length) => Value::Bytes(reader.bytes(*length)?.to_vec()),
+            Type::Format => Value::Text(self.format_tagged(reader)?),
+            Type::FormatSlice => {
+                let length = reader.uint(4)? as usize;
+                Value::Text(self.format_elements(length, reader)?)
+            }
+            Type::FormatArray(length) => Value::Text(self.format_elements(*length, reader)?),
+            Type::FormatSequence => {
+                // A sequence of tagged values terminated by index 0.
+                let mut text = String::new();
+                loop {
+                    let index = reader.u16()?;
+                    if index == 0 {
+                        break;
+                    }
+                    text.push_str(&self.format_index(index, reader)?);
+                }
+                Value::Text(text)
+            }
+            Type::Debug | Type::Display => {
+                // Formatted on the target and terminated by 0xff, which is never valid UTF-8.
+                let length = reader.data[reader.position..]
+                    .iter()
+                    .position(|&byte| byte == 0xff)
+                    .ok_or(FrameError::Truncated)?;
+                let text = String::from_utf8_lossy(reader.bytes(length)?).into_owned();
+                reader.bytes(1)?;
+                Value::Text(text)
+            }
+            Type::BitField(..) => unreachable!(),
+        })
+    }
+
+    /// Formats a value implementing `Format`, which starts with the index of its format string.
+    fn format_tagged(&self, reader: &mut Reader) -> Result<String, FrameError> {
+        let index = reader.u16()?;
+        self.format_index(index, reader)
+    }
+
+    fn format_index(&self, index: u16, reader: &mut Reader) -> Result<String, FrameError> {
+        let entry = self
+            .entries
+            .get(&index)
+            .ok_or(FrameError::UnknownIndex(index))?;
+        self.format_entry(entry, reader)
+    }
+
+    /// Formats the elements of a slice or array, which share one format string.
+    fn format_elements(&self, length: usize, reader: &mut Reader) -> Result<String, FrameError> {
+        let index = reader.u16()?;
+        let entry = self
+            .entries
+            .get(&index)
+            .ok_or(FrameError::UnknownIndex(index))?;
+        let mut elements = vec![];
+        for _ in 0..length {
+            elements.push(self.format_entry(entry, reader)?);
+        }
+        Ok(format!("[{}]", elements.join(", ")))
+    }
+
+    fn format_entry(&self, entry: &Entry, reader: &mut Reader) -> Result<String, FrameError> {
+        // Derived enums list their variants separated by `|` and send a discriminant first.
+        let variants = split_variants(&entry.format);
+        if entry.tag != "defmt_derived" || variants.len() < 2 {
+            return self.format(&entry.format, reader);
+        }
+        let size = if variants.len() <= 0xff {
+            1
+        } else if variants.len() <= 0xffff {
+            2
+        } else {
+            4
+        };
+        let discriminant = reader.uint(size)? as usize;
+        let variant = variants.get(discriminant).ok_or(FrameError::Corrupted)?;
+        self.format(variant, reader)
+    }
+}
+
+/// Splits the incoming stream into frames and decodes them.
+pub struct DefmtDecoder<'t> {
+    table: &'t DefmtTable,
+    buffer: Vec<u8>,
+}
+
+impl<'t> DefmtDecoder<'t> {
+    pub fn new(table: &'t DefmtTable) -> Self {
+        Self {
+            table,
+            buffer: vec![],
+        }
+    }
+
+    /// Decodes all frames completed by `data`.
+    /// Incomplete frames are kept until the rest of them arrives with a later call.
+    pub fn feed(&mut self, data: &[u8]) -> Vec<Result<LogFrame, FrameError>> {
+        self.buffer.extend_from_slice(data);
+        let mut frames = vec![];
+        match self.table.encoding {
+            Encoding::Rzcobs => {
+                while let Some(end) = self.buffer.iter().position(|&byte| byte == 0) {
+                    let frame = self.buffer.drain(..=end).collect::<Vec<_>>();
+                    // The target starts with a separator to terminate a frame left by a previous boot.
+                    if end == 0 {
+                        continue;
+                    }
+                    frames.push(
+                        rzcobs_decode(&frame[..end])
+                            .and_then(|data| self.table.decode(&data).map(|(frame, _)| frame)),
+                    );
+                }
+            }
+            Encoding::Raw => {
+                while !self.buffer.is_empty() {
+                    match self.table.decode(&self.buffer) {
+                        Ok((frame, length)) => {
+                            self.buffer.drain(..length);
+                            frames.push(Ok(frame));
+                        }
+                        Err(FrameError::Truncated) => break,
+                        Err(e) => {
+                            // Raw frames have no delimiter to resynchronize on.
+                            self.buffer.clear();
+                            frames.push(Err(e));
+                        }
+                    }
+                }
+            }
+        }
+        frames
+    }
+}
+
+/// Decodes an rzCOBS frame without its terminating zero.
+/// The result may carry trailing zeros, which the frame decoder ignores.
+fn rzcobs_decode(data: &[u8]) -> Result<Vec<u8>, FrameError> {
+    // The encoding is built to be decoded backwards.
+    let mut output = vec![];
+    let mut input = data.iter().rev().cloned();
+    while let Some(byte) = input.next() {
+        match byte {
+            0x00 => return Err(FrameError::Corrupted),
+            0x01..=0x7f => {
+                // Every bit tells whether one of the next 7 bytes is a zero.
+                for bit in (0..7).rev() {
+                    if byte & (1 << bit) != 0 {
+                        output.push(0);
+                    } else {
+                        output.push(input.next().ok_or(FrameError::Corrupted)?);
+                    }
+                }
+            }
+            0x80..=0xfe => {
+                output.push(0);
+                for _ in 0..(byte & 0x7f) + 7 {
+                    output.push(input.next().ok_or(FrameError::Corrupted)?);
+                }
+            }
+            0xff => {
+                for _ in 0..134 {
+                    output.push(input.next().ok_or(FrameError::Corrupted)?);
+                }
+            }
+        }
+    }
+    output.reverse();
+    Ok(output)
+}
+
+struct Reader<'d> {
+    data: &'d [u8],
+    position: usize,
+}
+
+impl<'d> Reader<'d> {
+    fn bytes(&mut self, length: usize) -> Result<&'d [u8], FrameError> {
+        if self.data.len() - self.position < length {
+            return Err(FrameError::Truncated);
+        }
+        let bytes = &self.data[self.position..self.position + length];
+        self.position += length;
+        Ok(bytes)
+    }
+
+    fn uint(&mut self, size: usize) -> Result<u128, FrameError> {
+        Ok(self
+            .bytes(size)?
+            .iter()
+            .rev()
+            .fold(0, |value, &byte| value << 8 | u128::from(byte)))
+    }
+
+    fn int(&mut self, size: usize) -> Result<i128, FrameError> {
+        let shift = 128 - 8 * size as u32;
+        Ok((self.uint(size)? << shift) as i128 >> shift)
+    }
+
+    fn u16(&mut self) -> Result<u16, FrameError> {
+        Ok(self.uint(2)? as u16)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Type {
+    U8,
+    U16,
+    U32,
+    U64,
+    U128,
+    Usize,
+    I8,
+    I16,
+    I32,
+    I64,
+    I128,
+    Isize,
+    F32,
+    F64,
+    Bool,
+    Char,
+    Str,
+    IStr,
+    U8Slice,
+    U8Array(usize),
+    Format,
+    FormatSlice,
+    FormatArray(usize),
+    FormatSequence,
+    Debug,
+    Display,
+    /// The bits `start..end` of an unsigned integer.
+    BitField(u8, u8),
+}
+
+impl Type {
+    fn parse(ty: &str) -> Option<Self> {
+        Some(match ty {
+            "u8" => Type::U8,
+            "u16" => Type::U16,
+            "u32" => Type::U32,
+            "u64" => Type::U64,
+            "u128" => Type::U128,
+            "usize" => Type::Usize,
+            "i8" => Type::I8,
+            "i16" => Type::I16,
+            "i32" => Type::I32,
+            "i64" => Type::I64,
+            "i128" => Type::I128,
+            "isize" => Type::Isize,
+            "f32" => Type::F32,
+            "f64" => Type::F64,
+            "bool" => Type::Bool,
+            "char" => Type::Char,
+            "str" => Type::Str,
+            "istr" => Type::IStr,
+            "[u8]" => Type::U8Slice,
+            "?" => Type::Format,
+            "[?]" => Type::FormatSlice,
+            "__internal_FormatSequence" => Type::FormatSequence,
+            "__internal_Debug" => Type::Debug,
+            "__internal_Display" => Type::Display,
+            _ if ty.starts_with("[u8;") && ty.ends_with(']') => {
+                Type::U8Array(ty[4..ty.len() - 1].trim().parse().ok()?)
+            }
+            _ if ty.starts_with("[?;") && ty.ends_with(']') => {
+                Type::FormatArray(ty[3..ty.len() - 1].trim().parse().ok()?)
+            }
+            _ => {
+                let mut range = ty.splitn(2, "..");
+                let start = range.next()?.parse().ok()?;
+                let end = range.next()?.parse().ok()?;
+                if start >= end || end > 128 {
+                    return None;
+                }
+                Type::BitField(start, end)
+            }
+        })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Parameter {
+    index: usize,
+    ty: Type,
+    hint: String,
+}
+
+#[derive(Debug, PartialEq)]
+enum Fragment {
+    Literal(String),
+    Parameter(Parameter),
+}
+
+/// Parses a format string like `x = {=u8:x}, {0=0..4}`.
+fn parse_format(format: &str) -> Option<Vec<Fragment>> {
+    let mut fragments = vec![];
+    let mut literal = String::new();
+    let mut next_index = 0;
+    let mut chars = format.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                literal.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                literal.push('}');
+            }
+            '{' => {
+                let mut parameter = String::new();
+                loop {
+                    match chars.next()? {
+                        '}' => break,
+                        c => parameter.push(c),
+                    }
+                }
+
+                let digits = parameter
+                    .find(|c: char| !c.is_ascii_digit())
+                    .unwrap_or_else(|| parameter.len());
+                let index = if digits == 0 {
+                    next_index += 1;
+                    next_index - 1
+                } else {
+                    parameter[..digits].parse().ok()?
+                };
+                let rest = &parameter[digits..];
+                let (ty, hint) = match rest.find(':') {
+                    Some(colon) => (&rest[..colon], &rest[colon + 1..]),
+                    None => (rest, ""),
+                };
+                let ty = if ty.is_empty() {
+                    Type::Format
+                } else if ty.starts_with('=') {
+                    Type::parse(&ty[1..])?
+                } else {
+                    return None;
+                };
+
+                if !literal.is_empty() {
+                    fragments.push(Fragment::Literal(std::mem::replace(
+                        &mut literal,
+                        String::new(),
+                    )));
+                }
+                fragments.push(Fragment::Parameter(Parameter {
+                    index,
+                    ty,
+                    hint: hint.to_owned(),
+                }));
+            }
+            '}' => return None,
+            c => literal.push(c),
+        }
+    }
+    if !literal.is_empty() {
+        fragments.push(Fragment::Literal(literal));
+    }
+    Some(fragments)
+}
+
+/// Splits the format string of a derived enum into the ones of its variants.
+fn split_variants(format: &str) -> Vec<&str> {
+    let mut variants = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in format.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            '|' if depth == 0 => {
+                variants.push(&format[start..i]);
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    variants.push(&format[start..]);
+    variants
+}
+
+#[derive(Debug)]
+enum Value {
+    Unsigned(u128),
+    Signed(i128),
+    F32(f32),
+    F64(f64),
+    Bool(bool),
+    Char(char),
+    Str(String),
+    Bytes(Vec<u8>),
+    /// A value which is already formatted.
+    Text(String),
+}
+
+/// Renders a value according to a display hint like `x`, `#010b`, `a`, `?` or `ms`.
+fn render(value: &Value, hint: &str) -> String {
+    let alternate = hint.starts_with('#');
+    let hint = hint.trim_start_matches('#');
+    let (width, hint) = if hint.starts_with('0') {
+        let digits = hint
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or_else(|| hint.len());
+        (hint[..digits].parse().unwrap_or(0), &hint[digits..])
+    } else {
+        (0, hint)
+    };
+
+    match value {
+        Value::Unsigned(value) => render_integer(*value, hint, alternate, width),
+        // Negative numbers are shown in two's complement of the full width.
+        Value::Signed(value) if hint == "x" || hint == "X" || hint == "b" => {
+            render_integer(*value as u128, hint, alternate, width)
+        }
+        Value::Signed(value) => format!("{:0width$}", value, width = width),
+        Value::F32(value) => format!("{:?}", value),
+        Value::F64(value) => format!("{:?}", value),
+        Value::Bool(value) => value.to_string(),
+        Value::Char(value) if hint == "?" => format!("{:?}", value),
+        Value::Char(value) => value.to_string(),
+        Value::Str(value) if hint == "?" => format!("{:?}", value),
+        Value::Str(value) => value.clone(),
+        Value::Bytes(bytes) if hint == "a" => {
+            let escaped = bytes
+                .iter()
+                .flat_map(|&byte| std::ascii::escape_default(byte))
+                .map(char::from)
+                .collect::<String>();
+            format!("b\"{}\"", escaped)
+        }
+        Value::Bytes(bytes) => {
+            let bytes = bytes
+                .iter()
+                .map(|&byte| render_integer(u128::from(byte), hint, alternate, width))
+                .collect::<Vec<_>>();
+            format!("[{}]", bytes.join(", "))
+        }
+        Value::Text(text) => text.clone(),
+    }
+}
+
+fn render_integer(value: u128, hint: &str, alternate: bool, width: usize) -> String {
+    match (hint, alternate) {
+        ("x", false) => format!("{:0width$x}", value, width = width),
+        ("x", true) => format!("{:#0width$x}", value, width = width),
+        ("X", false) => format!("{:0width$X}", value, width = width),
+        ("X", true) => format!("{:#0width$X}", value, width = width),
+        ("b", false) => format!("{:0width$b}", value, width = width),
+        ("b", true) => format!("{:#0width$b}", value, width = width),
+        // Timestamps counting micro- or milliseconds, shown in seconds.
+        ("us", _) => format!("{}.{:06}", value / 1_000_000, value % 1_000_000),
+        ("ms", _) => format!("{}.{:03}", value / 1_000, value % 1_000),
+        _ => format!("{:0width$}", value, width = width),
+    }
+}
+
+/// Parses a flat JSON object with string values, as used in the names of the table entries.
+fn parse_json_object(text: &str) -> Option<HashMap<String, String>> {
+    let mut chars = text.trim().chars().peekable();
+    let mut object = HashMap::new();
+    if chars.next()? != '{' {
+        return None;
+    }
+    loop {
+        match chars.next()? {
+            '}' if object.is_empty() => break,
+            '"' => (),
+            _ => return None,
+        }
+        let key = parse_json_string(&mut chars)?;
+        if chars.next()? != ':' || chars.next()? != '"' {
+            return None;
+        }
+        let value = parse_json_string(&mut chars)?;
+        object.insert(key, value);
+        match chars.next()? {
+            ',' => (),
+            '}' => break,
+            _ => return None,
+        }
+    }
+    Some(object)
+}
+
+/// Parses the rest of a JSON string whose opening quote was consumed already.
+fn parse_json_string<I: Iterator<Item = char>>(chars: &mut I) -> Option<String> {
+    let mut string = String::new();
+    loop {
+        match chars.next()? {
+            '"' => return Some(string),
+            '\\' => match chars.next()? {
+                'n' => string.push('\n'),
+                't' => string.push('\t'),
+                'r' => string.push('\r'),
+                'u' => {
+                    let code = chars.by_ref().take(4).collect::<String>();
+                    string.push(std::char::from_u32(u32::from_str_radix(&code, 16).ok()?)?);
+                }
+                c => string.push(c),
+            },
+            c => string.push(c),
+        }
+    }
+}
+
+/// Maps the table indices to the source locations of their log statements,
+/// which the debug info records for the statics the table entries are made of.
+fn read_locations(
+    elf: &Elf,
+    data: &[u8],
+    start: u64,
+    size: u64,
+) -> Result<HashMap<u16, Location>, DefmtError> {
+    let endian = if elf.little_endian {
+        RunTimeEndian::Little
+    } else {
+        RunTimeEndian::Big
+    };
+    let load_section = |id: gimli::SectionId| -> Result<EndianSlice<RunTimeEndian>, DefmtError> {
+        let section =
+            elf.section_headers
+                .iter()
+                .find(|header| match elf.shdr_strtab.get(header.sh_name) {
+                    Some(Ok(name)) => name == id.name(),
+                    _ => false,
+                });
+        let bytes = match section {
+            Some(header) if header.sh_type != SHT_NOBITS => {
+                let start = header.sh_offset as usize;
+                start
+                    .checked_add(header.sh_size as usize)
+                    .and_then(|end| data.get(start..end))
+                    .ok_or(DefmtError::TruncatedSection)?
+            }
+            _ => &[],
+        };
+        Ok(EndianSlice::new(bytes, endian))
+    };
+    let load_supplementary = |_| Ok(EndianSlice::new(&[], endian));
+    let dwarf = gimli::Dwarf::load(load_section, load_supplementary)?;
+
+    let mut locations = HashMap::new();
+    let mut units = dwarf.units();
+    while let Some(header) = units.next()? {
+        let unit = dwarf.unit(header)?;
+        let mut entries = unit.entries();
+        while let Some((_, entry)) = entries.next_dfs()? {
+            if entry.tag() != gimli::DW_TAG_variable {
+                continue;
+            }
+            let address = match entry.attr_value(gimli::DW_AT_location)? {
+                Some(AttributeValue::Exprloc(expression)) => {
+                    match expression.operations(unit.encoding()).next()? {
+                        Some(gimli::Operation::Address { address }) => address,
+                        _ => continue,
+                    }
+                }
+                _ => continue,
+            };
+            if address < start || address >= start + size {
+                continue;
+            }
+
+            let line = match entry.attr_value(gimli::DW_AT_decl_line)? {
+                Some(line) => match line.udata_value() {
+                    Some(line) => line,
+                    None => continue,
+                },
+                None => continue,
+            };
+            let file = match entry.attr_value(gimli::DW_AT_decl_file)? {
+                Some(AttributeValue::FileIndex(index)) => file_name(&dwarf, &unit, index)?,
+                _ => None,
+            };
+            if let Some(file) = file {
+                locations.insert(address as u16, Location { file, line });
+            }
+        }
+    }
+    Ok(locations)
+}
+
+pub(crate) fn file_name<R: gimli::Reader>(
+    dwarf: &gimli::Dwarf<R>,
+    unit: &gimli::Unit<R>,
+    index: u64,
+) -> Result<Option<String>, gimli::Error> {
+    let header = match &unit.line_program {
+        Some(program) => program.header(),
+        None => return Ok(None),
+    };
+    let file = match header.file(index) {
+        Some(file) => file,
+        None => return Ok(None),
+    };
+
+    let mut path = PathBuf::new();
+    if let Some(directory) = file.directory(header) {
+        let directory = dwarf.attr_string(unit, directory)?;
+        path.push(Cow::as_ref(&directory.to_string_lossy()?));
+    }
+    let name = dwarf.attr_string(unit, file.path_name())?;
+    path.push(Cow::as_ref(&name.to_string_lossy()?));
+    Ok(Some(path.display().to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table() -> DefmtTable {
+        let mut entries = HashMap::new();
+        let mut add = |index, tag: &str, format: &str| {
+            entries.insert(
+                index,
+                Entry {
+                    tag: tag.to_owned(),
+                    format: format.to_owned(),
+                },
+            )
+        };
+        add(1, "defmt_info", "x = {=u8:x}, flags = {0=0..4:b}");
+        add(2, "defmt_derived", "None|Some({=u16})");
+        add(3, "defmt_warn", "got {} and {=str}");
+        let mut locations = HashMap::new();
+        locations.insert(
+            1,
+            Location {
+                file: "src/main.rs".to_owned(),
+                line: 12,
+            },
+        );
+        DefmtTable {
+            entries,
+            timestamp: Some("{=u32:ms}".to_owned()),
+            encoding: Encoding::Rzcobs,
+            locations,
+        }
+    }
+
+    #[test]
+    fn decodes_rzcobs() {
+        assert_eq!(
+            rzcobs_decode(&[0x01, 0x7e]).unwrap(),
+            &[0x01, 0, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(
+            rzcobs_decode(&[0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x40]).unwrap(),
+            &[0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x00]
+        );
+        assert_eq!(
+            rzcobs_decode(&[0x44, 0x5f, 0xff, 0x3f]).unwrap()[..14],
+            [0x00, 0x00, 0x00, 0x00, 0x00, 0x44, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff,]
+        );
+    }
+
+    #[test]
+    fn decodes_frames_across_chunks() {
+        let table = table();
+        let mut decoder = DefmtDecoder::new(&table);
+        // Index 3, timestamp 1234 ms, Some(0x0102), "hi", rzCOBS encoded.
+        let frame = [
+            0x00, 0x03, 0xd2, 0x04, 0x02, 0x32, 0x01, 0x02, 0x01, 0x02, 0x61, 0x68, 0x69, 0x79,
+            0x00,
+        ];
+        assert!(decoder.feed(&frame[..6]).is_empty());
+        let frames = decoder.feed(&frame[6..]);
+        assert_eq!(
+            frames,
+            vec![Ok(LogFrame {
+                level: Some(Level::Warn),
+                timestamp: Some("1.234".to_owned()),
+                message: "got Some(258) and hi".to_owned(),
+                location: None,
+            })]
+        );
+
+        let (frame, _) = table
+            .decode(&[0x01, 0x00, 0x10, 0x00, 0x00, 0x00, 0x2a])
+            .unwrap();
+        assert_eq!(
+            frame.to_string(),
+            "0.016 INFO  x = 2a, flags = 1010\n└─ src/main.rs:12"
+        );
+    }
+}
@@ -0,0 +1,248 @@
+use crate::cortex_m::{registers, Core};
+use crate::memory::MemoryAccess;
+use crate::stlink::STLinkError;
+
+const CFSR: u32 = 0xe000_ed28;
+const HFSR: u32 = 0xe000_ed2c;
+const DFSR: u32 = 0xe000_ed30;
+const MMFAR: u32 = 0xe000_ed34;
+const BFAR: u32 = 0xe000_ed38;
+/// Only implemented with the Security Extension.
+const SFSR: u32 = 0xe000_ede4;
+const SFAR: u32 = 0xe000_ede8;
+
+const CFSR_MMARVALID: u32 = 1 << 7;
+const CFSR_BFARVALID: u32 = 1 << 15;
+const SFSR_SFARVALID: u32 = 1 << 6;
+
+/// EXC_RETURN values have all of the upper byte set.
+const EXC_RETURN_PREFIX: u32 = 0xff00_0000;
+/// EXC_RETURN bit which is set if the frame is on the process stack.
+const EXC_RETURN_SPSEL: u32 = 1 << 2;
+/// EXC_RETURN bits of ARMv8-M telling whether the secure callee registers were stacked
+/// in front of the frame.
+const EXC_RETURN_DCRS: u32 = 1 << 5;
+const EXC_RETURN_S: u32 = 1 << 6;
+/// The integrity signature, a reserved word and R4 to R11.
+const ADDITIONAL_STATE_CONTEXT: u32 = 10 * 4;
+
+/// The fault status flags as `(register, bit, name, description)`.
+#[rustfmt::skip]
+const CFSR_FLAGS: &[(&str, u32, &str, &str)] = &[
+    ("MMFSR", 1 << 0, "IACCVIOL", "Instruction fetch from a location the MPU does not permit"),
+    ("MMFSR", 1 << 1, "DACCVIOL", "Data access to a location the MPU does not permit"),
+    ("MMFSR", 1 << 3, "MUNSTKERR", "MemManage fault on unstacking for an exception return"),
+    ("MMFSR", 1 << 4, "MSTKERR", "MemManage fault on stacking for exception entry"),
+    ("MMFSR", 1 << 5, "MLSPERR", "MemManage fault during lazy floating-point state preservation"),
+    ("MMFSR", 1 << 7, "MMARVALID", "MMFAR holds the faulting address"),
+    ("BFSR", 1 << 8, "IBUSERR", "Bus error on an instruction fetch"),
+    ("BFSR", 1 << 9, "PRECISERR", "Precise bus error on a data access"),
+    ("BFSR", 1 << 10, "IMPRECISERR", "Imprecise bus error on a data access, the stacked PC is past it"),
+    ("BFSR", 1 << 11, "UNSTKERR", "Bus fault on unstacking for an exception return"),
+    ("BFSR", 1 << 12, "STKERR", "Bus fault on stacking for exception entry"),
+    ("BFSR", 1 << 13, "LSPERR", "Bus fault during lazy floating-point state preservation"),
+    ("BFSR", 1 << 15, "BFARVALID", "BFAR holds the faulting address"),
+    ("UFSR", 1 << 16, "UNDEFINSTR", "Undefined instruction"),
+    ("UFSR", 1 << 17, "INVSTATE", "Invalid EPSR state, e.g. a branch to an address without the Thumb bit"),
+    ("UFSR", 1 << 18, "INVPC", "Invalid EXC_RETURN on an exception return"),
+    ("UFSR", 1 << 19, "NOCP", "Coprocessor access while it is disabled, e.g. the FPU"),
+    ("UFSR", 1 << 20, "STKOF", "Stack overflow detected by a stack limit register"),
+    ("UFSR", 1 << 24, "UNALIGNED", "Unaligned access"),
+    ("UFSR", 1 << 25, "DIVBYZERO", "Division by zero"),
+];
+
+#[rustfmt::skip]
+const HFSR_FLAGS: &[(&str, u32, &str, &str)] = &[
+    ("HFSR", 1 << 1, "VECTTBL", "Bus fault on a vector table read"),
+    ("HFSR", 1 << 30, "FORCED", "Escalated from a configurable fault which is disabled or could not run"),
+    ("HFSR", 1 << 31, "DEBUGEVT", "Debug event while halting debug is disabled"),
+];
+
+#[rustfmt::skip]
+const DFSR_FLAGS: &[(&str, u32, &str, &str)] = &[
+    ("DFSR", 1 << 0, "HALTED", "Halt request or single step"),
+    ("DFSR", 1 << 1, "BKPT", "Breakpoint"),
+    ("DFSR", 1 << 2, "DWTTRAP", "Watchpoint"),
+    ("DFSR", 1 << 3, "VCATCH", "Vector catch"),
+    ("DFSR", 1 << 4, "EXTERNAL", "External debug request"),
+];
+
+#[rustfmt::skip]
+const SFSR_FLAGS: &[(&str, u32, &str, &str)] = &[
+    ("SFSR", 1 << 0, "INVEP", "Non-secure code called a secure address which is no valid entry point"),
+    ("SFSR", 1 << 1, "INVIS", "Invalid integrity signature in the exception frame"),
+    ("SFSR", 1 << 2, "INVER", "Invalid exception return"),
+    ("SFSR", 1 << 3, "AUVIOL", "Non-secure access to a secure address"),
+    ("SFSR", 1 << 4, "INVTRAN", "Branch to non-secure code without a non-secure branch instruction"),
+    ("SFSR", 1 << 5, "LSPERR", "Security violation during lazy floating-point state preservation"),
+    ("SFSR", 1 << 6, "SFARVALID", "SFAR holds the faulting address"),
+    ("SFSR", 1 << 7, "LSERR", "Error while activating or deactivating lazy state preservation"),
+];
+
+/// The registers the core pushed on exception entry.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ExceptionFrame {
+    /// Where the frame starts on the stack.
+    pub address: u32,
+    pub exc_return: u32,
+    /// Whether the frame is on the process stack rather than the main stack.
+    pub process_stack: bool,
+    /// R0 to R3, R12, LR, PC and xPSR.
+    pub registers: [u32; 8],
+}
+
+impl ExceptionFrame {
+    pub fn lr(&self) -> u32 {
+        self.registers[5]
+    }
+
+    pub fn pc(&self) -> u32 {
+        self.registers[6]
+    }
+
+    pub fn xpsr(&self) -> u32 {
+        self.registers[7]
+    }
+}
+
+/// The fault state of a halted core.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FaultReport {
+    /// The active exception number from IPSR, zero in thread mode.
+    pub exception: u16,
+    /// Whether the core is locked up after a fault while handling a HardFault or NMI.
+    pub lockup: bool,
+    /// Only ARMv6-M and ARMv8-M Baseline lack the fault status registers.
+    pub cfsr: Option<u32>,
+    pub hfsr: Option<u32>,
+    pub dfsr: u32,
+    /// Only with the Security Extension.
+    pub sfsr: Option<u32>,
+    /// The fault addresses, where the status registers mark them valid.
+    pub mmfar: Option<u32>,
+    pub bfar: Option<u32>,
+    pub sfar: Option<u32>,
+    /// The frame stacked on entry to the active exception, if LR still holds its EXC_RETURN.
+    pub frame: Option<ExceptionFrame>,
+}
+
+impl FaultReport {
+    /// Reads the fault status of `core`, which has to be halted.
+    pub fn read<M: MemoryAccess>(mem: &mut M, core: Core) -> Result<Self, STLinkError> {
+        let info = core.info(mem)?;
+        let mainline = info.architecture.is_mainline();
+
+        let (cfsr, hfsr) = if mainline {
+            (
+                Some(mem.read_word32(CFSR, core.apsel)?),
+                Some(mem.read_word32(HFSR, core.apsel)?),
+            )
+        } else {
+            (None, None)
+        };
+        let sfsr = if mainline && info.trustzone {
+            Some(mem.read_word32(SFSR, core.apsel)?)
+        } else {
+            None
+        };
+        let mut report = Self {
+            exception: (core.read_register(mem, registers::XPSR)? & 0x1ff) as u16,
+            lockup: core.is_locked_up(mem)?,
+            cfsr,
+            hfsr,
+            dfsr: mem.read_word32(DFSR, core.apsel)?,
+            sfsr,
+            mmfar: None,
+            bfar: None,
+            sfar: None,
+            frame: None,
+        };
+        if cfsr.unwrap_or(0) & CFSR_MMARVALID != 0 {
+            report.mmfar = Some(mem.read_word32(MMFAR, core.apsel)?);
+        }
+        if cfsr.unwrap_or(0) & CFSR_BFARVALID != 0 {
+            report.bfar = Some(mem.read_word32(BFAR, core.apsel)?);
+        }
+        if sfsr.unwrap_or(0) & SFSR_SFARVALID != 0 {
+            report.sfar = Some(mem.read_word32(SFAR, core.apsel)?);
+        }
+
+        let exc_return = core.read_register(mem, registers::LR)?;
+        if (report.exception != 0 || report.lockup)
+            && exc_return & EXC_RETURN_PREFIX == EXC_RETURN_PREFIX
+        {
+            let process_stack = exc_return & EXC_RETURN_SPSEL != 0;
+            let sp = core.read_register(
+                mem,
+                if process_stack {
+                    registers::PSP
+                } else {
+                    registers::MSP
+                },
+            )?;
+            let secure_context = info.trustzone
+                && exc_return & EXC_RETURN_S != 0
+                && exc_return & EXC_RETURN_DCRS == 0;
+            let address = if secure_context {
+                sp + ADDITIONAL_STATE_CONTEXT
+            } else {
+                sp
+            };
+            let data = mem.read_mem32(address, 8 * 4, core.apsel)?;
+            let mut registers = [0; 8];
+            for (register, word) in registers.iter_mut().zip(data.chunks(4)) {
+                *register = u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+            }
+            report.frame = Some(ExceptionFrame {
+                address,
+                exc_return,
+                process_stack,
+                registers,
+            });
+        }
+        Ok(report)
+    }
+
+    /// Returns the set status flags as `(register, name, description)`.
+    pub fn flags(&self) -> Vec<(&'static str, &'static str, &'static str)> {
+        let registers = [
+            (self.cfsr, CFSR_FLAGS),
+            (self.hfsr, HFSR_FLAGS),
+            (Some(self.dfsr), DFSR_FLAGS),
+            (self.sfsr, SFSR_FLAGS),
+        ];
+        registers
+            .iter()
+            .filter_map(|&(value, flags)| value.map(|value| (value, flags)))
+            .flat_map(|(value, flags)| {
+                flags
+                    .iter()
+                    .filter(move |&&(_, bit, ..)| value & bit != 0)
+                    .map(|&(register, _, name, description)| (register, name, description))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists_the_set_flags() {
+        let report = FaultReport {
+            exception: 3,
+            lockup: false,
+            cfsr: Some(0x0000_8200),
+            hfsr: Some(0x4000_0000),
+            dfsr: 0x8,
+            sfsr: None,
+            mmfar: None,
+            bfar: Some(0x6000_0000),
+            sfar: None,
+            frame: None,
+        };
+        let names: Vec<_> = report.flags().iter().map(|&(_, name, _)| name).collect();
+        assert_eq!(names, ["PRECISERR", "BFARVALID", "FORCED", "VCATCH"]);
+    }
+}
@@ -0,0 +1,784 @@
+use std::path::Path;
+use std::time::Duration;
+
+use goblin::elf::section_header::SHT_NOBITS;
+use goblin::elf::Elf;
+use ssmarshal::deserialize;
+
+use crate::cortex_m::{registers, Core};
+use crate::image::{expected_contents, span_in, FlashReport, Segment};
+use crate::memory::MemoryAccess;
+use crate::stlink::STLinkError;
+
+#[derive(Debug)]
+pub enum FlashAlgorithmError {
+    IO(std::io::Error),
+    Elf(goblin::error::Error),
+    STLink(STLinkError),
+    MissingSymbol(&'static str),
+    MissingSection(&'static str),
+    /// The `FlashDevice` description is truncated, has no sectors or no page size.
+    InvalidDescription,
+    /// A section reaches past the end of the file.
+    TruncatedSection,
+    /// The algorithm is larger than any RAM, or it and its buffers do not fit into the given RAM.
+    DoesNotFit,
+    AddressOutOfRange(u32),
+    /// A function of the algorithm halted at the given PC instead of returning.
+    DidNotReturn(&'static str, u32),
+    /// A function of the algorithm returned a non-zero result.
+    Failed(&'static str, u32),
+}
+
+impl From<STLinkError> for FlashAlgorithmError {
+    fn from(e: STLinkError) -> Self {
+        FlashAlgorithmError::STLink(e)
+    }
+}
+
+/// What `Init` prepares the algorithm for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FlashOperation {
+    Erase = 1,
+    Program = 2,
+    Verify = 3,
+}
+
+/// A CMSIS-Pack flash algorithm, as found in the `.FLM` files of the device family packs.
+#[derive(Debug)]
+pub struct FlashAlgorithm {
+    /// The device name of the `FlashDevice` description.
+    pub name: String,
+    pub flash_start: u32,
+    pub flash_size: u32,
+    /// The amount of data `ProgramPage` takes at once.
+    pub page_size: u32,
+    /// The value of erased bytes.
+    pub erased_value: u8,
+    pub program_timeout: Duration,
+    pub erase_timeout: Duration,
+    /// The sectors as runs of `(size, start)`, each run lasting until the next one starts.
+    pub sectors: Vec<(u32, u32)>,
+    /// `PrgCode` followed by `PrgData`, linked to run at address 0.
+    code: Vec<u8>,
+    /// The offset of `PrgData`, which the algorithm expects in R9.
+    data_offset: u32,
+    init: Option<u32>,
+    uninit: Option<u32>,
+    erase_chip: Option<u32>,
+    erase_sector: u32,
+    program_page: u32,
+}
+
+impl FlashAlgorithm {
+    /// The most code and data an algorithm may take up, which is more than the RAM of any device.
+    const MAX_CODE_SIZE: u32 = 0x10_0000;
+
+    /// Reads the flash algorithm at `path`.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, FlashAlgorithmError> {
+        let data = std::fs::read(path).or_else(|e| Err(FlashAlgorithmError::IO(e)))?;
+        Self::from_elf(&data)
+    }
+
+    /// Reads the flash algorithm from the contents of an `.FLM` file.
+    pub fn from_elf(data: &[u8]) -> Result<Self, FlashAlgorithmError> {
+        let elf = Elf::parse(data).or_else(|e| Err(FlashAlgorithmError::Elf(e)))?;
+
+        let symbol = |name: &'static str| {
+            elf.syms
+                .iter()
+                .find(|symbol| match elf.strtab.get(symbol.st_name) {
+                    Some(Ok(symbol_name)) => symbol_name == name,
+                    _ => false,
+                })
+                .map(|symbol| symbol.st_value as u32)
+        };
+        let function = |name: &'static str| symbol(name).map(|address| address & !1);
+
+        let mut code = vec![];
+        let mut data_offset = None;
+        let mut description = None;
+        for header in &elf.section_headers {
+            let name = match elf.shdr_strtab.get(header.sh_name) {
+                Some(Ok(name)) => name,
+                _ => continue,
+            };
+            let contents = if header.sh_type == SHT_NOBITS {
+                None
+            } else {
+                let offset = header.sh_offset as usize;
+                let contents = offset
+                    .checked_add(header.sh_size as usize)
+                    .and_then(|end| data.get(offset..end))
+                    .ok_or(FlashAlgorithmError::TruncatedSection)?;
+                Some(contents)
+            };
+            match name {
+                "PrgCode" | "PrgData" => {
+                    let start = header.sh_addr as u32;
+                    let end = match start.checked_add(header.sh_size as u32) {
+                        Some(end) if end <= Self::MAX_CODE_SIZE => end as usize,
+                        _ => return Err(FlashAlgorithmError::DoesNotFit),
+                    };
+                    if name == "PrgData" {
+                        data_offset = Some(start);
+                    }
+                    if code.len() < end {
+                        code.resize(end, 0);
+                    }
+                    if let Some(contents) = contents {
+                        code[start as usize..end].copy_from_slice(contents);
+                    }
+                }
+                "DevDscr" => {
+                    let address = symbol("FlashDevice").unwrap_or(header.sh_addr as u32);
+                    let skip = address.saturating_sub(header.sh_addr as u32) as usize;
+                    description = contents.and_then(|contents| contents.get(skip..));
+                }
+                _ => (),
+            }
+        }
+        if code.is_empty() {
+            return Err(FlashAlgorithmError::MissingSection("PrgCode"));
+        }
+        let description = description.ok_or(FlashAlgorithmError::MissingSection("DevDscr"))?;
+        let description = Description::parse(description)?;
+
+        Ok(Self {
+            name: description.name,
+            flash_start: description.address,
+            flash_size: description.size,
+            page_size: description.page_size,
+            erased_value: description.erased_value,
+            program_timeout: Duration::from_millis(u64::from(description.program_timeout)),
+            erase_timeout: Duration::from_millis(u64::from(description.erase_timeout)),
+            sectors: description.sectors,
+            data_offset: data_offset.unwrap_or(code.len() as u32),
+            code,
+            init: function("Init"),
+            uninit: function("UnInit"),
+            erase_chip: function("EraseChip"),
+            erase_sector: function("EraseSector")
+                .ok_or(FlashAlgorithmError::MissingSymbol("EraseSector"))?,
+            program_page: function("ProgramPage")
+                .ok_or(FlashAlgorithmError::MissingSymbol("ProgramPage"))?,
+        })
+    }
+
+    /// Returns the start and size of the sector containing `addr`.
+    pub fn sector(&self, addr: u32) -> Option<(u32, u32)> {
+        if addr < self.flash_start || addr - self.flash_start >= self.flash_size {
+            return None;
+        }
+        let offset = addr - self.flash_start;
+        let (size, start) = self
+            .sectors
+            .iter()
+            .take_while(|&&(_, start)| start <= offset)
+            .last()?;
+        let start = start + (offset - start) / size * size;
+        Some((self.flash_start + start, *size))
+    }
+}
+
+/// The `FlashDevice` structure of an algorithm.
+struct Description {
+    name: String,
+    address: u32,
+    size: u32,
+    page_size: u32,
+    erased_value: u8,
+    program_timeout: u32,
+    erase_timeout: u32,
+    sectors: Vec<(u32, u32)>,
+}
+
+impl Description {
+    const NAME: usize = 2;
+    const NAME_LENGTH: usize = 128;
+    const ADDRESS: usize = 132;
+    const SIZE: usize = 136;
+    const PAGE_SIZE: usize = 140;
+    const ERASED_VALUE: usize = 148;
+    const PROGRAM_TIMEOUT: usize = 152;
+    const ERASE_TIMEOUT: usize = 156;
+    const SECTORS: usize = 160;
+    /// Marks the end of the sector list.
+    const SECTOR_END: u32 = 0xffff_ffff;
+
+    fn parse(data: &[u8]) -> Result<Self, FlashAlgorithmError> {
+        let word = |offset: usize| -> Result<u32, FlashAlgorithmError> {
+            data.get(offset..offset + 4)
+                .map(|bytes| deserialize::<u32>(bytes).unwrap().0)
+                .ok_or(FlashAlgorithmError::InvalidDescription)
+        };
+
+        let name = data
+            .get(Self::NAME..Self::NAME + Self::NAME_LENGTH)
+            .ok_or(FlashAlgorithmError::InvalidDescription)?;
+        let name = name.split(|&byte| byte == 0).next().unwrap_or(&[]);
+
+        let mut sectors = vec![];
+        let mut offset = Self::SECTORS;
+        loop {
+            let size = word(offset)?;
+            let start = word(offset + 4)?;
+            if size == Self::SECTOR_END && start == Self::SECTOR_END {
+                break;
+            }
+            if size == 0 {
+                return Err(FlashAlgorithmError::InvalidDescription);
+            }
+            sectors.push((size, start));
+            offset += 8;
+        }
+        let page_size = word(Self::PAGE_SIZE)?;
+        if sectors.is_empty() || page_size == 0 {
+            return Err(FlashAlgorithmError::InvalidDescription);
+        }
+
+        Ok(Self {
+            name: String::from_utf8_lossy(name).into_owned(),
+            address: word(Self::ADDRESS)?,
+            size: word(Self::SIZE)?,
+            page_size,
+            erased_value: data[Self::ERASED_VALUE],
+            program_timeout: word(Self::PROGRAM_TIMEOUT)?,
+            erase_timeout: word(Self::ERASE_TIMEOUT)?,
+            sectors,
+        })
+    }
+}
+
+/// Runs a flash algorithm on a halted core.
+///
+/// The algorithm is placed at the start of the given RAM, followed by its stack
+/// and two page buffers, so the next page can be downloaded while the current one is programmed.
+/// A single buffer is used if the RAM is too small for two.
+pub struct FlashLoader<'a, 'm, M: MemoryAccess> {
+    mem: &'m mut M,
+    core: Core,
+    algorithm: &'a FlashAlgorithm,
+    /// The address of the `BKPT` the functions return to.
+    breakpoint: u32,
+    code: u32,
+    stack_top: u32,
+    buffers: Vec<u32>,
+}
+
+impl<'a, 'm, M: MemoryAccess> FlashLoader<'a, 'm, M> {
+    /// Two `BKPT` instructions.
+    const HEADER: [u8; 4] = [0x00, 0xbe, 0x00, 0xbe];
+    const STACK_SIZE: u32 = 0x400;
+    /// The EPSR T bit, which has to be set to execute Thumb code.
+    const XPSR_THUMB: u32 = 1 << 24;
+    const R9: u16 = 9;
+    /// How long `Init` and `UnInit` may take.
+    const INIT_TIMEOUT: Duration = Duration::from_millis(1000);
+
+    /// Downloads the algorithm into the `ram_size` bytes of RAM at `ram_start`.
+    pub fn new(
+        mem: &'m mut M,
+        core: Core,
+        algorithm: &'a FlashAlgorithm,
+        ram_start: u32,
+        ram_size: u32,
+    ) -> Result<Self, FlashAlgorithmError> {
+        let code = ram_start + Self::HEADER.len() as u32;
+        let stack_top = (code + algorithm.code.len() as u32 + Self::STACK_SIZE + 7) & !7;
+        let available = (ram_start + ram_size).saturating_sub(stack_top);
+        let buffers = match available / algorithm.page_size.max(1) {
+            0 => return Err(FlashAlgorithmError::DoesNotFit),
+            1 => vec![stack_top],
+            _ => vec![stack_top, stack_top + algorithm.page_size],
+        };
+
+        let mut image = Self::HEADER.to_vec();
+        image.extend_from_slice(&algorithm.code);
+        while image.len() % 4 != 0 {
+            image.push(0);
+        }
+        mem.write_mem32(ram_start, image, core.apsel)?;
+
+        Ok(Self {
+            mem,
+            core,
+            algorithm,
+            breakpoint: ram_start,
+            code,
+            stack_top,
+            buffers,
+        })
+    }
+
+    /// Calls `Init` to prepare the flash for an operation.
+    pub fn init(&mut self, operation: FlashOperation) -> Result<(), FlashAlgorithmError> {
+        match self.algorithm.init {
+            Some(init) => self.call(
+                "Init",
+                init,
+                &[self.algorithm.flash_start, 0, operation as u32],
+                Self::INIT_TIMEOUT,
+            ),
+            None => Ok(()),
+        }
+    }
+
+    /// Calls `UnInit` after an operation.
+    pub fn uninit(&mut self, operation: FlashOperation) -> Result<(), FlashAlgorithmError> {
+        match self.algorithm.uninit {
+            Some(uninit) => self.call("UnInit", uninit, &[operation as u32], Self::INIT_TIMEOUT),
+            None => Ok(()),
+        }
+    }
+
+    /// Erases the sector containing `addr`.
+    pub fn erase_sector(&mut self, addr: u32) -> Result<(), FlashAlgorithmError> {
+        let (start, _) = self
+            .algorithm
+            .sector(addr)
+            .ok_or(FlashAlgorithmError::AddressOutOfRange(addr))?;
+        let timeout = self.algorithm.erase_timeout;
+        self.call(
+            "EraseSector",
+            self.algorithm.erase_sector,
+            &[start],
+            timeout,
+        )
+    }
+
+    /// Erases all sectors which overlap the `size` bytes at `addr`.
+    pub fn erase_range(&mut self, addr: u32, size: u32) -> Result<(), FlashAlgorithmError> {
+        let end = addr
+            .checked_add(size)
+            .ok_or(FlashAlgorithmError::AddressOutOfRange(addr))?;
+        let mut addr = addr;
+        while addr < end {
+            let (start, size) = self
+                .algorithm
+                .sector(addr)
+                .ok_or(FlashAlgorithmError::AddressOutOfRange(addr))?;
+            self.erase_sector(start)?;
+            addr = start + size;
+        }
+        Ok(())
+    }
+
+    /// Erases the whole flash, sector by sector if the algorithm has no `EraseChip`.
+    pub fn erase_chip(&mut self) -> Result<(), FlashAlgorithmError> {
+        match self.algorithm.erase_chip {
+            Some(erase_chip) => {
+                // A chip erase takes as long as erasing all sectors.
+                let sectors = self.algorithm.flash_size / self.algorithm.sectors[0].0;
+                let timeout = self.algorithm.erase_timeout * sectors.max(1);
+                self.call("EraseChip", erase_chip, &[], timeout)
+            }
+            None => self.erase_range(self.algorithm.flash_start, self.algorithm.flash_size),
+        }
+    }
+
+    /// Programs `data` to erased flash at `addr`, page by page.
+    /// Partial pages are padded with the erased value.
+    pub fn program(&mut self, addr: u32, data: &[u8]) -> Result<(), FlashAlgorithmError> {
+        let algorithm = self.algorithm;
+        let page_size = algorithm.page_size;
+        let flash_end = algorithm.flash_start + algorithm.flash_size;
+        match addr.checked_add(data.len() as u32) {
+            Some(end) if addr >= algorithm.flash_start && end <= flash_end => (),
+            _ => return Err(FlashAlgorithmError::AddressOutOfRange(addr)),
+        }
+        let start = addr - (addr - algorithm.flash_start) % page_size;
+        let mut padded = vec![algorithm.erased_value; (addr - start) as usize];
+        padded.extend_from_slice(data);
+        while padded.len() % page_size as usize != 0 {
+            padded.push(algorithm.erased_value);
+        }
+
+        let pages: Vec<_> = padded.chunks(page_size as usize).collect();
+        if pages.is_empty() {
+            return Ok(());
+        }
+        self.download(0, pages[0])?;
+        for (i, page) in pages.iter().enumerate() {
+            let buffer = self.buffers[i % self.buffers.len()];
+            let page_addr = start + i as u32 * page_size;
+            self.start(
+                algorithm.program_page,
+                &[page_addr, page.len() as u32, buffer],
+            )?;
+            // Download the next page while this one is programmed.
+            let next = pages.get(i + 1).filter(|_| self.buffers.len() > 1);
+            let downloaded = match next {
+                Some(next) => self.download(i + 1, next),
+                None => Ok(()),
+            };
+            self.finish("ProgramPage", algorithm.program_timeout)?;
+            downloaded?;
+            if self.buffers.len() == 1 && i + 1 < pages.len() {
+                self.download(i + 1, pages[i + 1])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Erases the sectors covered by `data` and programs it at `addr`.
+    pub fn program_flash(&mut self, addr: u32, data: &[u8]) -> Result<(), FlashAlgorithmError> {
+        self.init(FlashOperation::Erase)?;
+        self.erase_range(addr, data.len() as u32)?;
+        self.uninit(FlashOperation::Erase)?;
+        self.init(FlashOperation::Program)?;
+        self.program(addr, data)?;
+        self.uninit(FlashOperation::Program)
+    }
+
+    /// Programs all segments of an image.
+    /// Sectors which already hold their part of the image are skipped,
+    /// the others are erased and programmed.
+    pub fn program_segments(
+        &mut self,
+        segments: &[Segment],
+    ) -> Result<FlashReport, FlashAlgorithmError> {
+        let mut sectors = vec![];
+        for segment in segments {
+            let mut addr = segment.address;
+            while addr < segment.end() {
+                let sector = self
+                    .algorithm
+                    .sector(addr)
+                    .ok_or(FlashAlgorithmError::AddressOutOfRange(addr))?;
+                sectors.push(sector);
+                addr = sector.0 + sector.1;
+            }
+        }
+        sectors.dedup();
+
+        let mut report = FlashReport::default();
+        let mut changed = vec![];
+        for (start, size) in sectors {
+            let expected = expected_contents(segments, start, size, self.algorithm.erased_value);
+            if self.mem.read_mem32(start, size, self.core.apsel)? == expected {
+                report.sectors_skipped += 1;
+            } else {
+                changed.push((start, size, expected));
+            }
+        }
+        if changed.is_empty() {
+            return Ok(report);
+        }
+
+        self.init(FlashOperation::Erase)?;
+        for &(start, _, _) in &changed {
+            self.erase_sector(start)?;
+        }
+        self.uninit(FlashOperation::Erase)?;
+        self.init(FlashOperation::Program)?;
+        // Segments may share a page, which must only be programmed once.
+        for (start, size, expected) in &changed {
+            if let Some((from, to)) = span_in(segments, *start, *size) {
+                let data = &expected[(from - start) as usize..(to - start) as usize];
+                self.program(from, data)?;
+            }
+            report.sectors_written += 1;
+        }
+        self.uninit(FlashOperation::Program)?;
+        Ok(report)
+    }
+
+    fn download(&mut self, page: usize, data: &[u8]) -> Result<(), FlashAlgorithmError> {
+        let buffer = self.buffers[page % self.buffers.len()];
+        Ok(self
+            .mem
+            .write_mem32(buffer, data.to_vec(), self.core.apsel)?)
+    }
+
+    fn call(
+        &mut self,
+        name: &'static str,
+        function: u32,
+        arguments: &[u32],
+        timeout: Duration,
+    ) -> Result<(), FlashAlgorithmError> {
+        self.start(function, arguments)?;
+        self.finish(name, timeout)
+    }
+
+    /// Lets the core run `function` with the given arguments, returning to the breakpoint.
+    fn start(&mut self, function: u32, arguments: &[u32]) -> Result<(), FlashAlgorithmError> {
+        let core = self.core;
+        for (register, &argument) in arguments.iter().enumerate() {
+            core.write_register(self.mem, register as u16, argument)?;
+        }
+        let static_base = self.code + self.algorithm.data_offset;
+        core.write_register(self.mem, Self::R9, static_base)?;
+        core.write_register(self.mem, registers::SP, self.stack_top)?;
+        core.write_register(self.mem, registers::LR, self.breakpoint | 1)?;
+        core.write_register(self.mem, registers::PC, self.code + function)?;
+        core.write_register(self.mem, registers::XPSR, Self::XPSR_THUMB)?;
+        Ok(core.run(self.mem)?)
+    }
+
+    /// Waits for the function to return and checks its result.
+    fn finish(&mut self, name: &'static str, timeout: Duration) -> Result<(), FlashAlgorithmError> {
+        let core = self.core;
+        if let Err(e) = core.wait_for_halt(self.mem, timeout) {
+            // Do not leave the algorithm running.
+            core.halt(self.mem).ok();
+            return Err(e.into());
+        }
+        // A fault caught by the debugger or a BKPT of its own halts the algorithm elsewhere.
+        let pc = core.read_register(self.mem, registers::PC)?;
+        if pc != self.breakpoint {
+            return Err(FlashAlgorithmError::DidNotReturn(name, pc));
+        }
+        match core.read_register(self.mem, registers::R0)? {
+            0 => Ok(()),
+            result => Err(FlashAlgorithmError::Failed(name, result)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cortex_m::mock;
+    use crate::symbols::mock::Elf;
+    use goblin::elf::section_header::SHT_PROGBITS;
+    use goblin::elf::sym::{STT_FUNC, STT_OBJECT};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    const RAM: u32 = 0x2000_0000;
+    const PROGRAM_PAGE: u32 = 0x20;
+
+    /// The pages programmed, as `(address, buffer, data)`.
+    type Programmed = Rc<RefCell<Vec<(u32, u32, Vec<u8>)>>>;
+
+    /// A core which runs `ProgramPage` by copying the page buffer when it is polled for the halt,
+    /// so a buffer overwritten while the function runs programs the wrong data.
+    fn target() -> (mock::Target, Programmed) {
+        let programmed = Programmed::default();
+        let pages = programmed.clone();
+        let mut target = mock::Target::new();
+        target.firmware = Box::new(move |memory, registers| {
+            assert_eq!(registers[14], RAM | 1);
+            // The code follows the BKPT header.
+            if registers[15] == RAM + 4 + PROGRAM_PAGE {
+                let data = memory.read(registers[2], registers[1]);
+                pages.borrow_mut().push((registers[0], registers[2], data));
+            }
+            registers[0] = 0;
+            registers[15] = registers[14] & !1;
+        });
+        (target, programmed)
+    }
+
+    fn algorithm() -> FlashAlgorithm {
+        FlashAlgorithm {
+            name: "test".to_owned(),
+            flash_start: 0x0800_0000,
+            flash_size: 0x1000,
+            page_size: 0x100,
+            erased_value: 0xff,
+            program_timeout: Duration::from_millis(100),
+            erase_timeout: Duration::from_millis(100),
+            sectors: vec![(0x400, 0)],
+            code: vec![0; 0x40],
+            data_offset: 0x40,
+            init: None,
+            uninit: None,
+            erase_chip: None,
+            erase_sector: 0x10,
+            program_page: PROGRAM_PAGE,
+        }
+    }
+
+    #[test]
+    fn programs_pages_through_alternating_buffers() {
+        let algorithm = algorithm();
+        let data: Vec<u8> = (0..0x250).map(|i| i as u8).collect();
+        let mut expected = vec![0xff; 0x10];
+        expected.extend_from_slice(&data);
+        expected.resize(0x300, 0xff);
+
+        // The stack ends at 0x2000_0448, followed by room for two pages or only one.
+        for &(ram_size, buffers) in &[(0x1000, 2), (0x548, 1)] {
+            let (mut target, programmed) = target();
+            let mut loader =
+                FlashLoader::new(&mut target, Core::new(0), &algorithm, RAM, ram_size).unwrap();
+            loader.program(0x0800_0010, &data).unwrap();
+
+            let programmed = programmed.borrow();
+            assert_eq!(programmed.len(), 3);
+            for (i, (addr, buffer, page)) in programmed.iter().enumerate() {
+                assert_eq!(*addr, 0x0800_0000 + 0x100 * i as u32);
+                assert_eq!(*buffer, 0x2000_0448 + 0x100 * (i as u32 % buffers));
+                assert_eq!(page[..], expected[0x100 * i..0x100 * (i + 1)]);
+            }
+            // R9 points to PrgData.
+            assert_eq!(target.registers[9], RAM + 4 + 0x40);
+        }
+    }
+
+    /// A `FlashDevice` description of an STM32F4 with 1 MiB of flash.
+    fn description() -> Vec<u8> {
+        let mut data = vec![0; 200];
+        data[0] = 1;
+        data[2..13].copy_from_slice(b"STM32F4xx 1");
+        let words = [
+            (132, 0x0800_0000),
+            (136, 0x0010_0000),
+            (140, 0x400),
+            (152, 100),
+            (156, 6000),
+            (160, 0x4000),
+            (164, 0),
+            (168, 0x1_0000),
+            (172, 0x1_0000),
+            (176, 0x2_0000),
+            (180, 0x2_0000),
+            (184, 0xffff_ffff),
+            (188, 0xffff_ffff),
+        ];
+        for &(offset, value) in &words {
+            data[offset..offset + 4].copy_from_slice(&u32::to_le_bytes(value));
+        }
+        data[148] = 0xff;
+        data
+    }
+
+    #[test]
+    fn parses_the_device_description() {
+        let data = description();
+        let description = Description::parse(&data).unwrap();
+        assert_eq!(description.name, "STM32F4xx 1");
+        assert_eq!(description.page_size, 0x400);
+        assert_eq!(description.erased_value, 0xff);
+
+        let mut no_pages = data.clone();
+        no_pages[140..144].copy_from_slice(&[0; 4]);
+        match Description::parse(&no_pages) {
+            Err(FlashAlgorithmError::InvalidDescription) => (),
+            result => panic!("unexpected result {:?}", result.map(|d| d.page_size)),
+        }
+
+        let algorithm = FlashAlgorithm {
+            name: description.name,
+            flash_start: description.address,
+            flash_size: description.size,
+            page_size: description.page_size,
+            erased_value: description.erased_value,
+            program_timeout: Duration::from_millis(100),
+            erase_timeout: Duration::from_millis(6000),
+            sectors: description.sectors,
+            code: vec![],
+            data_offset: 0,
+            init: None,
+            uninit: None,
+            erase_chip: None,
+            erase_sector: 0,
+            program_page: 0,
+        };
+        assert_eq!(algorithm.sector(0x0800_4010), Some((0x0800_4000, 0x4000)));
+        assert_eq!(algorithm.sector(0x0801_2000), Some((0x0801_0000, 0x1_0000)));
+        assert_eq!(algorithm.sector(0x0806_0004), Some((0x0806_0000, 0x2_0000)));
+        assert_eq!(algorithm.sector(0x0810_0000), None);
+    }
+
+    #[test]
+    fn programs_pages_shared_by_segments_once() {
+        let algorithm = algorithm();
+        let segments = [
+            Segment {
+                address: 0x0800_0010,
+                data: vec![1; 0x10],
+            },
+            Segment {
+                address: 0x0800_0080,
+                data: vec![2; 0x90],
+            },
+        ];
+        let (mut target, programmed) = target();
+        let mut loader =
+            FlashLoader::new(&mut target, Core::new(0), &algorithm, RAM, 0x1000).unwrap();
+        let report = loader.program_segments(&segments).unwrap();
+        assert_eq!(report.sectors_written, 1);
+
+        let programmed = programmed.borrow();
+        let pages: Vec<_> = programmed.iter().map(|page| page.0).collect();
+        assert_eq!(pages, vec![0x0800_0000, 0x0800_0100]);
+        let page = &programmed[0].2;
+        assert_eq!(page[0x0f..0x11], [0xff, 1]);
+        assert_eq!(page[0x1f..0x21], [1, 0xff]);
+        assert_eq!(page[0x80], 2);
+    }
+
+    #[test]
+    fn fails_if_a_function_does_not_return_zero() {
+        let algorithm = algorithm();
+        let (mut halting, _) = target();
+        // Halts on a BKPT inside the algorithm, leaving R0 unchanged.
+        halting.firmware = Box::new(|_, registers| registers[15] = RAM + 4 + 0x12);
+        let mut loader =
+            FlashLoader::new(&mut halting, Core::new(0), &algorithm, RAM, 0x1000).unwrap();
+        match loader.erase_sector(0x0800_0000) {
+            Err(FlashAlgorithmError::DidNotReturn("EraseSector", 0x2000_0016)) => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+
+        let (mut failing, _) = target();
+        failing.firmware = Box::new(|_, registers| {
+            registers[0] = 1;
+            registers[15] = registers[14] & !1;
+        });
+        let mut loader =
+            FlashLoader::new(&mut failing, Core::new(0), &algorithm, RAM, 0x1000).unwrap();
+        match loader.erase_sector(0x0800_0000) {
+            Err(FlashAlgorithmError::Failed("EraseSector", 1)) => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    fn flm(code_address: u32, data_type: u32) -> Elf {
+        Elf::new()
+            .symbol("Init", 0x01, 0x10, STT_FUNC)
+            .symbol("EraseSector", 0x11, 0x10, STT_FUNC)
+            .symbol("ProgramPage", 0x21, 0x10, STT_FUNC)
+            .symbol("FlashDevice", 0x100, 200, STT_OBJECT)
+            .section("PrgCode", SHT_PROGBITS, code_address, &[0x70, 0x47, 0, 0])
+            .section("PrgData", data_type, 0x20, &[0; 8])
+            .section("DevDscr", SHT_PROGBITS, 0x100, &description())
+    }
+
+    #[test]
+    fn reads_the_algorithm_from_its_elf() {
+        let algorithm = FlashAlgorithm::from_elf(&flm(0, SHT_NOBITS).build()).unwrap();
+        assert_eq!(algorithm.name, "STM32F4xx 1");
+        assert_eq!(algorithm.code.len(), 0x28);
+        assert_eq!(algorithm.code[..4], [0x70, 0x47, 0, 0]);
+        assert_eq!(algorithm.data_offset, 0x20);
+        assert_eq!(algorithm.init, Some(0));
+        assert_eq!(algorithm.uninit, None);
+        assert_eq!(algorithm.erase_sector, 0x10);
+        assert_eq!(algorithm.program_page, 0x20);
+    }
+
+    #[test]
+    fn rejects_sections_it_cannot_load() {
+        // Linked far beyond the RAM of any device.
+        match FlashAlgorithm::from_elf(&flm(0xffff_fffe, SHT_PROGBITS).build()) {
+            Err(FlashAlgorithmError::DoesNotFit) => (),
+            result => panic!("unexpected result {:?}", result.map(|a| a.name)),
+        }
+
+        // PrgCode, which follows the null section and the symbol and string tables,
+        // claims more contents than the file has.
+        let mut data = flm(0, SHT_PROGBITS).build();
+        let shoff = u32::from_le_bytes([data[32], data[33], data[34], data[35]]) as usize;
+        let sh_size = shoff + 4 * 40 + 20;
+        data[sh_size..sh_size + 4].copy_from_slice(&0x1000u32.to_le_bytes());
+        match FlashAlgorithm::from_elf(&data) {
+            Err(FlashAlgorithmError::TruncatedSection) => (),
+            result => panic!("unexpected result {:?}", result.map(|a| a.name)),
+        }
+    }
+}
@@ -0,0 +1,1482 @@
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use crate::cortex_m::{registers, Core, DEMCR, DEMCR_TRCENA};
+use crate::itm::{ItmDecoder, TracePacket};
+use crate::memory::MemoryAccess;
+use crate::rtos::{FreeRtos, Task, TaskState};
+use crate::rtt::{Rtt, RttError};
+use crate::stlink::{STLink, STLinkError};
+use crate::stm32::{identify, Stm32Family};
+use crate::trace::{configure_trace, TraceConfig};
+
+#[derive(Debug)]
+pub enum GdbError {
+    IO(std::io::Error),
+    STLink(STLinkError),
+}
+
+impl From<STLinkError> for GdbError {
+    fn from(e: STLinkError) -> Self {
+        GdbError::STLink(e)
+    }
+}
+
+impl From<std::io::Error> for GdbError {
+    fn from(e: std::io::Error) -> Self {
+        GdbError::IO(e)
+    }
+}
+
+/// The kind of a memory region in the memory map reported to GDB.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RegionKind {
+    Ram,
+    Rom,
+    /// Flash, which is reported as read-only memory as the server can't program it.
+    Flash,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemoryRegion {
+    pub kind: RegionKind,
+    pub start: u32,
+    pub length: u32,
+}
+
+/// The registers as numbered in the target description: name, type and DCRSR selector.
+const REGISTERS: [(&str, &str, u16); 23] = [
+    ("r0", "uint32", 0),
+    ("r1", "uint32", 1),
+    ("r2", "uint32", 2),
+    ("r3", "uint32", 3),
+    ("r4", "uint32", 4),
+    ("r5", "uint32", 5),
+    ("r6", "uint32", 6),
+    ("r7", "uint32", 7),
+    ("r8", "uint32", 8),
+    ("r9", "uint32", 9),
+    ("r10", "uint32", 10),
+    ("r11", "uint32", 11),
+    ("r12", "uint32", 12),
+    ("sp", "data_ptr", registers::SP),
+    ("lr", "uint32", registers::LR),
+    ("pc", "code_ptr", registers::PC),
+    ("xpsr", "uint32", registers::XPSR),
+    ("msp", "data_ptr", registers::MSP),
+    ("psp", "data_ptr", registers::PSP),
+    // These four share one DCRSR selector, one byte each.
+    ("primask", "uint32", registers::SPECIAL),
+    ("basepri", "uint32", registers::SPECIAL),
+    ("faultmask", "uint32", registers::SPECIAL),
+    ("control", "uint32", registers::SPECIAL),
+];
+
+/// Number of the first register in the `org.gnu.gdb.arm.m-system` feature.
+const FIRST_SYSTEM_REGISTER: usize = 17;
+/// Number of the first register packed into the special register selector.
+const FIRST_SPECIAL_REGISTER: usize = 19;
+
+const FP_CTRL: u32 = 0xe000_2000;
+const FP_CTRL_KEY: u32 = 1 << 1;
+const FP_CTRL_ENABLE: u32 = 1 << 0;
+const FP_COMP0: u32 = 0xe000_2008;
+const FP_COMP_ENABLE: u32 = 1 << 0;
+const FP_COMP_REPLACE_LOWER: u32 = 0x1 << 30;
+const FP_COMP_REPLACE_UPPER: u32 = 0x2 << 30;
+
+const DWT_CTRL: u32 = 0xe000_1000;
+const DWT_COMP0: u32 = 0xe000_1020;
+const DWT_FUNCTION_READ: u32 = 0x5;
+const DWT_FUNCTION_WRITE: u32 = 0x6;
+const DWT_FUNCTION_ACCESS: u32 = 0x7;
+
+/// The signals reported in stop replies.
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+/// How often the cores are polled while they run.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The largest packet GDB may send us.
+const PACKET_SIZE: usize = 0x4000;
+
+/// Probe functions the `monitor` commands are routed to, besides memory access.
+///
+/// This is implemented by `STLink`.
+pub trait Monitor: MemoryAccess {
+    fn target_voltage(&mut self) -> Result<f32, STLinkError>;
+    /// Sets the fastest clock of the wire protocol in use not above `hz` and returns its frequency.
+    fn set_speed(&mut self, hz: u32) -> Result<u32, STLinkError>;
+    /// Starts the SWV reception and returns the baud rate it runs at.
+    fn start_trace(&mut self, baud: u32, trace_clk: u32) -> Result<u32, STLinkError>;
+    fn stop_trace(&mut self) -> Result<(), STLinkError>;
+    /// Returns the SWV bytes received since the last call.
+    fn poll_trace(&mut self) -> Result<Vec<u8>, STLinkError>;
+}
+
+impl<'a> Monitor for STLink<'a> {
+    fn target_voltage(&mut self) -> Result<f32, STLinkError> {
+        self.get_target_voltage()
+    }
+
+    fn set_speed(&mut self, hz: u32) -> Result<u32, STLinkError> {
+        self.set_frequency(hz)
+    }
+
+    fn start_trace(&mut self, baud: u32, trace_clk: u32) -> Result<u32, STLinkError> {
+        self.start_swv(baud, trace_clk)
+    }
+
+    fn stop_trace(&mut self) -> Result<(), STLinkError> {
+        self.stop_swv()
+    }
+
+    fn poll_trace(&mut self) -> Result<Vec<u8>, STLinkError> {
+        match self.swv_bytes_available()? {
+            0 => Ok(vec![]),
+            available => self.read_swv(available as usize),
+        }
+    }
+}
+
+/// A core together with the state of its breakpoint and watchpoint units.
+struct Target {
+    core: Core,
+    /// The addresses of the FPB comparators in use.
+    breakpoints: Vec<Option<u32>>,
+    fpb_revision: u32,
+    /// The address, length and function of the DWT comparators in use.
+    watchpoints: Vec<Option<(u32, u32, u32)>>,
+}
+
+impl Target {
+    /// Halts the core, enables its FPB and DWT and clears all comparators.
+    fn attach<M: MemoryAccess>(mem: &mut M, core: Core) -> Result<Self, STLinkError> {
+        core.halt(mem)?;
+
+        let fp_ctrl = mem.read_word32(FP_CTRL, core.apsel)?;
+        let count = ((fp_ctrl >> 8) & 0x70) | ((fp_ctrl >> 4) & 0xf);
+        mem.write_word32(FP_CTRL, FP_CTRL_KEY | FP_CTRL_ENABLE, core.apsel)?;
+
+        let demcr = mem.read_word32(DEMCR, core.apsel)?;
+        mem.write_word32(DEMCR, demcr | DEMCR_TRCENA, core.apsel)?;
+        let dwt_ctrl = mem.read_word32(DWT_CTRL, core.apsel)?;
+
+        let mut target = Self {
+            core,
+            breakpoints: vec![None; count as usize],
+            fpb_revision: fp_ctrl >> 28,
+            watchpoints: vec![None; (dwt_ctrl >> 28) as usize],
+        };
+        target.clear(mem)?;
+        Ok(target)
+    }
+
+    fn clear<M: MemoryAccess>(&mut self, mem: &mut M) -> Result<(), STLinkError> {
+        for slot in 0..self.breakpoints.len() {
+            self.set_breakpoint_slot(mem, slot, None)?;
+        }
+        for slot in 0..self.watchpoints.len() {
+            self.set_watchpoint_slot(mem, slot, None)?;
+        }
+        Ok(())
+    }
+
+    /// Returns `false` if all comparators are in use.
+    fn insert_breakpoint<M: MemoryAccess>(
+        &mut self,
+        mem: &mut M,
+        address: u32,
+    ) -> Result<bool, STLinkError> {
+        if self.breakpoints.contains(&Some(address)) {
+            return Ok(true);
+        }
+        match self.breakpoints.iter().position(Option::is_none) {
+            Some(slot) => self.set_breakpoint_slot(mem, slot, Some(address))?,
+            None => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    fn remove_breakpoint<M: MemoryAccess>(
+        &mut self,
+        mem: &mut M,
+        address: u32,
+    ) -> Result<(), STLinkError> {
+        if let Some(slot) = self.breakpoints.iter().position(|&b| b == Some(address)) {
+            self.set_breakpoint_slot(mem, slot, None)?;
+        }
+        Ok(())
+    }
+
+    /// Returns `false` if all comparators are in use.
+    fn insert_watchpoint<M: MemoryAccess>(
+        &mut self,
+        mem: &mut M,
+        watchpoint: (u32, u32, u32),
+    ) -> Result<bool, STLinkError> {
+        match self.watchpoints.iter().position(Option::is_none) {
+            Some(slot) => self.set_watchpoint_slot(mem, slot, Some(watchpoint))?,
+            None => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    fn remove_watchpoint<M: MemoryAccess>(
+        &mut self,
+        mem: &mut M,
+        address: u32,
+        size: u32,
+    ) -> Result<(), STLinkError> {
+        if let Some(slot) = self
+            .watchpoints
+            .iter()
+            .position(|w| w.map_or(false, |(a, s, _)| a == address && s == size))
+        {
+            self.set_watchpoint_slot(mem, slot, None)?;
+        }
+        Ok(())
+    }
+
+    /// Moves the halted core past a breakpoint at the current instruction.
+    /// Returns whether the core was stepped.
+    fn step_over_breakpoint<M: MemoryAccess>(&mut self, mem: &mut M) -> Result<bool, STLinkError> {
+        let pc = self.core.read_register(mem, registers::PC)?;
+        match self.breakpoints.iter().position(|&b| b == Some(pc)) {
+            Some(slot) => {
+                self.set_breakpoint_slot(mem, slot, None)?;
+                self.core.step(mem)?;
+                self.set_breakpoint_slot(mem, slot, Some(pc))?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn set_breakpoint_slot<M: MemoryAccess>(
+        &mut self,
+        mem: &mut M,
+        slot: usize,
+        address: Option<u32>,
+    ) -> Result<(), STLinkError> {
+        let value = match address {
+            // FPB version 2 takes any instruction address.
+            Some(address) if self.fpb_revision >= 1 => address & !0x1 | FP_COMP_ENABLE,
+            // FPB version 1 matches a word and selects its halfword.
+            Some(address) => {
+                let replace = if address & 0x2 == 0 {
+                    FP_COMP_REPLACE_LOWER
+                } else {
+                    FP_COMP_REPLACE_UPPER
+                };
+                replace | (address & 0x1fff_fffc) | FP_COMP_ENABLE
+            }
+            None => 0,
+        };
+        mem.write_word32(FP_COMP0 + 4 * slot as u32, value, self.core.apsel)?;
+        self.breakpoints[slot] = address;
+        Ok(())
+    }
+
+    fn set_watchpoint_slot<M: MemoryAccess>(
+        &mut self,
+        mem: &mut M,
+        slot: usize,
+        watchpoint: Option<(u32, u32, u32)>,
+    ) -> Result<(), STLinkError> {
+        let base = DWT_COMP0 + 16 * slot as u32;
+        match watchpoint {
+            Some((address, size, function)) => {
+                mem.write_word32(base, address, self.core.apsel)?;
+                mem.write_word32(base + 4, size.trailing_zeros(), self.core.apsel)?;
+                mem.write_word32(base + 8, function, self.core.apsel)?;
+            }
+            None => mem.write_word32(base + 8, 0, self.core.apsel)?,
+        }
+        self.watchpoints[slot] = watchpoint;
+        Ok(())
+    }
+}
+
+/// A GDB Remote Serial Protocol server debugging Cortex-M cores.
+///
+/// Every core is exposed as a GDB thread, numbered from 1 in the order the cores were given.
+/// The cores are stopped and resumed together.
+/// With FreeRTOS awareness, the tasks which are switched out on the first core follow as
+/// further threads, whose registers are read from their stacks.
+pub struct GdbServer<'m, M: Monitor> {
+    mem: &'m mut M,
+    cores: Vec<Core>,
+    targets: Vec<Target>,
+    regions: Vec<MemoryRegion>,
+    /// The target register and memory accesses go to, as selected by `Hg`.
+    current: usize,
+    /// The target single steps apply to, as selected by `Hc`.
+    stepping: usize,
+    /// Decodes the SWV data forwarded to the GDB console.
+    swv: Option<ItmDecoder>,
+    /// The RTT control block whose up channel 0 is forwarded to the GDB console.
+    rtt: Option<Rtt>,
+    rtos: Option<FreeRtos>,
+    /// The name of the task running on the first core.
+    running_task: Option<String>,
+    /// The switched out tasks as of the last stop.
+    tasks: Vec<Task>,
+    no_ack: bool,
+}
+
+impl<'m, M: Monitor> GdbServer<'m, M> {
+    /// Creates a server for `cores`. `regions` is reported to GDB as the memory map if not empty.
+    pub fn new(mem: &'m mut M, cores: Vec<Core>, regions: Vec<MemoryRegion>) -> Self {
+        Self {
+            mem,
+            cores,
+            targets: vec![],
+            regions,
+            current: 0,
+            stepping: 0,
+            swv: None,
+            rtt: None,
+            rtos: None,
+            running_task: None,
+            tasks: vec![],
+            no_ack: false,
+        }
+    }
+
+    /// Exposes the FreeRTOS tasks of the firmware as threads.
+    pub fn set_rtos(&mut self, rtos: FreeRtos) {
+        self.rtos = Some(rtos);
+    }
+
+    /// Serves one GDB session until the client detaches or disconnects.
+    pub fn serve(&mut self, stream: TcpStream) -> Result<(), GdbError> {
+        self.no_ack = false;
+        self.current = 0;
+        self.stepping = 0;
+        self.targets = vec![];
+        for &core in &self.cores {
+            self.targets.push(Target::attach(self.mem, core)?);
+        }
+        self.refresh_tasks();
+
+        let mut connection = Connection::new(stream);
+        loop {
+            let packet = match connection.next_event(None)? {
+                Some(Event::Packet(packet)) => packet,
+                Some(Event::Interrupt) => continue,
+                None => break,
+            };
+            if !self.no_ack {
+                connection.send_raw(b"+")?;
+            }
+
+            // Binary payloads must not go through the text conversion.
+            if packet.first() == Some(&b'X') {
+                let response = self
+                    .write_memory_binary(&packet[1..])
+                    .unwrap_or_else(|_| "E01".to_owned());
+                connection.send(response.as_bytes())?;
+                continue;
+            }
+
+            let packet = String::from_utf8_lossy(&packet).into_owned();
+            let response = match packet.as_bytes().first() {
+                Some(b'D') => {
+                    self.detach()?;
+                    connection.send(b"OK")?;
+                    break;
+                }
+                Some(b'k') => {
+                    self.detach()?;
+                    break;
+                }
+                Some(b'c') | Some(b's') => {
+                    let reply = self.resume(&packet, &mut connection)?;
+                    self.refresh_tasks();
+                    reply
+                }
+                _ => self.handle(&packet),
+            };
+            connection.send(response.as_bytes())?;
+            if packet == "QStartNoAckMode" {
+                self.no_ack = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles a packet which does not resume the cores.
+    fn handle(&mut self, packet: &str) -> String {
+        let command = match packet.as_bytes().first() {
+            Some(&command) => command,
+            None => return String::new(),
+        };
+        let result = match command {
+            b'?' => Ok(self.stop_reply(SIGTRAP, self.current)),
+            b'g' => self.read_registers(),
+            b'G' => self.write_registers(&packet[1..]),
+            b'p' => self.read_register(&packet[1..]),
+            b'P' => self.write_register(&packet[1..]),
+            b'm' => self.read_memory(&packet[1..]),
+            b'M' => self.write_memory_hex(&packet[1..]),
+            b'Z' => self.insert_point(&packet[1..]),
+            b'z' => self.remove_point(&packet[1..]),
+            b'H' => Ok(self.select_thread(&packet[1..])),
+            b'T' => Ok(match self.thread(&packet[1..]) {
+                Some(_) => "OK".to_owned(),
+                None => "E01".to_owned(),
+            }),
+            b'q' | b'Q' => Ok(self.query(packet)),
+            // Unsupported packets are answered with an empty response.
+            _ => Ok(String::new()),
+        };
+        result.unwrap_or_else(|_| "E01".to_owned())
+    }
+
+    fn query(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            let mut features = format!(
+                "PacketSize={:x};QStartNoAckMode+;qXfer:features:read+",
+                PACKET_SIZE
+            );
+            if !self.regions.is_empty() {
+                features.push_str(";qXfer:memory-map:read+");
+            }
+            features
+        } else if let Some(request) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            xfer_chunk(&target_description(), request)
+        } else if let Some(request) = packet.strip_prefix("qXfer:memory-map:read::") {
+            if self.regions.is_empty() {
+                String::new()
+            } else {
+                xfer_chunk(&memory_map(&self.regions), request)
+            }
+        } else if let Some(command) = packet.strip_prefix("qRcmd,") {
+            let command = decode_hex(command).unwrap_or_default();
+            let output = self.monitor(String::from_utf8_lossy(&command).trim());
+            encode_hex(output.as_bytes())
+        } else if let Some(thread) = packet.strip_prefix("qThreadExtraInfo,") {
+            match self.thread(thread) {
+                Some(index) => encode_hex(self.thread_info(index).as_bytes()),
+                None => "E01".to_owned(),
+            }
+        } else if packet == "QStartNoAckMode" {
+            "OK".to_owned()
+        } else if packet == "qAttached" {
+            "1".to_owned()
+        } else if packet == "qC" {
+            format!("QC{:x}", self.current + 1)
+        } else if packet == "qfThreadInfo" {
+            let threads = (1..=self.targets.len() + self.tasks.len())
+                .map(|thread| format!("{:x}", thread))
+                .collect::<Vec<_>>();
+            format!("m{}", threads.join(","))
+        } else if packet == "qsThreadInfo" {
+            "l".to_owned()
+        } else {
+            String::new()
+        }
+    }
+
+    /// Returns the index of the target or task a thread ID refers to.
+    /// Tasks are indexed after the targets.
+    fn thread(&self, id: &str) -> Option<usize> {
+        let index = (parse_hex(id)? as usize).checked_sub(1)?;
+        if index < self.targets.len() + self.tasks.len() {
+            Some(index)
+        } else {
+            None
+        }
+    }
+
+    fn thread_info(&self, index: usize) -> String {
+        match index.checked_sub(self.targets.len()) {
+            Some(task) => {
+                let task = &self.tasks[task];
+                format!(
+                    "{} ({:?}, priority {})",
+                    task.name, task.state, task.priority
+                )
+            }
+            None => match &self.running_task {
+                Some(name) if index == 0 => {
+                    format!(
+                        "Cortex-M on AP{}, running {}",
+                        self.targets[0].core.apsel, name
+                    )
+                }
+                _ => format!("Cortex-M on AP{}", self.targets[index].core.apsel),
+            },
+        }
+    }
+
+    /// The core the current thread runs on.
+    fn core(&self) -> Core {
+        // FreeRTOS tasks run on the first core.
+        self.targets
+            .get(self.current)
+            .unwrap_or(&self.targets[0])
+            .core
+    }
+
+    /// Reads the task lists again after the cores stopped.
+    fn refresh_tasks(&mut self) {
+        self.running_task = None;
+        self.tasks = vec![];
+        if let Some(rtos) = &self.rtos {
+            // The lists are garbage until the scheduler initialized them, so errors just mean no tasks.
+            let tasks = rtos
+                .tasks(self.mem, self.targets[0].core.apsel)
+                .unwrap_or_default();
+            for task in tasks {
+                if task.state == TaskState::Running {
+                    self.running_task = Some(task.name);
+                } else {
+                    self.tasks.push(task);
+                }
+            }
+        }
+        // Selected tasks may have been switched in meanwhile.
+        if self.current >= self.targets.len() + self.tasks.len() {
+            self.current = 0;
+        }
+        if self.stepping >= self.targets.len() + self.tasks.len() {
+            self.stepping = 0;
+        }
+    }
+
+    /// Handles `Hg` and `Hc`. The IDs 0 and -1 for any or all threads keep the selection.
+    fn select_thread(&mut self, packet: &str) -> String {
+        let (operation, id) = match (packet.get(..1), packet.get(1..)) {
+            (Some(operation), Some(id)) => (operation, id),
+            _ => return "E01".to_owned(),
+        };
+        if id == "0" || id == "-1" {
+            return "OK".to_owned();
+        }
+        match (operation, self.thread(id)) {
+            ("g", Some(index)) => self.current = index,
+            ("c", Some(index)) => self.stepping = index,
+            _ => return "E01".to_owned(),
+        }
+        "OK".to_owned()
+    }
+
+    fn stop_reply(&self, signal: u8, index: usize) -> String {
+        format!("T{:02x}thread:{:x};", signal, index + 1)
+    }
+
+    /// Continues all cores or steps one of them and waits until they stop again.
+    /// Returns the stop reply.
+    fn resume(&mut self, packet: &str, connection: &mut Connection) -> Result<String, GdbError> {
+        let step = packet.starts_with('s');
+        let index = if step { self.stepping } else { self.current };
+        // Tasks which are switched out can only be resumed by the scheduler of their core.
+        let index = if index < self.targets.len() { index } else { 0 };
+        if packet.len() > 1 {
+            let address = parse_hex(&packet[1..]).unwrap_or(0);
+            self.targets[index]
+                .core
+                .write_register(self.mem, registers::PC, address)?;
+        }
+
+        if step {
+            if !self.targets[index].step_over_breakpoint(self.mem)? {
+                self.targets[index].core.step(self.mem)?;
+            }
+            self.targets[index].core.halt_reason(self.mem)?;
+            self.current = index;
+            return Ok(self.stop_reply(SIGTRAP, index));
+        }
+
+        for target in &mut self.targets {
+            // Leave breakpoints at the current instructions before resuming.
+            target.step_over_breakpoint(self.mem)?;
+            target.core.halt_reason(self.mem)?;
+        }
+        for target in &self.targets {
+            target.core.run(self.mem)?;
+        }
+
+        loop {
+            self.forward_output(connection)?;
+            for index in 0..self.targets.len() {
+                if self.targets[index].core.is_halted(self.mem)? {
+                    self.halt_all()?;
+                    self.current = index;
+                    return Ok(self.stop_reply(SIGTRAP, index));
+                }
+            }
+            match connection.next_event(Some(POLL_INTERVAL))? {
+                Some(Event::Interrupt) => {
+                    self.halt_all()?;
+                    return Ok(self.stop_reply(SIGINT, self.current));
+                }
+                // Nothing else may arrive while the target runs.
+                Some(Event::Packet(_)) => (),
+                None => (),
+            }
+        }
+    }
+
+    fn halt_all(&mut self) -> Result<(), STLinkError> {
+        for target in &self.targets {
+            if !target.core.is_halted(self.mem)? {
+                target.core.halt(self.mem)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn detach(&mut self) -> Result<(), GdbError> {
+        for target in &mut self.targets {
+            target.clear(self.mem)?;
+            target.core.run(self.mem)?;
+        }
+        Ok(())
+    }
+
+    /// Sends the SWV and RTT output received so far to the GDB console.
+    fn forward_output(&mut self, connection: &mut Connection) -> Result<(), GdbError> {
+        let mut output = vec![];
+        if let Some(decoder) = &mut self.swv {
+            let data = self.mem.poll_trace()?;
+            for packet in decoder.feed(&data) {
+                if let TracePacket::Instrumentation { payload, .. } = packet {
+                    output.extend_from_slice(&payload);
+                }
+            }
+        }
+        if let Some(rtt) = &self.rtt {
+            // A control block the target is still setting up is not fatal.
+            if let Ok(data) = rtt.read(self.mem, 0) {
+                output.extend_from_slice(&data);
+            }
+        }
+        if !output.is_empty() {
+            connection.send(format!("O{}", encode_hex(&output)).as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Runs a `monitor` command and returns the text to print.
+    fn monitor(&mut self, command: &str) -> String {
+        let arguments = command.split_whitespace().collect::<Vec<_>>();
+        let result = match arguments[..] {
+            ["reset"] | ["reset", "halt"] => self.reset(false),
+            ["reset", "run"] => self.reset(true),
+            ["vtg"] => self
+                .mem
+                .target_voltage()
+                .map(|voltage| format!("Target voltage: {:.2} V\n", voltage)),
+            ["speed", hz] => match hz.parse() {
+                Ok(hz) => self
+                    .mem
+                    .set_speed(hz)
+                    .map(|hz| format!("Clock set to {} Hz.\n", hz)),
+                Err(_) => return "The speed has to be given in Hz.\n".to_owned(),
+            },
+            ["swv", "start", trace_clk, baud] => self.start_swv(trace_clk, baud, "1"),
+            ["swv", "start", trace_clk, baud, ports] => self.start_swv(trace_clk, baud, ports),
+            ["swv", "stop"] => {
+                self.swv = None;
+                self.mem.stop_trace().map(|_| "SWV stopped.\n".to_owned())
+            }
+            ["rtt", "attach", address] => match parse_hex(address) {
+                Some(address) => {
+                    let rtt = Rtt::attach(self.mem, self.core().apsel, address);
+                    self.start_rtt(rtt)
+                }
+                None => return "The address has to be given in hexadecimal.\n".to_owned(),
+            },
+            ["rtt", "scan"] => self.scan_rtt("20000000", "10000"),
+            ["rtt", "scan", start, size] => self.scan_rtt(start, size),
+            ["rtt", "stop"] => {
+                self.rtt = None;
+                Ok("RTT stopped.\n".to_owned())
+            }
+            _ => return MONITOR_HELP.to_owned(),
+        };
+        result.unwrap_or_else(|e| format!("Failed: {:?}\n", e))
+    }
+
+    fn reset(&mut self, run: bool) -> Result<String, STLinkError> {
+        // The system reset reaches all cores, so the first one is used to catch the reset vector.
+        self.targets[0].core.reset_and_halt(self.mem)?;
+        self.halt_all()?;
+        self.refresh_tasks();
+        if run {
+            for target in &self.targets {
+                target.core.run(self.mem)?;
+            }
+            Ok("Target reset and running.\n".to_owned())
+        } else {
+            Ok("Target reset and halted.\n".to_owned())
+        }
+    }
+
+    fn start_swv(
+        &mut self,
+        trace_clk: &str,
+        baud: &str,
+        ports: &str,
+    ) -> Result<String, STLinkError> {
+        let (trace_clk, baud, ports) = match (trace_clk.parse(), baud.parse(), parse_hex(ports)) {
+            (Ok(trace_clk), Ok(baud), Some(ports)) => (trace_clk, baud, ports),
+            _ => return Ok("Usage: swv start <trace clock> <baud> [<ports in hex>]\n".to_owned()),
+        };
+        let apsel = self.core().apsel;
+        let mut config = TraceConfig::new(trace_clk, baud);
+        config.stimulus_ports = ports;
+        config.dbgmcu_cr = identify(self.mem, apsel)?
+            .and_then(|info| info.family)
+            .map(Stm32Family::dbgmcu_cr);
+        configure_trace(self.mem, apsel, &config)?;
+        let baud = self.mem.start_trace(baud, trace_clk)?;
+        self.swv = Some(ItmDecoder::new());
+        Ok(format!(
+            "SWV started at {} baud, stimulus ports are forwarded while the target runs.\n",
+            baud
+        ))
+    }
+
+    fn scan_rtt(&mut self, start: &str, size: &str) -> Result<String, STLinkError> {
+        match (parse_hex(start), parse_hex(size)) {
+            (Some(start), Some(size)) => {
+                let rtt = Rtt::scan(self.mem, self.core().apsel, start, size);
+                self.start_rtt(rtt)
+            }
+            _ => Ok("Usage: rtt scan [<start> <size> in hexadecimal]\n".to_owned()),
+        }
+    }
+
+    fn start_rtt(&mut self, rtt: Result<Rtt, RttError>) -> Result<String, STLinkError> {
+        match rtt {
+            Ok(rtt) => {
+                let output = format!(
+                    "Found the RTT control block at 0x{:08x}, up channel 0 is forwarded while the target runs.\n",
+                    rtt.address
+                );
+                self.rtt = Some(rtt);
+                Ok(output)
+            }
+            Err(RttError::STLink(e)) => Err(e),
+            Err(e) => Ok(format!("Failed: {:?}\n", e)),
+        }
+    }
+
+    fn read_registers(&mut self) -> Result<String, STLinkError> {
+        let mut response = String::new();
+        for number in 0..REGISTERS.len() {
+            response.push_str(&self.register(number)?);
+        }
+        Ok(response)
+    }
+
+    fn write_registers(&mut self, data: &str) -> Result<String, STLinkError> {
+        // The registers of switched out tasks are read only.
+        if self.current >= self.targets.len() {
+            return Ok("E01".to_owned());
+        }
+        let values = decode_hex(data).ok_or(STLinkError::UnknownError)?;
+        for (number, value) in values.chunks(4).enumerate().take(REGISTERS.len()) {
+            if value.len() == 4 {
+                self.set_register(number, le_u32(value))?;
+            }
+        }
+        Ok("OK".to_owned())
+    }
+
+    fn read_register(&mut self, data: &str) -> Result<String, STLinkError> {
+        let number = parse_hex(data).ok_or(STLinkError::UnknownError)? as usize;
+        if number >= REGISTERS.len() {
+            return Ok("E01".to_owned());
+        }
+        self.register(number)
+    }
+
+    fn write_register(&mut self, data: &str) -> Result<String, STLinkError> {
+        let mut parts = data.splitn(2, '=');
+        let number = parts
+            .next()
+            .and_then(parse_hex)
+            .ok_or(STLinkError::UnknownError)? as usize;
+        let value = parts
+            .next()
+            .and_then(decode_hex)
+            .ok_or(STLinkError::UnknownError)?;
+        if number >= REGISTERS.len() || value.len() != 4 || self.current >= self.targets.len() {
+            return Ok("E01".to_owned());
+        }
+        self.set_register(number, le_u32(&value))?;
+        Ok("OK".to_owned())
+    }
+
+    /// Formats a register of the current thread for a reply.
+    fn register(&mut self, number: usize) -> Result<String, STLinkError> {
+        if let Some(task) = self.current.checked_sub(self.targets.len()) {
+            // Only the stacked registers of a task are known.
+            return Ok(match self.tasks[task].registers {
+                Some(registers) if number < registers.len() => hex_u32(registers[number]),
+                _ => "xxxxxxxx".to_owned(),
+            });
+        }
+        let core = self.targets[self.current].core;
+        let value = core.read_register(self.mem, REGISTERS[number].2)?;
+        if number >= FIRST_SPECIAL_REGISTER {
+            let shift = 8 * (number - FIRST_SPECIAL_REGISTER);
+            Ok(hex_u32((value >> shift) & 0xff))
+        } else {
+            Ok(hex_u32(value))
+        }
+    }
+
+    fn set_register(&mut self, number: usize, value: u32) -> Result<(), STLinkError> {
+        let core = self.targets[self.current].core;
+        let selector = REGISTERS[number].2;
+        let value = if number >= FIRST_SPECIAL_REGISTER {
+            let shift = 8 * (number - FIRST_SPECIAL_REGISTER);
+            let special = core.read_register(self.mem, selector)?;
+            (special & !(0xff << shift)) | ((value & 0xff) << shift)
+        } else {
+            value
+        };
+        core.write_register(self.mem, selector, value)
+    }
+
+    fn read_memory(&mut self, data: &str) -> Result<String, STLinkError> {
+        let (address, length) = parse_address_length(data).ok_or(STLinkError::UnknownError)?;
+        let apsel = self.core().apsel;
+        let bytes = if address % 4 == 0 && length % 4 == 0 {
+            self.mem.read_mem32(address, length, apsel)?
+        } else {
+            self.mem.read_mem8(address, length, apsel)?
+        };
+        Ok(encode_hex(&bytes))
+    }
+
+    fn write_memory_hex(&mut self, data: &str) -> Result<String, STLinkError> {
+        let mut parts = data.splitn(2, ':');
+        let (address, length) = parts
+            .next()
+            .and_then(parse_address_length)
+            .ok_or(STLinkError::UnknownError)?;
+        let bytes = parts
+            .next()
+            .and_then(decode_hex)
+            .ok_or(STLinkError::UnknownError)?;
+        if bytes.len() != length as usize {
+            return Ok("E01".to_owned());
+        }
+        self.write_memory(address, bytes)
+    }
+
+    /// Writes the payload of an `X` packet, which was already unescaped by the connection.
+    fn write_memory_binary(&mut self, data: &[u8]) -> Result<String, STLinkError> {
+        let colon = data
+            .iter()
+            .position(|&byte| byte == b':')
+            .ok_or(STLinkError::UnknownError)?;
+        let (address, length) = std::str::from_utf8(&data[..colon])
+            .ok()
+            .and_then(parse_address_length)
+            .ok_or(STLinkError::UnknownError)?;
+        let bytes = data[colon + 1..].to_vec();
+        if bytes.len() != length as usize {
+            return Ok("E01".to_owned());
+        }
+        self.write_memory(address, bytes)
+    }
+
+    fn write_memory(&mut self, address: u32, bytes: Vec<u8>) -> Result<String, STLinkError> {
+        if bytes.is_empty() {
+            return Ok("OK".to_owned());
+        }
+        let apsel = self.core().apsel;
+        if address % 4 == 0 && bytes.len() % 4 == 0 {
+            self.mem.write_mem32(address, bytes, apsel)?;
+        } else {
+            self.mem.write_mem8(address, bytes, apsel)?;
+        }
+        Ok("OK".to_owned())
+    }
+
+    /// Breakpoints and watchpoints are set on all cores, as GDB expects them for all threads.
+    fn insert_point(&mut self, data: &str) -> Result<String, STLinkError> {
+        let (kind, address, length) = parse_point(data).ok_or(STLinkError::UnknownError)?;
+        let mut inserted = true;
+        match kind {
+            // Flash can't be patched with BKPT instructions, so software breakpoints use the FPB as well.
+            0 | 1 => {
+                for target in &mut self.targets {
+                    inserted &= target.insert_breakpoint(self.mem, address)?;
+                }
+            }
+            2..=4 => {
+                let function = match kind {
+                    2 => DWT_FUNCTION_WRITE,
+                    3 => DWT_FUNCTION_READ,
+                    _ => DWT_FUNCTION_ACCESS,
+                };
+                let (aligned, size) = match watched_range(address, length) {
+                    Some(range) => range,
+                    None => return Ok("E01".to_owned()),
+                };
+                for target in &mut self.targets {
+                    inserted &= target.insert_watchpoint(self.mem, (aligned, size, function))?;
+                }
+            }
+            _ => return Ok(String::new()),
+        }
+        if inserted {
+            Ok("OK".to_owned())
+        } else {
+            // Don't leave the point behind on some of the cores.
+            self.remove_point(data)?;
+            Ok("E01".to_owned())
+        }
+    }
+
+    fn remove_point(&mut self, data: &str) -> Result<String, STLinkError> {
+        let (kind, address, length) = parse_point(data).ok_or(STLinkError::UnknownError)?;
+        match kind {
+            0 | 1 => {
+                for target in &mut self.targets {
+                    target.remove_breakpoint(self.mem, address)?;
+                }
+            }
+            2..=4 => {
+                let (aligned, size) = match watched_range(address, length) {
+                    Some(range) => range,
+                    None => return Ok("E01".to_owned()),
+                };
+                for target in &mut self.targets {
+                    target.remove_watchpoint(self.mem, aligned, size)?;
+                }
+            }
+            _ => return Ok(String::new()),
+        }
+        Ok("OK".to_owned())
+    }
+}
+
+const MONITOR_HELP: &str = "Supported monitor commands:
+  reset [halt|run]
+  vtg
+  speed <hz>
+  swv start <trace clock> <baud> [<ports in hex>]
+  swv stop
+  rtt attach <address in hex>
+  rtt scan [<start> <size> in hex]
+  rtt stop
+";
+
+/// Something received from GDB.
+#[derive(Debug, PartialEq)]
+enum Event {
+    /// The unescaped payload of a packet.
+    Packet(Vec<u8>),
+    /// A Ctrl-C.
+    Interrupt,
+}
+
+struct Connection {
+    stream: TcpStream,
+    buffer: Vec<u8>,
+}
+
+impl Connection {
+    fn new(stream: TcpStream) -> Self {
+        Self {
+            stream,
+            buffer: vec![],
+        }
+    }
+
+    /// Waits for the next packet or interrupt.
+    /// Returns `None` if the client disconnected or nothing arrived within `timeout`.
+    fn next_event(&mut self, timeout: Option<Duration>) -> Result<Option<Event>, GdbError> {
+        self.stream.set_read_timeout(timeout)?;
+        loop {
+            match parse_event(&mut self.buffer) {
+                Some((event, true)) => return Ok(Some(event)),
+                // Ask for a retransmission of a corrupted packet.
+                Some((_, false)) => self.send_raw(b"-")?,
+                None => {
+                    let mut chunk = [0; 1024];
+                    match self.stream.read(&mut chunk) {
+                        Ok(0) => return Ok(None),
+                        Ok(count) => self.buffer.extend_from_slice(&chunk[..count]),
+                        Err(ref e)
+                            if e.kind() == ErrorKind::WouldBlock
+                                || e.kind() == ErrorKind::TimedOut =>
+                        {
+                            return Ok(None)
+                        }
+                        Err(e) => return Err(GdbError::IO(e)),
+                    }
+                }
+            }
+        }
+    }
+
+    fn send(&mut self, payload: &[u8]) -> Result<(), GdbError> {
+        let mut packet = vec![b'$'];
+        for &byte in payload {
+            // These have to be escaped anywhere in a packet.
+            if byte == b'$' || byte == b'#' || byte == b'}' || byte == b'*' {
+                packet.push(b'}');
+                packet.push(byte ^ 0x20);
+            } else {
+                packet.push(byte);
+            }
+        }
+        let checksum = packet[1..]
+            .iter()
+            .fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        packet.extend_from_slice(format!("#{:02x}", checksum).as_bytes());
+        self.send_raw(&packet)
+    }
+
+    fn send_raw(&mut self, data: &[u8]) -> Result<(), GdbError> {
+        self.stream.write_all(data)?;
+        self.stream.flush()?;
+        Ok(())
+    }
+}
+
+/// Takes the next event out of the received bytes.
+/// Returns the event and whether its checksum matched, or `None` if more data is needed.
+fn parse_event(buffer: &mut Vec<u8>) -> Option<(Event, bool)> {
+    loop {
+        match buffer.first()? {
+            0x03 => {
+                buffer.remove(0);
+                return Some((Event::Interrupt, true));
+            }
+            b'$' => break,
+            // Acknowledgements and line noise.
+            _ => {
+                buffer.remove(0);
+            }
+        }
+    }
+
+    let end = buffer.iter().position(|&byte| byte == b'#')?;
+    if buffer.len() < end + 3 {
+        return None;
+    }
+    let packet = buffer.drain(..end + 3).collect::<Vec<_>>();
+    let data = &packet[1..end];
+    let checksum = std::str::from_utf8(&packet[end + 1..])
+        .ok()
+        .and_then(|checksum| u8::from_str_radix(checksum, 16).ok());
+    let valid = checksum == Some(data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)));
+
+    let mut payload = vec![];
+    let mut escaped = false;
+    for &byte in data {
+        if escaped {
+            payload.push(byte ^ 0x20);
+            escaped = false;
+        } else if byte == b'}' {
+            escaped = true;
+        } else {
+            payload.push(byte);
+        }
+    }
+    Some((Event::Packet(payload), valid))
+}
+
+/// Answers a `qXfer` read of `document` given the `offset,length` part of the request.
+fn xfer_chunk(document: &str, request: &str) -> String {
+    let (offset, length) = match parse_address_length(request) {
+        Some((offset, length)) => (offset as usize, length as usize),
+        None => return "E01".to_owned(),
+    };
+    if offset >= document.len() {
+        return "l".to_owned();
+    }
+    let end = usize::min(offset + length, document.len());
+    let marker = if end == document.len() { 'l' } else { 'm' };
+    format!("{}{}", marker, &document[offset..end])
+}
+
+fn target_description() -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n<target version=\"1.0\">\n<architecture>arm</architecture>\n<feature name=\"org.gnu.gdb.arm.m-profile\">\n",
+    );
+    for (number, (name, ty, _)) in REGISTERS.iter().enumerate() {
+        if number == FIRST_SYSTEM_REGISTER {
+            xml.push_str("</feature>\n<feature name=\"org.gnu.gdb.arm.m-system\">\n");
+        }
+        xml.push_str(&format!(
+            "<reg name=\"{}\" bitsize=\"32\" regnum=\"{}\" type=\"{}\"/>\n",
+            name, number, ty
+        ));
+    }
+    xml.push_str("</feature>\n</target>\n");
+    xml
+}
+
+fn memory_map(regions: &[MemoryRegion]) -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?>\n<!DOCTYPE memory-map PUBLIC \"+//IDN gnu.org//DTD GDB Memory Map V1.0//EN\" \"http://sourceware.org/gdb/gdb-memory-map.dtd\">\n<memory-map>\n",
+    );
+    for region in regions {
+        // Without the vFlash packets GDB must not try to write flash, so it is read-only.
+        let kind = match region.kind {
+            RegionKind::Ram => "ram",
+            RegionKind::Rom | RegionKind::Flash => "rom",
+        };
+        xml.push_str(&format!(
+            "<memory type=\"{}\" start=\"0x{:08x}\" length=\"0x{:x}\"/>\n",
+            kind, region.start, region.length
+        ));
+    }
+    xml.push_str("</memory-map>\n");
+    xml
+}
+
+fn parse_hex(text: &str) -> Option<u32> {
+    u32::from_str_radix(text, 16).ok()
+}
+
+/// Parses `address,length` in hexadecimal.
+fn parse_address_length(text: &str) -> Option<(u32, u32)> {
+    let mut parts = text.splitn(2, ',');
+    Some((parse_hex(parts.next()?)?, parse_hex(parts.next()?)?))
+}
+
+/// Parses the `type,address,kind` of a `Z` or `z` packet.
+fn parse_point(text: &str) -> Option<(u32, u32, u32)> {
+    let mut parts = text.split(',');
+    let kind = parse_hex(parts.next()?)?;
+    let address = parse_hex(parts.next()?)?;
+    // Conditions may follow the length after a semicolon.
+    let length = parse_hex(parts.next()?.split(';').next()?)?;
+    Some((kind, address, length))
+}
+
+/// Returns the address and size of the range a DWT comparator has to watch to cover `length`
+/// bytes at `address`. The DWT matches naturally aligned power of two sized ranges only.
+fn watched_range(address: u32, length: u32) -> Option<(u32, u32)> {
+    let size = u64::from(length.max(1)).next_power_of_two();
+    let aligned = u64::from(address) & !(size - 1);
+    if size > 1 << 31 || aligned + size < u64::from(address) + u64::from(length) {
+        return None;
+    }
+    Some((aligned as u32, size as u32))
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if text.len() % 2 != 0 {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Formats a register value in target byte order.
+fn hex_u32(value: u32) -> String {
+    encode_hex(&value.to_le_bytes())
+}
+
+fn le_u32(bytes: &[u8]) -> u32 {
+    u32::from(bytes[0])
+        | u32::from(bytes[1]) << 8
+        | u32::from(bytes[2]) << 16
+        | u32::from(bytes[3]) << 24
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cortex_m::mock;
+
+    impl Monitor for mock::Target {
+        fn target_voltage(&mut self) -> Result<f32, STLinkError> {
+            Ok(3.3)
+        }
+
+        fn set_speed(&mut self, hz: u32) -> Result<u32, STLinkError> {
+            Ok(hz)
+        }
+
+        fn start_trace(&mut self, baud: u32, _: u32) -> Result<u32, STLinkError> {
+            Ok(baud)
+        }
+
+        fn stop_trace(&mut self) -> Result<(), STLinkError> {
+            Ok(())
+        }
+
+        fn poll_trace(&mut self) -> Result<Vec<u8>, STLinkError> {
+            Ok(vec![])
+        }
+    }
+
+    #[test]
+    fn answers_empty_packets_as_unsupported() {
+        let mut mem = mock::Target::new();
+        let mut server = GdbServer::new(&mut mem, vec![Core::new(0)], vec![]);
+        assert_eq!(server.handle(""), "");
+    }
+
+    /// A core with six breakpoint and four watchpoint comparators, attached to a server.
+    fn attach(mem: &mut mock::Target) -> GdbServer<'_, mock::Target> {
+        mem.memory.set_word(FP_CTRL, 1 << 28 | 6 << 4);
+        mem.memory.set_word(DWT_CTRL, 4 << 28);
+        let mut server = GdbServer::new(mem, vec![Core::new(0)], vec![]);
+        let target = Target::attach(server.mem, Core::new(0)).unwrap();
+        server.targets = vec![target];
+        server
+    }
+
+    #[test]
+    fn reads_and_writes_registers() {
+        let mut mem = mock::Target::new();
+        mem.registers[0] = 0x1122_3344;
+        mem.registers[15] = 0x0800_0100;
+        mem.registers[registers::SPECIAL as usize] = 0x0102_0304;
+        let mut server = attach(&mut mem);
+
+        assert_eq!(server.handle("p0"), "44332211");
+        assert_eq!(server.handle("pf"), "00010008");
+        // PRIMASK and CONTROL are the lowest and highest byte of the special registers.
+        assert_eq!(server.handle("p13"), "04000000");
+        assert_eq!(server.handle("p16"), "01000000");
+        assert_eq!(server.handle("p17"), "E01");
+        assert_eq!(server.handle("g").len(), 8 * REGISTERS.len());
+        assert!(server.handle("g").starts_with("44332211"));
+
+        assert_eq!(server.handle("P0=78563412"), "OK");
+        assert_eq!(server.handle("P14=ff000000"), "OK");
+        assert_eq!(server.handle("P0=7856"), "E01");
+        assert_eq!(server.mem.registers[0], 0x1234_5678);
+        assert_eq!(
+            server.mem.registers[registers::SPECIAL as usize],
+            0x0102_ff04
+        );
+
+        let values: Vec<u8> = (0..REGISTERS.len() as u32)
+            .flat_map(|i| (0x100 * i).to_le_bytes().to_vec())
+            .collect();
+        assert_eq!(server.handle(&format!("G{}", encode_hex(&values))), "OK");
+        assert_eq!(server.mem.registers[1], 0x100);
+        assert_eq!(server.mem.registers[registers::PSP as usize], 0x1200);
+        assert_eq!(server.handle("p12"), "00120000");
+        // Only the low byte of each special register is kept.
+        assert_eq!(server.handle("p13"), "00000000");
+        assert_eq!(server.mem.registers[registers::SPECIAL as usize], 0);
+    }
+
+    #[test]
+    fn reads_and_writes_memory() {
+        let mut mem = mock::Target::new();
+        let mut server = attach(&mut mem);
+        server.mem.memory.writes.clear();
+
+        assert_eq!(server.handle("M20000000,4:01020304"), "OK");
+        assert_eq!(server.handle("m20000000,4"), "01020304");
+        assert_eq!(server.handle("m20000001,2"), "0203");
+        assert_eq!(server.handle("M20000005,1:ff"), "OK");
+        assert_eq!(server.write_memory_binary(b"20000008,3:a#}").unwrap(), "OK");
+        assert_eq!(server.handle("m20000004,8"), "00ff000061237d00");
+        assert_eq!(server.handle("M20000000,2:01"), "E01");
+        assert_eq!(server.handle("mzz"), "E01");
+        // Aligned words are written with word accesses.
+        assert_eq!(
+            server.mem.memory.writes,
+            vec![
+                (32, 0x2000_0000, 4),
+                (8, 0x2000_0005, 1),
+                (8, 0x2000_0008, 3)
+            ]
+        );
+    }
+
+    #[test]
+    fn inserts_and_removes_points() {
+        let mut mem = mock::Target::new();
+        let mut server = attach(&mut mem);
+        let word = |server: &GdbServer<mock::Target>, addr| server.mem.memory.word(addr);
+
+        assert_eq!(server.handle("Z1,8000100,2"), "OK");
+        assert_eq!(server.handle("Z0,8000100,2"), "OK");
+        assert_eq!(word(&server, FP_COMP0), 0x0800_0101);
+        assert_eq!(word(&server, FP_COMP0 + 4), 0);
+        for i in 1..6 {
+            assert_eq!(
+                server.handle(&format!("Z1,{:x},2", 0x0800_0200 + 2 * i)),
+                "OK"
+            );
+        }
+        assert_eq!(server.handle("Z1,8000300,2"), "E01");
+        assert_eq!(server.handle("z1,8000100,2"), "OK");
+        assert_eq!(word(&server, FP_COMP0), 0);
+
+        assert_eq!(server.handle("Z2,20000006,2"), "OK");
+        assert_eq!(word(&server, DWT_COMP0), 0x2000_0006);
+        assert_eq!(word(&server, DWT_COMP0 + 4), 1);
+        assert_eq!(word(&server, DWT_COMP0 + 8), DWT_FUNCTION_WRITE);
+        // Four bytes at 0x2000_0006 are not within an aligned range of four or eight bytes.
+        assert_eq!(server.handle("Z3,20000006,4"), "E01");
+        assert_eq!(server.handle("Z4,20000006,4"), "E01");
+        assert_eq!(server.handle("Z4,20000004,4"), "OK");
+        assert_eq!(word(&server, DWT_COMP0 + 16 + 8), DWT_FUNCTION_ACCESS);
+        assert_eq!(server.handle("Z2,fffffffc,8"), "E01");
+        assert_eq!(server.handle("Z2,0,ffffffff"), "E01");
+        assert_eq!(server.handle("z2,fffffffc,8"), "E01");
+        assert_eq!(server.handle("z2,20000006,2"), "OK");
+        assert_eq!(word(&server, DWT_COMP0 + 8), 0);
+        assert_eq!(server.handle("Z5,0,4"), "");
+    }
+
+    fn target(apsel: u8) -> Target {
+        Target {
+            core: Core::new(apsel),
+            breakpoints: vec![],
+            fpb_revision: 0,
+            watchpoints: vec![],
+        }
+    }
+
+    fn task(name: &str) -> Task {
+        Task {
+            tcb: 0,
+            name: name.to_owned(),
+            priority: 1,
+            state: TaskState::Ready,
+            stack_base: 0,
+            stack_high_water_mark: 0,
+            registers: None,
+        }
+    }
+
+    #[test]
+    fn runs_monitor_commands() {
+        let mut mem = mock::Target::new();
+        let mut server = GdbServer::new(&mut mem, vec![Core::new(0)], vec![]);
+        server.targets = vec![target(0)];
+
+        assert_eq!(server.monitor("vtg"), "Target voltage: 3.30 V\n");
+        assert_eq!(
+            server.monitor("speed  4000000"),
+            "Clock set to 4000000 Hz.\n"
+        );
+        assert_eq!(
+            server.monitor("speed fast"),
+            "The speed has to be given in Hz.\n"
+        );
+        assert_eq!(
+            server.monitor("swv start 72000000 2000000 3"),
+            "SWV started at 2000000 baud, stimulus ports are forwarded while the target runs.\n"
+        );
+        assert!(server.swv.is_some());
+        assert_eq!(server.monitor("swv stop"), "SWV stopped.\n");
+        assert!(server.swv.is_none());
+        assert_eq!(
+            server.monitor("swv start fast 2000000"),
+            "Usage: swv start <trace clock> <baud> [<ports in hex>]\n"
+        );
+        assert_eq!(
+            server.monitor("rtt attach 2000000g"),
+            "The address has to be given in hexadecimal.\n"
+        );
+        assert_eq!(
+            server.monitor("rtt attach 20000000"),
+            "Failed: ControlBlockNotFound\n"
+        );
+        assert_eq!(server.monitor("rtt stop"), "RTT stopped.\n");
+        assert_eq!(server.monitor(""), MONITOR_HELP);
+        assert_eq!(server.monitor("reset now"), MONITOR_HELP);
+
+        // GDB sends the command hex encoded and prints the hex encoded output.
+        assert_eq!(
+            server.query(&format!("qRcmd,{}", encode_hex(b"vtg"))),
+            encode_hex(b"Target voltage: 3.30 V\n")
+        );
+    }
+
+    #[test]
+    fn selects_cores_and_tasks_as_threads() {
+        let mut mem = mock::Target::new();
+        let mut server = GdbServer::new(&mut mem, vec![Core::new(0), Core::new(1)], vec![]);
+        server.targets = vec![target(0), target(1)];
+        server.tasks = vec![task("IDLE")];
+        server.running_task = Some("main".to_owned());
+
+        assert_eq!(server.query("qfThreadInfo"), "m1,2,3");
+        assert_eq!(server.select_thread("g2"), "OK");
+        assert_eq!(server.current, 1);
+        assert_eq!(server.query("qC"), "QC2");
+        assert_eq!(server.core().apsel, 1);
+        assert_eq!(server.select_thread("c3"), "OK");
+        assert_eq!(server.stepping, 2);
+        assert_eq!(server.select_thread("g3"), "OK");
+        // Tasks run on the first core.
+        assert_eq!(server.core().apsel, 0);
+
+        assert_eq!(server.select_thread("g0"), "OK");
+        assert_eq!(server.select_thread("g-1"), "OK");
+        assert_eq!(server.current, 2);
+        assert_eq!(server.select_thread("g4"), "E01");
+        assert_eq!(server.select_thread("gx"), "E01");
+        assert_eq!(server.select_thread("x1"), "E01");
+        assert_eq!(server.select_thread(""), "E01");
+        assert_eq!(server.handle("H"), "E01");
+
+        assert_eq!(server.thread_info(0), "Cortex-M on AP0, running main");
+        assert_eq!(server.thread_info(1), "Cortex-M on AP1");
+        assert_eq!(server.thread_info(2), "IDLE (Ready, priority 1)");
+        assert_eq!(server.handle("T3"), "OK");
+        assert_eq!(server.handle("T4"), "E01");
+    }
+
+    #[test]
+    fn maps_flash_as_read_only() {
+        let regions = [
+            MemoryRegion {
+                kind: RegionKind::Flash,
+                start: 0x0800_0000,
+                length: 0x10_0000,
+            },
+            MemoryRegion {
+                kind: RegionKind::Ram,
+                start: 0x2000_0000,
+                length: 0x2_0000,
+            },
+        ];
+        let xml = memory_map(&regions);
+        assert!(xml.contains("<memory type=\"rom\" start=\"0x08000000\" length=\"0x100000\"/>"));
+        assert!(xml.contains("<memory type=\"ram\" start=\"0x20000000\" length=\"0x20000\"/>"));
+        assert!(!xml.contains("flash"));
+    }
+
+    #[test]
+    fn parses_packets_acks_and_interrupts() {
+        let mut buffer = b"+$m8000000,4#25\x03$X0,1:}]#00$g#6".to_vec();
+        assert_eq!(
+            parse_event(&mut buffer),
+            Some((Event::Packet(b"m8000000,4".to_vec()), true))
+        );
+        assert_eq!(parse_event(&mut buffer), Some((Event::Interrupt, true)));
+        assert_eq!(
+            parse_event(&mut buffer),
+            Some((Event::Packet(b"X0,1:}".to_vec()), false))
+        );
+        // The checksum of the last packet is incomplete.
+        assert_eq!(parse_event(&mut buffer), None);
+        assert_eq!(buffer, b"$g#6");
+    }
+}
@@ -0,0 +1,411 @@
+use std::path::Path;
+
+use goblin::elf::program_header::PT_LOAD;
+use goblin::elf::Elf;
+
+#[derive(Debug)]
+pub enum ImageError {
+    IO(std::io::Error),
+    Elf(goblin::error::Error),
+    /// A malformed line of a HEX or S-record file.
+    InvalidRecord(usize),
+    /// A record whose checksum does not match.
+    ChecksumMismatch(usize),
+    /// Raw binaries need the address they are loaded to.
+    MissingBaseAddress,
+    /// Two segments, given by their start addresses, overlap.
+    Overlap(u32, u32),
+    /// A segment, given by its start address, reaches past the end of the address space.
+    AddressOverflow(u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageFormat {
+    Elf,
+    IntelHex,
+    SRecord,
+    Binary,
+}
+
+impl ImageFormat {
+    /// Guesses the format from the contents of a file.
+    pub fn detect(data: &[u8]) -> Self {
+        if data.starts_with(b"\x7fELF") {
+            ImageFormat::Elf
+        } else if data.first() == Some(&b':') {
+            ImageFormat::IntelHex
+        } else if data.len() > 1 && data[0] == b'S' && data[1].is_ascii_digit() {
+            ImageFormat::SRecord
+        } else {
+            ImageFormat::Binary
+        }
+    }
+}
+
+/// A contiguous block of the image.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub address: u32,
+    pub data: Vec<u8>,
+}
+
+impl Segment {
+    pub fn end(&self) -> u32 {
+        self.address + self.data.len() as u32
+    }
+}
+
+/// The contents of a firmware file, normalized to sorted and non-overlapping segments.
+/// Adjacent segments are merged.
+#[derive(Debug, Default)]
+pub struct Image {
+    pub segments: Vec<Segment>,
+    /// The entry point, if the file contains one.
+    pub entry: Option<u32>,
+}
+
+impl Image {
+    /// Reads the image at `path`, detecting the format if it is not given.
+    /// `base` is the load address of raw binaries.
+    pub fn load<P: AsRef<Path>>(
+        path: P,
+        format: Option<ImageFormat>,
+        base: Option<u32>,
+    ) -> Result<Self, ImageError> {
+        let data = std::fs::read(path).or_else(|e| Err(ImageError::IO(e)))?;
+        let format = format.unwrap_or_else(|| ImageFormat::detect(&data));
+        Self::parse(&data, format, base)
+    }
+
+    pub fn parse(data: &[u8], format: ImageFormat, base: Option<u32>) -> Result<Self, ImageError> {
+        let (segments, entry) = match format {
+            ImageFormat::Elf => parse_elf(data)?,
+            ImageFormat::IntelHex => parse_hex(data)?,
+            ImageFormat::SRecord => parse_srec(data)?,
+            ImageFormat::Binary => {
+                let address = base.ok_or(ImageError::MissingBaseAddress)?;
+                let segment = Segment {
+                    address,
+                    data: data.to_vec(),
+                };
+                (vec![segment], None)
+            }
+        };
+        Ok(Self {
+            segments: normalize(segments)?,
+            entry,
+        })
+    }
+
+    /// The number of bytes in all segments.
+    pub fn size(&self) -> usize {
+        self.segments.iter().map(|segment| segment.data.len()).sum()
+    }
+}
+
+/// How many sectors a flash operation erased and programmed, and how many it left alone
+/// because they already held the image.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct FlashReport {
+    pub sectors_written: u32,
+    pub sectors_skipped: u32,
+}
+
+/// Returns the parts of the segments which fall into the `size` bytes at `start`.
+pub(crate) fn parts_in(segments: &[Segment], start: u32, size: u32) -> Vec<(u32, &[u8])> {
+    let end = start + size;
+    segments
+        .iter()
+        .filter(|segment| segment.address < end && segment.end() > start)
+        .map(|segment| {
+            let from = u32::max(segment.address, start);
+            let to = u32::min(segment.end(), end);
+            let offset = (from - segment.address) as usize;
+            (from, &segment.data[offset..offset + (to - from) as usize])
+        })
+        .collect()
+}
+
+/// Returns the range from the first to the last byte of the segments within the `size` bytes at `start`.
+pub(crate) fn span_in(segments: &[Segment], start: u32, size: u32) -> Option<(u32, u32)> {
+    let parts = parts_in(segments, start, size);
+    let from = parts.iter().map(|&(address, _)| address).min()?;
+    let to = parts
+        .iter()
+        .map(|&(address, data)| address + data.len() as u32)
+        .max()?;
+    Some((from, to))
+}
+
+/// Returns the contents of the `size` bytes at `start` after erasing them and programming the segments.
+pub(crate) fn expected_contents(
+    segments: &[Segment],
+    start: u32,
+    size: u32,
+    erased_value: u8,
+) -> Vec<u8> {
+    let mut contents = vec![erased_value; size as usize];
+    for (address, data) in parts_in(segments, start, size) {
+        let offset = (address - start) as usize;
+        contents[offset..offset + data.len()].copy_from_slice(data);
+    }
+    contents
+}
+
+/// Sorts the segments, merges adjacent ones and rejects overlaps.
+/// Segments have to end below 4 GiB, so `Segment::end` does not overflow.
+fn normalize(mut segments: Vec<Segment>) -> Result<Vec<Segment>, ImageError> {
+    segments.retain(|segment| !segment.data.is_empty());
+    for segment in &segments {
+        if u64::from(segment.address) + segment.data.len() as u64 > u64::from(u32::MAX) {
+            return Err(ImageError::AddressOverflow(segment.address));
+        }
+    }
+    segments.sort_by_key(|segment| segment.address);
+
+    let mut normalized: Vec<Segment> = vec![];
+    for segment in segments {
+        match normalized.last_mut() {
+            Some(last) if segment.address < last.end() => {
+                return Err(ImageError::Overlap(last.address, segment.address));
+            }
+            Some(last) if segment.address == last.end() => last.data.extend(segment.data),
+            _ => normalized.push(segment),
+        }
+    }
+    Ok(normalized)
+}
+
+/// Collects the loadable program headers at their physical addresses.
+fn parse_elf(data: &[u8]) -> Result<(Vec<Segment>, Option<u32>), ImageError> {
+    let elf = Elf::parse(data).or_else(|e| Err(ImageError::Elf(e)))?;
+    let segments = elf
+        .program_headers
+        .iter()
+        .filter(|header| header.p_type == PT_LOAD && header.p_filesz > 0)
+        .map(|header| {
+            let offset = header.p_offset as usize;
+            let contents =
+                data.get(offset..offset + header.p_filesz as usize)
+                    .ok_or(ImageError::Elf(goblin::error::Error::Malformed(
+                        "Program header exceeds the file.".to_string(),
+                    )))?;
+            Ok(Segment {
+                address: header.p_paddr as u32,
+                data: contents.to_vec(),
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok((segments, Some(elf.entry as u32)))
+}
+
+/// Decodes the hex digits of a record, checking that there is an even number of them.
+fn decode_record(digits: &str, line: usize) -> Result<Vec<u8>, ImageError> {
+    if digits.len() % 2 != 0 || !digits.is_ascii() {
+        return Err(ImageError::InvalidRecord(line));
+    }
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16))
+        .collect::<Result<Vec<_>, _>>()
+        .or_else(|_| Err(ImageError::InvalidRecord(line)))
+}
+
+fn parse_hex(data: &[u8]) -> Result<(Vec<Segment>, Option<u32>), ImageError> {
+    const DATA: u8 = 0x00;
+    const END_OF_FILE: u8 = 0x01;
+    const EXTENDED_SEGMENT_ADDRESS: u8 = 0x02;
+    const START_SEGMENT_ADDRESS: u8 = 0x03;
+    const EXTENDED_LINEAR_ADDRESS: u8 = 0x04;
+    const START_LINEAR_ADDRESS: u8 = 0x05;
+
+    let text = String::from_utf8_lossy(data);
+    let mut segments = vec![];
+    let mut entry = None;
+    let mut base = 0;
+    for (i, line) in text.lines().enumerate() {
+        let line_number = i + 1;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if !line.starts_with(':') {
+            return Err(ImageError::InvalidRecord(line_number));
+        }
+        let record = decode_record(&line[1..], line_number)?;
+        if record.len() < 5 || record.len() != 5 + record[0] as usize {
+            return Err(ImageError::InvalidRecord(line_number));
+        }
+        if record.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) != 0 {
+            return Err(ImageError::ChecksumMismatch(line_number));
+        }
+        let offset = u32::from(record[1]) << 8 | u32::from(record[2]);
+        let payload = &record[4..record.len() - 1];
+        let word = |payload: &[u8]| {
+            payload
+                .iter()
+                .fold(0u32, |value, &byte| value << 8 | u32::from(byte))
+        };
+        match record[3] {
+            DATA => segments.push(Segment {
+                address: base + offset,
+                data: payload.to_vec(),
+            }),
+            END_OF_FILE => break,
+            EXTENDED_SEGMENT_ADDRESS if payload.len() == 2 => base = word(payload) << 4,
+            EXTENDED_LINEAR_ADDRESS if payload.len() == 2 => base = word(payload) << 16,
+            START_SEGMENT_ADDRESS if payload.len() == 4 => {
+                let (segment, offset) = (word(&payload[..2]), word(&payload[2..]));
+                entry = Some((segment << 4) + offset);
+            }
+            START_LINEAR_ADDRESS if payload.len() == 4 => entry = Some(word(payload)),
+            _ => return Err(ImageError::InvalidRecord(line_number)),
+        }
+    }
+    Ok((segments, entry))
+}
+
+fn parse_srec(data: &[u8]) -> Result<(Vec<Segment>, Option<u32>), ImageError> {
+    let text = String::from_utf8_lossy(data);
+    let mut segments = vec![];
+    let mut entry = None;
+    for (i, line) in text.lines().enumerate() {
+        let line_number = i + 1;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let kind = match line.as_bytes() {
+            [b'S', kind, ..] if kind.is_ascii_digit() => kind - b'0',
+            _ => return Err(ImageError::InvalidRecord(line_number)),
+        };
+        let record = decode_record(&line[2..], line_number)?;
+        if record.is_empty() || record.len() != 1 + record[0] as usize {
+            return Err(ImageError::InvalidRecord(line_number));
+        }
+        if record.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) != 0xff {
+            return Err(ImageError::ChecksumMismatch(line_number));
+        }
+        let address_length = match kind {
+            0 | 1 | 5 | 9 => 2,
+            2 | 6 | 8 => 3,
+            3 | 7 => 4,
+            _ => return Err(ImageError::InvalidRecord(line_number)),
+        };
+        if record.len() < 2 + address_length {
+            return Err(ImageError::InvalidRecord(line_number));
+        }
+        let address = record[1..=address_length]
+            .iter()
+            .fold(0u32, |value, &byte| value << 8 | u32::from(byte));
+        let payload = &record[1 + address_length..record.len() - 1];
+        match kind {
+            1..=3 => segments.push(Segment {
+                address,
+                data: payload.to_vec(),
+            }),
+            7..=9 => entry = Some(address),
+            // Headers and record counts.
+            _ => (),
+        }
+    }
+    Ok((segments, entry))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::symbols::mock::Elf;
+    use goblin::elf::program_header::PT_NOTE;
+
+    #[test]
+    fn parses_intel_hex_with_extended_addresses() {
+        let hex = b":020000040800F2\n\
+                    :0400000001020304F2\n\
+                    :0400040005060708DE\n\
+                    :0400000508000101ED\n\
+                    :00000001FF\n";
+        let image = Image::parse(hex, ImageFormat::detect(hex), None).unwrap();
+        assert_eq!(
+            image.segments,
+            vec![Segment {
+                address: 0x0800_0000,
+                data: vec![1, 2, 3, 4, 5, 6, 7, 8],
+            }]
+        );
+        assert_eq!(image.entry, Some(0x0800_0101));
+
+        let overlapping = b":0400000001020304F2\n:020002000506F1\n";
+        match Image::parse(overlapping, ImageFormat::IntelHex, None) {
+            Err(ImageError::Overlap(0, 2)) => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+
+        let past_the_end = b":02000004FFFFFC\n\
+                             :10FFF800000102030405060708090A0B0C0D0E0F81\n";
+        match Image::parse(past_the_end, ImageFormat::IntelHex, None) {
+            Err(ImageError::AddressOverflow(0xffff_fff8)) => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn parses_s_records() {
+        let srec = b"S00600004844521B\n\
+                     S30908000000AABBCCDDE0\n\
+                     S70508000000F2\n";
+        let image = Image::parse(srec, ImageFormat::detect(srec), None).unwrap();
+        assert_eq!(
+            image.segments,
+            vec![Segment {
+                address: 0x0800_0000,
+                data: vec![0xaa, 0xbb, 0xcc, 0xdd],
+            }]
+        );
+        assert_eq!(image.entry, Some(0x0800_0000));
+    }
+
+    #[test]
+    fn loads_elf_segments_at_their_physical_addresses() {
+        // .data is linked to run from RAM, but stored in flash after .text.
+        let elf = Elf::new()
+            .segment(PT_LOAD, 0x0800_0000, 0x0800_0000, &[1, 2, 3, 4])
+            .segment(PT_LOAD, 0x2000_0000, 0x0800_0004, &[5, 6])
+            .segment(PT_NOTE, 0, 0, &[7, 8])
+            .entry(0x0800_0001)
+            .build();
+        let image = Image::parse(&elf, ImageFormat::detect(&elf), None).unwrap();
+        assert_eq!(
+            image.segments,
+            vec![Segment {
+                address: 0x0800_0000,
+                data: vec![1, 2, 3, 4, 5, 6],
+            }]
+        );
+        assert_eq!(image.entry, Some(0x0800_0001));
+    }
+
+    #[test]
+    fn loads_raw_binaries_at_the_base_address() {
+        let data = [0x00, 0x10, 0x00, 0x20];
+        assert_eq!(ImageFormat::detect(&data), ImageFormat::Binary);
+        let image = Image::parse(&data, ImageFormat::Binary, Some(0x0800_0000)).unwrap();
+        assert_eq!(
+            image.segments,
+            vec![Segment {
+                address: 0x0800_0000,
+                data: data.to_vec(),
+            }]
+        );
+        assert_eq!(image.entry, None);
+
+        match Image::parse(&data, ImageFormat::Binary, None) {
+            Err(ImageError::MissingBaseAddress) => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+        match Image::parse(&data, ImageFormat::Binary, Some(0xffff_fffc)) {
+            Err(ImageError::AddressOverflow(0xffff_fffc)) => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+}
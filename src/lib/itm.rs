@@ -0,0 +1,504 @@
+/// Relation of a local timestamp to the packet it belongs to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimestampRelation {
+    /// The timestamp is synchronous to the corresponding ITM or DWT data.
+    Sync,
+    /// The timestamp was delayed relative to the data.
+    TimestampDelayed,
+    /// The data was delayed relative to the timestamp.
+    DataDelayed,
+    /// Both the data and the timestamp were delayed.
+    BothDelayed,
+}
+
+/// The action reported by an exception trace packet.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExceptionAction {
+    Entered,
+    Exited,
+    Returned,
+}
+
+/// The kind of access reported by a data trace value packet.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DataAccess {
+    Read,
+    Write,
+}
+
+/// A decoded ITM/DWT packet.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TracePacket {
+    Sync,
+    Overflow,
+    LocalTimestamp {
+        delta: u32,
+        relation: TimestampRelation,
+    },
+    /// Bits [25:0] of the global timestamp.
+    GlobalTimestamp1 {
+        timestamp: u32,
+        wrap: bool,
+        clock_change: bool,
+    },
+    /// The high order bits [47:26] or [63:26] of the global timestamp.
+    GlobalTimestamp2 {
+        timestamp: u64,
+    },
+    /// An extension packet. Without `hardware` set, `value` is the stimulus port page
+    /// the following ITM data belongs to, which the decoder applies to their port numbers.
+    Extension {
+        hardware: bool,
+        value: u32,
+    },
+    /// Data written to an ITM stimulus port, numbered across all pages.
+    Instrumentation {
+        port: u8,
+        payload: Vec<u8>,
+    },
+    EventCounter {
+        cpi: bool,
+        exc: bool,
+        sleep: bool,
+        lsu: bool,
+        fold: bool,
+        cyc: bool,
+    },
+    ExceptionTrace {
+        exception: u16,
+        action: ExceptionAction,
+    },
+    /// A periodic PC sample. `None` if the core was sleeping.
+    PcSample {
+        pc: Option<u32>,
+    },
+    DataTracePc {
+        comparator: u8,
+        pc: u32,
+    },
+    DataTraceAddress {
+        comparator: u8,
+        address: u16,
+    },
+    DataTraceValue {
+        comparator: u8,
+        access: DataAccess,
+        value: u32,
+    },
+    /// A packet with a reserved header or discriminator.
+    Unknown {
+        header: u8,
+        payload: Vec<u8>,
+    },
+}
+
+enum State {
+    Header,
+    /// Counting the zero bytes of a synchronization packet.
+    Sync(usize),
+    /// Waiting for a fixed amount of payload bytes.
+    Payload(usize),
+    /// Waiting for payload bytes until the continuation bit is cleared.
+    Continuation,
+}
+
+/// Streaming decoder for the ITM protocol.
+///
+/// Bytes may be fed in arbitrary chunks; packets split over several
+/// chunks are completed with the next call.
+pub struct ItmDecoder {
+    state: State,
+    header: u8,
+    payload: Vec<u8>,
+    /// The stimulus port page of the last extension packet.
+    page: u8,
+}
+
+impl Default for ItmDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ItmDecoder {
+    /// Minimum number of zero bytes preceding the final byte of a synchronization packet.
+    const SYNC_ZEROS: usize = 5;
+
+    const HEADER_OVERFLOW: u8 = 0x70;
+    const HEADER_GTS1: u8 = 0x94;
+    const HEADER_GTS2: u8 = 0xb4;
+
+    /// Continuation bit of multi byte payloads.
+    const CONTINUATION: u8 = 0x80;
+
+    pub fn new() -> Self {
+        Self {
+            state: State::Header,
+            header: 0,
+            payload: vec![],
+            page: 0,
+        }
+    }
+
+    /// Decodes `data` and returns all packets completed by it.
+    pub fn feed(&mut self, data: &[u8]) -> Vec<TracePacket> {
+        let mut packets = vec![];
+        for &byte in data {
+            if let Some(packet) = self.push(byte) {
+                packets.push(packet);
+            }
+        }
+        packets
+    }
+
+    fn push(&mut self, byte: u8) -> Option<TracePacket> {
+        match self.state {
+            State::Header => self.header(byte),
+            State::Sync(zeros) => {
+                if byte == 0x00 {
+                    self.state = State::Sync(zeros + 1);
+                    None
+                } else if byte == 0x80 && zeros >= Self::SYNC_ZEROS {
+                    self.state = State::Header;
+                    Some(TracePacket::Sync)
+                } else {
+                    // Not a synchronization packet after all. Resume with this byte as a header.
+                    self.state = State::Header;
+                    self.header(byte)
+                }
+            }
+            State::Payload(remaining) => {
+                self.payload.push(byte);
+                if remaining > 1 {
+                    self.state = State::Payload(remaining - 1);
+                    None
+                } else {
+                    self.state = State::Header;
+                    Some(self.finish())
+                }
+            }
+            State::Continuation => {
+                self.payload.push(byte);
+                // No payload is longer than 7 bytes, so stop there in case of garbage.
+                if byte & Self::CONTINUATION == 0 || self.payload.len() == 7 {
+                    self.state = State::Header;
+                    Some(self.finish())
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    fn header(&mut self, byte: u8) -> Option<TracePacket> {
+        self.header = byte;
+        self.payload.clear();
+
+        if byte == 0x00 {
+            self.state = State::Sync(1);
+            return None;
+        }
+        if byte == Self::HEADER_OVERFLOW {
+            return Some(TracePacket::Overflow);
+        }
+
+        match byte & 0x03 {
+            0b00 => {
+                if byte & 0x0f == 0x00 {
+                    // Local timestamp.
+                    if byte & Self::CONTINUATION == 0 {
+                        // Format 2 carries the delta in the header.
+                        return Some(TracePacket::LocalTimestamp {
+                            delta: ((byte >> 4) & 0x07) as u32,
+                            relation: TimestampRelation::Sync,
+                        });
+                    }
+                    self.state = State::Continuation;
+                    None
+                } else if byte & 0x0b == 0x08 {
+                    // Extension.
+                    if byte & Self::CONTINUATION == 0 {
+                        return Some(self.finish());
+                    }
+                    self.state = State::Continuation;
+                    None
+                } else if byte == Self::HEADER_GTS1 || byte == Self::HEADER_GTS2 {
+                    self.state = State::Continuation;
+                    None
+                } else {
+                    Some(TracePacket::Unknown {
+                        header: byte,
+                        payload: vec![],
+                    })
+                }
+            }
+            size => {
+                // Source packets have 1, 2 or 4 payload bytes.
+                self.state = State::Payload(1 << (size - 1));
+                None
+            }
+        }
+    }
+
+    /// Decodes the packet formed by the current header and payload.
+    fn finish(&mut self) -> TracePacket {
+        let header = self.header;
+        let payload = std::mem::replace(&mut self.payload, vec![]);
+
+        if header & 0x03 == 0 {
+            if header & 0x0f == 0x00 {
+                let relation = match (header >> 4) & 0x03 {
+                    0b00 => TimestampRelation::Sync,
+                    0b01 => TimestampRelation::TimestampDelayed,
+                    0b10 => TimestampRelation::DataDelayed,
+                    _ => TimestampRelation::BothDelayed,
+                };
+                return TracePacket::LocalTimestamp {
+                    delta: Self::continued_value(&payload) as u32,
+                    relation,
+                };
+            }
+            if header == Self::HEADER_GTS1 {
+                let mut timestamp = Self::continued_value(&payload) as u32;
+                let mut wrap = false;
+                let mut clock_change = false;
+                if payload.len() == 4 {
+                    // The last byte carries the clock change and wrap flags above bit 25.
+                    clock_change = payload[3] & 0x20 != 0;
+                    wrap = payload[3] & 0x40 != 0;
+                    timestamp &= 0x03ff_ffff;
+                }
+                return TracePacket::GlobalTimestamp1 {
+                    timestamp,
+                    wrap,
+                    clock_change,
+                };
+            }
+            if header == Self::HEADER_GTS2 {
+                return TracePacket::GlobalTimestamp2 {
+                    timestamp: Self::continued_value(&payload),
+                };
+            }
+            // Extension: 3 bits in the header, 7 bits from every payload byte.
+            let value =
+                ((header >> 4) & 0x07) as u32 | (Self::continued_value(&payload) << 3) as u32;
+            let hardware = header & 0x04 != 0;
+            if !hardware {
+                // There are 8 pages of 32 stimulus ports.
+                self.page = (value & 0x07) as u8;
+            }
+            return TracePacket::Extension { hardware, value };
+        }
+
+        let address = header >> 3;
+        if header & 0x04 == 0 {
+            return TracePacket::Instrumentation {
+                port: self.page << 5 | address,
+                payload,
+            };
+        }
+
+        let value = payload
+            .iter()
+            .rev()
+            .fold(0u32, |value, &byte| (value << 8) | byte as u32);
+        match address {
+            0 if payload.len() == 1 => TracePacket::EventCounter {
+                cpi: value & 0x01 != 0,
+                exc: value & 0x02 != 0,
+                sleep: value & 0x04 != 0,
+                lsu: value & 0x08 != 0,
+                fold: value & 0x10 != 0,
+                cyc: value & 0x20 != 0,
+            },
+            1 if payload.len() == 2 => {
+                let action = match (value >> 12) & 0x03 {
+                    0b01 => Some(ExceptionAction::Entered),
+                    0b10 => Some(ExceptionAction::Exited),
+                    0b11 => Some(ExceptionAction::Returned),
+                    _ => None,
+                };
+                match action {
+                    Some(action) => TracePacket::ExceptionTrace {
+                        exception: (value & 0x01ff) as u16,
+                        action,
+                    },
+                    None => TracePacket::Unknown { header, payload },
+                }
+            }
+            2 if payload.len() == 4 => TracePacket::PcSample { pc: Some(value) },
+            2 if payload.len() == 1 => TracePacket::PcSample { pc: None },
+            8..=15 if address & 0x01 == 0 && payload.len() == 4 => TracePacket::DataTracePc {
+                comparator: (address >> 1) & 0x03,
+                pc: value,
+            },
+            8..=15 if payload.len() == 2 => TracePacket::DataTraceAddress {
+                comparator: (address >> 1) & 0x03,
+                address: value as u16,
+            },
+            16..=23 => TracePacket::DataTraceValue {
+                comparator: (address >> 1) & 0x03,
+                access: if address & 0x01 == 0 {
+                    DataAccess::Read
+                } else {
+                    DataAccess::Write
+                },
+                value,
+            },
+            _ => TracePacket::Unknown { header, payload },
+        }
+    }
+
+    /// Assembles a value from 7 bit groups, least significant group first.
+    fn continued_value(payload: &[u8]) -> u64 {
+        payload.iter().enumerate().fold(0, |value, (i, &byte)| {
+            value | (((byte & !Self::CONTINUATION) as u64) << (7 * i))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_sync_and_overflow() {
+        let mut decoder = ItmDecoder::new();
+        let packets = decoder.feed(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x80, 0x70]);
+        assert_eq!(packets, vec![TracePacket::Sync, TracePacket::Overflow]);
+    }
+
+    #[test]
+    fn decodes_instrumentation_across_chunks() {
+        let mut decoder = ItmDecoder::new();
+        // Port 1, 4 bytes: "abcd".
+        assert_eq!(decoder.feed(&[0x0b, b'a', b'b']), vec![]);
+        assert_eq!(
+            decoder.feed(&[b'c', b'd', 0x01, b'x']),
+            vec![
+                TracePacket::Instrumentation {
+                    port: 1,
+                    payload: b"abcd".to_vec()
+                },
+                TracePacket::Instrumentation {
+                    port: 0,
+                    payload: b"x".to_vec()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn decodes_timestamps() {
+        let mut decoder = ItmDecoder::new();
+        let packets = decoder.feed(&[0x30, 0xc0, 0x81, 0x01, 0x94, 0x85, 0x00]);
+        assert_eq!(
+            packets,
+            vec![
+                TracePacket::LocalTimestamp {
+                    delta: 3,
+                    relation: TimestampRelation::Sync
+                },
+                TracePacket::LocalTimestamp {
+                    delta: 0x81,
+                    relation: TimestampRelation::Sync
+                },
+                TracePacket::GlobalTimestamp1 {
+                    timestamp: 5,
+                    wrap: false,
+                    clock_change: false
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn decodes_global_timestamp_flags() {
+        let mut decoder = ItmDecoder::new();
+        let clock_change = [0x94, 0x81, 0x80, 0x80, 0x20];
+        let wrap = [0x94, 0x82, 0x80, 0x80, 0x40];
+        let packets = decoder.feed(&[clock_change, wrap].concat());
+        assert_eq!(
+            packets,
+            vec![
+                TracePacket::GlobalTimestamp1 {
+                    timestamp: 1,
+                    wrap: false,
+                    clock_change: true
+                },
+                TracePacket::GlobalTimestamp1 {
+                    timestamp: 2,
+                    wrap: true,
+                    clock_change: false
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn applies_the_stimulus_port_page() {
+        let mut decoder = ItmDecoder::new();
+        // Page 1, port 2 of it, then page 0 again.
+        let packets = decoder.feed(&[0x18, 0x11, b'a', 0x08, 0x11, b'b']);
+        assert_eq!(
+            packets,
+            vec![
+                TracePacket::Extension {
+                    hardware: false,
+                    value: 1
+                },
+                TracePacket::Instrumentation {
+                    port: 34,
+                    payload: b"a".to_vec()
+                },
+                TracePacket::Extension {
+                    hardware: false,
+                    value: 0
+                },
+                TracePacket::Instrumentation {
+                    port: 2,
+                    payload: b"b".to_vec()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn decodes_hardware_packets() {
+        let mut decoder = ItmDecoder::new();
+        let packets = decoder.feed(&[
+            // Exception 15 (SysTick) entered.
+            0x0e, 0x0f, 0x10, // PC sample.
+            0x17, 0x78, 0x56, 0x34, 0x12, // Sleeping PC sample.
+            0x15, 0x00, // Event counter with CYC set.
+            0x05, 0x20, // Data trace write of comparator 1.
+            0x9d, 0x2a,
+        ]);
+        assert_eq!(
+            packets,
+            vec![
+                TracePacket::ExceptionTrace {
+                    exception: 15,
+                    action: ExceptionAction::Entered
+                },
+                TracePacket::PcSample {
+                    pc: Some(0x12345678)
+                },
+                TracePacket::PcSample { pc: None },
+                TracePacket::EventCounter {
+                    cpi: false,
+                    exc: false,
+                    sleep: false,
+                    lsu: false,
+                    fold: false,
+                    cyc: true
+                },
+                TracePacket::DataTraceValue {
+                    comparator: 1,
+                    access: DataAccess::Write,
+                    value: 0x2a
+                },
+            ]
+        );
+    }
+}
@@ -1,11 +1,120 @@
 mod usb_interface;
 pub mod constants;
 mod stlink;
+mod swim;
+pub mod stm8;
+pub mod stm32;
+mod swv;
+pub mod itm;
+mod memory;
+mod trace;
+mod symbols;
+mod profiler;
+mod timeline;
+mod rtt;
+mod defmt;
+pub mod cortex_m;
+mod semihosting;
+mod gdb_server;
+mod rtos;
+mod flash_algorithm;
+mod image;
+mod crc;
+mod fault;
+mod backtrace;
 
 pub use crate::stlink::{
     STLink,
     STLinkError,
 };
+pub use crate::swim::{
+    ByteMemory,
+    Swim,
+    SwimSpeed,
+};
+pub use crate::swv::{
+    swo_divider,
+    SwvOverflow,
+    SwvReader,
+};
+pub use crate::memory::MemoryAccess;
+pub use crate::trace::{
+    configure_trace,
+    TraceConfig,
+};
+pub use crate::symbols::{
+    Symbol,
+    SymbolError,
+    Symbols,
+};
+pub use crate::profiler::{
+    sample_pcsr,
+    Profile,
+};
+pub use crate::timeline::{
+    ExceptionEvent,
+    ExceptionNames,
+    Timeline,
+};
+pub use crate::rtt::{
+    Rtt,
+    RttChannel,
+    RttError,
+};
+pub use crate::defmt::{
+    DefmtDecoder,
+    DefmtError,
+    DefmtTable,
+    Encoding,
+    FrameError,
+    Level,
+    Location,
+    LogFrame,
+};
+pub use crate::semihosting::{
+    Semihosting,
+    SemihostingOutcome,
+};
+pub use crate::gdb_server::{
+    GdbError,
+    GdbServer,
+    MemoryRegion,
+    Monitor,
+    RegionKind,
+};
+pub use crate::rtos::{
+    FreeRtos,
+    RtosError,
+    Task,
+    TaskState,
+};
+pub use crate::flash_algorithm::{
+    FlashAlgorithm,
+    FlashAlgorithmError,
+    FlashLoader,
+    FlashOperation,
+};
+pub use crate::crc::{
+    crc32,
+    CrcStub,
+    CrcUnit,
+};
+pub use crate::backtrace::{
+    BacktraceError,
+    StackFrame,
+    Unwinder,
+};
+pub use crate::fault::{
+    ExceptionFrame,
+    FaultReport,
+};
+pub use crate::image::{
+    FlashReport,
+    Image,
+    ImageError,
+    ImageFormat,
+    Segment,
+};
 pub use crate::usb_interface::{
     STLinkUSBDevice,
     get_all_plugged_devices,
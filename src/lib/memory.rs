@@ -0,0 +1,251 @@
+use ssmarshal::deserialize;
+
+use crate::stlink::{AccessPort, STLink, STLinkError};
+
+/// Access to target memory through a MEM-AP.
+///
+/// This is implemented by `STLink` and allows the target helpers to run
+/// against simulated memory as well.
+pub trait MemoryAccess {
+    fn read_mem32(
+        &mut self,
+        addr: u32,
+        size: u32,
+        apsel: AccessPort,
+    ) -> Result<Vec<u8>, STLinkError>;
+    fn write_mem32(
+        &mut self,
+        addr: u32,
+        data: Vec<u8>,
+        apsel: AccessPort,
+    ) -> Result<(), STLinkError>;
+    fn read_mem16(
+        &mut self,
+        addr: u32,
+        size: u32,
+        apsel: AccessPort,
+    ) -> Result<Vec<u8>, STLinkError>;
+    fn write_mem16(
+        &mut self,
+        addr: u32,
+        data: Vec<u8>,
+        apsel: AccessPort,
+    ) -> Result<(), STLinkError>;
+    fn read_mem8(
+        &mut self,
+        addr: u32,
+        size: u32,
+        apsel: AccessPort,
+    ) -> Result<Vec<u8>, STLinkError>;
+    fn write_mem8(
+        &mut self,
+        addr: u32,
+        data: Vec<u8>,
+        apsel: AccessPort,
+    ) -> Result<(), STLinkError>;
+
+    /// Reads a single word.
+    fn read_word32(&mut self, addr: u32, apsel: AccessPort) -> Result<u32, STLinkError> {
+        let data = self.read_mem32(addr, 4, apsel)?;
+        // Unwrap is ok!
+        Ok(deserialize(&data[0..4]).unwrap().0)
+    }
+
+    /// Writes a single word.
+    fn write_word32(
+        &mut self,
+        addr: u32,
+        value: u32,
+        apsel: AccessPort,
+    ) -> Result<(), STLinkError> {
+        self.write_mem32(
+            addr,
+            vec![
+                value as u8,
+                (value >> 8) as u8,
+                (value >> 16) as u8,
+                (value >> 24) as u8,
+            ],
+            apsel,
+        )
+    }
+}
+
+impl<'a> MemoryAccess for STLink<'a> {
+    fn read_mem32(
+        &mut self,
+        addr: u32,
+        size: u32,
+        apsel: AccessPort,
+    ) -> Result<Vec<u8>, STLinkError> {
+        STLink::read_mem32(self, addr, size, apsel)
+    }
+
+    fn write_mem32(
+        &mut self,
+        addr: u32,
+        data: Vec<u8>,
+        apsel: AccessPort,
+    ) -> Result<(), STLinkError> {
+        STLink::write_mem32(self, addr, data, apsel)
+    }
+
+    fn read_mem16(
+        &mut self,
+        addr: u32,
+        size: u32,
+        apsel: AccessPort,
+    ) -> Result<Vec<u8>, STLinkError> {
+        STLink::read_mem16(self, addr, size, apsel)
+    }
+
+    fn write_mem16(
+        &mut self,
+        addr: u32,
+        data: Vec<u8>,
+        apsel: AccessPort,
+    ) -> Result<(), STLinkError> {
+        STLink::write_mem16(self, addr, data, apsel)
+    }
+
+    fn read_mem8(
+        &mut self,
+        addr: u32,
+        size: u32,
+        apsel: AccessPort,
+    ) -> Result<Vec<u8>, STLinkError> {
+        STLink::read_mem8(self, addr, size, apsel)
+    }
+
+    fn write_mem8(
+        &mut self,
+        addr: u32,
+        data: Vec<u8>,
+        apsel: AccessPort,
+    ) -> Result<(), STLinkError> {
+        STLink::write_mem8(self, addr, data, apsel)
+    }
+}
+
+/// Simulated target memory for the tests of the target helpers.
+#[cfg(test)]
+pub(crate) mod mock {
+    use std::collections::HashMap;
+
+    use super::MemoryAccess;
+    use crate::stlink::{AccessPort, STLinkError};
+
+    /// Sparse memory where everything not written reads as zero.
+    /// Writes are logged as `(width in bits, address, size)`.
+    #[derive(Default)]
+    pub struct Memory {
+        bytes: HashMap<u32, u8>,
+        /// Whether reading bytes which were never written faults, like unmapped addresses.
+        faulting: bool,
+        pub writes: Vec<(u8, u32, usize)>,
+    }
+
+    impl Memory {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Memory where only the bytes written before can be read.
+        pub fn faulting() -> Self {
+            Self {
+                faulting: true,
+                ..Self::default()
+            }
+        }
+
+        pub fn read(&self, addr: u32, size: u32) -> Vec<u8> {
+            (addr..addr + size)
+                .map(|addr| *self.bytes.get(&addr).unwrap_or(&0))
+                .collect()
+        }
+
+        fn checked_read(&self, addr: u32, size: u32) -> Result<Vec<u8>, STLinkError> {
+            if self.faulting && (addr..addr + size).any(|addr| !self.bytes.contains_key(&addr)) {
+                return Err(STLinkError::TransferFault(addr, size as u16));
+            }
+            Ok(self.read(addr, size))
+        }
+
+        pub fn write(&mut self, addr: u32, data: &[u8]) {
+            for (offset, &byte) in data.iter().enumerate() {
+                self.bytes.insert(addr + offset as u32, byte);
+            }
+        }
+
+        pub fn word(&self, addr: u32) -> u32 {
+            let data = self.read(addr, 4);
+            u32::from_le_bytes([data[0], data[1], data[2], data[3]])
+        }
+
+        pub fn set_word(&mut self, addr: u32, value: u32) {
+            self.write(addr, &value.to_le_bytes());
+        }
+
+        fn log(&mut self, width: u8, addr: u32, data: Vec<u8>) -> Result<(), STLinkError> {
+            self.writes.push((width, addr, data.len()));
+            self.write(addr, &data);
+            Ok(())
+        }
+    }
+
+    impl MemoryAccess for Memory {
+        fn read_mem32(
+            &mut self,
+            addr: u32,
+            size: u32,
+            _: AccessPort,
+        ) -> Result<Vec<u8>, STLinkError> {
+            self.checked_read(addr, size)
+        }
+
+        fn write_mem32(
+            &mut self,
+            addr: u32,
+            data: Vec<u8>,
+            _: AccessPort,
+        ) -> Result<(), STLinkError> {
+            self.log(32, addr, data)
+        }
+
+        fn read_mem16(
+            &mut self,
+            addr: u32,
+            size: u32,
+            _: AccessPort,
+        ) -> Result<Vec<u8>, STLinkError> {
+            self.checked_read(addr, size)
+        }
+
+        fn write_mem16(
+            &mut self,
+            addr: u32,
+            data: Vec<u8>,
+            _: AccessPort,
+        ) -> Result<(), STLinkError> {
+            self.log(16, addr, data)
+        }
+
+        fn read_mem8(
+            &mut self,
+            addr: u32,
+            size: u32,
+            _: AccessPort,
+        ) -> Result<Vec<u8>, STLinkError> {
+            self.checked_read(addr, size)
+        }
+
+        fn write_mem8(
+            &mut self,
+            addr: u32,
+            data: Vec<u8>,
+            _: AccessPort,
+        ) -> Result<(), STLinkError> {
+            self.log(8, addr, data)
+        }
+    }
+}
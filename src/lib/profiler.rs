@@ -0,0 +1,233 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+use crate::cortex_m::{DEMCR, DEMCR_TRCENA};
+use crate::itm::TracePacket;
+use crate::memory::MemoryAccess;
+use crate::stlink::{AccessPort, STLinkError};
+use crate::symbols::Symbols;
+
+const DWT_PCSR: u32 = 0xe000_101c;
+
+/// Name under which samples taken while the core slept are reported.
+const SLEEPING: &str = "[sleeping]";
+
+/// A collection of PC samples.
+#[derive(Default)]
+pub struct Profile {
+    samples: HashMap<u32, u64>,
+    sleeping: u64,
+    total: u64,
+}
+
+impl Profile {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a sample. `None` means the core was sleeping or halted.
+    pub fn add_sample(&mut self, pc: Option<u32>) {
+        match pc {
+            Some(pc) => *self.samples.entry(pc).or_insert(0) += 1,
+            None => self.sleeping += 1,
+        }
+        self.total += 1;
+    }
+
+    /// Records all PC sample packets of a decoded SWV stream.
+    pub fn add_packets(&mut self, packets: &[TracePacket]) {
+        for packet in packets {
+            if let TracePacket::PcSample { pc } = packet {
+                self.add_sample(*pc);
+            }
+        }
+    }
+
+    /// Total number of samples taken.
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Returns the sample count per function, hottest first.
+    /// Without symbols, samples are grouped per address.
+    pub fn hot_functions(&self, symbols: Option<&Symbols>) -> Vec<(String, u64)> {
+        let mut functions = HashMap::new();
+        for (&pc, &count) in &self.samples {
+            let name = match symbols.and_then(|symbols| symbols.function_at(pc)) {
+                Some(symbol) => symbol.name.clone(),
+                None => format!("0x{:08x}", pc),
+            };
+            *functions.entry(name).or_insert(0) += count;
+        }
+        if self.sleeping > 0 {
+            functions.insert(SLEEPING.to_owned(), self.sleeping);
+        }
+
+        let mut functions = functions.into_iter().collect::<Vec<_>>();
+        functions.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        functions
+    }
+
+    /// Writes the samples in the collapsed stack format understood by flamegraph tools.
+    /// Samples carry no call stack, so every stack consists of the sampled function only.
+    pub fn write_collapsed<W: Write>(
+        &self,
+        symbols: Option<&Symbols>,
+        w: &mut W,
+    ) -> std::io::Result<()> {
+        for (name, count) in self.hot_functions(symbols) {
+            writeln!(w, "{} {}", name.replace(';', ":"), count)?;
+        }
+        Ok(())
+    }
+
+    /// Writes a flamegraph SVG of the samples.
+    pub fn write_flamegraph<W: Write>(
+        &self,
+        symbols: Option<&Symbols>,
+        w: &mut W,
+    ) -> std::io::Result<()> {
+        const WIDTH: f64 = 1200.0;
+        const FRAME_HEIGHT: f64 = 16.0;
+        const HEIGHT: f64 = 2.0 * FRAME_HEIGHT + 20.0;
+
+        writeln!(
+            w,
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}" font-family="monospace" font-size="12">"#,
+            WIDTH, HEIGHT
+        )?;
+        writeln!(
+            w,
+            r#"<rect x="0" y="{}" width="{}" height="{}" fill="rgb(240,140,60)"><title>all ({} samples)</title></rect>"#,
+            HEIGHT - FRAME_HEIGHT,
+            WIDTH,
+            FRAME_HEIGHT - 1.0,
+            self.total
+        )?;
+
+        let mut x = 0.0;
+        for (i, (name, count)) in self.hot_functions(symbols).iter().enumerate() {
+            let width = WIDTH * *count as f64 / u64::max(self.total, 1) as f64;
+            let name = name
+                .replace('&', "&amp;")
+                .replace('<', "&lt;")
+                .replace('>', "&gt;");
+            writeln!(
+                w,
+                r#"<g><title>{} ({} samples, {:.2}%)</title><rect x="{:.2}" y="{}" width="{:.2}" height="{}" fill="rgb(230,{},50)"/>"#,
+                name,
+                count,
+                100.0 * *count as f64 / self.total as f64,
+                x,
+                HEIGHT - 2.0 * FRAME_HEIGHT,
+                width,
+                FRAME_HEIGHT - 1.0,
+                100 + (i * 37) % 120
+            )?;
+            // Only label frames which are wide enough to hold some text.
+            if width > 40.0 {
+                let chars = ((width - 6.0) / 7.0) as usize;
+                let label = if name.chars().count() > chars {
+                    name.chars()
+                        .take(chars.saturating_sub(2))
+                        .collect::<String>()
+                        + ".."
+                } else {
+                    name.clone()
+                };
+                writeln!(
+                    w,
+                    r#"<text x="{:.2}" y="{}">{}</text>"#,
+                    x + 3.0,
+                    HEIGHT - FRAME_HEIGHT - 4.0,
+                    label
+                )?;
+            }
+            writeln!(w, "</g>")?;
+            x += width;
+        }
+        writeln!(w, "</svg>")
+    }
+}
+
+/// Samples the PC non-intrusively by polling DWT_PCSR for `duration`.
+pub fn sample_pcsr<M: MemoryAccess>(
+    mem: &mut M,
+    apsel: AccessPort,
+    profile: &mut Profile,
+    duration: Duration,
+) -> Result<(), STLinkError> {
+    // DWT_PCSR reads as zero unless the DWT is enabled.
+    let demcr = mem.read_word32(DEMCR, apsel)?;
+    mem.write_word32(DEMCR, demcr | DEMCR_TRCENA, apsel)?;
+
+    let instant = Instant::now();
+    while instant.elapsed() < duration {
+        // The core is halted or sleeping if PCSR reads all ones.
+        let pc = mem.read_word32(DWT_PCSR, apsel)?;
+        profile.add_sample(if pc == 0xffff_ffff { None } else { Some(pc) });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::symbols::mock::Elf;
+    use goblin::elf::sym::STT_FUNC;
+
+    fn profile() -> Profile {
+        let mut profile = Profile::new();
+        for &pc in &[0x100, 0x104, 0x108, 0x200, 0x204, 0x300] {
+            profile.add_sample(Some(pc));
+        }
+        profile.add_sample(Some(0x100));
+        profile.add_sample(None);
+        profile.add_sample(None);
+        profile
+    }
+
+    #[test]
+    fn ranks_hot_functions() {
+        let elf = Elf::new()
+            .symbol("loop", 0x101, 0x10, STT_FUNC)
+            .symbol("isr", 0x201, 0x10, STT_FUNC);
+        let symbols = Symbols::from_elf(&elf.build()).unwrap();
+        let profile = profile();
+        assert_eq!(profile.total(), 9);
+
+        let hot = |symbols| profile.hot_functions(symbols);
+        // Equal counts are ordered by name.
+        assert_eq!(
+            hot(Some(&symbols)),
+            vec![
+                ("loop".to_owned(), 4),
+                ("[sleeping]".to_owned(), 2),
+                ("isr".to_owned(), 2),
+                ("0x00000300".to_owned(), 1),
+            ]
+        );
+        assert_eq!(hot(None)[0], ("0x00000100".to_owned(), 2));
+        assert_eq!(hot(None).len(), 7);
+    }
+
+    #[test]
+    fn writes_collapsed_stacks() {
+        let elf = Elf::new().symbol("<T as a;b>::run", 0x101, 0x10, STT_FUNC);
+        let symbols = Symbols::from_elf(&elf.build()).unwrap();
+        let mut profile = Profile::new();
+        profile.add_sample(Some(0x100));
+        profile.add_sample(Some(0x400));
+
+        let mut collapsed = vec![];
+        profile
+            .write_collapsed(Some(&symbols), &mut collapsed)
+            .unwrap();
+        // Semicolons separate the frames of a stack.
+        assert_eq!(
+            String::from_utf8(collapsed).unwrap(),
+            "0x00000400 1\n<T as a:b>::run 1\n"
+        );
+    }
+}
@@ -0,0 +1,467 @@
+use ssmarshal::deserialize;
+
+use crate::cortex_m::CPUID;
+use crate::memory::MemoryAccess;
+use crate::stlink::{AccessPort, STLinkError};
+use crate::symbols::Symbols;
+
+#[derive(Debug)]
+pub enum RtosError {
+    STLink(STLinkError),
+    /// The firmware lacks a symbol the kernel always defines.
+    MissingSymbol(&'static str),
+    /// A task list at the given address does not end where it should.
+    CorruptList(u32),
+}
+
+impl From<STLinkError> for RtosError {
+    fn from(e: STLinkError) -> Self {
+        RtosError::STLink(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TaskState {
+    Running,
+    Ready,
+    Blocked,
+    Suspended,
+    /// Deleted, but not yet cleaned up by the idle task.
+    Deleted,
+}
+
+/// A FreeRTOS task.
+#[derive(Debug, Clone)]
+pub struct Task {
+    /// The address of the task control block.
+    pub tcb: u32,
+    pub name: String,
+    pub priority: u32,
+    pub state: TaskState,
+    /// The lowest address of the stack.
+    pub stack_base: u32,
+    /// The number of bytes at the bottom of the stack which were never used.
+    pub stack_high_water_mark: u32,
+    /// R0 to R15 and xPSR as saved on the stack by the last context switch.
+    /// `None` for the running task, whose registers are in the core.
+    pub registers: Option<[u32; 17]>,
+}
+
+/// List_t: uxNumberOfItems, pxIndex and the end marker (xItemValue, pxNext, pxPrevious).
+const LIST_SIZE: u32 = 20;
+const LIST_END: u32 = 8;
+const LIST_END_NEXT: u32 = 12;
+/// ListItem_t: xItemValue, pxNext, pxPrevious, pvOwner and pvContainer.
+const ITEM_NEXT: u32 = 4;
+const ITEM_OWNER: u32 = 12;
+
+/// The TCB_t members of the default configuration without MPU wrappers.
+const TCB_EVENT_ITEM_CONTAINER: u32 = 24 + 16;
+const TCB_PRIORITY: u32 = 44;
+const TCB_STACK: u32 = 48;
+const TCB_NAME: u32 = 52;
+/// The default configMAX_TASK_NAME_LEN.
+const TASK_NAME_LENGTH: u32 = 16;
+
+/// The value unused stack is filled with.
+const STACK_FILL_BYTE: u8 = 0xa5;
+/// Upper bound for the stack scanned for the high water mark.
+const MAX_STACK_SCAN: u32 = 0x1_0000;
+
+/// Sanity limit for the number of tasks in a list, to not follow a corrupted list forever.
+const MAX_TASKS: usize = 256;
+
+const CPACR: u32 = 0xe000_ed88;
+const CPACR_CP10_CP11: u32 = 0xf << 20;
+/// EXC_RETURN of an exception which returns to thread mode using the process stack.
+const EXC_RETURN_THREAD_PSP: u32 = 0xffff_fffd;
+/// EXC_RETURN bit which is cleared if the exception frame includes the FPU registers.
+const EXC_RETURN_STANDARD_FRAME: u32 = 1 << 4;
+/// xPSR bit which is set if the exception entry aligned the stack by an extra word.
+const XPSR_STACK_ALIGNED: u32 = 1 << 9;
+
+/// Reads the task lists of a FreeRTOS kernel.
+///
+/// The register layout saved on context switches is the one of the GCC ARM_CM0, ARM_CM3 and ARM_CM4F ports.
+pub struct FreeRtos {
+    current_tcb: u32,
+    ready_lists: u32,
+    priorities: u32,
+    /// The lists of tasks which are not ready, with the state of their tasks.
+    lists: Vec<(u32, TaskState)>,
+}
+
+impl FreeRtos {
+    /// Finds the kernel variables in the symbols of the firmware.
+    pub fn new(symbols: &Symbols) -> Result<Self, RtosError> {
+        let current_tcb = symbols
+            .get("pxCurrentTCB")
+            .ok_or(RtosError::MissingSymbol("pxCurrentTCB"))?;
+        let ready_lists = symbols
+            .get("pxReadyTasksLists")
+            .ok_or(RtosError::MissingSymbol("pxReadyTasksLists"))?;
+
+        let mut lists = vec![];
+        for &(name, state) in &[
+            ("xPendingReadyList", TaskState::Ready),
+            ("xDelayedTaskList1", TaskState::Blocked),
+            ("xDelayedTaskList2", TaskState::Blocked),
+            // These two only exist if INCLUDE_vTaskSuspend and INCLUDE_vTaskDelete are set.
+            ("xSuspendedTaskList", TaskState::Suspended),
+            ("xTasksWaitingTermination", TaskState::Deleted),
+        ] {
+            if let Some(symbol) = symbols.get(name) {
+                lists.push((symbol.address, state));
+            }
+        }
+
+        Ok(Self {
+            current_tcb: current_tcb.address,
+            ready_lists: ready_lists.address,
+            // The array has configMAX_PRIORITIES entries.
+            priorities: u32::max(ready_lists.size / LIST_SIZE, 1),
+            lists,
+        })
+    }
+
+    /// Reads all tasks. The core should be halted for the lists to be consistent.
+    pub fn tasks<M: MemoryAccess>(
+        &self,
+        mem: &mut M,
+        apsel: AccessPort,
+    ) -> Result<Vec<Task>, RtosError> {
+        let current = mem.read_word32(self.current_tcb, apsel)?;
+        // CPACR only exists with the Main Extension, ARMv6-M and ARMv8-M Baseline report 0xc.
+        let mainline = mem.read_word32(CPUID, apsel)? >> 16 & 0xf == 0xf;
+        let fpu = mainline && mem.read_word32(CPACR, apsel)? & CPACR_CP10_CP11 != 0;
+
+        let mut tcbs = vec![];
+        for priority in 0..self.priorities {
+            for tcb in read_list(mem, apsel, self.ready_lists + priority * LIST_SIZE)? {
+                tcbs.push((tcb, TaskState::Ready));
+            }
+        }
+        for &(list, state) in &self.lists {
+            for tcb in read_list(mem, apsel, list)? {
+                // Tasks blocked without a timeout are kept in the suspended list.
+                let state = if state == TaskState::Suspended
+                    && mem.read_word32(tcb + TCB_EVENT_ITEM_CONTAINER, apsel)? != 0
+                {
+                    TaskState::Blocked
+                } else {
+                    state
+                };
+                tcbs.push((tcb, state));
+            }
+        }
+        // The running task may be in a delayed list already, if it was halted while blocking.
+        if current != 0 && !tcbs.iter().any(|&(tcb, _)| tcb == current) {
+            tcbs.push((current, TaskState::Running));
+        }
+
+        let mut tasks = vec![];
+        for (tcb, state) in tcbs {
+            let state = if tcb == current {
+                TaskState::Running
+            } else {
+                state
+            };
+            tasks.push(read_task(mem, apsel, tcb, state, fpu)?);
+        }
+        Ok(tasks)
+    }
+}
+
+/// Returns the owners of the items of the list at `list`.
+fn read_list<M: MemoryAccess>(
+    mem: &mut M,
+    apsel: AccessPort,
+    list: u32,
+) -> Result<Vec<u32>, RtosError> {
+    let mut owners = vec![];
+    let mut item = mem.read_word32(list + LIST_END_NEXT, apsel)?;
+    while item != list + LIST_END {
+        if owners.len() == MAX_TASKS || item == 0 {
+            return Err(RtosError::CorruptList(list));
+        }
+        owners.push(mem.read_word32(item + ITEM_OWNER, apsel)?);
+        item = mem.read_word32(item + ITEM_NEXT, apsel)?;
+    }
+    Ok(owners)
+}
+
+fn read_task<M: MemoryAccess>(
+    mem: &mut M,
+    apsel: AccessPort,
+    tcb: u32,
+    state: TaskState,
+    fpu: bool,
+) -> Result<Task, RtosError> {
+    let top_of_stack = mem.read_word32(tcb, apsel)?;
+    let priority = mem.read_word32(tcb + TCB_PRIORITY, apsel)?;
+    let stack_base = mem.read_word32(tcb + TCB_STACK, apsel)?;
+    let name = mem.read_mem8(tcb + TCB_NAME, TASK_NAME_LENGTH, apsel)?;
+    let name = name.split(|&byte| byte == 0).next().unwrap_or(&[]);
+
+    let registers = if state == TaskState::Running {
+        None
+    } else {
+        Some(read_stacked_registers(mem, apsel, top_of_stack, fpu)?)
+    };
+
+    Ok(Task {
+        tcb,
+        name: String::from_utf8_lossy(name).into_owned(),
+        priority,
+        state,
+        stack_base,
+        stack_high_water_mark: stack_high_water_mark(mem, apsel, stack_base, top_of_stack)?,
+        registers,
+    })
+}
+
+/// Reads the registers PendSV saved on the stack of a task which is switched out.
+fn read_stacked_registers<M: MemoryAccess>(
+    mem: &mut M,
+    apsel: AccessPort,
+    top_of_stack: u32,
+    fpu: bool,
+) -> Result<[u32; 17], RtosError> {
+    // R4 to R11 first. The ARM_CM4F port follows them with EXC_RETURN and, if the task used
+    // the FPU, S16 to S31. Firmware may enable the FPU and still use the ARM_CM3 port, which
+    // stacks R0 there instead, so only a word which is a thread mode EXC_RETURN selects the
+    // ARM_CM4F layout.
+    let mut software = 8;
+    let mut extended_frame = false;
+    if fpu {
+        let exc_return = mem.read_word32(top_of_stack + 8 * 4, apsel)?;
+        if exc_return | EXC_RETURN_STANDARD_FRAME == EXC_RETURN_THREAD_PSP {
+            software += 1;
+            if exc_return & EXC_RETURN_STANDARD_FRAME == 0 {
+                software += 16;
+                extended_frame = true;
+            }
+        }
+    }
+    let saved = read_words(mem, apsel, top_of_stack, 8)?;
+    // The exception frame: R0 to R3, R12, LR, PC and xPSR.
+    let frame = read_words(mem, apsel, top_of_stack + software * 4, 8)?;
+
+    let mut registers = [0; 17];
+    registers[0..4].copy_from_slice(&frame[0..4]);
+    registers[4..12].copy_from_slice(&saved);
+    registers[12] = frame[4];
+    registers[14] = frame[5];
+    registers[15] = frame[6];
+    registers[16] = frame[7];
+
+    // The stack pointer of the task once the frame is popped.
+    let mut sp = top_of_stack + software * 4 + 8 * 4;
+    if extended_frame {
+        // S0 to S15, FPSCR and a reserved word.
+        sp += 18 * 4;
+    }
+    if frame[7] & XPSR_STACK_ALIGNED != 0 {
+        sp += 4;
+    }
+    registers[13] = sp;
+    Ok(registers)
+}
+
+/// Counts the bytes at the bottom of a stack which still hold the fill value.
+fn stack_high_water_mark<M: MemoryAccess>(
+    mem: &mut M,
+    apsel: AccessPort,
+    stack_base: u32,
+    top_of_stack: u32,
+) -> Result<u32, RtosError> {
+    const CHUNK: u32 = 256;
+
+    let end = u32::min(top_of_stack, stack_base.saturating_add(MAX_STACK_SCAN)) & !0x3;
+    let mut address = stack_base & !0x3;
+    while address < end {
+        let size = u32::min(CHUNK, end - address);
+        let chunk = mem.read_mem32(address, size, apsel)?;
+        if let Some(used) = chunk.iter().position(|&byte| byte != STACK_FILL_BYTE) {
+            return Ok(address + used as u32 - stack_base);
+        }
+        address += size;
+    }
+    Ok(end.saturating_sub(stack_base))
+}
+
+fn read_words<M: MemoryAccess>(
+    mem: &mut M,
+    apsel: AccessPort,
+    address: u32,
+    words: u32,
+) -> Result<Vec<u32>, RtosError> {
+    let data = mem.read_mem32(address, words * 4, apsel)?;
+    // Unwrap is ok!
+    Ok(data
+        .chunks(4)
+        .map(|word| deserialize(word).unwrap().0)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::mock::Memory;
+
+    #[test]
+    fn reads_tasks_and_their_stacked_registers() {
+        let mut ram = Memory::new();
+        // A Cortex-M0+, which has no CPACR to read.
+        ram.set_word(CPUID, 0x410c_c601);
+        ram.set_word(CPACR, CPACR_CP10_CP11);
+        let (running, idle) = (0x2000_0100, 0x2000_0200);
+        ram.set_word(0x2000_0000, running);
+
+        // Each ready list holds one task, linked through xStateListItem.
+        for &(list, tcb) in &[(0x2000_0010, idle), (0x2000_0024, running)] {
+            ram.set_word(list, 1);
+            ram.set_word(list + LIST_END_NEXT, tcb + 4);
+            ram.set_word(tcb + 4 + ITEM_NEXT, list + LIST_END);
+            ram.set_word(tcb + 4 + ITEM_OWNER, tcb);
+        }
+        ram.set_word(running + TCB_PRIORITY, 1);
+        ram.set_word(running + TCB_STACK, 0x2000_0500);
+        ram.set_word(running + TCB_NAME, u32::from_le_bytes(*b"main"));
+
+        ram.set_word(idle, 0x2000_0380);
+        ram.set_word(idle + TCB_STACK, 0x2000_0300);
+        ram.set_word(idle + TCB_NAME, u32::from_le_bytes(*b"IDLE"));
+        for addr in (0x2000_0300..0x2000_0380).step_by(4) {
+            ram.set_word(addr, 0xa5a5_a5a5);
+        }
+        // R4 to R11, then R0 to R3, R12, LR, PC and xPSR.
+        let stacked = [
+            4,
+            5,
+            6,
+            7,
+            8,
+            9,
+            10,
+            11,
+            0,
+            1,
+            2,
+            3,
+            12,
+            0x0800_0101,
+            0x0800_0200,
+            0x0100_0000,
+        ];
+        for (i, &value) in stacked.iter().enumerate() {
+            ram.set_word(0x2000_0380 + 4 * i as u32, value);
+        }
+
+        let rtos = FreeRtos {
+            current_tcb: 0x2000_0000,
+            ready_lists: 0x2000_0010,
+            priorities: 2,
+            lists: vec![],
+        };
+        let tasks = rtos.tasks(&mut ram, 0).unwrap();
+        assert_eq!(tasks.len(), 2);
+
+        assert_eq!(tasks[0].name, "IDLE");
+        assert_eq!(tasks[0].state, TaskState::Ready);
+        assert_eq!(tasks[0].stack_high_water_mark, 0x80);
+        assert_eq!(
+            tasks[0].registers,
+            Some([
+                0,
+                1,
+                2,
+                3,
+                4,
+                5,
+                6,
+                7,
+                8,
+                9,
+                10,
+                11,
+                12,
+                0x2000_03c0,
+                0x0800_0101,
+                0x0800_0200,
+                0x0100_0000
+            ])
+        );
+
+        assert_eq!(tasks[1].name, "main");
+        assert_eq!(tasks[1].state, TaskState::Running);
+        assert_eq!(tasks[1].priority, 1);
+        assert_eq!(tasks[1].registers, None);
+    }
+
+    #[test]
+    fn reads_registers_stacked_by_fpu_ports() {
+        let mut ram = Memory::new();
+        let registers = |sp, xpsr| {
+            Some([
+                0,
+                1,
+                2,
+                3,
+                4,
+                5,
+                6,
+                7,
+                8,
+                9,
+                10,
+                11,
+                12,
+                sp,
+                0x0800_0101,
+                0x0800_0200,
+                xpsr,
+            ])
+        };
+        let fill = |ram: &mut Memory, address: u32, words: &[u32]| {
+            for (i, &value) in words.iter().enumerate() {
+                ram.set_word(address + 4 * i as u32, value);
+            }
+        };
+        let saved = [4, 5, 6, 7, 8, 9, 10, 11];
+
+        // ARM_CM4F with a task which used the FPU: EXC_RETURN, S16 to S31, then an extended
+        // frame which was aligned by an extra word.
+        fill(&mut ram, 0x2000_0400, &saved);
+        ram.set_word(0x2000_0420, 0xffff_ffed);
+        fill(&mut ram, 0x2000_0424, &[0x3f80_0000; 16]);
+        fill(
+            &mut ram,
+            0x2000_0464,
+            &[0, 1, 2, 3, 12, 0x0800_0101, 0x0800_0200, 0x0100_0200],
+        );
+        let stacked = read_stacked_registers(&mut ram, 0, 0x2000_0400, true).ok();
+        assert_eq!(stacked, registers(0x2000_04d0, 0x0100_0200));
+
+        // ARM_CM4F with a task which never used the FPU: EXC_RETURN and a standard frame.
+        fill(&mut ram, 0x2000_0500, &saved);
+        ram.set_word(0x2000_0520, 0xffff_fffd);
+        fill(
+            &mut ram,
+            0x2000_0524,
+            &[0, 1, 2, 3, 12, 0x0800_0101, 0x0800_0200, 0x0100_0000],
+        );
+        let stacked = read_stacked_registers(&mut ram, 0, 0x2000_0500, true).ok();
+        assert_eq!(stacked, registers(0x2000_0544, 0x0100_0000));
+
+        // ARM_CM3 on a core which has the FPU enabled: R0 directly follows R11.
+        fill(&mut ram, 0x2000_0600, &saved);
+        fill(
+            &mut ram,
+            0x2000_0620,
+            &[0, 1, 2, 3, 12, 0x0800_0101, 0x0800_0200, 0x0100_0000],
+        );
+        let stacked = read_stacked_registers(&mut ram, 0, 0x2000_0600, true).ok();
+        assert_eq!(stacked, registers(0x2000_0640, 0x0100_0000));
+    }
+}
@@ -0,0 +1,373 @@
+use ssmarshal::deserialize;
+
+use crate::memory::MemoryAccess;
+use crate::stlink::{AccessPort, STLinkError};
+
+#[derive(Debug)]
+pub enum RttError {
+    STLink(STLinkError),
+    ControlBlockNotFound,
+    CorruptControlBlock,
+    NoSuchChannel(usize),
+}
+
+impl From<STLinkError> for RttError {
+    fn from(e: STLinkError) -> Self {
+        RttError::STLink(e)
+    }
+}
+
+/// An up (target to host) or down (host to target) RTT channel.
+#[derive(Debug, Clone)]
+pub struct RttChannel {
+    pub number: usize,
+    pub name: Option<String>,
+    /// Address of the buffer descriptor in the control block.
+    descriptor: u32,
+    buffer: u32,
+    pub size: u32,
+}
+
+/// A SEGGER RTT control block found in target RAM.
+#[derive(Debug)]
+pub struct Rtt {
+    pub address: u32,
+    apsel: AccessPort,
+    pub up_channels: Vec<RttChannel>,
+    pub down_channels: Vec<RttChannel>,
+}
+
+impl Rtt {
+    /// The ID at the start of every control block.
+    const ID: &'static [u8] = b"SEGGER RTT";
+
+    /// Size of the control block header: the 16 byte ID and the two channel counts.
+    const HEADER_SIZE: u32 = 24;
+
+    /// Size of a buffer descriptor: name, buffer, size, write offset, read offset and flags.
+    const DESCRIPTOR_SIZE: u32 = 24;
+    const WR_OFF: u32 = 12;
+    const RD_OFF: u32 = 16;
+
+    /// Sanity limit for the number of channels and the channel name length.
+    const MAX_CHANNELS: u32 = 32;
+    const MAX_NAME_LENGTH: u32 = 32;
+
+    /// Searches `size` bytes of RAM starting at `start` for the control block and attaches to it.
+    pub fn scan<M: MemoryAccess>(
+        mem: &mut M,
+        apsel: AccessPort,
+        start: u32,
+        size: u32,
+    ) -> Result<Self, RttError> {
+        // A range wrapping around the address space cannot hold the control block.
+        start
+            .checked_add(size)
+            .ok_or(RttError::ControlBlockNotFound)?;
+        let ram = read_bytes(mem, apsel, start, size)?;
+        let offset = ram
+            .windows(Self::ID.len())
+            .position(|window| window == Self::ID)
+            .ok_or(RttError::ControlBlockNotFound)?;
+        Self::attach(mem, apsel, start + offset as u32)
+    }
+
+    /// Attaches to the control block at `address`, e.g. the `_SEGGER_RTT` symbol.
+    pub fn attach<M: MemoryAccess>(
+        mem: &mut M,
+        apsel: AccessPort,
+        address: u32,
+    ) -> Result<Self, RttError> {
+        address
+            .checked_add(Self::HEADER_SIZE)
+            .ok_or(RttError::ControlBlockNotFound)?;
+        let header = read_bytes(mem, apsel, address, Self::HEADER_SIZE)?;
+        if !header.starts_with(Self::ID) {
+            return Err(RttError::ControlBlockNotFound);
+        }
+        let up_count = word(&header, 16);
+        let down_count = word(&header, 20);
+        if up_count > Self::MAX_CHANNELS || down_count > Self::MAX_CHANNELS {
+            return Err(RttError::CorruptControlBlock);
+        }
+
+        let size = (up_count + down_count) * Self::DESCRIPTOR_SIZE;
+        (address + Self::HEADER_SIZE)
+            .checked_add(size)
+            .ok_or(RttError::CorruptControlBlock)?;
+        let descriptors = read_bytes(mem, apsel, address + Self::HEADER_SIZE, size)?;
+        let mut channels = vec![];
+        for i in 0..(up_count + down_count) {
+            let offset = (i * Self::DESCRIPTOR_SIZE) as usize;
+            let name = word(&descriptors, offset);
+            let number = if i < up_count { i } else { i - up_count };
+            let buffer = word(&descriptors, offset + 4);
+            let size = word(&descriptors, offset + 8);
+            // The offsets into the buffer are only checked against its size.
+            buffer
+                .checked_add(size)
+                .ok_or(RttError::CorruptControlBlock)?;
+            channels.push(RttChannel {
+                number: number as usize,
+                // A name we fail to read is no reason to reject the channel.
+                name: if name == 0 {
+                    None
+                } else {
+                    read_string(mem, apsel, name, Self::MAX_NAME_LENGTH).unwrap_or(None)
+                },
+                descriptor: address + Self::HEADER_SIZE + offset as u32,
+                buffer,
+                size,
+            });
+        }
+        let down_channels = channels.split_off(up_count as usize);
+
+        Ok(Self {
+            address,
+            apsel,
+            up_channels: channels,
+            down_channels,
+        })
+    }
+
+    /// Reads all data available in an up channel and frees it in the target buffer.
+    pub fn read<M: MemoryAccess>(&self, mem: &mut M, channel: usize) -> Result<Vec<u8>, RttError> {
+        let channel = self
+            .up_channels
+            .get(channel)
+            .ok_or(RttError::NoSuchChannel(channel))?;
+        let offsets = read_bytes(mem, self.apsel, channel.descriptor + Self::WR_OFF, 8)?;
+        let write = word(&offsets, 0);
+        let read = word(&offsets, 4);
+        if write >= channel.size || read >= channel.size {
+            return Err(RttError::CorruptControlBlock);
+        }
+        if write == read {
+            return Ok(vec![]);
+        }
+
+        // The data wraps around at the end of the buffer.
+        let mut data = if write > read {
+            read_bytes(mem, self.apsel, channel.buffer + read, write - read)?
+        } else {
+            read_bytes(mem, self.apsel, channel.buffer + read, channel.size - read)?
+        };
+        if write < read && write > 0 {
+            data.extend(read_bytes(mem, self.apsel, channel.buffer, write)?);
+        }
+
+        mem.write_word32(channel.descriptor + Self::RD_OFF, write, self.apsel)?;
+        Ok(data)
+    }
+
+    /// Writes as much of `data` into a down channel as fits in its buffer.
+    /// Returns the number of bytes written.
+    pub fn write<M: MemoryAccess>(
+        &self,
+        mem: &mut M,
+        channel: usize,
+        data: &[u8],
+    ) -> Result<usize, RttError> {
+        let channel = self
+            .down_channels
+            .get(channel)
+            .ok_or(RttError::NoSuchChannel(channel))?;
+        let offsets = read_bytes(mem, self.apsel, channel.descriptor + Self::WR_OFF, 8)?;
+        let write = word(&offsets, 0);
+        let read = word(&offsets, 4);
+        if write >= channel.size || read >= channel.size {
+            return Err(RttError::CorruptControlBlock);
+        }
+
+        // One byte always stays free to tell a full buffer from an empty one.
+        let free = if read > write {
+            read - write - 1
+        } else {
+            channel.size - write + read - 1
+        };
+        let count = u32::min(free, data.len() as u32);
+        if count == 0 {
+            return Ok(0);
+        }
+
+        let first = u32::min(count, channel.size - write);
+        write_bytes(
+            mem,
+            self.apsel,
+            channel.buffer + write,
+            &data[..first as usize],
+        )?;
+        if count > first {
+            write_bytes(
+                mem,
+                self.apsel,
+                channel.buffer,
+                &data[first as usize..count as usize],
+            )?;
+        }
+
+        mem.write_word32(
+            channel.descriptor + Self::WR_OFF,
+            (write + count) % channel.size,
+            self.apsel,
+        )?;
+        Ok(count as usize)
+    }
+}
+
+/// Reads `size` bytes at an arbitrary address through word accesses.
+fn read_bytes<M: MemoryAccess>(
+    mem: &mut M,
+    apsel: AccessPort,
+    addr: u32,
+    size: u32,
+) -> Result<Vec<u8>, STLinkError> {
+    if size == 0 {
+        return Ok(vec![]);
+    }
+    let start = addr & !0x3;
+    // Rounding up may reach past the top of the address space.
+    let end = (addr as u64 + size as u64 + 3) & !0x3;
+    let data = mem.read_mem32(start, (end - start as u64) as u32, apsel)?;
+    let offset = (addr - start) as usize;
+    data.get(offset..offset + size as usize)
+        .map(|data| data.to_vec())
+        .ok_or(STLinkError::NotEnoughBytesRead)
+}
+
+/// Writes `data` to an arbitrary address.
+/// Only the unaligned ends use 8-bit accesses, which the probe limits to 64 bytes each.
+fn write_bytes<M: MemoryAccess>(
+    mem: &mut M,
+    apsel: AccessPort,
+    addr: u32,
+    data: &[u8],
+) -> Result<(), STLinkError> {
+    let head = usize::min(((4 - addr % 4) % 4) as usize, data.len());
+    let body = (data.len() - head) & !0x3;
+    if head > 0 {
+        mem.write_mem8(addr, data[..head].to_vec(), apsel)?;
+    }
+    if body > 0 {
+        mem.write_mem32(addr + head as u32, data[head..head + body].to_vec(), apsel)?;
+    }
+    if head + body < data.len() {
+        mem.write_mem8(
+            addr + (head + body) as u32,
+            data[head + body..].to_vec(),
+            apsel,
+        )?;
+    }
+    Ok(())
+}
+
+/// Reads a zero terminated string of at most `max` bytes.
+fn read_string<M: MemoryAccess>(
+    mem: &mut M,
+    apsel: AccessPort,
+    addr: u32,
+    max: u32,
+) -> Result<Option<String>, STLinkError> {
+    let max = u64::min(max as u64, (1 << 32) - addr as u64) as u32;
+    let data = read_bytes(mem, apsel, addr, max)?;
+    let end = data
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(data.len());
+    Ok(String::from_utf8(data[..end].to_vec()).ok())
+}
+
+fn word(data: &[u8], offset: usize) -> u32 {
+    // Unwrap is ok!
+    deserialize(&data[offset..offset + 4]).unwrap().0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::mock::Memory;
+
+    const UP_BUFFER: u32 = 0x2000_0100;
+    const DOWN_BUFFER: u32 = 0x2000_0200;
+
+    /// A control block at 0x2000_0000 with one up and one down channel.
+    fn control_block(size: u32) -> Memory {
+        let mut mem = Memory::new();
+        mem.write(0x2000_0000, b"SEGGER RTT");
+        mem.set_word(0x2000_0010, 1);
+        mem.set_word(0x2000_0014, 1);
+        mem.set_word(0x2000_0018 + 4, UP_BUFFER);
+        mem.set_word(0x2000_0018 + 8, size);
+        mem.set_word(0x2000_0030 + 4, DOWN_BUFFER);
+        mem.set_word(0x2000_0030 + 8, size);
+        mem
+    }
+
+    #[test]
+    fn reads_wrapped_up_data() {
+        let mut mem = control_block(16);
+        mem.write(UP_BUFFER + 12, b"abcd");
+        mem.write(UP_BUFFER, b"efgh");
+        mem.set_word(0x2000_0018 + Rtt::WR_OFF, 4);
+        mem.set_word(0x2000_0018 + Rtt::RD_OFF, 12);
+
+        let rtt = Rtt::scan(&mut mem, 0, 0x2000_0000, 0x100).unwrap();
+        assert_eq!(rtt.read(&mut mem, 0).unwrap(), b"abcdefgh");
+        assert_eq!(mem.word(0x2000_0018 + Rtt::RD_OFF), 4);
+        assert_eq!(rtt.read(&mut mem, 0).unwrap(), b"");
+    }
+
+    #[test]
+    fn rejects_buffers_past_the_address_space() {
+        let mut mem = control_block(0x200);
+        mem.set_word(0x2000_0018 + 4, 0xffff_ff00);
+        match Rtt::attach(&mut mem, 0, 0x2000_0000) {
+            Err(RttError::CorruptControlBlock) => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+
+        for &(start, size) in &[(0xffff_ff00, 0x200), (0xffff_fff0, 0x10)] {
+            match Rtt::scan(&mut mem, 0, start, size) {
+                Err(RttError::ControlBlockNotFound) => (),
+                result => panic!("unexpected result {:?}", result),
+            }
+        }
+    }
+
+    #[test]
+    fn writes_wrapped_down_data() {
+        let mut mem = control_block(16);
+        mem.set_word(0x2000_0030 + Rtt::WR_OFF, 13);
+        mem.set_word(0x2000_0030 + Rtt::RD_OFF, 5);
+
+        let rtt = Rtt::attach(&mut mem, 0, 0x2000_0000).unwrap();
+        // Only 7 bytes are free, one has to stay empty.
+        assert_eq!(rtt.write(&mut mem, 0, b"0123456789").unwrap(), 7);
+        assert_eq!(mem.read(DOWN_BUFFER + 13, 3), b"012");
+        assert_eq!(mem.read(DOWN_BUFFER, 5), b"3456\0");
+        assert_eq!(mem.word(0x2000_0030 + Rtt::WR_OFF), 4);
+        assert_eq!(rtt.write(&mut mem, 0, b"789").unwrap(), 0);
+    }
+
+    #[test]
+    fn writes_large_blocks_with_word_accesses() {
+        let mut mem = control_block(256);
+        mem.set_word(0x2000_0030 + Rtt::WR_OFF, 1);
+        mem.set_word(0x2000_0030 + Rtt::RD_OFF, 1);
+        mem.writes.clear();
+
+        let rtt = Rtt::attach(&mut mem, 0, 0x2000_0000).unwrap();
+        let data: Vec<u8> = (0..200).collect();
+        assert_eq!(rtt.write(&mut mem, 0, &data).unwrap(), 200);
+        assert_eq!(mem.read(DOWN_BUFFER + 1, 200), data);
+        assert_eq!(
+            mem.writes,
+            [
+                (8, DOWN_BUFFER + 1, 3),
+                (32, DOWN_BUFFER + 4, 196),
+                (8, DOWN_BUFFER + 200, 1),
+                (32, 0x2000_0030 + Rtt::WR_OFF, 4),
+            ]
+        );
+    }
+}
@@ -0,0 +1,435 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use ssmarshal::deserialize;
+
+use crate::cortex_m::{registers, Core, HaltReason};
+use crate::memory::MemoryAccess;
+use crate::stlink::STLinkError;
+
+/// The Thumb encoding of `BKPT 0xAB`, which requests a semihosting operation.
+const BKPT_SEMIHOSTING: u16 = 0xbeab;
+
+const SYS_OPEN: u32 = 0x01;
+const SYS_CLOSE: u32 = 0x02;
+const SYS_WRITEC: u32 = 0x03;
+const SYS_WRITE0: u32 = 0x04;
+const SYS_WRITE: u32 = 0x05;
+const SYS_READ: u32 = 0x06;
+const SYS_CLOCK: u32 = 0x10;
+const SYS_TIME: u32 = 0x11;
+const SYS_EXIT: u32 = 0x18;
+const SYS_EXIT_EXTENDED: u32 = 0x20;
+
+/// The exit reason of a regular application exit.
+const ADP_STOPPED_APPLICATION_EXIT: u32 = 0x2_0026;
+
+/// Upper bound for strings read from the target, to not read memory forever.
+const MAX_STRING_LENGTH: u32 = 4096;
+
+/// The value returned to the target when an operation failed.
+const FAILURE: u32 = 0xffff_ffff;
+
+/// What happened when a halted core was checked for a semihosting request.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SemihostingOutcome {
+    /// The core did not halt on a semihosting breakpoint and was left halted.
+    Halted(HaltReason),
+    /// The request was serviced and the core runs again.
+    Resumed,
+    /// The target exited with the given status code. The core was left halted.
+    Exited(i32),
+}
+
+enum Handle {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+}
+
+/// Services the semihosting requests of a target on the host.
+///
+/// Files can only be opened inside the root directory,
+/// the special file `:tt` maps to the console.
+pub struct Semihosting {
+    root: PathBuf,
+    handles: HashMap<u32, Handle>,
+    next_handle: u32,
+    start: Instant,
+}
+
+impl Semihosting {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self {
+            root: root.into(),
+            handles: HashMap::new(),
+            next_handle: 1,
+            start: Instant::now(),
+        }
+    }
+
+    /// Checks whether the halted core stopped on `BKPT 0xAB`.
+    /// If so, the request is serviced, the result written to R0 and the core resumed past the breakpoint.
+    pub fn handle<M: MemoryAccess>(
+        &mut self,
+        mem: &mut M,
+        core: &Core,
+    ) -> Result<SemihostingOutcome, STLinkError> {
+        let reason = core.halt_reason(mem)?;
+        if reason != HaltReason::Breakpoint {
+            return Ok(SemihostingOutcome::Halted(reason));
+        }
+        let pc = core.read_register(mem, registers::PC)?;
+        let instruction = mem.read_mem8(pc & !0x1, 2, core.apsel)?;
+        if u16::from(instruction[0]) | u16::from(instruction[1]) << 8 != BKPT_SEMIHOSTING {
+            return Ok(SemihostingOutcome::Halted(reason));
+        }
+
+        let operation = core.read_register(mem, registers::R0)?;
+        let parameter = core.read_register(mem, registers::R1)?;
+        let result = match operation {
+            SYS_EXIT => {
+                // On 32 bit targets, the parameter is the reason itself.
+                let code = if parameter == ADP_STOPPED_APPLICATION_EXIT {
+                    0
+                } else {
+                    1
+                };
+                return Ok(SemihostingOutcome::Exited(code));
+            }
+            SYS_EXIT_EXTENDED => {
+                let block = read_block(mem, core, parameter, 2)?;
+                let code = if block[0] == ADP_STOPPED_APPLICATION_EXIT {
+                    block[1] as i32
+                } else {
+                    1
+                };
+                return Ok(SemihostingOutcome::Exited(code));
+            }
+            _ => self.service(mem, core, operation, parameter)?,
+        };
+
+        core.write_register(mem, registers::R0, result)?;
+        core.write_register(mem, registers::PC, pc + 2)?;
+        core.run(mem)?;
+        Ok(SemihostingOutcome::Resumed)
+    }
+
+    /// Performs an operation which does not end the session and returns its result.
+    fn service<M: MemoryAccess>(
+        &mut self,
+        mem: &mut M,
+        core: &Core,
+        operation: u32,
+        parameter: u32,
+    ) -> Result<u32, STLinkError> {
+        Ok(match operation {
+            SYS_WRITEC => {
+                let c = mem.read_mem8(parameter, 1, core.apsel)?;
+                console_write(&mut std::io::stdout(), &c);
+                0
+            }
+            SYS_WRITE0 => {
+                let string = read_string(mem, core, parameter, MAX_STRING_LENGTH)?;
+                console_write(&mut std::io::stdout(), &string);
+                0
+            }
+            SYS_OPEN => {
+                let block = read_block(mem, core, parameter, 3)?;
+                let name = read_string(mem, core, block[0], block[2])?;
+                let name = String::from_utf8_lossy(&name).into_owned();
+                match self.open(&name, block[1]) {
+                    Some(handle) => {
+                        let number = self.next_handle;
+                        self.next_handle += 1;
+                        self.handles.insert(number, handle);
+                        number
+                    }
+                    None => FAILURE,
+                }
+            }
+            SYS_CLOSE => {
+                let block = read_block(mem, core, parameter, 1)?;
+                match self.handles.remove(&block[0]) {
+                    Some(_) => 0,
+                    None => FAILURE,
+                }
+            }
+            SYS_WRITE => {
+                let block = read_block(mem, core, parameter, 3)?;
+                let data = if block[2] > 0 {
+                    mem.read_mem8(block[1], block[2], core.apsel)?
+                } else {
+                    vec![]
+                };
+                // Returns the number of bytes which were not written.
+                let written = match self.handles.get_mut(&block[0]) {
+                    Some(Handle::Stdout) => console_write(&mut std::io::stdout(), &data),
+                    Some(Handle::Stderr) => console_write(&mut std::io::stderr(), &data),
+                    Some(Handle::File(file)) => file.write_all(&data).is_ok(),
+                    _ => false,
+                };
+                if written {
+                    0
+                } else {
+                    block[2]
+                }
+            }
+            SYS_READ => {
+                let block = read_block(mem, core, parameter, 3)?;
+                let mut data = vec![0; block[2] as usize];
+                let count = match self.handles.get_mut(&block[0]) {
+                    Some(Handle::Stdin) => std::io::stdin().read(&mut data).ok(),
+                    Some(Handle::File(file)) => file.read(&mut data).ok(),
+                    _ => None,
+                };
+                match count {
+                    Some(count) => {
+                        if count > 0 {
+                            mem.write_mem8(block[1], data[..count].to_vec(), core.apsel)?;
+                        }
+                        // Returns the number of bytes which were not read.
+                        block[2] - count as u32
+                    }
+                    None => FAILURE,
+                }
+            }
+            SYS_CLOCK => (self.start.elapsed().as_millis() / 10) as u32,
+            SYS_TIME => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|time| time.as_secs() as u32)
+                .unwrap_or(0),
+            _ => FAILURE,
+        })
+    }
+
+    /// Opens a file with one of the `fopen` modes, numbered `r`, `rb`, `r+`, `r+b`, `w`, ... `a+b`.
+    fn open(&self, name: &str, mode: u32) -> Option<Handle> {
+        if name == ":tt" {
+            return match mode {
+                0..=3 => Some(Handle::Stdin),
+                4..=7 => Some(Handle::Stdout),
+                8..=11 => Some(Handle::Stderr),
+                _ => None,
+            };
+        }
+
+        let path = sandboxed(&self.root, name)?;
+        let mut options = OpenOptions::new();
+        match mode {
+            0..=1 => options.read(true),
+            2..=3 => options.read(true).write(true),
+            4..=5 => options.write(true).create(true).truncate(true),
+            6..=7 => options.read(true).write(true).create(true).truncate(true),
+            8..=9 => options.append(true).create(true),
+            10..=11 => options.read(true).append(true).create(true),
+            _ => return None,
+        };
+        options.open(path).ok().map(Handle::File)
+    }
+}
+
+/// Resolves a path requested by the target inside the root directory.
+/// Returns `None` for absolute paths and paths leaving the root.
+fn sandboxed(root: &Path, name: &str) -> Option<PathBuf> {
+    let mut path = root.to_path_buf();
+    for component in Path::new(name).components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::CurDir => (),
+            _ => return None,
+        }
+    }
+    Some(path)
+}
+
+fn console_write<W: Write>(w: &mut W, data: &[u8]) -> bool {
+    w.write_all(data).and_then(|_| w.flush()).is_ok()
+}
+
+/// Reads the parameter block of an operation.
+fn read_block<M: MemoryAccess>(
+    mem: &mut M,
+    core: &Core,
+    addr: u32,
+    words: u32,
+) -> Result<Vec<u32>, STLinkError> {
+    let data = mem.read_mem32(addr, words * 4, core.apsel)?;
+    // Unwrap is ok!
+    Ok(data
+        .chunks(4)
+        .map(|word| deserialize(word).unwrap().0)
+        .collect())
+}
+
+/// Reads a zero terminated string of at most `max` bytes.
+fn read_string<M: MemoryAccess>(
+    mem: &mut M,
+    core: &Core,
+    addr: u32,
+    max: u32,
+) -> Result<Vec<u8>, STLinkError> {
+    const CHUNK: u32 = 64;
+
+    let mut string = vec![];
+    while (string.len() as u32) < max {
+        let size = u32::min(CHUNK, max - string.len() as u32);
+        let chunk = mem.read_mem8(addr + string.len() as u32, size, core.apsel)?;
+        match chunk.iter().position(|&byte| byte == 0) {
+            Some(end) => {
+                string.extend_from_slice(&chunk[..end]);
+                break;
+            }
+            None => string.extend_from_slice(&chunk),
+        }
+    }
+    Ok(string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cortex_m::mock::Target;
+    use crate::cortex_m::{DFSR, DFSR_BKPT};
+
+    const PC: u32 = 0x0800_0100;
+    const BLOCK: u32 = 0x2000_0000;
+    const BUFFER: u32 = 0x2000_0100;
+    const NAME: u32 = 0x2000_0200;
+
+    /// Halts the target on `BKPT 0xAB` with `operation` and its parameter block and services it.
+    fn request(
+        host: &mut Semihosting,
+        target: &mut Target,
+        operation: u32,
+        block: &[u32],
+    ) -> SemihostingOutcome {
+        target.memory.write(PC, &BKPT_SEMIHOSTING.to_le_bytes());
+        target.memory.set_word(DFSR, DFSR_BKPT);
+        for (i, &word) in block.iter().enumerate() {
+            target.memory.set_word(BLOCK + 4 * i as u32, word);
+        }
+        target.registers[registers::R0 as usize] = operation;
+        target.registers[registers::R1 as usize] = BLOCK;
+        target.registers[registers::PC as usize] = PC;
+        host.handle(target, &Core::new(0)).unwrap()
+    }
+
+    #[test]
+    fn leaves_other_halts_alone() {
+        let mut host = Semihosting::new("/tmp");
+        let mut target = Target::new();
+        target.memory.write(PC, &[0x00, 0xbe]);
+        target.memory.set_word(DFSR, DFSR_BKPT);
+        target.registers[registers::PC as usize] = PC;
+        assert_eq!(
+            host.handle(&mut target, &Core::new(0)).unwrap(),
+            SemihostingOutcome::Halted(HaltReason::Breakpoint)
+        );
+        // The reason was cleared.
+        assert_eq!(
+            host.handle(&mut target, &Core::new(0)).unwrap(),
+            SemihostingOutcome::Halted(HaltReason::Unknown)
+        );
+        assert_eq!(target.resumed, 0);
+    }
+
+    #[test]
+    fn writes_and_reads_files() {
+        let root = std::env::temp_dir().join(format!("semihosting-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let mut host = Semihosting::new(&root);
+        let mut target = Target::new();
+        target.memory.write(NAME, b"out.txt\0");
+
+        // Opened for writing with mode "w".
+        let outcome = request(&mut host, &mut target, SYS_OPEN, &[NAME, 4, 7]);
+        assert_eq!(outcome, SemihostingOutcome::Resumed);
+        let handle = target.registers[0];
+        assert_ne!(handle, FAILURE);
+        // The core resumes past the breakpoint.
+        assert_eq!(target.registers[registers::PC as usize], PC + 2);
+        assert_eq!(target.resumed, 1);
+
+        target.memory.write(BUFFER, b"hello");
+        request(&mut host, &mut target, SYS_WRITE, &[handle, BUFFER, 5]);
+        assert_eq!(target.registers[0], 0);
+        request(&mut host, &mut target, SYS_CLOSE, &[handle]);
+        assert_eq!(target.registers[0], 0);
+        assert_eq!(std::fs::read(root.join("out.txt")).unwrap(), b"hello");
+
+        // Writing to a closed handle transfers nothing.
+        request(&mut host, &mut target, SYS_WRITE, &[handle, BUFFER, 5]);
+        assert_eq!(target.registers[0], 5);
+
+        // Reading returns the number of bytes which were not read.
+        request(&mut host, &mut target, SYS_OPEN, &[NAME, 0, 7]);
+        let handle = target.registers[0];
+        request(
+            &mut host,
+            &mut target,
+            SYS_READ,
+            &[handle, BUFFER + 0x10, 8],
+        );
+        assert_eq!(target.registers[0], 3);
+        assert_eq!(target.memory.read(BUFFER + 0x10, 8), b"hello\0\0\0");
+        request(
+            &mut host,
+            &mut target,
+            SYS_READ,
+            &[handle, BUFFER + 0x10, 8],
+        );
+        assert_eq!(target.registers[0], 8);
+
+        // Paths leaving the root cannot be opened.
+        target.memory.write(NAME, b"../out.txt\0");
+        request(&mut host, &mut target, SYS_OPEN, &[NAME, 4, 10]);
+        assert_eq!(target.registers[0], FAILURE);
+        assert_eq!(target.resumed, 8);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn reports_exit_codes() {
+        let mut host = Semihosting::new("/tmp");
+        let mut target = Target::new();
+        // SYS_EXIT passes the reason itself rather than a parameter block.
+        target.memory.write(PC, &BKPT_SEMIHOSTING.to_le_bytes());
+        target.registers[registers::PC as usize] = PC;
+        for &(reason, code) in &[(ADP_STOPPED_APPLICATION_EXIT, 0), (0x2_0023, 1)] {
+            target.memory.set_word(DFSR, DFSR_BKPT);
+            target.registers[registers::R0 as usize] = SYS_EXIT;
+            target.registers[registers::R1 as usize] = reason;
+            assert_eq!(
+                host.handle(&mut target, &Core::new(0)).unwrap(),
+                SemihostingOutcome::Exited(code)
+            );
+        }
+
+        let block = [ADP_STOPPED_APPLICATION_EXIT, 3];
+        let outcome = request(&mut host, &mut target, SYS_EXIT_EXTENDED, &block);
+        assert_eq!(outcome, SemihostingOutcome::Exited(3));
+        let outcome = request(&mut host, &mut target, SYS_EXIT_EXTENDED, &[0x2_0023, 3]);
+        assert_eq!(outcome, SemihostingOutcome::Exited(1));
+
+        // The core stays halted on the breakpoint.
+        assert_eq!(target.registers[registers::PC as usize], PC);
+        assert_eq!(target.resumed, 0);
+    }
+
+    #[test]
+    fn keeps_files_inside_the_root() {
+        let root = Path::new("/tmp/root");
+        assert_eq!(
+            sandboxed(root, "./logs/out.txt"),
+            Some(root.join("logs/out.txt"))
+        );
+        assert_eq!(sandboxed(root, "../secret"), None);
+        assert_eq!(sandboxed(root, "/etc/passwd"), None);
+    }
+}
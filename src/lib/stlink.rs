@@ -5,15 +5,17 @@ use probe_rs::debug_probe::DebugProbe;
 use probe_rs::protocol::WireProtocol;
 
 use crate::constants::{commands, JTagFrequencyToDivider, Status, SwdFrequencyToDelayCount};
+use crate::swv::swo_divider;
 use crate::usb_interface::{STLinkUSBDevice, TIMEOUT};
 
-type AccessPort = u8;
+pub type AccessPort = u8;
 
 pub struct STLink<'a> {
-    device: STLinkUSBDevice<'a>,
+    pub(crate) device: STLinkUSBDevice<'a>,
     hw_version: u8,
     jtag_version: u8,
     protocol: WireProtocol,
+    swv_running: bool,
 }
 
 #[derive(Debug)]
@@ -32,6 +34,11 @@ pub enum STLinkError {
     RegisterAddressMustBe16Bit,
     NotEnoughBytesRead,
     EndpointNotFound,
+    SwimBusyTimeout,
+    SwimError(u8),
+    SwimNoBuffer,
+    SwvBaudRateNotSupported,
+    CoreTimeout,
 }
 
 pub trait ToSTLinkErr<T> {
@@ -60,6 +67,9 @@ impl<'a> DebugProbe for STLink<'a> {
 
     /// Closes the ST-Link USB device.
     fn close(&mut self) -> Result<(), Self::Error> {
+        if self.swv_running {
+            self.stop_swv()?;
+        }
         self.enter_idle()?;
         self.device.close().or_else(|e| Err(STLinkError::USB(e)))
     }
@@ -236,12 +246,36 @@ impl<'a> STLink<'a> {
     /// Port number to use to indicate DP registers.
     const DP_PORT: u16 = 0xffff;
 
+    /// Size of the trace buffer of the ST-Link in bytes.
+    pub const SWV_BUFFER_SIZE: u16 = 4096;
+
+    /// Maximum SWO baud rates the ST-Link V2 and V3 can receive.
+    const SWV_MAX_BAUD_V2: u32 = 2_000_000;
+    const SWV_MAX_BAUD_V3: u32 = 24_000_000;
+
+    /// DP register addresses.
+    const DP_ABORT: u32 = 0x0;
+    const DP_CTRL_STAT: u32 = 0x4;
+
+    /// Bits of the SW-DP ABORT register that clear the sticky flags.
+    const ABORT_STKCMPCLR: u32 = 0x02;
+    const ABORT_STKERRCLR: u32 = 0x04;
+    const ABORT_WDERRCLR: u32 = 0x08;
+    const ABORT_ORUNERRCLR: u32 = 0x10;
+
+    /// Sticky flags of the CTRL/STAT register.
+    /// On a JTAG-DP these are cleared by writing a one to them.
+    const CTRLSTAT_STICKYORUN: u32 = 0x02;
+    const CTRLSTAT_STICKYCMP: u32 = 0x10;
+    const CTRLSTAT_STICKYERR: u32 = 0x20;
+
     pub fn new(device: STLinkUSBDevice<'a>) -> Self {
         Self {
             device,
             hw_version: 0,
             jtag_version: 0,
             protocol: WireProtocol::Swd,
+            swv_running: false,
         }
     }
 
@@ -312,12 +346,14 @@ impl<'a> STLink<'a> {
         &mut self,
         frequency: SwdFrequencyToDelayCount,
     ) -> Result<(), STLinkError> {
+        let frequency = frequency as u16;
         let mut buf = [0; 2];
         self.device.write(
             vec![
                 commands::JTAG_COMMAND,
                 commands::SWD_SET_FREQ,
                 frequency as u8,
+                (frequency >> 8) as u8,
             ],
             &[],
             &mut buf,
@@ -331,12 +367,14 @@ impl<'a> STLink<'a> {
         &mut self,
         frequency: JTagFrequencyToDivider,
     ) -> Result<(), STLinkError> {
+        let frequency = frequency as u16;
         let mut buf = [0; 2];
         self.device.write(
             vec![
                 commands::JTAG_COMMAND,
                 commands::JTAG_SET_FREQ,
                 frequency as u8,
+                (frequency >> 8) as u8,
             ],
             &[],
             &mut buf,
@@ -345,6 +383,47 @@ impl<'a> STLink<'a> {
         Self::check_status(&buf)
     }
 
+    /// Sets the fastest clock of the wire protocol in use not above `hz`.
+    /// Returns the frequency it runs at.
+    pub fn set_frequency(&mut self, hz: u32) -> Result<u32, STLinkError> {
+        match self.protocol {
+            WireProtocol::Swd => {
+                use SwdFrequencyToDelayCount::*;
+                let (frequency, actual) = match hz {
+                    4_600_000..=std::u32::MAX => (Hz4600000, 4_600_000),
+                    1_800_000..=4_599_999 => (Hz1800000, 1_800_000),
+                    1_200_000..=1_799_999 => (Hz1200000, 1_200_000),
+                    950_000..=1_199_999 => (Hz950000, 950_000),
+                    650_000..=949_999 => (Hz650000, 650_000),
+                    480_000..=649_999 => (Hz480000, 480_000),
+                    400_000..=479_999 => (Hz400000, 400_000),
+                    360_000..=399_999 => (Hz360000, 360_000),
+                    240_000..=359_999 => (Hz240000, 240_000),
+                    150_000..=239_999 => (Hz150000, 150_000),
+                    125_000..=149_999 => (Hz125000, 125_000),
+                    _ => (Hz100000, 100_000),
+                };
+                self.set_swd_frequency(frequency)?;
+                Ok(actual)
+            }
+            WireProtocol::Jtag => {
+                use JTagFrequencyToDivider::*;
+                let (frequency, actual) = match hz {
+                    18_000_000..=std::u32::MAX => (Hz18000000, 18_000_000),
+                    9_000_000..=17_999_999 => (Hz9000000, 9_000_000),
+                    4_500_000..=8_999_999 => (Hz4500000, 4_500_000),
+                    2_250_000..=4_499_999 => (Hz2250000, 2_250_000),
+                    1_120_000..=2_249_999 => (Hz1120000, 1_120_000),
+                    560_000..=1_119_999 => (Hz560000, 560_000),
+                    280_000..=559_999 => (Hz280000, 280_000),
+                    _ => (Hz140000, 140_000),
+                };
+                self.set_jtag_frequency(frequency)?;
+                Ok(actual)
+            }
+        }
+    }
+
     pub fn open_ap(&mut self, apsel: AccessPort) -> Result<(), STLinkError> {
         if self.jtag_version < Self::MIN_JTAG_VERSION_MULTI_AP {
             return Err(STLinkError::JTagDoesNotSupportMultipleAP);
@@ -396,6 +475,79 @@ impl<'a> STLink<'a> {
         return Self::check_status(&buf);
     }
 
+    /// Starts the reception of SWV trace data at the SWO baud rate closest to `baud`.
+    /// `trace_clk` is the clock feeding the TPIU of the target, which can only generate
+    /// integer fractions of it. Returns the baud rate the reception runs at.
+    pub fn start_swv(&mut self, baud: u32, trace_clk: u32) -> Result<u32, STLinkError> {
+        let max_baud = if self.hw_version >= 3 {
+            Self::SWV_MAX_BAUD_V3
+        } else {
+            Self::SWV_MAX_BAUD_V2
+        };
+        let baud = match swo_divider(trace_clk, baud) {
+            Some(divider) if trace_clk / divider <= max_baud => trace_clk / divider,
+            _ => return Err(STLinkError::SwvBaudRateNotSupported),
+        };
+
+        if self.swv_running {
+            self.stop_swv()?;
+        }
+
+        let mut buf = [0; 2];
+        self.device.write(
+            vec![
+                commands::JTAG_COMMAND,
+                commands::SWV_START_TRACE_RECEPTION,
+                Self::SWV_BUFFER_SIZE as u8,
+                (Self::SWV_BUFFER_SIZE >> 8) as u8,
+                baud as u8,
+                (baud >> 8) as u8,
+                (baud >> 16) as u8,
+                (baud >> 24) as u8,
+            ],
+            &[],
+            &mut buf,
+            TIMEOUT,
+        )?;
+        Self::check_status(&buf)?;
+        self.swv_running = true;
+        Ok(baud)
+    }
+
+    /// Stops the reception of SWV trace data.
+    pub fn stop_swv(&mut self) -> Result<(), STLinkError> {
+        let mut buf = [0; 2];
+        self.device.write(
+            vec![commands::JTAG_COMMAND, commands::SWV_STOP_TRACE_RECEPTION],
+            &[],
+            &mut buf,
+            TIMEOUT,
+        )?;
+        self.swv_running = false;
+        Self::check_status(&buf)
+    }
+
+    /// Returns the number of SWV bytes buffered in the ST-Link.
+    pub fn swv_bytes_available(&mut self) -> Result<u16, STLinkError> {
+        let mut buf = [0; 2];
+        self.device.write(
+            vec![
+                commands::JTAG_COMMAND,
+                commands::SWV_GET_TRACE_NEW_RECORD_NB,
+            ],
+            &[],
+            &mut buf,
+            TIMEOUT,
+        )?;
+        // Unwrap is ok!
+        Ok(deserialize(&buf[0..2]).unwrap().0)
+    }
+
+    /// Reads `size` buffered bytes from the SWV endpoint.
+    pub fn read_swv(&mut self, size: usize) -> Result<Vec<u8>, STLinkError> {
+        self.device.read_swv(size, TIMEOUT)
+    }
+
     /// Validates the status given.
     /// Returns an `Err(STLinkError::UnknownError)` if the status is not `Status::JtagOk`.
     /// Returns Ok(()) otherwise.
@@ -408,13 +560,47 @@ impl<'a> STLink<'a> {
         }
     }
 
+    /// Clears the sticky error flags of the DP.
+    /// A SW-DP clears them through the ABORT register, whereas a JTAG-DP
+    /// needs the flags written back to CTRL/STAT.
     pub fn clear_sticky_error(&mut self) -> Result<(), STLinkError> {
-        // TODO: Implement this as soon as CoreSight is implemented
-        // match self.protocol {
-        //     WireProtocol::Jtag => self.write_dap_register(Self::DP_PORT, dap.DP_CTRL_STAT, dap.CTRLSTAT_STICKYERR),
-        //     WireProtocol::Swd => self.write_dap_register(Self::DP_PORT, dap.DP_ABORT, dap.ABORT_STKERRCLR)
-        // }
-        Ok(())
+        match self.protocol {
+            WireProtocol::Jtag => {
+                // Keep the power up requests intact while writing the flags back.
+                let ctrl_stat = self.read_register(Self::DP_PORT, Self::DP_CTRL_STAT)?;
+                self.write_register(
+                    Self::DP_PORT,
+                    Self::DP_CTRL_STAT,
+                    ctrl_stat
+                        | Self::CTRLSTAT_STICKYERR
+                        | Self::CTRLSTAT_STICKYCMP
+                        | Self::CTRLSTAT_STICKYORUN,
+                )
+            }
+            WireProtocol::Swd => self.write_register(
+                Self::DP_PORT,
+                Self::DP_ABORT,
+                Self::ABORT_STKERRCLR
+                    | Self::ABORT_STKCMPCLR
+                    | Self::ABORT_WDERRCLR
+                    | Self::ABORT_ORUNERRCLR,
+            ),
+        }
+    }
+
+    /// Reads the IDCODE of the attached target.
+    /// For SWD this is the DPIDR, for JTAG the IDCODE scanned out of the TAP.
+    pub fn read_idcode(&mut self) -> Result<u32, STLinkError> {
+        let mut buf = [0; 12];
+        self.device.write(
+            vec![commands::JTAG_COMMAND, commands::JTAG_READ_IDCODES],
+            &[],
+            &mut buf,
+            TIMEOUT,
+        )?;
+        Self::check_status(&buf)?;
+        // Unwrap is ok!
+        Ok(deserialize(&buf[4..8]).unwrap().0)
     }
 
     pub fn read_mem(
@@ -440,7 +626,7 @@ impl<'a> STLink<'a> {
                 (transfer_size >> 8) as u8,
                 apsel,
             ];
-            let mut buf = Vec::with_capacity(transfer_size as usize);
+            let mut buf = vec![0; transfer_size as usize];
             self.device.write(cmd, &[], buf.as_mut_slice(), TIMEOUT)?;
             result.extend(buf.into_iter());
 
@@ -464,9 +650,9 @@ impl<'a> STLink<'a> {
             } else if status == Status::SwdDpFault as u16 {
                 true
             } else if status == Status::JtagOk as u16 {
-                return Err(STLinkError::UnknownError);
-            } else {
                 false
+            } else {
+                return Err(STLinkError::UnknownError);
             } {
                 self.clear_sticky_error().ok();
                 return Err(STLinkError::TransferFault(
@@ -523,9 +709,9 @@ impl<'a> STLink<'a> {
             } else if status == Status::SwdDpFault as u16 {
                 true
             } else if status == Status::JtagOk as u16 {
-                return Err(STLinkError::UnknownError);
-            } else {
                 false
+            } else {
+                return Err(STLinkError::UnknownError);
             } {
                 self.clear_sticky_error().ok();
                 return Err(STLinkError::TransferFault(
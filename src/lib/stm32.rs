@@ -0,0 +1,2256 @@
+use std::time::{Duration, Instant};
+
+use crate::cortex_m::Core;
+use crate::crc::{crc32, CrcStub, CrcUnit};
+use crate::image::{expected_contents, parts_in, span_in, FlashReport, Segment};
+use crate::memory::MemoryAccess;
+use crate::stlink::{AccessPort, STLinkError};
+use crate::usb_interface::TIMEOUT;
+
+/// The STM32 product lines, which differ in their flash controller.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stm32Family {
+    F0,
+    F1,
+    F3,
+    F4,
+    F7,
+    G0,
+    G4,
+    L0,
+    L4,
+    H7,
+}
+
+/// Flash layout of an STM32 part.
+#[derive(Debug)]
+pub struct Stm32Device {
+    pub name: &'static str,
+    pub family: Stm32Family,
+    pub flash_start: u32,
+    pub flash_size: u32,
+    /// The erase units (pages or sectors) in address order, as runs of `(count, size)`.
+    pub sectors: &'static [(u32, u32)],
+    /// The number of banks the sectors are evenly split into.
+    pub banks: u32,
+}
+
+impl Stm32Device {
+    /// Returns the start address and size of the sector with the given index.
+    pub fn sector(&self, index: u32) -> Option<(u32, u32)> {
+        let mut start = self.flash_start;
+        let mut first = 0;
+        for &(count, size) in self.sectors {
+            if index < first + count {
+                return Some((start + (index - first) * size, size));
+            }
+            start += count * size;
+            first += count;
+        }
+        None
+    }
+
+    /// Returns the index of the sector containing `addr`.
+    pub fn sector_index(&self, addr: u32) -> Option<u32> {
+        let mut start = self.flash_start;
+        let mut first = 0;
+        for &(count, size) in self.sectors {
+            if addr >= start && addr < start + count * size {
+                return Some(first + (addr - start) / size);
+            }
+            start += count * size;
+            first += count;
+        }
+        None
+    }
+
+    pub fn sector_count(&self) -> u32 {
+        self.sectors.iter().map(|&(count, _)| count).sum()
+    }
+
+    /// Returns the bank of a sector and its number within that bank.
+    fn bank_of_sector(&self, index: u32) -> (u32, u32) {
+        let per_bank = self.sector_count() / self.banks;
+        (index / per_bank, index % per_bank)
+    }
+
+    /// The value of erased bytes, which is zero on the L0.
+    pub fn erased_value(&self) -> u8 {
+        match self.family {
+            Stm32Family::L0 => 0x00,
+            _ => 0xff,
+        }
+    }
+
+    fn bank_of_address(&self, addr: u32) -> u32 {
+        (addr - self.flash_start) / (self.flash_size / self.banks)
+    }
+}
+
+/// Built-in table of common STM32 parts.
+#[rustfmt::skip]
+pub const DEVICES: &[Stm32Device] = &[
+    Stm32Device { name: "STM32F030x8", family: Stm32Family::F0, flash_start: 0x0800_0000, flash_size: 64 * 1024, sectors: &[(64, 1024)], banks: 1 },
+    Stm32Device { name: "STM32F072xB", family: Stm32Family::F0, flash_start: 0x0800_0000, flash_size: 128 * 1024, sectors: &[(64, 2048)], banks: 1 },
+    Stm32Device { name: "STM32F103x8", family: Stm32Family::F1, flash_start: 0x0800_0000, flash_size: 64 * 1024, sectors: &[(64, 1024)], banks: 1 },
+    Stm32Device { name: "STM32F103xB", family: Stm32Family::F1, flash_start: 0x0800_0000, flash_size: 128 * 1024, sectors: &[(128, 1024)], banks: 1 },
+    Stm32Device { name: "STM32F103xE", family: Stm32Family::F1, flash_start: 0x0800_0000, flash_size: 512 * 1024, sectors: &[(256, 2048)], banks: 1 },
+    Stm32Device { name: "STM32F303xC", family: Stm32Family::F3, flash_start: 0x0800_0000, flash_size: 256 * 1024, sectors: &[(128, 2048)], banks: 1 },
+    Stm32Device { name: "STM32F401xE", family: Stm32Family::F4, flash_start: 0x0800_0000, flash_size: 512 * 1024, sectors: &[(4, 16 * 1024), (1, 64 * 1024), (3, 128 * 1024)], banks: 1 },
+    Stm32Device { name: "STM32F407xG", family: Stm32Family::F4, flash_start: 0x0800_0000, flash_size: 1024 * 1024, sectors: &[(4, 16 * 1024), (1, 64 * 1024), (7, 128 * 1024)], banks: 1 },
+    Stm32Device { name: "STM32F411xE", family: Stm32Family::F4, flash_start: 0x0800_0000, flash_size: 512 * 1024, sectors: &[(4, 16 * 1024), (1, 64 * 1024), (3, 128 * 1024)], banks: 1 },
+    Stm32Device { name: "STM32F429xI", family: Stm32Family::F4, flash_start: 0x0800_0000, flash_size: 2048 * 1024, sectors: &[(4, 16 * 1024), (1, 64 * 1024), (7, 128 * 1024), (4, 16 * 1024), (1, 64 * 1024), (7, 128 * 1024)], banks: 2 },
+    Stm32Device { name: "STM32F746xG", family: Stm32Family::F7, flash_start: 0x0800_0000, flash_size: 1024 * 1024, sectors: &[(4, 32 * 1024), (1, 128 * 1024), (3, 256 * 1024)], banks: 1 },
+    Stm32Device { name: "STM32G071xB", family: Stm32Family::G0, flash_start: 0x0800_0000, flash_size: 128 * 1024, sectors: &[(64, 2048)], banks: 1 },
+    Stm32Device { name: "STM32G431xB", family: Stm32Family::G4, flash_start: 0x0800_0000, flash_size: 128 * 1024, sectors: &[(64, 2048)], banks: 1 },
+    Stm32Device { name: "STM32G474xE", family: Stm32Family::G4, flash_start: 0x0800_0000, flash_size: 512 * 1024, sectors: &[(256, 2048)], banks: 2 },
+    Stm32Device { name: "STM32L053x8", family: Stm32Family::L0, flash_start: 0x0800_0000, flash_size: 64 * 1024, sectors: &[(512, 128)], banks: 1 },
+    Stm32Device { name: "STM32L432xC", family: Stm32Family::L4, flash_start: 0x0800_0000, flash_size: 256 * 1024, sectors: &[(128, 2048)], banks: 1 },
+    Stm32Device { name: "STM32L476xG", family: Stm32Family::L4, flash_start: 0x0800_0000, flash_size: 1024 * 1024, sectors: &[(512, 2048)], banks: 2 },
+    Stm32Device { name: "STM32H743xI", family: Stm32Family::H7, flash_start: 0x0800_0000, flash_size: 2048 * 1024, sectors: &[(16, 128 * 1024)], banks: 2 },
+    Stm32Device { name: "STM32H750xB", family: Stm32Family::H7, flash_start: 0x0800_0000, flash_size: 128 * 1024, sectors: &[(1, 128 * 1024)], banks: 1 },
+];
+
+/// Looks up a device in the built-in table by its (case insensitive) name.
+pub fn find_device(name: &str) -> Option<&'static Stm32Device> {
+    DEVICES
+        .iter()
+        .find(|device| device.name.eq_ignore_ascii_case(name))
+}
+
+/// Where DBGMCU_IDCODE is found: on most parts, on the Cortex-M0(+) parts and on the H7.
+const IDCODE_ADDRESSES: [u32; 3] = [0xe004_2000, 0x4001_5800, 0x5c00_1000];
+
+/// A product line as identified by the DEV_ID of DBGMCU_IDCODE.
+struct Part {
+    dev_id: u16,
+    name: &'static str,
+    family: Stm32Family,
+    /// The silicon revisions by REV_ID.
+    revisions: &'static [(u16, &'static str)],
+}
+
+#[rustfmt::skip]
+const PARTS: &[Part] = &[
+    Part { dev_id: 0x410, name: "STM32F10x medium-density", family: Stm32Family::F1, revisions: &[(0x0000, "A"), (0x2000, "B"), (0x2001, "Z"), (0x2003, "Y")] },
+    Part { dev_id: 0x414, name: "STM32F10x high-density", family: Stm32Family::F1, revisions: &[(0x1000, "A"), (0x1001, "Z"), (0x1003, "Y")] },
+    Part { dev_id: 0x440, name: "STM32F030x8/F05x", family: Stm32Family::F0, revisions: &[(0x1000, "1"), (0x2000, "2")] },
+    Part { dev_id: 0x444, name: "STM32F03x", family: Stm32Family::F0, revisions: &[(0x1000, "1")] },
+    Part { dev_id: 0x448, name: "STM32F07x", family: Stm32Family::F0, revisions: &[(0x1000, "1"), (0x1001, "2")] },
+    Part { dev_id: 0x422, name: "STM32F30x/F31x", family: Stm32Family::F3, revisions: &[(0x1001, "Z"), (0x1003, "Y"), (0x2000, "B")] },
+    Part { dev_id: 0x413, name: "STM32F405/407/415/417", family: Stm32Family::F4, revisions: &[(0x1000, "A"), (0x1001, "Z"), (0x1003, "Y"), (0x1007, "1"), (0x100f, "4")] },
+    Part { dev_id: 0x419, name: "STM32F42x/F43x", family: Stm32Family::F4, revisions: &[(0x1000, "A"), (0x1003, "Y"), (0x1007, "1"), (0x2001, "3")] },
+    Part { dev_id: 0x431, name: "STM32F411", family: Stm32Family::F4, revisions: &[(0x1000, "A")] },
+    Part { dev_id: 0x449, name: "STM32F74x/F75x", family: Stm32Family::F7, revisions: &[(0x1000, "A"), (0x1001, "Z")] },
+    Part { dev_id: 0x460, name: "STM32G07x/G08x", family: Stm32Family::G0, revisions: &[(0x1000, "A"), (0x2000, "B")] },
+    Part { dev_id: 0x468, name: "STM32G431/G441", family: Stm32Family::G4, revisions: &[(0x1000, "A"), (0x2000, "B"), (0x2001, "Z")] },
+    Part { dev_id: 0x469, name: "STM32G47x/G48x", family: Stm32Family::G4, revisions: &[(0x1000, "A"), (0x2000, "B"), (0x2001, "Z")] },
+    Part { dev_id: 0x417, name: "STM32L05x/L06x", family: Stm32Family::L0, revisions: &[(0x1000, "A"), (0x1008, "Z"), (0x1038, "Y")] },
+    Part { dev_id: 0x435, name: "STM32L43x/L44x", family: Stm32Family::L4, revisions: &[(0x1000, "A"), (0x1001, "Z")] },
+    Part { dev_id: 0x415, name: "STM32L47x/L48x", family: Stm32Family::L4, revisions: &[(0x1003, "3"), (0x1007, "4")] },
+    Part { dev_id: 0x450, name: "STM32H74x/H75x", family: Stm32Family::H7, revisions: &[(0x1001, "Z"), (0x1003, "Y"), (0x2001, "X"), (0x2003, "V")] },
+];
+
+impl Stm32Family {
+    /// The address of DBGMCU_CR, which configures the trace pins.
+    pub fn dbgmcu_cr(self) -> u32 {
+        match self {
+            Stm32Family::G0 | Stm32Family::L0 => 0x4001_5804,
+            Stm32Family::H7 => 0x5c00_1004,
+            _ => 0xe004_2004,
+        }
+    }
+
+    /// The addresses of the flash size register and of the three words of the unique ID.
+    fn id_registers(self) -> (u32, [u32; 3]) {
+        match self {
+            Stm32Family::F0 | Stm32Family::F3 => {
+                (0x1fff_f7cc, [0x1fff_f7ac, 0x1fff_f7b0, 0x1fff_f7b4])
+            }
+            Stm32Family::F1 => (0x1fff_f7e0, [0x1fff_f7e8, 0x1fff_f7ec, 0x1fff_f7f0]),
+            Stm32Family::F4 => (0x1fff_7a22, [0x1fff_7a10, 0x1fff_7a14, 0x1fff_7a18]),
+            Stm32Family::F7 => (0x1ff0_f442, [0x1ff0_f420, 0x1ff0_f424, 0x1ff0_f428]),
+            Stm32Family::G0 | Stm32Family::G4 | Stm32Family::L4 => {
+                (0x1fff_75e0, [0x1fff_7590, 0x1fff_7594, 0x1fff_7598])
+            }
+            // The words of the unique ID are not contiguous on the L0.
+            Stm32Family::L0 => (0x1ff8_007c, [0x1ff8_0050, 0x1ff8_0054, 0x1ff8_0064]),
+            Stm32Family::H7 => (0x1ff1_e880, [0x1ff1_e800, 0x1ff1_e804, 0x1ff1_e808]),
+        }
+    }
+}
+
+/// The identity of an STM32 as read from its DBGMCU and device electronic signature.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChipInfo {
+    pub idcode: u32,
+    pub dev_id: u16,
+    pub rev_id: u16,
+    /// The product line, if the DEV_ID is known.
+    pub part: Option<&'static str>,
+    pub family: Option<Stm32Family>,
+    /// The silicon revision, if the REV_ID is known.
+    pub revision: Option<&'static str>,
+    /// The size of the flash in kB.
+    pub flash_size: Option<u32>,
+    pub unique_id: Option<[u32; 3]>,
+}
+
+impl ChipInfo {
+    fn new(idcode: u32) -> Self {
+        let dev_id = (idcode & 0xfff) as u16;
+        let rev_id = (idcode >> 16) as u16;
+        let part = PARTS.iter().find(|part| part.dev_id == dev_id);
+        Self {
+            idcode,
+            dev_id,
+            rev_id,
+            part: part.map(|part| part.name),
+            family: part.map(|part| part.family),
+            revision: part.and_then(|part| {
+                part.revisions
+                    .iter()
+                    .find(|&&(id, _)| id == rev_id)
+                    .map(|&(_, revision)| revision)
+            }),
+            flash_size: None,
+            unique_id: None,
+        }
+    }
+}
+
+/// Reads DBGMCU_IDCODE from the addresses the families place it at and, for a known part,
+/// its flash size and unique ID. Returns `None` if no IDCODE could be read.
+pub fn identify<M: MemoryAccess>(
+    mem: &mut M,
+    apsel: AccessPort,
+) -> Result<Option<ChipInfo>, STLinkError> {
+    let mut unknown = None;
+    for &address in IDCODE_ADDRESSES.iter() {
+        let idcode = match mem.read_word32(address, apsel) {
+            Ok(idcode) => idcode,
+            Err(STLinkError::TransferFault(..)) => continue,
+            Err(e) => return Err(e),
+        };
+        if idcode & 0xfff == 0 {
+            continue;
+        }
+        let mut info = ChipInfo::new(idcode);
+        let family = match info.family {
+            Some(family) => family,
+            None => {
+                unknown = unknown.or(Some(info));
+                continue;
+            }
+        };
+
+        // The signature is not readable on every part, e.g. under readout protection.
+        let (flash_size, unique_id) = family.id_registers();
+        info.flash_size = match mem.read_word32(flash_size & !0x3, apsel) {
+            Ok(value) => Some(value >> ((flash_size & 0x3) * 8) & 0xffff),
+            Err(STLinkError::TransferFault(..)) => None,
+            Err(e) => return Err(e),
+        };
+        let mut words = [0; 3];
+        for (word, &address) in words.iter_mut().zip(unique_id.iter()) {
+            *word = match mem.read_word32(address, apsel) {
+                Ok(value) => value,
+                Err(STLinkError::TransferFault(..)) => return Ok(Some(info)),
+                Err(e) => return Err(e),
+            };
+        }
+        info.unique_id = Some(words);
+        return Ok(Some(info));
+    }
+    Ok(unknown)
+}
+
+#[derive(Debug)]
+pub enum Stm32FlashError {
+    STLink(STLinkError),
+    AddressOutOfRange(u32),
+    /// The key sequence did not unlock the controller.
+    Locked,
+    Timeout,
+    WriteProtected(u32),
+    /// The location was not erased or the controller failed to program it.
+    ProgrammingError(u32),
+    AlignmentError(u32),
+    ParallelismError(u32),
+    SequenceError(u32),
+    SizeError(u32),
+    OperationError(u32),
+    VerificationFailed(u32),
+    /// The option bytes of the family have no field of this name.
+    UnknownOptionField(String),
+    /// The value does not fit into the option field.
+    OptionValueOutOfRange(&'static str, u32),
+    /// The readout protection is at level 2, which cannot be undone.
+    PermanentlyProtected,
+}
+
+impl From<STLinkError> for Stm32FlashError {
+    fn from(e: STLinkError) -> Self {
+        Stm32FlashError::STLink(e)
+    }
+}
+
+/// The flash controller generations.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Controller {
+    /// Page based with half-word programming (F0, F1, F3).
+    F1,
+    /// Sector based with selectable parallelism (F4, F7).
+    F4,
+    /// Page based with double-word programming (G0, G4, L4).
+    L4,
+    /// Page based with the PECR register and word programming (L0).
+    L0,
+    /// Sector based with one register set per bank and 256 bit programming (H7).
+    H7,
+}
+
+impl Stm32Family {
+    fn controller(self) -> Controller {
+        match self {
+            Stm32Family::F0 | Stm32Family::F1 | Stm32Family::F3 => Controller::F1,
+            Stm32Family::F4 | Stm32Family::F7 => Controller::F4,
+            Stm32Family::G0 | Stm32Family::G4 | Stm32Family::L4 => Controller::L4,
+            Stm32Family::L0 => Controller::L0,
+            Stm32Family::H7 => Controller::H7,
+        }
+    }
+
+    /// The CRC peripheral and the RCC bit clocking it.
+    pub fn crc_unit(self) -> CrcUnit {
+        let (base, clock_enable) = match self {
+            Stm32Family::F0 | Stm32Family::F1 | Stm32Family::F3 => {
+                (0x4002_3000, (0x4002_1014, 1 << 6))
+            }
+            Stm32Family::F4 | Stm32Family::F7 => (0x4002_3000, (0x4002_3830, 1 << 12)),
+            Stm32Family::G0 => (0x4002_3000, (0x4002_1038, 1 << 12)),
+            Stm32Family::L0 => (0x4002_3000, (0x4002_1030, 1 << 12)),
+            Stm32Family::G4 | Stm32Family::L4 => (0x4002_3000, (0x4002_1048, 1 << 12)),
+            Stm32Family::H7 => (0x5802_4c00, (0x5802_44e0, 1 << 19)),
+        };
+        CrcUnit {
+            base,
+            clock_enable,
+            // Only the first generation has a fixed polynomial.
+            programmable: !matches!(self, Stm32Family::F1 | Stm32Family::F4),
+        }
+    }
+}
+
+/// The status flags which abort an operation, with the error they are reported as.
+type ErrorFlags = &'static [(u32, fn(u32) -> Stm32FlashError)];
+
+const F1_ERRORS: ErrorFlags = &[
+    (1 << 4, Stm32FlashError::WriteProtected),
+    (1 << 2, Stm32FlashError::ProgrammingError),
+];
+const F4_ERRORS: ErrorFlags = &[
+    (1 << 4, Stm32FlashError::WriteProtected),
+    (1 << 5, Stm32FlashError::AlignmentError),
+    (1 << 6, Stm32FlashError::ParallelismError),
+    (1 << 7, Stm32FlashError::SequenceError),
+    (1 << 1, Stm32FlashError::OperationError),
+];
+const L4_ERRORS: ErrorFlags = &[
+    (1 << 4, Stm32FlashError::WriteProtected),
+    (1 << 3, Stm32FlashError::ProgrammingError),
+    (1 << 5, Stm32FlashError::AlignmentError),
+    (1 << 6, Stm32FlashError::SizeError),
+    (1 << 7, Stm32FlashError::SequenceError),
+    (1 << 8, Stm32FlashError::SequenceError),
+    (1 << 9, Stm32FlashError::ProgrammingError),
+    (1 << 1, Stm32FlashError::OperationError),
+];
+const L0_ERRORS: ErrorFlags = &[
+    (1 << 8, Stm32FlashError::WriteProtected),
+    (1 << 9, Stm32FlashError::AlignmentError),
+    (1 << 10, Stm32FlashError::SizeError),
+    (1 << 16, Stm32FlashError::ProgrammingError),
+    (1 << 17, Stm32FlashError::OperationError),
+];
+const H7_ERRORS: ErrorFlags = &[
+    (1 << 17, Stm32FlashError::WriteProtected),
+    (1 << 18, Stm32FlashError::SequenceError),
+    (1 << 19, Stm32FlashError::SequenceError),
+    (1 << 21, Stm32FlashError::ParallelismError),
+    (1 << 22, Stm32FlashError::OperationError),
+];
+
+/// An option byte register, which may be programmed through a different address than it is read from.
+struct OptionRegister {
+    name: &'static str,
+    read: u32,
+    write: u32,
+    /// Control and status bits which are no option bits.
+    reserved: u32,
+}
+
+/// A named field of the option byte registers, as `(name, register, shift, width)`.
+type OptionFields = &'static [(&'static str, usize, u32, u32)];
+
+const F1_OPTION_REGISTERS: &[OptionRegister] = &[
+    OptionRegister {
+        name: "OBR",
+        read: 0x4002_201c,
+        write: 0x4002_201c,
+        reserved: 0x1,
+    },
+    OptionRegister {
+        name: "WRPR",
+        read: 0x4002_2020,
+        write: 0x4002_2020,
+        reserved: 0,
+    },
+];
+#[rustfmt::skip]
+const F1_OPTION_FIELDS: OptionFields = &[
+    ("RDPRT", 0, 1, 1), ("WDG_SW", 0, 2, 1), ("nRST_STOP", 0, 3, 1), ("nRST_STDBY", 0, 4, 1),
+    ("DATA0", 0, 10, 8), ("DATA1", 0, 18, 8), ("WRP", 1, 0, 32),
+];
+#[rustfmt::skip]
+const F0_OPTION_FIELDS: OptionFields = &[
+    ("RDPRT", 0, 1, 2), ("WDG_SW", 0, 8, 1), ("nRST_STOP", 0, 9, 1), ("nRST_STDBY", 0, 10, 1),
+    ("nBOOT1", 0, 12, 1), ("VDDA_MONITOR", 0, 13, 1), ("DATA0", 0, 16, 8), ("DATA1", 0, 24, 8),
+    ("WRP", 1, 0, 32),
+];
+
+const L0_OPTION_REGISTERS: &[OptionRegister] = &[
+    OptionRegister {
+        name: "OPTR",
+        read: 0x4002_201c,
+        write: 0x4002_201c,
+        reserved: 0,
+    },
+    OptionRegister {
+        name: "WRPROT1",
+        read: 0x4002_2020,
+        write: 0x4002_2020,
+        reserved: 0,
+    },
+];
+#[rustfmt::skip]
+const L0_OPTION_FIELDS: OptionFields = &[
+    ("RDP", 0, 0, 8), ("WPRMOD", 0, 8, 1), ("BOR_LEV", 0, 16, 4), ("WDG_SW", 0, 20, 1),
+    ("nRST_STOP", 0, 21, 1), ("nRST_STDBY", 0, 22, 1), ("BFB2", 0, 23, 1), ("nBOOT1", 0, 31, 1),
+    ("WRP", 1, 0, 32),
+];
+
+const F4_OPTION_REGISTERS: &[OptionRegister] = &[
+    // OPTLOCK and OPTSTRT.
+    OptionRegister {
+        name: "OPTCR",
+        read: 0x4002_3c14,
+        write: 0x4002_3c14,
+        reserved: 0x3,
+    },
+];
+#[rustfmt::skip]
+const F4_OPTION_FIELDS: OptionFields = &[
+    ("BOR_LEV", 0, 2, 2), ("WDG_SW", 0, 5, 1), ("nRST_STOP", 0, 6, 1), ("nRST_STDBY", 0, 7, 1),
+    ("RDP", 0, 8, 8), ("nWRP", 0, 16, 12), ("SPRMOD", 0, 31, 1),
+];
+
+const F7_OPTION_REGISTERS: &[OptionRegister] = &[
+    OptionRegister {
+        name: "OPTCR",
+        read: 0x4002_3c14,
+        write: 0x4002_3c14,
+        reserved: 0x3,
+    },
+    OptionRegister {
+        name: "OPTCR1",
+        read: 0x4002_3c18,
+        write: 0x4002_3c18,
+        reserved: 0,
+    },
+];
+#[rustfmt::skip]
+const F7_OPTION_FIELDS: OptionFields = &[
+    ("BOR_LEV", 0, 2, 2), ("WWDG_SW", 0, 4, 1), ("IWDG_SW", 0, 5, 1), ("nRST_STOP", 0, 6, 1),
+    ("nRST_STDBY", 0, 7, 1), ("RDP", 0, 8, 8), ("nWRP", 0, 16, 8), ("IWDG_STDBY", 0, 30, 1),
+    ("IWDG_STOP", 0, 31, 1), ("BOOT_ADD0", 1, 0, 16), ("BOOT_ADD1", 1, 16, 16),
+];
+
+const L4_OPTION_REGISTERS: &[OptionRegister] = &[
+    OptionRegister {
+        name: "OPTR",
+        read: 0x4002_2020,
+        write: 0x4002_2020,
+        reserved: 0,
+    },
+    OptionRegister {
+        name: "PCROP1SR",
+        read: 0x4002_2024,
+        write: 0x4002_2024,
+        reserved: 0,
+    },
+    OptionRegister {
+        name: "PCROP1ER",
+        read: 0x4002_2028,
+        write: 0x4002_2028,
+        reserved: 0,
+    },
+    OptionRegister {
+        name: "WRP1AR",
+        read: 0x4002_202c,
+        write: 0x4002_202c,
+        reserved: 0,
+    },
+    OptionRegister {
+        name: "WRP1BR",
+        read: 0x4002_2030,
+        write: 0x4002_2030,
+        reserved: 0,
+    },
+];
+#[rustfmt::skip]
+const L4_OPTION_FIELDS: OptionFields = &[
+    ("RDP", 0, 0, 8), ("BOR_LEV", 0, 8, 3), ("nRST_STOP", 0, 12, 1), ("nRST_STDBY", 0, 13, 1),
+    ("nRST_SHDW", 0, 14, 1), ("IWDG_SW", 0, 16, 1), ("IWDG_STOP", 0, 17, 1),
+    ("IWDG_STDBY", 0, 18, 1), ("WWDG_SW", 0, 19, 1), ("nBOOT1", 0, 23, 1), ("SRAM2_PE", 0, 24, 1),
+    ("SRAM2_RST", 0, 25, 1), ("nSWBOOT0", 0, 26, 1), ("nBOOT0", 0, 27, 1),
+    ("PCROP1_STRT", 1, 0, 16), ("PCROP1_END", 2, 0, 16), ("PCROP_RDP", 2, 31, 1),
+    ("WRP1A_STRT", 3, 0, 8), ("WRP1A_END", 3, 16, 8), ("WRP1B_STRT", 4, 0, 8), ("WRP1B_END", 4, 16, 8),
+];
+#[rustfmt::skip]
+const G0_OPTION_FIELDS: OptionFields = &[
+    ("RDP", 0, 0, 8), ("BOR_EN", 0, 8, 1), ("BORF_LEV", 0, 9, 2), ("BORR_LEV", 0, 11, 2),
+    ("nRST_STOP", 0, 13, 1), ("nRST_STDBY", 0, 14, 1), ("nRST_SHDW", 0, 15, 1), ("IWDG_SW", 0, 16, 1),
+    ("IWDG_STOP", 0, 17, 1), ("IWDG_STDBY", 0, 18, 1), ("WWDG_SW", 0, 19, 1),
+    ("RAM_PARITY_CHECK", 0, 22, 1), ("nBOOT_SEL", 0, 24, 1), ("nBOOT1", 0, 25, 1), ("nBOOT0", 0, 26, 1),
+    ("NRST_MODE", 0, 27, 2), ("IRHEN", 0, 29, 1),
+    ("PCROP1A_STRT", 1, 0, 8), ("PCROP1A_END", 2, 0, 8), ("PCROP_RDP", 2, 31, 1),
+    ("WRP1A_STRT", 3, 0, 6), ("WRP1A_END", 3, 16, 6), ("WRP1B_STRT", 4, 0, 6), ("WRP1B_END", 4, 16, 6),
+];
+
+const H7_OPTION_REGISTERS: &[OptionRegister] = &[
+    // OPT_BUSY and OPTCHANGEERR.
+    OptionRegister {
+        name: "OPTSR",
+        read: 0x5200_201c,
+        write: 0x5200_2020,
+        reserved: 1 << 0 | 1 << 30,
+    },
+    OptionRegister {
+        name: "PRAR1",
+        read: 0x5200_2028,
+        write: 0x5200_202c,
+        reserved: 0,
+    },
+    OptionRegister {
+        name: "WPSN1",
+        read: 0x5200_2038,
+        write: 0x5200_203c,
+        reserved: 0,
+    },
+    OptionRegister {
+        name: "BOOT",
+        read: 0x5200_2040,
+        write: 0x5200_2044,
+        reserved: 0,
+    },
+];
+#[rustfmt::skip]
+const H7_OPTION_FIELDS: OptionFields = &[
+    ("BOR_LEV", 0, 2, 2), ("IWDG1_SW", 0, 4, 1), ("NRST_STOP_D1", 0, 6, 1), ("NRST_STBY_D1", 0, 7, 1),
+    ("RDP", 0, 8, 8), ("FZ_IWDG_STOP", 0, 17, 1), ("FZ_IWDG_SDBY", 0, 18, 1), ("ST_RAM_SIZE", 0, 19, 2),
+    ("SECURITY", 0, 21, 1), ("IO_HSLV", 0, 29, 1), ("SWAP_BANK_OPT", 0, 31, 1),
+    ("PROT_AREA_START1", 1, 0, 12), ("PROT_AREA_END1", 1, 16, 12), ("DMEP1", 1, 31, 1),
+    ("nWRPSN1", 2, 0, 8), ("BOOT_ADD0", 3, 0, 16), ("BOOT_ADD1", 3, 16, 16),
+];
+
+impl Stm32Family {
+    fn option_layout(self) -> (&'static [OptionRegister], OptionFields) {
+        match self {
+            Stm32Family::F0 | Stm32Family::F3 => (F1_OPTION_REGISTERS, F0_OPTION_FIELDS),
+            Stm32Family::F1 => (F1_OPTION_REGISTERS, F1_OPTION_FIELDS),
+            Stm32Family::L0 => (L0_OPTION_REGISTERS, L0_OPTION_FIELDS),
+            Stm32Family::F4 => (F4_OPTION_REGISTERS, F4_OPTION_FIELDS),
+            Stm32Family::F7 => (F7_OPTION_REGISTERS, F7_OPTION_FIELDS),
+            Stm32Family::G0 => (L4_OPTION_REGISTERS, G0_OPTION_FIELDS),
+            Stm32Family::G4 | Stm32Family::L4 => (L4_OPTION_REGISTERS, L4_OPTION_FIELDS),
+            Stm32Family::H7 => (H7_OPTION_REGISTERS, H7_OPTION_FIELDS),
+        }
+    }
+}
+
+/// The readout protection level.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RdpLevel {
+    /// No protection.
+    Level0,
+    /// The debugger cannot access the flash. Regressing to level 0 mass erases it.
+    Level1,
+    /// Debugging is disabled for good.
+    Level2,
+}
+
+/// What the debugger found out about the readout protection of a target.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReadoutProtection {
+    /// The level selected by the option bytes, if they could be read.
+    pub configured: Option<RdpLevel>,
+    /// Whether reading the start of the flash ended in a transfer fault.
+    pub flash_faults: bool,
+}
+
+impl ReadoutProtection {
+    pub fn level(&self) -> RdpLevel {
+        match self.configured {
+            Some(RdpLevel::Level2) => RdpLevel::Level2,
+            Some(RdpLevel::Level1) => RdpLevel::Level1,
+            _ if self.flash_faults => RdpLevel::Level1,
+            _ => RdpLevel::Level0,
+        }
+    }
+}
+
+/// The option byte registers of a device.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OptionBytes {
+    family: Stm32Family,
+    /// The raw register values in the order of `registers()`.
+    pub values: Vec<u32>,
+}
+
+impl OptionBytes {
+    /// The names of the option registers.
+    pub fn registers(&self) -> Vec<&'static str> {
+        let (registers, _) = self.family.option_layout();
+        registers.iter().map(|register| register.name).collect()
+    }
+
+    /// The decoded fields as `(name, value)`.
+    pub fn fields(&self) -> Vec<(&'static str, u32)> {
+        let (_, fields) = self.family.option_layout();
+        fields
+            .iter()
+            .map(|&(name, register, shift, width)| {
+                (name, self.values[register] >> shift & Self::mask(width))
+            })
+            .collect()
+    }
+
+    pub fn get(&self, name: &str) -> Option<u32> {
+        self.fields()
+            .into_iter()
+            .find(|(field, _)| field.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value)
+    }
+
+    /// Sets a field, which is only written to the target by `Stm32Flash::write_option_bytes`.
+    pub fn set(&mut self, name: &str, value: u32) -> Result<(), Stm32FlashError> {
+        let (_, fields) = self.family.option_layout();
+        let &(name, register, shift, width) = fields
+            .iter()
+            .find(|(field, ..)| field.eq_ignore_ascii_case(name))
+            .ok_or_else(|| Stm32FlashError::UnknownOptionField(name.to_string()))?;
+        if value & !Self::mask(width) != 0 {
+            return Err(Stm32FlashError::OptionValueOutOfRange(name, value));
+        }
+        let mask = Self::mask(width) << shift;
+        self.values[register] = self.values[register] & !mask | value << shift;
+        Ok(())
+    }
+
+    /// Returns the fields which differ from `other` as `(name, value, other value)`.
+    pub fn diff(&self, other: &OptionBytes) -> Vec<(&'static str, u32, u32)> {
+        self.fields()
+            .into_iter()
+            .zip(other.fields())
+            .filter(|((_, a), (_, b))| a != b)
+            .map(|((name, a), (_, b))| (name, a, b))
+            .collect()
+    }
+
+    pub fn rdp_level(&self) -> RdpLevel {
+        match (self.get("RDP"), self.get("RDPRT")) {
+            (Some(0xaa), _) | (None, Some(0)) => RdpLevel::Level0,
+            (Some(0xcc), _) => RdpLevel::Level2,
+            // RDPRT only reaches level 2 on the F0 and F3.
+            (None, Some(3)) if self.family != Stm32Family::F1 => RdpLevel::Level2,
+            _ => RdpLevel::Level1,
+        }
+    }
+
+    fn mask(width: u32) -> u32 {
+        if width == 32 {
+            0xffff_ffff
+        } else {
+            (1 << width) - 1
+        }
+    }
+}
+
+/// Addresses and flags of the registers of one bank of a controller.
+struct Registers {
+    keyr: u32,
+    sr: u32,
+    cr: u32,
+    /// The register the status flags are cleared in by writing ones.
+    clear: u32,
+    /// CR bits which start an erase of the whole bank.
+    mass_erase: u32,
+    lock: u32,
+    busy: u32,
+    eop: u32,
+    errors: ErrorFlags,
+}
+
+/// Programs the flash of an STM32 target through the registers of its flash controller.
+pub struct Stm32Flash<'m, M: MemoryAccess> {
+    mem: &'m mut M,
+    apsel: AccessPort,
+    device: &'static Stm32Device,
+    crc: Option<CrcStub>,
+}
+
+impl<'m, M: MemoryAccess> Stm32Flash<'m, M> {
+    /// Unlock keys of FLASH_KEYR.
+    const KEYS: [u32; 2] = [0x4567_0123, 0xcdef_89ab];
+
+    /// Unlock keys of FLASH_PEKEYR and FLASH_PRGKEYR on the L0.
+    const PEKEYS: [u32; 2] = [0x89ab_cdef, 0x0203_0405];
+    const PRGKEYS: [u32; 2] = [0x8c9d_aebf, 0x1314_1516];
+
+    /// Bits of FLASH_CR.
+    const F1_CR_PG: u32 = 1 << 0;
+    const F1_CR_PER: u32 = 1 << 1;
+    const F1_CR_STRT: u32 = 1 << 6;
+    const F1_AR: u32 = 0x14;
+    const F4_CR_PG: u32 = 1 << 0;
+    const F4_CR_SER: u32 = 1 << 1;
+    const F4_CR_SNB_SHIFT: u32 = 3;
+    /// Sectors of the second bank are numbered from 16.
+    const F4_CR_SNB_BANK2: u32 = 0x10;
+    const F4_CR_PSIZE_X32: u32 = 0x2 << 8;
+    const F4_CR_STRT: u32 = 1 << 16;
+    const L4_CR_PG: u32 = 1 << 0;
+    const L4_CR_PER: u32 = 1 << 1;
+    const L4_CR_PNB_SHIFT: u32 = 3;
+    const L4_CR_BKER: u32 = 1 << 11;
+    const L4_CR_STRT: u32 = 1 << 16;
+    const H7_CR_PG: u32 = 1 << 1;
+    const H7_CR_SER: u32 = 1 << 2;
+    const H7_CR_PSIZE_X64: u32 = 0x3 << 4;
+    const H7_CR_START: u32 = 1 << 7;
+    const H7_CR_SNB_SHIFT: u32 = 8;
+
+    /// Registers and bits of the L0 controller.
+    const L0_PECR: u32 = 0x4002_2004;
+    const L0_PEKEYR: u32 = 0x4002_200c;
+    const L0_PRGKEYR: u32 = 0x4002_2010;
+    const L0_SR: u32 = 0x4002_2018;
+    const L0_PECR_PELOCK: u32 = 1 << 0;
+    const L0_PECR_PRGLOCK: u32 = 1 << 1;
+    const L0_PECR_PROG: u32 = 1 << 3;
+    const L0_PECR_ERASE: u32 = 1 << 9;
+    const L0_SR_BSY: u32 = 1 << 0;
+    const L0_SR_EOP: u32 = 1 << 1;
+
+    /// Number of bytes written between two status checks on controllers which stall
+    /// the bus while they are busy.
+    const BATCH_SIZE: usize = 256;
+
+    const ERASE_TIMEOUT: Duration = Duration::from_secs(10);
+    const MASS_ERASE_TIMEOUT: Duration = Duration::from_secs(60);
+
+    /// Unlock keys of FLASH_OPTKEYR.
+    const OPTKEYS: [u32; 2] = [0x0819_2a3b, 0x4c5d_6e7f];
+    const F4_OPTKEYR: u32 = 0x4002_3c08;
+    const F4_OPTCR: u32 = 0x4002_3c14;
+    const F4_OPTCR_OPTLOCK: u32 = 1 << 0;
+    const F4_OPTCR_OPTSTRT: u32 = 1 << 1;
+    const L4_OPTKEYR: u32 = 0x4002_200c;
+    const L4_CR_OPTSTRT: u32 = 1 << 17;
+    const L4_CR_OBL_LAUNCH: u32 = 1 << 27;
+    const L4_CR_OPTLOCK: u32 = 1 << 30;
+    const H7_OPTKEYR: u32 = 0x5200_2008;
+    const H7_OPTCR: u32 = 0x5200_2018;
+    const H7_OPTCR_OPTLOCK: u32 = 1 << 0;
+    const H7_OPTCR_OPTSTART: u32 = 1 << 1;
+    const H7_OPTSR_CUR: u32 = 0x5200_201c;
+    const H7_OPTSR_OPT_BUSY: u32 = 1 << 0;
+    const H7_OPTSR_OPTCHANGEERR: u32 = 1 << 30;
+    const H7_OPTCCR: u32 = 0x5200_2024;
+    const F1_OPTKEYR: u32 = 0x4002_2008;
+    const F1_CR_OPTPG: u32 = 1 << 4;
+    const F1_CR_OPTER: u32 = 1 << 5;
+    const F1_CR_OPTWRE: u32 = 1 << 9;
+    /// Only implemented on the F0 and F3.
+    const F1_CR_OBL_LAUNCH: u32 = 1 << 13;
+    /// RDP, USER, DATA0, DATA1 and WRP0 to WRP3, each a byte followed by its complement.
+    const F1_OPTION_BYTES: u32 = 0x1fff_f800;
+    /// Unlock keys of FLASH_OPTKEYR on the L0.
+    const L0_OPTKEYS: [u32; 2] = [0xfbea_d9c8, 0x2425_2627];
+    const L0_OPTKEYR: u32 = 0x4002_2014;
+    const L0_PECR_OPTLOCK: u32 = 1 << 2;
+    const L0_PECR_OBL_LAUNCH: u32 = 1 << 18;
+    /// Four words, each a half-word followed by its complement.
+    const L0_OPTION_BYTES: u32 = 0x1ff8_0000;
+
+    pub fn new(mem: &'m mut M, apsel: AccessPort, device: &'static Stm32Device) -> Self {
+        Self {
+            mem,
+            apsel,
+            device,
+            crc: None,
+        }
+    }
+
+    /// Compares flash contents through a CRC computed on the target instead of reading them back.
+    /// The routine is placed at `ram_start` and the core has to stay halted.
+    pub fn use_crc_stub(&mut self, ram_start: u32) -> Result<(), Stm32FlashError> {
+        let core = Core::new(self.apsel);
+        let unit = self.device.family.crc_unit();
+        self.crc = Some(CrcStub::load(self.mem, core, ram_start, Some(unit))?);
+        Ok(())
+    }
+
+    fn controller(&self) -> Controller {
+        self.device.family.controller()
+    }
+
+    fn registers(&self, bank: u32) -> Registers {
+        match self.controller() {
+            Controller::F1 => Registers {
+                keyr: 0x4002_2004,
+                sr: 0x4002_200c,
+                cr: 0x4002_2010,
+                clear: 0x4002_200c,
+                mass_erase: 1 << 2,
+                lock: 1 << 7,
+                busy: 1 << 0,
+                eop: 1 << 5,
+                errors: F1_ERRORS,
+            },
+            Controller::F4 => Registers {
+                keyr: 0x4002_3c04,
+                sr: 0x4002_3c0c,
+                cr: 0x4002_3c10,
+                clear: 0x4002_3c0c,
+                // MER erases the first bank, MER1 the second one.
+                mass_erase: if bank == 0 { 1 << 2 } else { 1 << 15 },
+                lock: 1 << 31,
+                busy: 1 << 16,
+                eop: 1 << 0,
+                errors: F4_ERRORS,
+            },
+            Controller::L4 => Registers {
+                keyr: 0x4002_2008,
+                sr: 0x4002_2010,
+                cr: 0x4002_2014,
+                clear: 0x4002_2010,
+                mass_erase: if bank == 0 { 1 << 2 } else { 1 << 15 },
+                lock: 1 << 31,
+                // BSY, and CFGBSY on the G0.
+                busy: (1 << 16) | (1 << 18),
+                eop: 1 << 0,
+                errors: L4_ERRORS,
+            },
+            Controller::L0 => Registers {
+                keyr: Self::L0_PEKEYR,
+                sr: Self::L0_SR,
+                cr: Self::L0_PECR,
+                clear: Self::L0_SR,
+                mass_erase: 0,
+                lock: Self::L0_PECR_PELOCK,
+                busy: Self::L0_SR_BSY,
+                eop: Self::L0_SR_EOP,
+                errors: L0_ERRORS,
+            },
+            Controller::H7 => Registers {
+                keyr: 0x5200_2004 + 0x100 * bank,
+                sr: 0x5200_2010 + 0x100 * bank,
+                cr: 0x5200_200c + 0x100 * bank,
+                clear: 0x5200_2014 + 0x100 * bank,
+                // BER and START.
+                mass_erase: (1 << 3) | Self::H7_CR_START,
+                lock: 1 << 0,
+                // BSY and QW.
+                busy: (1 << 0) | (1 << 2),
+                eop: 1 << 16,
+                errors: H7_ERRORS,
+            },
+        }
+    }
+
+    /// Unlocks the flash controller through the key sequence.
+    pub fn unlock(&mut self) -> Result<(), Stm32FlashError> {
+        if self.controller() == Controller::L0 {
+            return self.unlock_l0();
+        }
+        // The H7 has a key register per bank, the others one for all banks.
+        let banks = if self.controller() == Controller::H7 {
+            self.device.banks
+        } else {
+            1
+        };
+        for bank in 0..banks {
+            let registers = self.registers(bank);
+            if self.read_register(registers.cr)? & registers.lock == 0 {
+                continue;
+            }
+            self.write_register(registers.keyr, Self::KEYS[0])?;
+            self.write_register(registers.keyr, Self::KEYS[1])?;
+            if self.read_register(registers.cr)? & registers.lock != 0 {
+                return Err(Stm32FlashError::Locked);
+            }
+        }
+        Ok(())
+    }
+
+    fn unlock_l0(&mut self) -> Result<(), Stm32FlashError> {
+        if self.read_register(Self::L0_PECR)? & Self::L0_PECR_PELOCK != 0 {
+            self.write_register(Self::L0_PEKEYR, Self::PEKEYS[0])?;
+            self.write_register(Self::L0_PEKEYR, Self::PEKEYS[1])?;
+        }
+        if self.read_register(Self::L0_PECR)? & Self::L0_PECR_PRGLOCK != 0 {
+            self.write_register(Self::L0_PRGKEYR, Self::PRGKEYS[0])?;
+            self.write_register(Self::L0_PRGKEYR, Self::PRGKEYS[1])?;
+        }
+        if self.read_register(Self::L0_PECR)? & (Self::L0_PECR_PELOCK | Self::L0_PECR_PRGLOCK) != 0
+        {
+            return Err(Stm32FlashError::Locked);
+        }
+        Ok(())
+    }
+
+    /// Locks the flash controller again.
+    pub fn lock(&mut self) -> Result<(), Stm32FlashError> {
+        let banks = if self.controller() == Controller::H7 {
+            self.device.banks
+        } else {
+            1
+        };
+        for bank in 0..banks {
+            let registers = self.registers(bank);
+            self.write_register(registers.cr, registers.lock)?;
+        }
+        Ok(())
+    }
+
+    /// Erases the sector (or page) with the given index.
+    pub fn erase_sector(&mut self, index: u32) -> Result<(), Stm32FlashError> {
+        let (start, _) = self
+            .device
+            .sector(index)
+            .ok_or(Stm32FlashError::AddressOutOfRange(index))?;
+        let (bank, number) = self.device.bank_of_sector(index);
+        let registers = self.registers(bank);
+        self.clear_status(&registers)?;
+
+        match self.controller() {
+            Controller::F1 => {
+                self.write_register(registers.cr, Self::F1_CR_PER)?;
+                self.write_register(registers.cr - 0x10 + Self::F1_AR, start)?;
+                self.write_register(registers.cr, Self::F1_CR_PER | Self::F1_CR_STRT)?;
+            }
+            Controller::F4 => {
+                let snb = if bank == 0 {
+                    number
+                } else {
+                    Self::F4_CR_SNB_BANK2 | number
+                };
+                let cr = Self::F4_CR_PSIZE_X32 | Self::F4_CR_SER | snb << Self::F4_CR_SNB_SHIFT;
+                self.write_register(registers.cr, cr)?;
+                self.write_register(registers.cr, cr | Self::F4_CR_STRT)?;
+            }
+            Controller::L4 => {
+                let mut cr = Self::L4_CR_PER | number << Self::L4_CR_PNB_SHIFT;
+                if bank == 1 {
+                    cr |= Self::L4_CR_BKER;
+                }
+                self.write_register(registers.cr, cr)?;
+                self.write_register(registers.cr, cr | Self::L4_CR_STRT)?;
+            }
+            Controller::L0 => {
+                // A page is erased by writing a zero word to it.
+                self.write_register(registers.cr, Self::L0_PECR_ERASE | Self::L0_PECR_PROG)?;
+                self.write_register(start, 0)?;
+            }
+            Controller::H7 => {
+                let cr = Self::H7_CR_SER | Self::H7_CR_PSIZE_X64 | number << Self::H7_CR_SNB_SHIFT;
+                self.write_register(registers.cr, cr)?;
+                self.write_register(registers.cr, cr | Self::H7_CR_START)?;
+            }
+        }
+        let result = self.wait_for_completion(&registers, start, Self::ERASE_TIMEOUT);
+        self.write_register(registers.cr, 0)?;
+        result
+    }
+
+    /// Erases all sectors which overlap the `size` bytes at `addr`.
+    pub fn erase_range(&mut self, addr: u32, size: u32) -> Result<(), Stm32FlashError> {
+        if size == 0 {
+            return Ok(());
+        }
+        self.check_range(addr, size)?;
+        let first = self.device.sector_index(addr).unwrap_or(0);
+        let last = self.device.sector_index(addr + size - 1).unwrap_or(first);
+        for index in first..=last {
+            self.erase_sector(index)?;
+        }
+        Ok(())
+    }
+
+    /// Erases the whole flash.
+    pub fn mass_erase(&mut self) -> Result<(), Stm32FlashError> {
+        match self.controller() {
+            // There is no mass erase besides the readout protection regression.
+            Controller::L0 => {
+                for index in 0..self.device.sector_count() {
+                    self.erase_sector(index)?;
+                }
+                Ok(())
+            }
+            Controller::H7 => {
+                for bank in 0..self.device.banks {
+                    let registers = self.registers(bank);
+                    self.clear_status(&registers)?;
+                    self.write_register(registers.cr, Self::H7_CR_PSIZE_X64)?;
+                    self.write_register(
+                        registers.cr,
+                        Self::H7_CR_PSIZE_X64 | registers.mass_erase,
+                    )?;
+                    let result = self.wait_for_completion(
+                        &registers,
+                        self.device.flash_start,
+                        Self::MASS_ERASE_TIMEOUT,
+                    );
+                    self.write_register(registers.cr, 0)?;
+                    result?;
+                }
+                Ok(())
+            }
+            controller => {
+                let registers = self.registers(0);
+                let mut cr = (0..self.device.banks)
+                    .map(|bank| self.registers(bank).mass_erase)
+                    .fold(0, |cr, bits| cr | bits);
+                cr |= match controller {
+                    Controller::F1 => Self::F1_CR_STRT,
+                    Controller::F4 => Self::F4_CR_PSIZE_X32 | Self::F4_CR_STRT,
+                    _ => Self::L4_CR_STRT,
+                };
+                self.clear_status(&registers)?;
+                self.write_register(registers.cr, cr & !(Self::F1_CR_STRT | Self::L4_CR_STRT))?;
+                self.write_register(registers.cr, cr)?;
+                let result = self.wait_for_completion(
+                    &registers,
+                    self.device.flash_start,
+                    Self::MASS_ERASE_TIMEOUT,
+                );
+                self.write_register(registers.cr, 0)?;
+                result
+            }
+        }
+    }
+
+    /// Programs `data` to erased flash at `addr`.
+    /// The data is padded with the erased value to the programming parallelism of the family:
+    /// a half-word on the F0, F1 and F3, a word on the F4, F7 and L0,
+    /// a double-word on the G0, G4 and L4 and 256 bits on the H7.
+    pub fn program(&mut self, addr: u32, data: &[u8]) -> Result<(), Stm32FlashError> {
+        self.check_range(addr, data.len() as u32)?;
+        let unit = match self.controller() {
+            Controller::F1 => 2,
+            Controller::F4 | Controller::L0 => 4,
+            Controller::L4 => 8,
+            Controller::H7 => 32,
+        };
+        let start = addr - addr % unit;
+        let erased_value = self.device.erased_value();
+        let mut padded = vec![erased_value; (addr - start) as usize];
+        padded.extend_from_slice(data);
+        while padded.len() % unit as usize != 0 {
+            padded.push(erased_value);
+        }
+
+        match self.controller() {
+            Controller::F1 => {
+                let registers = self.registers(0);
+                self.program_chunks(&registers, Self::F1_CR_PG, start, &padded, Self::BATCH_SIZE)
+            }
+            Controller::F4 => {
+                let registers = self.registers(0);
+                let cr = Self::F4_CR_PSIZE_X32 | Self::F4_CR_PG;
+                self.program_chunks(&registers, cr, start, &padded, Self::BATCH_SIZE)
+            }
+            Controller::L4 => {
+                let registers = self.registers(0);
+                self.program_chunks(&registers, Self::L4_CR_PG, start, &padded, 8)
+            }
+            Controller::L0 => {
+                // Word programming needs no bit set once PECR is unlocked.
+                let registers = self.registers(0);
+                self.program_chunks(&registers, 0, start, &padded, 4)
+            }
+            Controller::H7 => {
+                // Program each bank's part through its own registers.
+                let mut offset = 0;
+                while offset < padded.len() {
+                    let addr = start + offset as u32;
+                    let bank = self.device.bank_of_address(addr);
+                    let bank_end = self.device.flash_start
+                        + (bank + 1) * (self.device.flash_size / self.device.banks);
+                    let end = usize::min(padded.len(), (bank_end - start) as usize);
+                    let registers = self.registers(bank);
+                    let cr = Self::H7_CR_PG | Self::H7_CR_PSIZE_X64;
+                    self.program_chunks(&registers, cr, addr, &padded[offset..end], 32)?;
+                    offset = end;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Writes `data` in chunks of `chunk_size` bytes with `cr` set and waits for each chunk to complete.
+    fn program_chunks(
+        &mut self,
+        registers: &Registers,
+        cr: u32,
+        addr: u32,
+        data: &[u8],
+        chunk_size: usize,
+    ) -> Result<(), Stm32FlashError> {
+        self.clear_status(registers)?;
+        if cr != 0 {
+            self.write_register(registers.cr, cr)?;
+        }
+        let mut result = Ok(());
+        for (i, chunk) in data.chunks(chunk_size).enumerate() {
+            let chunk_addr = addr + (i * chunk_size) as u32;
+            let written = if self.controller() == Controller::F1 {
+                self.mem.write_mem16(chunk_addr, chunk.to_vec(), self.apsel)
+            } else {
+                self.mem.write_mem32(chunk_addr, chunk.to_vec(), self.apsel)
+            };
+            result = written
+                .map_err(Stm32FlashError::from)
+                .and_then(|_| self.wait_for_completion(registers, chunk_addr, TIMEOUT));
+            if result.is_err() {
+                break;
+            }
+        }
+        if cr != 0 {
+            self.write_register(registers.cr, 0)?;
+        }
+        result
+    }
+
+    /// Erases the sectors covered by `data`, programs it at `addr` and verifies it.
+    /// Other data in the erased sectors is lost.
+    pub fn program_flash(&mut self, addr: u32, data: &[u8]) -> Result<(), Stm32FlashError> {
+        self.check_range(addr, data.len() as u32)?;
+        self.unlock()?;
+        self.erase_range(addr, data.len() as u32)?;
+        self.program(addr, data)?;
+        self.verify(addr, data)
+    }
+
+    /// Programs all segments of an image and verifies them.
+    /// Sectors which already hold their part of the image are skipped,
+    /// the others are erased and programmed.
+    pub fn program_segments(
+        &mut self,
+        segments: &[Segment],
+    ) -> Result<FlashReport, Stm32FlashError> {
+        let mut sectors = vec![];
+        for segment in segments {
+            self.check_range(segment.address, segment.data.len() as u32)?;
+            if segment.data.is_empty() {
+                continue;
+            }
+            let first = self.device.sector_index(segment.address).unwrap_or(0);
+            let last = self.device.sector_index(segment.end() - 1).unwrap_or(first);
+            sectors.extend(first..=last);
+        }
+        sectors.sort();
+        sectors.dedup();
+
+        let mut report = FlashReport::default();
+        let mut changed = vec![];
+        for index in sectors {
+            // Unwrap is ok, the index was looked up from an address!
+            let (start, size) = self.device.sector(index).unwrap();
+            let expected = expected_contents(segments, start, size, self.device.erased_value());
+            if self.holds(start, &expected)? {
+                report.sectors_skipped += 1;
+            } else {
+                changed.push((index, start, size, expected));
+            }
+        }
+        if changed.is_empty() {
+            return Ok(report);
+        }
+
+        self.unlock()?;
+        for &(index, _, _, _) in &changed {
+            self.erase_sector(index)?;
+        }
+        // Segments may share a programming unit, which must only be programmed once,
+        // so the sector is programmed from its first to its last byte of data at once.
+        for (_, start, size, expected) in &changed {
+            if let Some((from, to)) = span_in(segments, *start, *size) {
+                let data = &expected[(from - start) as usize..(to - start) as usize];
+                self.program(from, data)?;
+            }
+        }
+        for (_, start, size, expected) in &changed {
+            let (start, size) = (*start, *size);
+            if self.crc.is_some() {
+                if !self.holds(start, expected)? {
+                    return Err(Stm32FlashError::VerificationFailed(start));
+                }
+            } else {
+                for (addr, data) in parts_in(segments, start, size) {
+                    self.verify(addr, data)?;
+                }
+            }
+            report.sectors_written += 1;
+        }
+        Ok(report)
+    }
+
+    /// Reads the option byte registers.
+    pub fn read_option_bytes(&mut self) -> Result<OptionBytes, Stm32FlashError> {
+        let (registers, _) = self.device.family.option_layout();
+        let mut values = vec![];
+        for register in registers {
+            values.push(self.read_register(register.read)? & !register.reserved);
+        }
+        Ok(OptionBytes {
+            family: self.device.family,
+            values,
+        })
+    }
+
+    /// Programs the option bytes through the OPTLOCK and OPTSTRT sequence, or by writing
+    /// the option bytes in memory on the F0, F1, F3 and L0.
+    /// Regressing the readout protection to level 0 mass erases the flash.
+    /// The new values take effect after `reload_option_bytes` or a reset.
+    pub fn write_option_bytes(&mut self, options: &OptionBytes) -> Result<(), Stm32FlashError> {
+        let (registers, _) = self.device.family.option_layout();
+        let controller = self.controller();
+        let (optkeyr, optcr, optlock) = match controller {
+            Controller::F4 => (Self::F4_OPTKEYR, Self::F4_OPTCR, Self::F4_OPTCR_OPTLOCK),
+            Controller::L4 => (Self::L4_OPTKEYR, self.registers(0).cr, Self::L4_CR_OPTLOCK),
+            Controller::H7 => (Self::H7_OPTKEYR, Self::H7_OPTCR, Self::H7_OPTCR_OPTLOCK),
+            Controller::F1 => return self.write_option_bytes_f1(options),
+            Controller::L0 => return self.write_option_bytes_l0(options),
+        };
+
+        self.unlock()?;
+        if self.read_register(optcr)? & optlock != 0 {
+            self.write_register(optkeyr, Self::OPTKEYS[0])?;
+            self.write_register(optkeyr, Self::OPTKEYS[1])?;
+            if self.read_register(optcr)? & optlock != 0 {
+                return Err(Stm32FlashError::Locked);
+            }
+        }
+
+        let flash_start = self.device.flash_start;
+        match controller {
+            Controller::F4 => {
+                // OPTCR holds the control bits too, so it is written last.
+                for (register, &value) in registers.iter().zip(&options.values).rev() {
+                    self.write_register(register.write, value & !register.reserved)?;
+                }
+                let optcr = options.values[0] & !registers[0].reserved;
+                self.write_register(Self::F4_OPTCR, optcr | Self::F4_OPTCR_OPTSTRT)?;
+                let registers = self.registers(0);
+                self.wait_for_completion(&registers, flash_start, Self::MASS_ERASE_TIMEOUT)?;
+                self.write_register(Self::F4_OPTCR, optcr | Self::F4_OPTCR_OPTLOCK)
+            }
+            Controller::L4 => {
+                for (register, &value) in registers.iter().zip(&options.values) {
+                    self.write_register(register.write, value & !register.reserved)?;
+                }
+                let registers = self.registers(0);
+                self.clear_status(&registers)?;
+                self.write_register(registers.cr, Self::L4_CR_OPTSTRT)?;
+                self.wait_for_completion(&registers, flash_start, Self::MASS_ERASE_TIMEOUT)
+            }
+            _ => {
+                for (register, &value) in registers.iter().zip(&options.values) {
+                    self.write_register(register.write, value & !register.reserved)?;
+                }
+                self.write_register(Self::H7_OPTCCR, Self::H7_OPTSR_OPTCHANGEERR)?;
+                self.write_register(Self::H7_OPTCR, Self::H7_OPTCR_OPTSTART)?;
+                let start = Instant::now();
+                let status = loop {
+                    let status = self.read_register(Self::H7_OPTSR_CUR)?;
+                    if status & Self::H7_OPTSR_OPT_BUSY == 0 {
+                        break status;
+                    }
+                    if start.elapsed() > Self::MASS_ERASE_TIMEOUT {
+                        return Err(Stm32FlashError::Timeout);
+                    }
+                };
+                self.write_register(Self::H7_OPTCR, Self::H7_OPTCR_OPTLOCK)?;
+                if status & Self::H7_OPTSR_OPTCHANGEERR != 0 {
+                    return Err(Stm32FlashError::OperationError(Self::H7_OPTSR_CUR));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Erases the option bytes of the F0, F1 and F3 and programs them byte by byte.
+    fn write_option_bytes_f1(&mut self, options: &OptionBytes) -> Result<(), Stm32FlashError> {
+        let registers = self.registers(0);
+        self.unlock()?;
+        if self.read_register(registers.cr)? & Self::F1_CR_OPTWRE == 0 {
+            self.write_register(Self::F1_OPTKEYR, Self::KEYS[0])?;
+            self.write_register(Self::F1_OPTKEYR, Self::KEYS[1])?;
+            if self.read_register(registers.cr)? & Self::F1_CR_OPTWRE == 0 {
+                return Err(Stm32FlashError::Locked);
+            }
+        }
+
+        // OBR only holds the state of RDP, any other value than the level 0 key selects level 1.
+        let family = self.device.family;
+        let rdp = match options.rdp_level() {
+            RdpLevel::Level0 if family == Stm32Family::F1 => 0xa5,
+            RdpLevel::Level0 => 0xaa,
+            RdpLevel::Level1 => 0x00,
+            RdpLevel::Level2 => 0xcc,
+        };
+        let (obr, wrpr) = (options.values[0], options.values[1]);
+        let (user, data) = if family == Stm32Family::F1 {
+            (2, 10)
+        } else {
+            (8, 16)
+        };
+        let bytes = [
+            rdp,
+            obr >> user,
+            obr >> data,
+            obr >> (data + 8),
+            wrpr,
+            wrpr >> 8,
+            wrpr >> 16,
+            wrpr >> 24,
+        ];
+
+        // Clearing OPTWRE locks the option bytes again, so it is kept set.
+        self.clear_status(&registers)?;
+        let cr = Self::F1_CR_OPTWRE | Self::F1_CR_OPTER;
+        self.write_register(registers.cr, cr)?;
+        self.write_register(registers.cr, cr | Self::F1_CR_STRT)?;
+        let erased =
+            self.wait_for_completion(&registers, Self::F1_OPTION_BYTES, Self::ERASE_TIMEOUT);
+        self.write_register(registers.cr, Self::F1_CR_OPTWRE)?;
+        erased?;
+
+        // The complements are programmed by the controller.
+        self.write_register(registers.cr, Self::F1_CR_OPTWRE | Self::F1_CR_OPTPG)?;
+        let mut result = Ok(());
+        for (i, &byte) in bytes.iter().enumerate() {
+            let addr = Self::F1_OPTION_BYTES + 2 * i as u32;
+            result = self
+                .mem
+                .write_mem16(addr, vec![byte as u8, 0], self.apsel)
+                .map_err(Stm32FlashError::from)
+                .and_then(|_| self.wait_for_completion(&registers, addr, TIMEOUT));
+            if result.is_err() {
+                break;
+            }
+        }
+        self.write_register(registers.cr, Self::F1_CR_OPTWRE)?;
+        result
+    }
+
+    /// Programs the option words of the L0, which are erased by the controller as they are written.
+    fn write_option_bytes_l0(&mut self, options: &OptionBytes) -> Result<(), Stm32FlashError> {
+        self.unlock_l0()?;
+        if self.read_register(Self::L0_PECR)? & Self::L0_PECR_OPTLOCK != 0 {
+            self.write_register(Self::L0_OPTKEYR, Self::L0_OPTKEYS[0])?;
+            self.write_register(Self::L0_OPTKEYR, Self::L0_OPTKEYS[1])?;
+            if self.read_register(Self::L0_PECR)? & Self::L0_PECR_OPTLOCK != 0 {
+                return Err(Stm32FlashError::Locked);
+            }
+        }
+
+        // RDP and WPRMOD, the user bits, and the two halves of WRPROT1.
+        let (optr, wrprot) = (options.values[0], options.values[1]);
+        let words = [optr & 0x1ff, optr >> 16, wrprot & 0xffff, wrprot >> 16];
+        let registers = self.registers(0);
+        self.clear_status(&registers)?;
+        for (i, &word) in words.iter().enumerate() {
+            let addr = Self::L0_OPTION_BYTES + 4 * i as u32;
+            self.write_register(addr, word | !word << 16)?;
+            self.wait_for_completion(&registers, addr, Self::ERASE_TIMEOUT)?;
+        }
+        Ok(())
+    }
+
+    /// Makes the controller load the programmed option bytes where it can do so without
+    /// a power cycle. This resets the target on the F0, F3, G0, G4, L0 and L4.
+    pub fn reload_option_bytes(&mut self) -> Result<(), Stm32FlashError> {
+        let (cr, obl_launch) = match (self.controller(), self.device.family) {
+            (Controller::L4, _) => (self.registers(0).cr, Self::L4_CR_OBL_LAUNCH),
+            (Controller::L0, _) => (Self::L0_PECR, Self::L0_PECR_OBL_LAUNCH),
+            // The F1 loads them on the next reset.
+            (Controller::F1, Stm32Family::F0) | (Controller::F1, Stm32Family::F3) => (
+                self.registers(0).cr,
+                Self::F1_CR_OPTWRE | Self::F1_CR_OBL_LAUNCH,
+            ),
+            _ => return Ok(()),
+        };
+        match self.write_register(cr, obl_launch) {
+            // The reset may cut the transfer short.
+            Err(Stm32FlashError::STLink(STLinkError::TransferFault(..))) => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Reads the readout protection level from the option bytes and checks whether the
+    /// flash faults on reads, which is how level 1 shows when the option bytes are not accessible.
+    pub fn readout_protection(&mut self) -> Result<ReadoutProtection, Stm32FlashError> {
+        let configured = match self.read_option_bytes() {
+            Ok(options) => Some(options.rdp_level()),
+            Err(Stm32FlashError::STLink(STLinkError::TransferFault(..))) => None,
+            Err(e) => return Err(e),
+        };
+        let flash_faults = match self.mem.read_word32(self.device.flash_start, self.apsel) {
+            Ok(_) => false,
+            Err(STLinkError::TransferFault(..)) => true,
+            Err(e) => return Err(e.into()),
+        };
+        Ok(ReadoutProtection {
+            configured,
+            flash_faults,
+        })
+    }
+
+    /// Regresses the readout protection to level 0, which mass erases the flash.
+    /// The target may reset or drop the connection while it erases, so the caller has to
+    /// reconnect and check `readout_protection` to see when the regression is complete.
+    pub fn regress_readout_protection(&mut self) -> Result<(), Stm32FlashError> {
+        let mut options = self.read_option_bytes()?;
+        match options.rdp_level() {
+            RdpLevel::Level2 => return Err(Stm32FlashError::PermanentlyProtected),
+            RdpLevel::Level0 => return Ok(()),
+            RdpLevel::Level1 => (),
+        }
+        // The F0, F1 and F3 only report the level in RDPRT, which selects the level 0 key when written.
+        match options.get("RDP") {
+            Some(_) => options.set("RDP", 0xaa)?,
+            None => options.set("RDPRT", 0)?,
+        }
+        match self
+            .write_option_bytes(&options)
+            .and_then(|_| self.reload_option_bytes())
+        {
+            Err(Stm32FlashError::STLink(STLinkError::TransferFault(..))) => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Checks whether the flash at `addr` holds `expected`, which has to be word aligned.
+    fn holds(&mut self, addr: u32, expected: &[u8]) -> Result<bool, Stm32FlashError> {
+        Ok(match self.crc {
+            Some(crc) => crc.crc(self.mem, addr, expected.len() as u32)? == crc32(expected),
+            None => {
+                self.mem
+                    .read_mem32(addr, expected.len() as u32, self.apsel)?
+                    == expected
+            }
+        })
+    }
+
+    /// Compares `data.len()` bytes at `addr` with `data`.
+    /// Without a CRC routine or for unaligned data, the flash is read back.
+    pub fn verify(&mut self, addr: u32, data: &[u8]) -> Result<(), Stm32FlashError> {
+        if self.crc.is_some() && addr % 4 == 0 && data.len() % 4 == 0 {
+            return match self.holds(addr, data)? {
+                true => Ok(()),
+                false => Err(Stm32FlashError::VerificationFailed(addr)),
+            };
+        }
+        let start = addr & !0x3;
+        let size = (addr - start + data.len() as u32 + 3) & !0x3;
+        let readback = self.mem.read_mem32(start, size, self.apsel)?;
+        let readback = &readback[(addr - start) as usize..];
+        match readback.iter().zip(data.iter()).position(|(a, b)| a != b) {
+            Some(offset) => Err(Stm32FlashError::VerificationFailed(addr + offset as u32)),
+            None => Ok(()),
+        }
+    }
+
+    /// Polls the status register until the operation at `addr` has ended and decodes its error flags.
+    fn wait_for_completion(
+        &mut self,
+        registers: &Registers,
+        addr: u32,
+        timeout: Duration,
+    ) -> Result<(), Stm32FlashError> {
+        let start = Instant::now();
+        let status = loop {
+            let status = self.read_register(registers.sr)?;
+            if status & registers.busy == 0 {
+                break status;
+            }
+            if start.elapsed() > timeout {
+                return Err(Stm32FlashError::Timeout);
+            }
+        };
+        self.clear_status(registers)?;
+        match registers
+            .errors
+            .iter()
+            .find(|&&(flag, _)| status & flag != 0)
+        {
+            Some((_, error)) => Err(error(addr)),
+            None => Ok(()),
+        }
+    }
+
+    /// Clears EOP and the error flags of earlier operations.
+    fn clear_status(&mut self, registers: &Registers) -> Result<(), Stm32FlashError> {
+        let flags = registers
+            .errors
+            .iter()
+            .fold(registers.eop, |flags, &(flag, _)| flags | flag);
+        self.write_register(registers.clear, flags)
+    }
+
+    fn read_register(&mut self, addr: u32) -> Result<u32, Stm32FlashError> {
+        Ok(self.mem.read_word32(addr, self.apsel)?)
+    }
+
+    fn write_register(&mut self, addr: u32, value: u32) -> Result<(), Stm32FlashError> {
+        Ok(self.mem.write_word32(addr, value, self.apsel)?)
+    }
+
+    fn check_range(&self, addr: u32, size: u32) -> Result<(), Stm32FlashError> {
+        let end = self.device.flash_start + self.device.flash_size;
+        if addr < self.device.flash_start {
+            return Err(Stm32FlashError::AddressOutOfRange(addr));
+        }
+        match addr.checked_add(size) {
+            Some(range_end) if range_end <= end => Ok(()),
+            _ => Err(Stm32FlashError::AddressOutOfRange(end)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::mock::Memory;
+
+    /// A flash controller which programs and erases a memory image like the hardware.
+    struct Simulated {
+        device: &'static Stm32Device,
+        flash: Vec<u8>,
+        /// LOCK of each bank, or PELOCK and PRGLOCK on the L0.
+        locked: [bool; 2],
+        /// The number of correct keys written to the key registers of each bank,
+        /// or PEKEYR and PRGKEYR on the L0, and to OPTKEYR.
+        keys: [usize; 3],
+        /// OPTWRE on the F1, or a cleared OPTLOCK on the L0.
+        options_unlocked: bool,
+        cr: [u32; 2],
+        sr: [u32; 2],
+        /// FLASH_AR of the F1 controller.
+        ar: u32,
+        /// Bytes collected until a whole programming unit was written, and their address.
+        buffer: Vec<u8>,
+        buffer_addr: u32,
+        /// Everything besides the flash and its controller.
+        memory: Memory,
+    }
+
+    impl Simulated {
+        fn new(name: &str) -> Self {
+            let device = find_device(name).unwrap();
+            Self {
+                device,
+                flash: vec![device.erased_value(); device.flash_size as usize],
+                locked: [true; 2],
+                keys: [0; 3],
+                options_unlocked: false,
+                cr: [0; 2],
+                sr: [0; 2],
+                ar: 0,
+                buffer: vec![],
+                buffer_addr: 0,
+                memory: Memory::new(),
+            }
+        }
+
+        fn controller(&self) -> Controller {
+            self.device.family.controller()
+        }
+
+        /// Returns the register and bank of a controller register address.
+        fn register(&self, addr: u32) -> Option<(u32, usize)> {
+            match self.controller() {
+                Controller::F1 | Controller::L0 | Controller::L4 if addr & !0xff == 0x4002_2000 => {
+                    Some((addr & 0xff, 0))
+                }
+                Controller::F4 if addr & !0xff == 0x4002_3c00 => Some((addr & 0xff, 0)),
+                Controller::H7 if addr & !0x1ff == 0x5200_2000 => {
+                    Some((addr & 0xff, (addr as usize >> 8) & 1))
+                }
+                _ => None,
+            }
+        }
+
+        /// Offsets of KEYR, SR, CR and the clear register, and the bits of LOCK, STRT and PG.
+        fn layout(&self) -> (u32, u32, u32, u32, u32, u32, u32) {
+            match self.controller() {
+                Controller::F1 => (0x04, 0x0c, 0x10, 0x0c, 1 << 7, 1 << 6, 1 << 0),
+                Controller::F4 => (0x04, 0x0c, 0x10, 0x0c, 1 << 31, 1 << 16, 1 << 0),
+                Controller::L4 => (0x08, 0x10, 0x14, 0x10, 1 << 31, 1 << 16, 1 << 0),
+                // PEKEYR, SR and PECR, with no start or programming bit.
+                Controller::L0 => (0x0c, 0x18, 0x04, 0x18, 1 << 0, 0, 0),
+                Controller::H7 => (0x04, 0x10, 0x0c, 0x14, 1 << 0, 1 << 7, 1 << 1),
+            }
+        }
+
+        /// Advances the unlock sequence of key register `slot`, returning whether it completed.
+        fn key(&mut self, slot: usize, keys: [u32; 2], value: u32) -> bool {
+            if value == keys[self.keys[slot]] && self.keys[slot] == 1 {
+                self.keys[slot] = 0;
+                true
+            } else if value == keys[self.keys[slot]] {
+                self.keys[slot] = 1;
+                false
+            } else {
+                self.keys[slot] = 0;
+                false
+            }
+        }
+
+        /// Returns the value of a simulated register, the others are kept in `memory`.
+        fn read_register(&self, offset: u32, bank: usize) -> Option<u32> {
+            let controller = self.controller();
+            let (_, sr, cr, _, lock, _, _) = self.layout();
+            let options_locked = !self.options_unlocked as u32;
+            Some(if offset == sr {
+                self.sr[bank]
+            } else if offset == cr && controller == Controller::L0 {
+                let locked = self.locked[0] as u32 | (self.locked[1] as u32) << 1;
+                self.cr[0] | locked | options_locked << 2
+            } else if offset == cr && controller == Controller::F1 {
+                let locked = if self.locked[0] { lock } else { 0 };
+                self.cr[0] | locked | (self.options_unlocked as u32) << 9
+            } else if offset == cr {
+                self.cr[bank] | if self.locked[bank] { lock } else { 0 }
+            } else {
+                return None;
+            })
+        }
+
+        /// Writes a simulated register, returning false for the ones kept in `memory`.
+        fn write_register(&mut self, offset: u32, bank: usize, value: u32) -> bool {
+            let controller = self.controller();
+            let (keyr, _, cr, clear, lock, start, _) = self.layout();
+            if controller == Controller::F1 && offset == 0x08 {
+                if self.key(2, [0x4567_0123, 0xcdef_89ab], value) && !self.locked[0] {
+                    self.options_unlocked = true;
+                }
+            } else if controller == Controller::L0 && offset == 0x14 {
+                if !self.locked[0] && self.key(2, [0xfbea_d9c8, 0x2425_2627], value) {
+                    self.options_unlocked = true;
+                }
+            } else if offset == keyr && controller == Controller::L0 {
+                if self.key(0, [0x89ab_cdef, 0x0203_0405], value) {
+                    self.locked[0] = false;
+                }
+            } else if offset == keyr {
+                if self.key(bank, [0x4567_0123, 0xcdef_89ab], value) {
+                    self.locked[bank] = false;
+                }
+            } else if controller == Controller::L0 && offset == 0x10 {
+                // PRGKEYR only accepts its keys once PECR is unlocked.
+                if !self.locked[0] && self.key(1, [0x8c9d_aebf, 0x1314_1516], value) {
+                    self.locked[1] = false;
+                }
+            } else if offset == clear {
+                self.sr[bank] &= !value;
+            } else if controller == Controller::F1 && offset == 0x14 {
+                self.ar = value;
+            } else if offset == cr && controller == Controller::L0 && !self.locked[0] {
+                if value & lock != 0 {
+                    self.locked = [true; 2];
+                }
+                if value & 1 << 1 != 0 {
+                    self.locked[1] = true;
+                }
+                if value & (lock | 1 << 2) != 0 {
+                    self.options_unlocked = false;
+                }
+                self.cr[0] = value & !0x7;
+            } else if offset == cr && !self.locked[bank] {
+                if value & lock != 0 {
+                    self.locked[bank] = true;
+                }
+                if controller == Controller::F1 && value & 1 << 9 == 0 {
+                    self.options_unlocked = false;
+                }
+                self.cr[bank] = value & !(lock | start | 1 << 9);
+                if value & start != 0 {
+                    self.erase(bank, value);
+                }
+            } else if offset != cr {
+                return false;
+            }
+            true
+        }
+
+        /// Programs the option bytes of the F0, F1 and F3, or the option words of the L0.
+        fn program_options(&mut self, addr: u32, data: Vec<u8>) {
+            if self.controller() == Controller::L0 {
+                if self.locked[0] || !self.options_unlocked {
+                    // WRPERR
+                    self.sr[0] |= 1 << 8;
+                    return;
+                }
+                self.memory.write(addr, &data);
+            } else if self.cr[0] & 1 << 4 == 0
+                || !self.options_unlocked
+                || self.memory.read(addr, 2) != [0xff, 0xff]
+            {
+                // PGERR
+                self.sr[0] |= 1 << 2;
+            } else {
+                self.memory.write(addr, &[data[0], !data[0]]);
+            }
+        }
+
+        fn erase(&mut self, bank: usize, cr: u32) {
+            let number = match self.controller() {
+                Controller::F1 if cr & 1 << 5 != 0 => {
+                    self.memory.write(0x1fff_f800, &[0xff; 16]);
+                    return;
+                }
+                // MER erases everything, PER the page FLASH_AR points to.
+                Controller::F1 if cr & 1 << 2 != 0 => {
+                    for byte in &mut self.flash {
+                        *byte = 0xff;
+                    }
+                    return;
+                }
+                Controller::F1 => self.device.sector_index(self.ar).unwrap(),
+                Controller::F4 => {
+                    let snb = (cr >> 3) & 0x1f;
+                    // Sectors 12 and up of the first bank do not exist.
+                    (snb & 0xf) + (snb >> 4) * self.device.sector_count() / 2
+                }
+                Controller::L4 => {
+                    let per_bank = self.device.sector_count() / self.device.banks;
+                    ((cr >> 3) & 0xff) + ((cr >> 11) & 1) * per_bank
+                }
+                // The page of the word written with ERASE and PROG set.
+                Controller::L0 => self.device.sector_index(cr).unwrap(),
+                Controller::H7 => ((cr >> 8) & 0x7) + bank as u32 * self.device.sector_count() / 2,
+            };
+            let (start, size) = self.device.sector(number).unwrap();
+            let offset = (start - self.device.flash_start) as usize;
+            let erased_value = self.device.erased_value();
+            for byte in &mut self.flash[offset..offset + size as usize] {
+                *byte = erased_value;
+            }
+        }
+
+        fn program(&mut self, addr: u32, data: Vec<u8>) {
+            let bank = self.device.bank_of_address(addr) as usize;
+            let controller = self.controller();
+            let (_, _, _, _, _, _, pg) = self.layout();
+            if controller == Controller::L0 {
+                if self.locked[0] || self.locked[1] {
+                    // WRPERR
+                    self.sr[0] |= 1 << 8;
+                    return;
+                }
+                // ERASE and PROG
+                if self.cr[0] & 0x208 == 0x208 {
+                    self.erase(0, addr);
+                    return;
+                }
+            } else if self.cr[bank] & pg == 0 {
+                // PGSERR, or PGERR on the F1.
+                self.sr[bank] |= match controller {
+                    Controller::F1 => 1 << 2,
+                    Controller::H7 => 1 << 18,
+                    _ => 1 << 7,
+                };
+                return;
+            }
+            if self.buffer.is_empty() {
+                self.buffer_addr = addr;
+            }
+            self.buffer.extend(data);
+            let unit = match controller {
+                Controller::F1 => 2,
+                Controller::F4 | Controller::L0 => 4,
+                Controller::L4 => 8,
+                Controller::H7 => 32,
+            };
+            let erased_value = self.device.erased_value();
+            while self.buffer.len() >= unit {
+                let word: Vec<u8> = self.buffer.drain(..unit).collect();
+                let offset = (self.buffer_addr - self.device.flash_start) as usize;
+                self.buffer_addr += unit as u32;
+                let target = &mut self.flash[offset..offset + unit];
+                // PGERR, NOTZEROERR and PROGERR for words which are not erased.
+                let not_erased = match controller {
+                    Controller::F1 => 1 << 2,
+                    Controller::L0 => 1 << 16,
+                    Controller::L4 => 1 << 3,
+                    _ => 0,
+                };
+                if not_erased != 0 && target.iter().any(|&b| b != erased_value) {
+                    self.sr[bank] |= not_erased;
+                    continue;
+                }
+                for (byte, new) in target.iter_mut().zip(word) {
+                    *byte = if erased_value == 0 { new } else { *byte & new };
+                }
+            }
+        }
+
+        fn in_flash(&self, addr: u32) -> bool {
+            addr >= self.device.flash_start
+                && addr - self.device.flash_start < self.device.flash_size
+        }
+
+        fn read(&self, addr: u32, size: u32) -> Vec<u8> {
+            let offset = (addr - self.device.flash_start) as usize;
+            self.flash[offset..offset + size as usize].to_vec()
+        }
+
+        fn in_options(&self, addr: u32) -> bool {
+            let start = match self.controller() {
+                Controller::F1 => 0x1fff_f800,
+                Controller::L0 => 0x1ff8_0000,
+                _ => return false,
+            };
+            addr >= start && addr < start + 16
+        }
+
+        fn read_any(&mut self, addr: u32, size: u32) -> Result<Vec<u8>, STLinkError> {
+            let register = self
+                .register(addr)
+                .and_then(|(offset, bank)| self.read_register(offset, bank));
+            Ok(match register {
+                Some(value) => value.to_le_bytes()[..size as usize].to_vec(),
+                None if self.in_flash(addr) => self.read(addr, size),
+                None => self.memory.read_mem8(addr, size, 0)?,
+            })
+        }
+
+        fn write_any(&mut self, addr: u32, data: Vec<u8>) -> Result<(), STLinkError> {
+            if let Some((offset, bank)) = self.register(addr) {
+                let mut value = [0; 4];
+                value[..data.len()].copy_from_slice(&data);
+                if self.write_register(offset, bank, u32::from_le_bytes(value)) {
+                    return Ok(());
+                }
+            }
+            if self.in_flash(addr) {
+                self.program(addr, data);
+            } else if self.in_options(addr) {
+                self.program_options(addr, data);
+            } else {
+                self.memory.write_mem8(addr, data, 0)?;
+            }
+            Ok(())
+        }
+    }
+
+    impl MemoryAccess for Simulated {
+        fn read_mem32(
+            &mut self,
+            addr: u32,
+            size: u32,
+            _: AccessPort,
+        ) -> Result<Vec<u8>, STLinkError> {
+            self.read_any(addr, size)
+        }
+
+        fn write_mem32(
+            &mut self,
+            addr: u32,
+            data: Vec<u8>,
+            _: AccessPort,
+        ) -> Result<(), STLinkError> {
+            self.write_any(addr, data)
+        }
+
+        fn read_mem16(
+            &mut self,
+            addr: u32,
+            size: u32,
+            _: AccessPort,
+        ) -> Result<Vec<u8>, STLinkError> {
+            self.read_any(addr, size)
+        }
+
+        fn write_mem16(
+            &mut self,
+            addr: u32,
+            data: Vec<u8>,
+            _: AccessPort,
+        ) -> Result<(), STLinkError> {
+            self.write_any(addr, data)
+        }
+
+        fn read_mem8(
+            &mut self,
+            addr: u32,
+            size: u32,
+            _: AccessPort,
+        ) -> Result<Vec<u8>, STLinkError> {
+            self.read_any(addr, size)
+        }
+
+        fn write_mem8(
+            &mut self,
+            addr: u32,
+            data: Vec<u8>,
+            _: AccessPort,
+        ) -> Result<(), STLinkError> {
+            self.write_any(addr, data)
+        }
+    }
+
+    /// Memory holding the given words, with reads of any other address faulting.
+    fn signature(words: &[(u32, u32)]) -> Memory {
+        let mut memory = Memory::faulting();
+        for &(addr, value) in words {
+            memory.set_word(addr, value);
+        }
+        memory
+    }
+
+    #[test]
+    fn sector_tables_cover_the_flash() {
+        for device in DEVICES {
+            let (start, size) = device.sector(device.sector_count() - 1).unwrap();
+            assert_eq!(start + size, device.flash_start + device.flash_size);
+            assert_eq!(device.sector_count() % device.banks, 0);
+        }
+        let device = find_device("stm32f407xg").unwrap();
+        assert_eq!(device.sector_index(0x0802_0000), Some(5));
+        assert_eq!(device.sector(4), Some((0x0801_0000, 64 * 1024)));
+    }
+
+    #[test]
+    fn erases_and_programs_sectors() {
+        let mut target = Simulated::new("STM32F407xG");
+        target.flash[0] = 0x12;
+        target.flash[0x4000] = 0x00;
+        let data: Vec<u8> = (0..301).map(|i| i as u8).collect();
+        let mut flash = Stm32Flash::new(&mut target, 0, target_device("STM32F407xG"));
+        flash.program_flash(0x0800_4002, &data).unwrap();
+        flash.lock().unwrap();
+
+        assert_eq!(target.read(0x0800_4002, 301), data);
+        assert_eq!(target.read(0x0800_4000, 2), vec![0xff, 0xff]);
+        // The sector in front was not erased.
+        assert_eq!(target.flash[0], 0x12);
+        assert!(target.locked[0]);
+    }
+
+    #[test]
+    fn erases_and_programs_pages() {
+        for &name in &["STM32F072xB", "STM32F103xB", "STM32F303xC", "STM32L053x8"] {
+            let mut target = Simulated::new(name);
+            let device = target.device;
+            let (start, size) = device.sector(2).unwrap();
+            let offset = (start - device.flash_start) as usize;
+            // Leftovers in the page in front and in both pages written.
+            for byte in &mut target.flash[offset - 1..offset + 2 * size as usize] {
+                *byte = 0x3c;
+            }
+            let data: Vec<u8> = (0..size + 7).map(|i| i as u8 | 1).collect();
+            let mut flash = Stm32Flash::new(&mut target, 0, device);
+            flash.program_flash(start + 1, &data).unwrap();
+            flash.lock().unwrap();
+
+            assert_eq!(target.read(start + 1, size + 7), data, "{}", name);
+            let erased = device.erased_value();
+            assert_eq!(target.read(start, 1), vec![erased], "{}", name);
+            assert_eq!(target.read(start + size + 8, 1), vec![erased], "{}", name);
+            assert_eq!(target.read(start - 1, 1), vec![0x3c], "{}", name);
+            assert!(target.locked[0], "{}", name);
+            assert_eq!(target.sr[0], 0, "{}", name);
+        }
+    }
+
+    #[test]
+    fn mass_erases_pages() {
+        for &name in &["STM32F103x8", "STM32L053x8"] {
+            let mut target = Simulated::new(name);
+            let device = target.device;
+            for byte in &mut target.flash {
+                *byte = 0x3c;
+            }
+            let mut flash = Stm32Flash::new(&mut target, 0, device);
+            flash.unlock().unwrap();
+            flash.mass_erase().unwrap();
+
+            let erased = device.erased_value();
+            assert!(target.flash.iter().all(|&byte| byte == erased), "{}", name);
+        }
+    }
+
+    #[test]
+    fn rejects_ranges_past_the_flash() {
+        let mut target = Simulated::new("STM32F103x8");
+        let mut flash = Stm32Flash::new(&mut target, 0, target_device("STM32F103x8"));
+        match flash.program_flash(0x0800_f000, &[0; 0x1001]) {
+            Err(Stm32FlashError::AddressOutOfRange(addr)) => assert_eq!(addr, 0x0801_0000),
+            result => panic!("unexpected result {:?}", result),
+        }
+        match flash.erase_range(0x0800_0100, u32::MAX) {
+            Err(Stm32FlashError::AddressOutOfRange(addr)) => assert_eq!(addr, 0x0801_0000),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn programs_across_banks() {
+        let mut target = Simulated::new("STM32H743xI");
+        for byte in &mut target.flash[0x0f_0000..0x11_0000] {
+            *byte = 0;
+        }
+        let data = vec![0x5a; 64];
+        let mut flash = Stm32Flash::new(&mut target, 0, target_device("STM32H743xI"));
+        flash.program_flash(0x080f_ffe0, &data).unwrap();
+
+        assert_eq!(target.read(0x080f_ffe0, 64), data);
+        // Both sectors next to the bank boundary were erased.
+        assert_eq!(target.read(0x080e_0000, 4), vec![0xff; 4]);
+        assert_eq!(target.read(0x0811_fffc, 4), vec![0xff; 4]);
+        assert!(!target.locked[0] && !target.locked[1]);
+    }
+
+    #[test]
+    fn skips_unchanged_sectors() {
+        let mut target = Simulated::new("STM32F407xG");
+        let mut segment = Segment {
+            address: 0x0800_0000,
+            data: (0..0x5000).map(|i| i as u8).collect(),
+        };
+        let device = target_device("STM32F407xG");
+        let mut flash = Stm32Flash::new(&mut target, 0, device);
+        let report = flash.program_segments(&[segment.clone()]).unwrap();
+        assert_eq!(report.sectors_written, 2);
+
+        segment.data[0x4100] ^= 0xff;
+        let report = flash.program_segments(&[segment.clone()]).unwrap();
+        assert_eq!(
+            report,
+            FlashReport {
+                sectors_written: 1,
+                sectors_skipped: 1,
+            }
+        );
+        assert_eq!(target.read(0x0800_4100, 1), vec![segment.data[0x4100]]);
+    }
+
+    #[test]
+    fn programs_units_shared_by_segments_once() {
+        let mut target = Simulated::new("STM32L476xG");
+        // Both segments fall into the first double-word.
+        let segments = [
+            Segment {
+                address: 0x0800_0000,
+                data: vec![1, 2, 3],
+            },
+            Segment {
+                address: 0x0800_0004,
+                data: vec![4, 5, 6, 7, 8, 9],
+            },
+        ];
+        let mut flash = Stm32Flash::new(&mut target, 0, target_device("STM32L476xG"));
+        flash.program_segments(&segments).unwrap();
+
+        assert_eq!(
+            target.read(0x0800_0000, 12),
+            vec![1, 2, 3, 0xff, 4, 5, 6, 7, 8, 9, 0xff, 0xff]
+        );
+    }
+
+    #[test]
+    fn reports_programming_errors() {
+        let mut target = Simulated::new("STM32L476xG");
+        target.flash[0x808] = 0;
+        let mut flash = Stm32Flash::new(&mut target, 0, target_device("STM32L476xG"));
+        flash.unlock().unwrap();
+        match flash.program(0x0800_0800, &[0; 16]) {
+            Err(Stm32FlashError::ProgrammingError(addr)) => assert_eq!(addr, 0x0800_0808),
+            result => panic!("unexpected result {:?}", result),
+        }
+        // The error flag was cleared again.
+        assert_eq!(target.sr[0], 0);
+    }
+
+    #[test]
+    fn decodes_and_edits_option_bytes() {
+        let mut options = OptionBytes {
+            family: Stm32Family::L4,
+            values: vec![
+                0xffef_f8aa,
+                0xffff_0000,
+                0x0000_0000,
+                0xff00_ffff,
+                0xff00_ffff,
+            ],
+        };
+        assert_eq!(options.get("RDP"), Some(0xaa));
+        assert_eq!(options.get("iwdg_sw"), Some(1));
+        assert_eq!(options.get("WRP1A_STRT"), Some(0xff));
+        assert_eq!(options.rdp_level(), RdpLevel::Level0);
+
+        let original = options.clone();
+        options.set("RDP", 0xbb).unwrap();
+        options.set("nRST_STOP", 0).unwrap();
+        assert!(options.set("BOR_LEV", 8).is_err());
+        assert!(options.set("nWRP", 0).is_err());
+        assert_eq!(options.values[0], 0xffef_e8bb);
+        assert_eq!(options.rdp_level(), RdpLevel::Level1);
+        assert_eq!(
+            original.diff(&options),
+            vec![("RDP", 0xaa, 0xbb), ("nRST_STOP", 1, 0)]
+        );
+    }
+
+    #[test]
+    fn programs_option_bytes() {
+        // Level 1 with the watchdog and reset bits set.
+        let mut target = Simulated::new("STM32F103x8");
+        target.memory.set_word(0x4002_201c, 0x1e);
+        target.memory.set_word(0x4002_2020, 0xffff_ffff);
+        let mut flash = Stm32Flash::new(&mut target, 0, target_device("STM32F103x8"));
+        let mut options = flash.read_option_bytes().unwrap();
+        assert_eq!(options.rdp_level(), RdpLevel::Level1);
+        options.set("RDPRT", 0).unwrap();
+        options.set("DATA0", 0x12).unwrap();
+        flash.write_option_bytes(&options).unwrap();
+        #[rustfmt::skip]
+        let expected = [
+            0xa5, 0x5a, 0x07, 0xf8, 0x12, 0xed, 0x00, 0xff,
+            0xff, 0x00, 0xff, 0x00, 0xff, 0x00, 0xff, 0x00,
+        ];
+        assert_eq!(target.memory.read(0x1fff_f800, 16), expected);
+        assert_eq!(target.sr[0], 0);
+
+        let mut target = Simulated::new("STM32L053x8");
+        target.memory.set_word(0x4002_201c, 0x8070_00aa);
+        target.memory.set_word(0x4002_2020, 0x0000_0003);
+        let mut flash = Stm32Flash::new(&mut target, 0, target_device("STM32L053x8"));
+        let mut options = flash.read_option_bytes().unwrap();
+        options.set("RDP", 0xbb).unwrap();
+        flash.write_option_bytes(&options).unwrap();
+        flash.reload_option_bytes().unwrap();
+        let words: Vec<_> = (0..4)
+            .map(|i| target.memory.word(0x1ff8_0000 + 4 * i))
+            .collect();
+        assert_eq!(
+            words,
+            vec![0xff44_00bb, 0x7f8f_8070, 0xfffc_0003, 0xffff_0000]
+        );
+        // OBL_LAUNCH
+        assert_ne!(target.cr[0] & 1 << 18, 0);
+    }
+
+    #[test]
+    fn decodes_readout_protection_levels() {
+        let level = |name: &str, obr: u32| {
+            let mut target = Simulated::new(name);
+            target.memory.set_word(0x4002_201c, obr);
+            let mut flash = Stm32Flash::new(&mut target, 0, target_device(name));
+            flash.readout_protection().unwrap().level()
+        };
+        assert_eq!(level("STM32F103x8", 0x1c), RdpLevel::Level0);
+        assert_eq!(level("STM32F103x8", 0x1e), RdpLevel::Level1);
+        assert_eq!(level("STM32F072xB", 0x2), RdpLevel::Level1);
+        assert_eq!(level("STM32F303xC", 0x6), RdpLevel::Level2);
+        assert_eq!(level("STM32L053x8", 0x8070_00aa), RdpLevel::Level0);
+        assert_eq!(level("STM32L053x8", 0x8070_0000), RdpLevel::Level1);
+        assert_eq!(level("STM32L053x8", 0x8070_00cc), RdpLevel::Level2);
+
+        // Level 1 shows as a faulting flash when the option bytes cannot be read.
+        let protection = ReadoutProtection {
+            configured: None,
+            flash_faults: true,
+        };
+        assert_eq!(protection.level(), RdpLevel::Level1);
+    }
+
+    #[test]
+    fn regresses_readout_protection() {
+        let mut target = Simulated::new("STM32F072xB");
+        target.memory.set_word(0x4002_201c, 0xff00_0f02);
+        target.memory.set_word(0x4002_2020, 0xffff_ffff);
+        let mut flash = Stm32Flash::new(&mut target, 0, target_device("STM32F072xB"));
+        flash.regress_readout_protection().unwrap();
+        assert_eq!(
+            target.memory.read(0x1fff_f800, 4),
+            vec![0xaa, 0x55, 0x0f, 0xf0]
+        );
+
+        let mut target = Simulated::new("STM32L053x8");
+        target.memory.set_word(0x4002_201c, 0x8070_0055);
+        let mut flash = Stm32Flash::new(&mut target, 0, target_device("STM32L053x8"));
+        flash.regress_readout_protection().unwrap();
+        assert_eq!(target.memory.word(0x1ff8_0000), 0xff55_00aa);
+
+        let mut target = Simulated::new("STM32F303xC");
+        target.memory.set_word(0x4002_201c, 0x6);
+        let mut flash = Stm32Flash::new(&mut target, 0, target_device("STM32F303xC"));
+        match flash.regress_readout_protection() {
+            Err(Stm32FlashError::PermanentlyProtected) => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn identifies_chips() {
+        let mut f407 = signature(&[
+            (0xe004_2000, 0x1007_6413),
+            (0x1fff_7a20, 0x0400_ffff),
+            (0x1fff_7a10, 0x0021_003a),
+            (0x1fff_7a14, 0x3436_5104),
+            (0x1fff_7a18, 0x3530_3238),
+        ]);
+        let info = identify(&mut f407, 0).unwrap().unwrap();
+        assert_eq!(info.part, Some("STM32F405/407/415/417"));
+        assert_eq!(info.revision, Some("1"));
+        assert_eq!(info.flash_size, Some(1024));
+        assert_eq!(
+            info.unique_id,
+            Some([0x0021_003a, 0x3436_5104, 0x3530_3238])
+        );
+
+        // The Cortex-M0+ parts have their DBGMCU on the APB.
+        let mut g071 = signature(&[(0x4001_5800, 0x2000_6460), (0x1fff_75e0, 0x0080)]);
+        let info = identify(&mut g071, 0).unwrap().unwrap();
+        assert_eq!(info.family, Some(Stm32Family::G0));
+        assert_eq!(info.revision, Some("B"));
+        assert_eq!(info.flash_size, Some(128));
+        assert_eq!(info.unique_id, None);
+
+        let mut unknown = signature(&[(0xe004_2000, 0x1000_0999)]);
+        let info = identify(&mut unknown, 0).unwrap().unwrap();
+        assert_eq!((info.dev_id, info.part), (0x999, None));
+        assert!(identify(&mut signature(&[]), 0).unwrap().is_none());
+    }
+
+    fn target_device(name: &str) -> &'static Stm32Device {
+        find_device(name).unwrap()
+    }
+}
@@ -0,0 +1,477 @@
+use std::time::Instant;
+
+use crate::stlink::STLinkError;
+use crate::swim::ByteMemory;
+use crate::usb_interface::TIMEOUT;
+
+/// The STM8 product lines, which differ in their flash controller layout.
+#[derive(Debug, PartialEq)]
+pub enum Stm8Family {
+    S,
+    L,
+}
+
+/// Memory layout of an STM8 part.
+#[derive(Debug)]
+pub struct Stm8Device {
+    pub name: &'static str,
+    pub family: Stm8Family,
+    pub flash_start: u32,
+    pub flash_size: u32,
+    pub eeprom_start: u32,
+    pub eeprom_size: u32,
+    pub block_size: u32,
+}
+
+/// Built-in table of common STM8S/STM8L parts.
+#[rustfmt::skip]
+pub const DEVICES: &[Stm8Device] = &[
+    Stm8Device { name: "STM8S001J3", family: Stm8Family::S, flash_start: 0x8000, flash_size: 8 * 1024, eeprom_start: 0x4000, eeprom_size: 128, block_size: 64 },
+    Stm8Device { name: "STM8S003F3", family: Stm8Family::S, flash_start: 0x8000, flash_size: 8 * 1024, eeprom_start: 0x4000, eeprom_size: 128, block_size: 64 },
+    Stm8Device { name: "STM8S003K3", family: Stm8Family::S, flash_start: 0x8000, flash_size: 8 * 1024, eeprom_start: 0x4000, eeprom_size: 128, block_size: 64 },
+    Stm8Device { name: "STM8S103F3", family: Stm8Family::S, flash_start: 0x8000, flash_size: 8 * 1024, eeprom_start: 0x4000, eeprom_size: 640, block_size: 64 },
+    Stm8Device { name: "STM8S105C6", family: Stm8Family::S, flash_start: 0x8000, flash_size: 32 * 1024, eeprom_start: 0x4000, eeprom_size: 1024, block_size: 128 },
+    Stm8Device { name: "STM8S105K4", family: Stm8Family::S, flash_start: 0x8000, flash_size: 16 * 1024, eeprom_start: 0x4000, eeprom_size: 1024, block_size: 128 },
+    Stm8Device { name: "STM8S207C8", family: Stm8Family::S, flash_start: 0x8000, flash_size: 64 * 1024, eeprom_start: 0x4000, eeprom_size: 1536, block_size: 128 },
+    Stm8Device { name: "STM8S207RB", family: Stm8Family::S, flash_start: 0x8000, flash_size: 128 * 1024, eeprom_start: 0x4000, eeprom_size: 2048, block_size: 128 },
+    Stm8Device { name: "STM8S208RB", family: Stm8Family::S, flash_start: 0x8000, flash_size: 128 * 1024, eeprom_start: 0x4000, eeprom_size: 2048, block_size: 128 },
+    Stm8Device { name: "STM8L050J3", family: Stm8Family::L, flash_start: 0x8000, flash_size: 8 * 1024, eeprom_start: 0x1000, eeprom_size: 256, block_size: 64 },
+    Stm8Device { name: "STM8L051F3", family: Stm8Family::L, flash_start: 0x8000, flash_size: 8 * 1024, eeprom_start: 0x1000, eeprom_size: 256, block_size: 64 },
+    Stm8Device { name: "STM8L151C8", family: Stm8Family::L, flash_start: 0x8000, flash_size: 64 * 1024, eeprom_start: 0x1000, eeprom_size: 2048, block_size: 128 },
+    Stm8Device { name: "STM8L152C6", family: Stm8Family::L, flash_start: 0x8000, flash_size: 32 * 1024, eeprom_start: 0x1000, eeprom_size: 1024, block_size: 128 },
+    Stm8Device { name: "STM8L152R8", family: Stm8Family::L, flash_start: 0x8000, flash_size: 64 * 1024, eeprom_start: 0x1000, eeprom_size: 2048, block_size: 128 },
+];
+
+/// Looks up a device in the built-in table by its (case insensitive) name.
+pub fn find_device(name: &str) -> Option<&'static Stm8Device> {
+    DEVICES
+        .iter()
+        .find(|device| device.name.eq_ignore_ascii_case(name))
+}
+
+#[derive(Debug)]
+pub enum Stm8FlashError {
+    STLink(STLinkError),
+    AddressOutOfRange(u32),
+    WriteProtected(u32),
+    ProgrammingTimeout,
+    VerificationFailed(u32),
+}
+
+impl From<STLinkError> for Stm8FlashError {
+    fn from(e: STLinkError) -> Self {
+        Stm8FlashError::STLink(e)
+    }
+}
+
+/// Addresses of the flash controller registers of a family.
+struct FlashRegisters {
+    cr2: u32,
+    ncr2: Option<u32>,
+    iapsr: u32,
+    pukr: u32,
+    dukr: u32,
+}
+
+const STM8S_FLASH: FlashRegisters = FlashRegisters {
+    cr2: 0x505b,
+    ncr2: Some(0x505c),
+    iapsr: 0x505f,
+    pukr: 0x5062,
+    dukr: 0x5064,
+};
+
+const STM8L_FLASH: FlashRegisters = FlashRegisters {
+    cr2: 0x5051,
+    ncr2: None,
+    iapsr: 0x5054,
+    pukr: 0x5052,
+    dukr: 0x5053,
+};
+
+/// Programs the non-volatile memories of an STM8 target attached over SWIM.
+pub struct Stm8Flash<'m, M: ByteMemory> {
+    memory: &'m mut M,
+    device: &'static Stm8Device,
+}
+
+impl<'m, M: ByteMemory> Stm8Flash<'m, M> {
+    /// Start of the option bytes.
+    pub const OPTION_BYTES_START: u32 = 0x4800;
+
+    /// Address of the readout protection option byte.
+    const ROP: u32 = 0x4800;
+
+    /// Readout protection values. STM8S parts are protected by writing `0xaa`,
+    /// STM8L parts are unprotected as long as ROP reads `0xaa`.
+    const ROP_MAGIC: u8 = 0xaa;
+
+    /// Bits of FLASH_CR2.
+    const CR2_PRG: u8 = 0x01;
+    const CR2_OPT: u8 = 0x80;
+
+    /// Bits of FLASH_IAPSR.
+    const IAPSR_WR_PG_DIS: u8 = 0x01;
+    const IAPSR_PUL: u8 = 0x02;
+    const IAPSR_EOP: u8 = 0x04;
+    const IAPSR_DUL: u8 = 0x08;
+
+    /// Unlock keys for the program memory (FLASH_PUKR) and the data memory (FLASH_DUKR).
+    const PUKR_KEYS: [u8; 2] = [0x56, 0xae];
+    const DUKR_KEYS: [u8; 2] = [0xae, 0x56];
+
+    pub fn new(memory: &'m mut M, device: &'static Stm8Device) -> Self {
+        Self { memory, device }
+    }
+
+    fn registers(&self) -> &'static FlashRegisters {
+        match self.device.family {
+            Stm8Family::S => &STM8S_FLASH,
+            Stm8Family::L => &STM8L_FLASH,
+        }
+    }
+
+    /// Unlocks the program memory through the FLASH_PUKR key sequence.
+    pub fn unlock_program_memory(&mut self) -> Result<(), Stm8FlashError> {
+        let pukr = self.registers().pukr;
+        self.unlock(pukr, Self::PUKR_KEYS, Self::IAPSR_PUL)
+    }
+
+    /// Unlocks the data EEPROM and the option bytes through the FLASH_DUKR key sequence.
+    pub fn unlock_data_memory(&mut self) -> Result<(), Stm8FlashError> {
+        let dukr = self.registers().dukr;
+        self.unlock(dukr, Self::DUKR_KEYS, Self::IAPSR_DUL)
+    }
+
+    /// Locks both the program and the data memory again.
+    pub fn lock(&mut self) -> Result<(), Stm8FlashError> {
+        let iapsr = self.registers().iapsr;
+        let status = self.read_register(iapsr)?;
+        self.memory
+            .write_mem(iapsr, vec![status & !(Self::IAPSR_PUL | Self::IAPSR_DUL)])?;
+        Ok(())
+    }
+
+    fn unlock(&mut self, key_register: u32, keys: [u8; 2], flag: u8) -> Result<(), Stm8FlashError> {
+        let iapsr = self.registers().iapsr;
+        if self.read_register(iapsr)? & flag != 0 {
+            return Ok(());
+        }
+        self.memory.write_mem(key_register, vec![keys[0]])?;
+        self.memory.write_mem(key_register, vec![keys[1]])?;
+        if self.read_register(iapsr)? & flag == 0 {
+            return Err(Stm8FlashError::WriteProtected(key_register));
+        }
+        Ok(())
+    }
+
+    /// Programs `data` into the program memory at `addr` and verifies it.
+    pub fn program_flash(&mut self, addr: u32, data: &[u8]) -> Result<(), Stm8FlashError> {
+        self.check_range(
+            addr,
+            data.len() as u32,
+            self.device.flash_start,
+            self.device.flash_size,
+        )?;
+        self.unlock_program_memory()?;
+        self.program_blocks(addr, data)?;
+        self.verify(addr, data)
+    }
+
+    /// Programs `data` into the data EEPROM at `addr` and verifies it.
+    pub fn program_eeprom(&mut self, addr: u32, data: &[u8]) -> Result<(), Stm8FlashError> {
+        self.check_range(
+            addr,
+            data.len() as u32,
+            self.device.eeprom_start,
+            self.device.eeprom_size,
+        )?;
+        self.unlock_data_memory()?;
+        self.program_blocks(addr, data)?;
+        self.verify(addr, data)
+    }
+
+    /// Writes a single option byte.
+    /// On STM8S parts every option byte but ROP is followed by its complement,
+    /// which is written as well.
+    pub fn write_option_byte(&mut self, addr: u32, value: u8) -> Result<(), Stm8FlashError> {
+        self.unlock_data_memory()?;
+        self.set_cr2(Self::CR2_OPT)?;
+        let mut data = vec![value];
+        if self.device.family == Stm8Family::S && addr != Self::ROP {
+            data.push(!value);
+        }
+        self.memory.write_mem(addr, data.clone())?;
+        let result = self.wait_for_completion(addr);
+        self.set_cr2(0)?;
+        result?;
+        self.verify(addr, &data)
+    }
+
+    /// Enables or disables the readout protection.
+    /// Disabling the protection of a protected part erases the program memory.
+    pub fn set_readout_protection(&mut self, enabled: bool) -> Result<(), Stm8FlashError> {
+        let value = match self.device.family {
+            Stm8Family::S if enabled => Self::ROP_MAGIC,
+            Stm8Family::S => 0x00,
+            Stm8Family::L if enabled => 0x00,
+            Stm8Family::L => Self::ROP_MAGIC,
+        };
+        self.write_option_byte(Self::ROP, value)
+    }
+
+    /// Reads back `data.len()` bytes from `addr` and compares them with `data`.
+    pub fn verify(&mut self, addr: u32, data: &[u8]) -> Result<(), Stm8FlashError> {
+        let readback = self.memory.read_mem(addr, data.len() as u32)?;
+        match readback.iter().zip(data.iter()).position(|(a, b)| a != b) {
+            Some(offset) => Err(Stm8FlashError::VerificationFailed(addr + offset as u32)),
+            None => Ok(()),
+        }
+    }
+
+    /// Programs whole blocks covering `data`.
+    /// Partially covered blocks are filled up with their current contents.
+    fn program_blocks(&mut self, addr: u32, data: &[u8]) -> Result<(), Stm8FlashError> {
+        let block_size = self.device.block_size;
+        let end = addr + data.len() as u32;
+        let mut block_start = addr - addr % block_size;
+
+        while block_start < end {
+            let block_end = block_start + block_size;
+            let mut block = if block_start < addr || block_end > end {
+                self.memory.read_mem(block_start, block_size)?
+            } else {
+                vec![0; block_size as usize]
+            };
+
+            let copy_start = u32::max(block_start, addr);
+            let copy_end = u32::min(block_end, end);
+            block[(copy_start - block_start) as usize..(copy_end - block_start) as usize]
+                .copy_from_slice(&data[(copy_start - addr) as usize..(copy_end - addr) as usize]);
+
+            self.set_cr2(Self::CR2_PRG)?;
+            self.memory.write_mem(block_start, block)?;
+            self.wait_for_completion(block_start)?;
+
+            block_start = block_end;
+        }
+        Ok(())
+    }
+
+    /// Writes FLASH_CR2 and, on STM8S parts, its complement FLASH_NCR2.
+    fn set_cr2(&mut self, value: u8) -> Result<(), Stm8FlashError> {
+        let registers = self.registers();
+        self.memory.write_mem(registers.cr2, vec![value])?;
+        if let Some(ncr2) = registers.ncr2 {
+            self.memory.write_mem(ncr2, vec![!value])?;
+        }
+        Ok(())
+    }
+
+    /// Polls FLASH_IAPSR until the programming operation at `addr` has ended.
+    fn wait_for_completion(&mut self, addr: u32) -> Result<(), Stm8FlashError> {
+        let iapsr = self.registers().iapsr;
+        let start = Instant::now();
+        loop {
+            let status = self.read_register(iapsr)?;
+            if status & Self::IAPSR_WR_PG_DIS != 0 {
+                return Err(Stm8FlashError::WriteProtected(addr));
+            }
+            if status & Self::IAPSR_EOP != 0 {
+                return Ok(());
+            }
+            if start.elapsed() > TIMEOUT {
+                return Err(Stm8FlashError::ProgrammingTimeout);
+            }
+        }
+    }
+
+    fn read_register(&mut self, addr: u32) -> Result<u8, Stm8FlashError> {
+        Ok(self.memory.read_mem(addr, 1)?[0])
+    }
+
+    fn check_range(
+        &self,
+        addr: u32,
+        size: u32,
+        start: u32,
+        length: u32,
+    ) -> Result<(), Stm8FlashError> {
+        if addr < start {
+            return Err(Stm8FlashError::AddressOutOfRange(addr));
+        }
+        match addr.checked_add(size) {
+            Some(end) if end <= start + length => Ok(()),
+            _ => Err(Stm8FlashError::AddressOutOfRange(start + length)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The FLASH controller of an STM8S or STM8L in front of 64 kB of memory.
+    struct Simulated {
+        registers: &'static FlashRegisters,
+        memory: Vec<u8>,
+        keys: Vec<(u32, u8)>,
+        iapsr: u8,
+        cr2: u8,
+        ncr2: u8,
+        /// The start of each block programmed with FLASH_CR2.PRG.
+        blocks: Vec<u32>,
+    }
+
+    type Flash<'m> = Stm8Flash<'m, Simulated>;
+
+    impl Simulated {
+        fn new(device: &Stm8Device) -> Self {
+            Self {
+                registers: match device.family {
+                    Stm8Family::S => &STM8S_FLASH,
+                    Stm8Family::L => &STM8L_FLASH,
+                },
+                memory: vec![0; 0x10000],
+                keys: vec![],
+                iapsr: 0,
+                cr2: 0,
+                ncr2: 0xff,
+                blocks: vec![],
+            }
+        }
+
+        fn unlock(&mut self, register: u32, keys: [u8; 2], flag: u8) {
+            let written: Vec<u8> = self
+                .keys
+                .iter()
+                .filter(|(key_register, _)| *key_register == register)
+                .map(|&(_, key)| key)
+                .collect();
+            if written.ends_with(&keys) {
+                self.iapsr |= flag;
+            }
+        }
+
+        fn program(&mut self, addr: u32, data: Vec<u8>) {
+            let unlocked = if addr >= 0x8000 {
+                Flash::IAPSR_PUL
+            } else {
+                Flash::IAPSR_DUL
+            };
+            // The STM8S ignores FLASH_CR2 unless FLASH_NCR2 holds its complement.
+            let cr2 = match self.registers.ncr2 {
+                Some(_) if self.cr2 != !self.ncr2 => 0,
+                _ => self.cr2,
+            };
+            if self.iapsr & unlocked == 0 {
+                self.iapsr |= Flash::IAPSR_WR_PG_DIS;
+                return;
+            }
+            if cr2 & Flash::CR2_PRG != 0 {
+                self.blocks.push(addr);
+            }
+            self.memory[addr as usize..addr as usize + data.len()].copy_from_slice(&data);
+            self.iapsr |= Flash::IAPSR_EOP;
+        }
+    }
+
+    impl ByteMemory for Simulated {
+        fn read_mem(&mut self, addr: u32, size: u32) -> Result<Vec<u8>, STLinkError> {
+            if addr == self.registers.iapsr {
+                let status = self.iapsr;
+                // EOP and WR_PG_DIS are cleared by reading FLASH_IAPSR.
+                self.iapsr &= !(Flash::IAPSR_EOP | Flash::IAPSR_WR_PG_DIS);
+                return Ok(vec![status]);
+            }
+            Ok(self.memory[addr as usize..(addr + size) as usize].to_vec())
+        }
+
+        fn write_mem(&mut self, addr: u32, data: Vec<u8>) -> Result<(), STLinkError> {
+            let registers = self.registers;
+            if addr == registers.pukr || addr == registers.dukr {
+                self.keys.push((addr, data[0]));
+                self.unlock(registers.pukr, Flash::PUKR_KEYS, Flash::IAPSR_PUL);
+                self.unlock(registers.dukr, Flash::DUKR_KEYS, Flash::IAPSR_DUL);
+            } else if addr == registers.iapsr {
+                self.iapsr &= data[0] | !(Flash::IAPSR_PUL | Flash::IAPSR_DUL);
+            } else if addr == registers.cr2 {
+                self.cr2 = data[0];
+            } else if Some(addr) == registers.ncr2 {
+                self.ncr2 = data[0];
+            } else {
+                self.program(addr, data);
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn fills_partially_covered_blocks() {
+        for name in &["STM8S103F3", "STM8L051F3"] {
+            let device = find_device(name).unwrap();
+            let mut target = Simulated::new(device);
+            for byte in &mut target.memory[0x8000..0x8080] {
+                *byte = 0x11;
+            }
+
+            let mut flash = Stm8Flash::new(&mut target, device);
+            flash.program_flash(0x8030, &[0xaa; 0x20]).unwrap();
+            flash.lock().unwrap();
+
+            assert_eq!(target.blocks, vec![0x8000, 0x8040]);
+            assert_eq!(&target.memory[0x8000..0x8030], &[0x11; 0x30][..]);
+            assert_eq!(&target.memory[0x8030..0x8050], &[0xaa; 0x20][..]);
+            assert_eq!(&target.memory[0x8050..0x8080], &[0x11; 0x30][..]);
+            assert_eq!(target.iapsr & (Flash::IAPSR_PUL | Flash::IAPSR_DUL), 0);
+        }
+    }
+
+    #[test]
+    fn writes_option_byte_complements_on_stm8s() {
+        let device = find_device("STM8S103F3").unwrap();
+        let mut target = Simulated::new(device);
+        let mut flash = Stm8Flash::new(&mut target, device);
+        flash.write_option_byte(0x4803, 0x12).unwrap();
+        assert_eq!(&target.memory[0x4803..0x4805], &[0x12, 0xed]);
+        assert!(target.blocks.is_empty());
+
+        let device = find_device("STM8L051F3").unwrap();
+        let mut target = Simulated::new(device);
+        let mut flash = Stm8Flash::new(&mut target, device);
+        flash.write_option_byte(0x4803, 0x12).unwrap();
+        assert_eq!(&target.memory[0x4803..0x4805], &[0x12, 0x00]);
+    }
+
+    #[test]
+    fn writes_readout_protection() {
+        let rop = |name: &str, enabled: bool| {
+            let device = find_device(name).unwrap();
+            let mut target = Simulated::new(device);
+            let mut flash = Stm8Flash::new(&mut target, device);
+            flash.set_readout_protection(enabled).unwrap();
+            // ROP has no complement, not even on the STM8S.
+            target.memory[0x4800..0x4802].to_vec()
+        };
+        assert_eq!(rop("STM8S103F3", true), vec![0xaa, 0x00]);
+        assert_eq!(rop("STM8S103F3", false), vec![0x00, 0x00]);
+        assert_eq!(rop("STM8L051F3", true), vec![0x00, 0x00]);
+        assert_eq!(rop("STM8L051F3", false), vec![0xaa, 0x00]);
+    }
+
+    #[test]
+    fn rejects_ranges_outside_the_memory() {
+        let device = find_device("STM8S103F3").unwrap();
+        let mut target = Simulated::new(device);
+        let mut flash = Stm8Flash::new(&mut target, device);
+        match flash.program_flash(0x9ff0, &[0; 0x20]) {
+            Err(Stm8FlashError::AddressOutOfRange(0xa000)) => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+        match flash.program_eeprom(0x3fff, &[0]) {
+            Err(Stm8FlashError::AddressOutOfRange(0x3fff)) => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert!(target.blocks.is_empty());
+    }
+}
@@ -0,0 +1,234 @@
+use std::time::Instant;
+
+use probe_rs::debug_probe::DebugProbe;
+
+use crate::constants::commands;
+use crate::stlink::{STLink, STLinkError};
+use crate::usb_interface::{STLinkUSBDevice, TIMEOUT};
+
+/// Byte-wise access to the memory of an STM8 target.
+///
+/// This is implemented by `Swim` and allows the STM8 flash driver to run
+/// against a simulated flash controller as well.
+pub trait ByteMemory {
+    fn read_mem(&mut self, addr: u32, size: u32) -> Result<Vec<u8>, STLinkError>;
+    fn write_mem(&mut self, addr: u32, data: Vec<u8>) -> Result<(), STLinkError>;
+}
+
+/// The SWIM communication speeds supported by STM8 targets.
+pub enum SwimSpeed {
+    Low = 0,
+    High = 1,
+}
+
+/// A session with an STM8 target attached over SWIM.
+pub struct Swim<'a> {
+    link: STLink<'a>,
+    buffer_size: u16,
+}
+
+impl<'a> Swim<'a> {
+    /// Address of the SWIM control status register.
+    const SWIM_CSR: u32 = 0x7f80;
+
+    /// Bits of the SWIM control status register.
+    const CSR_SAFE_MASK: u8 = 0x80;
+    const CSR_SWIM_DM: u8 = 0x20;
+    const CSR_HS: u8 = 0x10;
+
+    /// Number of data bytes which fit into a SWIM_WRITEMEM command block.
+    const CMD_INLINE_DATA: usize = 8;
+
+    pub fn new(device: STLinkUSBDevice<'a>) -> Self {
+        Self {
+            link: STLink::new(device),
+            buffer_size: 0,
+        }
+    }
+
+    /// Opens the ST-Link and puts it into SWIM mode.
+    pub fn open(&mut self) -> Result<(), STLinkError> {
+        self.link.open()?;
+        self.link.device.write(
+            vec![commands::SWIM_COMMAND, commands::SWIM_ENTER],
+            &[],
+            &mut [],
+            TIMEOUT,
+        )?;
+        self.buffer_size = self.read_buffer_size()?;
+        Ok(())
+    }
+
+    /// Leaves SWIM mode and closes the ST-Link.
+    pub fn close(&mut self) -> Result<(), STLinkError> {
+        self.link.close()
+    }
+
+    /// Activates SWIM on the target.
+    /// The target is held in reset while the entry sequence is sent and
+    /// the debug module takes over before the reset is released.
+    pub fn attach(&mut self) -> Result<(), STLinkError> {
+        self.assert_reset()?;
+        self.command(vec![commands::SWIM_COMMAND, commands::SWIM_ENTER_SEQ])?;
+        self.write_mem(
+            Self::SWIM_CSR,
+            vec![Self::CSR_SAFE_MASK | Self::CSR_SWIM_DM],
+        )?;
+        self.deassert_reset()
+    }
+
+    /// Switches both the target and the ST-Link to the given SWIM speed.
+    pub fn set_speed(&mut self, speed: SwimSpeed) -> Result<(), STLinkError> {
+        let speed = speed as u8;
+        let mut csr = Self::CSR_SAFE_MASK | Self::CSR_SWIM_DM;
+        if speed == SwimSpeed::High as u8 {
+            csr |= Self::CSR_HS;
+        }
+        self.write_mem(Self::SWIM_CSR, vec![csr])?;
+        self.command(vec![commands::SWIM_COMMAND, commands::SWIM_SPEED, speed])
+    }
+
+    /// Drives the target reset line low.
+    pub fn assert_reset(&mut self) -> Result<(), STLinkError> {
+        self.command(vec![commands::SWIM_COMMAND, commands::SWIM_ASSERT_RESET])
+    }
+
+    /// Releases the target reset line.
+    pub fn deassert_reset(&mut self) -> Result<(), STLinkError> {
+        self.command(vec![commands::SWIM_COMMAND, commands::SWIM_DEASSERT_RESET])
+    }
+
+    /// Sends a SWIM SRST command, which resets the target without touching the reset line.
+    pub fn generate_reset(&mut self) -> Result<(), STLinkError> {
+        self.command(vec![commands::SWIM_COMMAND, commands::SWIM_GEN_RST])
+    }
+
+    /// Resynchronizes the SWIM line with the target.
+    pub fn resync(&mut self) -> Result<(), STLinkError> {
+        self.command(vec![commands::SWIM_COMMAND, commands::SWIM_RESET])
+    }
+
+    /// Reads `size` bytes from the target starting at `addr`.
+    pub fn read_mem(&mut self, mut addr: u32, mut size: u32) -> Result<Vec<u8>, STLinkError> {
+        let buffer_size = self.transfer_limit()?;
+        let mut result = Vec::with_capacity(size as usize);
+        while size > 0 {
+            let transfer_size = u32::min(size, buffer_size);
+
+            let cmd = vec![
+                commands::SWIM_COMMAND,
+                commands::SWIM_READMEM,
+                (transfer_size >> 8) as u8,
+                transfer_size as u8,
+                (addr >> 24) as u8,
+                (addr >> 16) as u8,
+                (addr >> 8) as u8,
+                addr as u8,
+            ];
+            self.command(cmd)?;
+
+            let mut buf = vec![0; transfer_size as usize];
+            self.link.device.write(
+                vec![commands::SWIM_COMMAND, commands::SWIM_READBUF],
+                &[],
+                buf.as_mut_slice(),
+                TIMEOUT,
+            )?;
+            result.extend(buf.into_iter());
+
+            addr += transfer_size;
+            size -= transfer_size;
+        }
+        Ok(result)
+    }
+
+    /// Writes `data` to the target starting at `addr`.
+    pub fn write_mem(&mut self, mut addr: u32, mut data: Vec<u8>) -> Result<(), STLinkError> {
+        let buffer_size = self.transfer_limit()? as usize;
+        while data.len() > 0 {
+            let transfer_size = usize::min(data.len(), buffer_size);
+            // The first bytes travel in the command block, the rest in the data phase.
+            let inline_size = usize::min(transfer_size, Self::CMD_INLINE_DATA);
+
+            let mut cmd = vec![
+                commands::SWIM_COMMAND,
+                commands::SWIM_WRITEMEM,
+                (transfer_size >> 8) as u8,
+                transfer_size as u8,
+                (addr >> 24) as u8,
+                (addr >> 16) as u8,
+                (addr >> 8) as u8,
+                addr as u8,
+            ];
+            cmd.extend_from_slice(&data[0..inline_size]);
+            self.link
+                .device
+                .write(cmd, &data[inline_size..transfer_size], &mut [], TIMEOUT)?;
+            self.wait_for_status()?;
+
+            addr += transfer_size as u32;
+            data.drain(..transfer_size);
+        }
+        Ok(())
+    }
+
+    /// Returns how many bytes a single transfer can move.
+    /// A zero buffer size, before `open` or reported by the probe, would never make progress.
+    fn transfer_limit(&self) -> Result<u32, STLinkError> {
+        match self.buffer_size {
+            0 => Err(STLinkError::SwimNoBuffer),
+            size => Ok(size as u32),
+        }
+    }
+
+    /// Reads the size of the SWIM buffer of the ST-Link.
+    fn read_buffer_size(&mut self) -> Result<u16, STLinkError> {
+        let mut buf = [0; 2];
+        self.link.device.write(
+            vec![commands::SWIM_COMMAND, commands::SWIM_READ_BUFFERSIZE],
+            &[],
+            &mut buf,
+            TIMEOUT,
+        )?;
+        Ok(buf[0] as u16 | (buf[1] as u16) << 8)
+    }
+
+    /// Sends a SWIM command without a data phase and waits for it to complete.
+    fn command(&mut self, cmd: Vec<u8>) -> Result<(), STLinkError> {
+        self.link.device.write(cmd, &[], &mut [], TIMEOUT)?;
+        self.wait_for_status()
+    }
+
+    /// Polls the SWIM status until the last command has finished.
+    fn wait_for_status(&mut self) -> Result<(), STLinkError> {
+        let start = Instant::now();
+        loop {
+            let mut buf = [0; 4];
+            self.link.device.write(
+                vec![commands::SWIM_COMMAND, commands::SWIM_READSTATUS],
+                &[],
+                &mut buf,
+                TIMEOUT,
+            )?;
+            match buf[0] {
+                commands::SWIM_OK => return Ok(()),
+                commands::SWIM_BUSY => {
+                    if start.elapsed() > TIMEOUT {
+                        return Err(STLinkError::SwimBusyTimeout);
+                    }
+                }
+                status => return Err(STLinkError::SwimError(status)),
+            }
+        }
+    }
+}
+
+impl<'a> ByteMemory for Swim<'a> {
+    fn read_mem(&mut self, addr: u32, size: u32) -> Result<Vec<u8>, STLinkError> {
+        Swim::read_mem(self, addr, size)
+    }
+
+    fn write_mem(&mut self, addr: u32, data: Vec<u8>) -> Result<(), STLinkError> {
+        Swim::write_mem(self, addr, data)
+    }
+}
@@ -0,0 +1,150 @@
+use std::collections::VecDeque;
+
+use crate::stlink::{STLink, STLinkError};
+
+/// Returns the divider of `trace_clk` which gets closest to the SWO baud rate `baud`.
+/// Returns `None` if `baud` is zero or more than twice `trace_clk`.
+pub fn swo_divider(trace_clk: u32, baud: u32) -> Option<u32> {
+    if baud == 0 {
+        return None;
+    }
+    match (trace_clk as u64 + baud as u64 / 2) / baud as u64 {
+        0 => None,
+        divider => Some(divider as u32),
+    }
+}
+
+/// Describes SWV data that got lost since the last check.
+#[derive(Debug, Default, PartialEq)]
+pub struct SwvOverflow {
+    /// The trace buffer of the ST-Link was full, so an unknown amount of data was lost.
+    pub probe: bool,
+    /// Number of bytes dropped because the ring buffer was full.
+    pub dropped: usize,
+}
+
+/// Drains the SWV endpoint of an ST-Link into a ring buffer.
+///
+/// Call `poll` regularly while the trace reception is running.
+/// When the ring buffer fills up, the oldest bytes are dropped.
+pub struct SwvReader {
+    buffer: VecDeque<u8>,
+    capacity: usize,
+    overflow: SwvOverflow,
+}
+
+impl SwvReader {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            buffer: VecDeque::with_capacity(capacity),
+            capacity,
+            overflow: SwvOverflow::default(),
+        }
+    }
+
+    /// Fetches all SWV bytes the ST-Link has buffered.
+    /// Returns the number of bytes received.
+    pub fn poll(&mut self, link: &mut STLink) -> Result<usize, STLinkError> {
+        let available = link.swv_bytes_available()?;
+        if available == 0 {
+            return Ok(0);
+        }
+        if available >= STLink::SWV_BUFFER_SIZE {
+            self.overflow.probe = true;
+        }
+        let data = link.read_swv(available as usize)?;
+        self.push(&data);
+        Ok(data.len())
+    }
+
+    fn push(&mut self, data: &[u8]) {
+        for &byte in data {
+            if self.buffer.len() == self.capacity {
+                self.buffer.pop_front();
+                self.overflow.dropped += 1;
+            }
+            self.buffer.push_back(byte);
+        }
+    }
+
+    /// Number of bytes waiting in the ring buffer.
+    pub fn len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    /// Moves up to `buf.len()` bytes out of the ring buffer into `buf`.
+    /// Returns the number of bytes copied.
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        let count = usize::min(buf.len(), self.buffer.len());
+        for (dst, src) in buf.iter_mut().zip(self.buffer.drain(..count)) {
+            *dst = src;
+        }
+        count
+    }
+
+    /// Takes all bytes out of the ring buffer.
+    pub fn drain(&mut self) -> Vec<u8> {
+        self.buffer.drain(..).collect()
+    }
+
+    /// Returns the data loss since the last call, if any.
+    pub fn take_overflow(&mut self) -> Option<SwvOverflow> {
+        if self.overflow == SwvOverflow::default() {
+            None
+        } else {
+            Some(std::mem::replace(
+                &mut self.overflow,
+                SwvOverflow::default(),
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_the_closest_divider() {
+        assert_eq!(swo_divider(72_000_000, 2_000_000), Some(36));
+        assert_eq!(swo_divider(72_000_000, 2_250_000), Some(32));
+        assert_eq!(swo_divider(16_000_000, 115_200), Some(139));
+        assert_eq!(swo_divider(8_000_000, 24_000_000), None);
+        assert_eq!(swo_divider(8_000_000, 0), None);
+    }
+
+    #[test]
+    fn reads_in_order() {
+        let mut reader = SwvReader::new(8);
+        reader.push(&[1, 2, 3, 4, 5]);
+        let mut buf = [0; 3];
+        assert_eq!(reader.read(&mut buf), 3);
+        assert_eq!(buf, [1, 2, 3]);
+        reader.push(&[6]);
+        assert_eq!(reader.len(), 3);
+        assert_eq!(reader.drain(), [4, 5, 6]);
+        assert!(reader.is_empty());
+        assert_eq!(reader.read(&mut buf), 0);
+        assert_eq!(reader.take_overflow(), None);
+    }
+
+    #[test]
+    fn drops_the_oldest_bytes_when_full() {
+        let mut reader = SwvReader::new(4);
+        reader.push(&[1, 2, 3]);
+        reader.push(&[4, 5, 6]);
+        assert_eq!(reader.drain(), [3, 4, 5, 6]);
+        assert_eq!(
+            reader.take_overflow(),
+            Some(SwvOverflow {
+                probe: false,
+                dropped: 2,
+            })
+        );
+        assert_eq!(reader.take_overflow(), None);
+    }
+}
@@ -0,0 +1,310 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use goblin::elf::{sym, Elf};
+
+#[derive(Debug)]
+pub enum SymbolError {
+    IO(std::io::Error),
+    Elf(goblin::error::Error),
+    Svd(roxmltree::Error),
+    /// A section reaches past the end of the ELF file.
+    TruncatedSection,
+}
+
+/// A symbol of the firmware ELF.
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    /// The demangled name.
+    pub name: String,
+    pub address: u32,
+    pub size: u32,
+}
+
+/// The symbol table of a firmware ELF.
+pub struct Symbols {
+    /// Function symbols sorted by address.
+    functions: Vec<Symbol>,
+    /// All named symbols by their raw name.
+    by_name: HashMap<String, Symbol>,
+}
+
+impl Symbols {
+    /// Reads the symbol table of the ELF at `path`.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, SymbolError> {
+        let data = std::fs::read(path).or_else(|e| Err(SymbolError::IO(e)))?;
+        Self::from_elf(&data)
+    }
+
+    /// Reads the symbol table from the contents of an ELF file.
+    pub fn from_elf(data: &[u8]) -> Result<Self, SymbolError> {
+        let elf = Elf::parse(data).or_else(|e| Err(SymbolError::Elf(e)))?;
+
+        let mut functions = vec![];
+        let mut by_name = HashMap::new();
+        for symbol in elf.syms.iter() {
+            let raw_name = match elf.strtab.get(symbol.st_name) {
+                Some(Ok(name)) if !name.is_empty() => name,
+                _ => continue,
+            };
+            let is_function = symbol.st_type() == sym::STT_FUNC;
+            // Thumb functions have the LSB of their address set.
+            let address = if is_function {
+                symbol.st_value as u32 & !1
+            } else {
+                symbol.st_value as u32
+            };
+            let symbol = Symbol {
+                name: format!("{:#}", rustc_demangle::demangle(raw_name)),
+                address,
+                size: symbol.st_size as u32,
+            };
+            if is_function {
+                functions.push(symbol.clone());
+            }
+            by_name.insert(raw_name.to_owned(), symbol);
+        }
+        functions.sort_by_key(|symbol| symbol.address);
+
+        Ok(Self { functions, by_name })
+    }
+
+    /// Returns the function containing `address`.
+    pub fn function_at(&self, address: u32) -> Option<&Symbol> {
+        let index = match self
+            .functions
+            .binary_search_by_key(&address, |symbol| symbol.address)
+        {
+            Ok(index) => index,
+            Err(0) => return None,
+            Err(index) => index - 1,
+        };
+        let symbol = &self.functions[index];
+        // A function may end at the top of the address space.
+        if (address as u64) < symbol.address as u64 + u32::max(symbol.size, 1) as u64 {
+            Some(symbol)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the symbol with the given raw (mangled) name.
+    pub fn get(&self, name: &str) -> Option<&Symbol> {
+        self.by_name.get(name)
+    }
+
+    /// Names `address` as `function+offset`, or as plain hex if no function contains it.
+    pub fn describe(&self, address: u32) -> String {
+        match self.function_at(address) {
+            Some(symbol) if symbol.address == address => symbol.name.clone(),
+            Some(symbol) => format!("{}+0x{:x}", symbol.name, address - symbol.address),
+            None => format!("0x{:08x}", address),
+        }
+    }
+}
+
+/// ELF files for the tests of the modules reading firmware ELFs.
+#[cfg(test)]
+pub(crate) mod mock {
+    use goblin::elf::section_header::{SHT_NOBITS, SHT_STRTAB, SHT_SYMTAB};
+    use goblin::elf::sym::STB_GLOBAL;
+
+    /// Builds a little endian 32-bit ARM ELF.
+    #[derive(Default)]
+    pub struct Elf {
+        /// Symbols as `(name, value, size, type)`.
+        symbols: Vec<(String, u32, u32, u8)>,
+        /// Further sections as `(name, type, address, contents)`.
+        sections: Vec<(String, u32, u32, Vec<u8>)>,
+        /// Program headers as `(type, virtual address, physical address, contents)`.
+        segments: Vec<(u32, u32, u32, Vec<u8>)>,
+        entry: u32,
+    }
+
+    fn push16(data: &mut Vec<u8>, value: u16) {
+        data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn push32(data: &mut Vec<u8>, value: u32) {
+        data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Appends `name` to a string table and returns its offset.
+    fn push_name(table: &mut Vec<u8>, name: &str) -> u32 {
+        let offset = table.len() as u32;
+        table.extend_from_slice(name.as_bytes());
+        table.push(0);
+        offset
+    }
+
+    impl Elf {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        pub fn symbol(mut self, name: &str, value: u32, size: u32, st_type: u8) -> Self {
+            self.symbols.push((name.to_owned(), value, size, st_type));
+            self
+        }
+
+        pub fn section(mut self, name: &str, sh_type: u32, address: u32, contents: &[u8]) -> Self {
+            self.sections
+                .push((name.to_owned(), sh_type, address, contents.to_vec()));
+            self
+        }
+
+        pub fn segment(mut self, p_type: u32, vaddr: u32, paddr: u32, contents: &[u8]) -> Self {
+            self.segments
+                .push((p_type, vaddr, paddr, contents.to_vec()));
+            self
+        }
+
+        pub fn entry(mut self, entry: u32) -> Self {
+            self.entry = entry;
+            self
+        }
+
+        pub fn build(&self) -> Vec<u8> {
+            let mut symtab = vec![0; 16];
+            let mut strtab = vec![0];
+            for (name, value, size, st_type) in &self.symbols {
+                push32(&mut symtab, push_name(&mut strtab, name));
+                push32(&mut symtab, *value);
+                push32(&mut symtab, *size);
+                symtab.push(STB_GLOBAL << 4 | st_type);
+                symtab.push(0);
+                // Absolute symbols, which need no section.
+                push16(&mut symtab, 0xfff1);
+            }
+            // Sections as `(name, type, address, link, entry size, contents)` after the null section.
+            let mut sections = vec![
+                (".symtab", SHT_SYMTAB, 0, 2, 16, symtab),
+                (".strtab", SHT_STRTAB, 0, 0, 0, strtab),
+                (".shstrtab", SHT_STRTAB, 0, 0, 0, vec![]),
+            ];
+            for (name, sh_type, address, contents) in &self.sections {
+                sections.push((name.as_str(), *sh_type, *address, 0, 0, contents.clone()));
+            }
+
+            let mut shstrtab = vec![0];
+            let names: Vec<u32> = sections
+                .iter()
+                .map(|section| push_name(&mut shstrtab, section.0))
+                .collect();
+            sections[2].5 = shstrtab;
+
+            let mut data = vec![0; 52];
+            let mut headers = vec![0; 40];
+            for ((_, sh_type, address, link, entsize, contents), name) in sections.iter().zip(names)
+            {
+                push32(&mut headers, name);
+                push32(&mut headers, *sh_type);
+                push32(&mut headers, 0);
+                push32(&mut headers, *address);
+                push32(&mut headers, data.len() as u32);
+                push32(&mut headers, contents.len() as u32);
+                push32(&mut headers, *link);
+                // The first global symbol follows the null symbol.
+                push32(&mut headers, if *sh_type == SHT_SYMTAB { 1 } else { 0 });
+                push32(&mut headers, 4);
+                push32(&mut headers, *entsize);
+                if *sh_type != SHT_NOBITS {
+                    data.extend_from_slice(contents);
+                }
+            }
+            let mut program_headers = vec![];
+            for (p_type, vaddr, paddr, contents) in &self.segments {
+                push32(&mut program_headers, *p_type);
+                push32(&mut program_headers, data.len() as u32);
+                push32(&mut program_headers, *vaddr);
+                push32(&mut program_headers, *paddr);
+                push32(&mut program_headers, contents.len() as u32);
+                push32(&mut program_headers, contents.len() as u32);
+                push32(&mut program_headers, 0);
+                push32(&mut program_headers, 4);
+                data.extend_from_slice(contents);
+            }
+            data.resize((data.len() + 3) & !3, 0);
+            let shoff = data.len() as u32;
+            data.extend_from_slice(&headers);
+            let phoff = if self.segments.is_empty() {
+                0
+            } else {
+                data.len() as u32
+            };
+            data.extend_from_slice(&program_headers);
+
+            let mut header = b"\x7fELF\x01\x01\x01".to_vec();
+            header.resize(16, 0);
+            push16(&mut header, 2);
+            push16(&mut header, 40);
+            push32(&mut header, 1);
+            push32(&mut header, self.entry);
+            push32(&mut header, phoff);
+            push32(&mut header, shoff);
+            push32(&mut header, 0x0500_0000);
+            push16(&mut header, 52);
+            push16(&mut header, 32);
+            push16(&mut header, self.segments.len() as u16);
+            push16(&mut header, 40);
+            push16(&mut header, sections.len() as u16 + 1);
+            push16(&mut header, 3);
+            data[..52].copy_from_slice(&header);
+            data
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn symbols() -> Symbols {
+        let elf = mock::Elf::new()
+            .symbol("main", 0x0800_0101, 0x40, sym::STT_FUNC)
+            .symbol(
+                "_ZN4demo4idle17h0123456789abcdefE",
+                0x0800_0141,
+                0x10,
+                sym::STT_FUNC,
+            )
+            .symbol("HardFault", 0x0800_0201, 0, sym::STT_FUNC)
+            .symbol("COUNTER", 0x2000_0001, 4, sym::STT_OBJECT)
+            .symbol("top", 0xffff_fff1, 0x20, sym::STT_FUNC);
+        Symbols::from_elf(&elf.build()).unwrap()
+    }
+
+    #[test]
+    fn finds_functions_by_address() {
+        let symbols = symbols();
+        // The Thumb bit of functions is stripped, but not that of data.
+        assert_eq!(symbols.get("main").unwrap().address, 0x0800_0100);
+        assert_eq!(symbols.get("COUNTER").unwrap().address, 0x2000_0001);
+
+        let name = |address| {
+            symbols
+                .function_at(address)
+                .map(|symbol| symbol.name.as_str())
+        };
+        assert_eq!(name(0x0800_00ff), None);
+        assert_eq!(name(0x0800_0100), Some("main"));
+        assert_eq!(name(0x0800_013f), Some("main"));
+        assert_eq!(name(0x0800_0140), Some("demo::idle"));
+        assert_eq!(name(0x0800_0150), None);
+        // Functions without a size cover their first byte.
+        assert_eq!(name(0x0800_0200), Some("HardFault"));
+        assert_eq!(name(0x0800_0201), None);
+        // Objects are not functions.
+        assert_eq!(name(0x2000_0000), None);
+        // Functions may end at the top of the address space.
+        assert_eq!(name(0xffff_ffff), Some("top"));
+    }
+
+    #[test]
+    fn describes_addresses() {
+        let symbols = symbols();
+        assert_eq!(symbols.describe(0x0800_0100), "main");
+        assert_eq!(symbols.describe(0x0800_0144), "demo::idle+0x4");
+        assert_eq!(symbols.describe(0x0800_0300), "0x08000300");
+    }
+}
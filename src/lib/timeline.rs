@@ -0,0 +1,308 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::Path;
+
+use goblin::elf::section_header::SHT_NOBITS;
+use goblin::elf::Elf;
+
+use crate::itm::{ExceptionAction, TracePacket};
+use crate::symbols::{SymbolError, Symbols};
+
+/// Names of the Cortex-M system exceptions.
+const SYSTEM_EXCEPTIONS: [(u16, &str); 11] = [
+    (1, "Reset"),
+    (2, "NMI"),
+    (3, "HardFault"),
+    (4, "MemManage"),
+    (5, "BusFault"),
+    (6, "UsageFault"),
+    (7, "SecureFault"),
+    (11, "SVCall"),
+    (12, "DebugMonitor"),
+    (14, "PendSV"),
+    (15, "SysTick"),
+];
+
+/// Exception number of the first external interrupt.
+const FIRST_IRQ: u16 = 16;
+
+/// Maps exception numbers to names.
+#[derive(Default)]
+pub struct ExceptionNames {
+    names: HashMap<u16, String>,
+}
+
+impl ExceptionNames {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Names the interrupts after the handlers in the vector table of a firmware ELF.
+    /// Handlers shared by several vectors, like a default handler, are not used as names.
+    pub fn from_elf(data: &[u8], symbols: &Symbols) -> Result<Self, SymbolError> {
+        let elf = Elf::parse(data).or_else(|e| Err(SymbolError::Elf(e)))?;
+        let mut names = Self::new();
+
+        let table =
+            elf.section_headers
+                .iter()
+                .find(|header| match elf.shdr_strtab.get(header.sh_name) {
+                    Some(Ok(name)) => name == ".vector_table" || name == ".isr_vector",
+                    _ => false,
+                });
+        let table = match table {
+            Some(header) if header.sh_type != SHT_NOBITS => {
+                let start = header.sh_offset as usize;
+                start
+                    .checked_add(header.sh_size as usize)
+                    .and_then(|end| data.get(start..end))
+                    .ok_or(SymbolError::TruncatedSection)?
+            }
+            _ => return Ok(names),
+        };
+
+        // The first word holds the initial stack pointer, so the handlers start at exception 1.
+        let handlers = table
+            .chunks(4)
+            .skip(1)
+            .filter(|word| word.len() == 4)
+            .map(|word| {
+                word[0] as u32
+                    | (word[1] as u32) << 8
+                    | (word[2] as u32) << 16
+                    | (word[3] as u32) << 24
+            })
+            .collect::<Vec<_>>();
+        let mut usage = HashMap::new();
+        for handler in &handlers {
+            *usage.entry(*handler).or_insert(0) += 1;
+        }
+        for (i, handler) in handlers.iter().enumerate() {
+            let exception = i as u16 + 1;
+            if *handler == 0 || usage[handler] > 1 || exception < FIRST_IRQ {
+                continue;
+            }
+            if let Some(symbol) = symbols.function_at(*handler & !1) {
+                names.names.insert(exception, symbol.name.clone());
+            }
+        }
+        Ok(names)
+    }
+
+    /// Names the interrupts as listed in a CMSIS-SVD file.
+    pub fn from_svd<P: AsRef<Path>>(path: P) -> Result<Self, SymbolError> {
+        let text = std::fs::read_to_string(path).or_else(|e| Err(SymbolError::IO(e)))?;
+        let document = roxmltree::Document::parse(&text).or_else(|e| Err(SymbolError::Svd(e)))?;
+
+        let mut names = Self::new();
+        for interrupt in document
+            .descendants()
+            .filter(|node| node.has_tag_name("interrupt"))
+        {
+            let child = |tag| {
+                interrupt
+                    .children()
+                    .find(|node| node.has_tag_name(tag))
+                    .and_then(|node| node.text())
+                    .map(str::trim)
+            };
+            if let (Some(name), Some(value)) = (child("name"), child("value")) {
+                if let Ok(irq) = value.parse::<u16>() {
+                    names.names.insert(irq + FIRST_IRQ, name.to_owned());
+                }
+            }
+        }
+        Ok(names)
+    }
+
+    /// Returns the name of an exception number.
+    pub fn name(&self, exception: u16) -> String {
+        if let Some(name) = self.names.get(&exception) {
+            return name.clone();
+        }
+        if exception == 0 {
+            return "Thread".to_owned();
+        }
+        match SYSTEM_EXCEPTIONS
+            .iter()
+            .find(|(number, _)| *number == exception)
+        {
+            Some((_, name)) => (*name).to_owned(),
+            None if exception >= FIRST_IRQ => format!("IRQ{}", exception - FIRST_IRQ),
+            None => format!("Exception{}", exception),
+        }
+    }
+}
+
+/// An exception trace event with its time in trace clock cycles.
+#[derive(Debug, PartialEq)]
+pub struct ExceptionEvent {
+    pub time: u64,
+    pub exception: u16,
+    pub action: ExceptionAction,
+}
+
+/// Collects the exception trace of a decoded SWV stream.
+#[derive(Default)]
+pub struct Timeline {
+    time: u64,
+    /// Events waiting for the local timestamp which follows them.
+    pending: Vec<(u16, ExceptionAction)>,
+    events: Vec<ExceptionEvent>,
+}
+
+impl Timeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn events(&self) -> &[ExceptionEvent] {
+        &self.events
+    }
+
+    /// Adds the exception trace and local timestamp packets of a decoded stream.
+    pub fn add_packets(&mut self, packets: &[TracePacket]) {
+        for packet in packets {
+            match packet {
+                TracePacket::ExceptionTrace { exception, action } => {
+                    self.pending.push((*exception, *action));
+                }
+                TracePacket::LocalTimestamp { delta, .. } => {
+                    self.time += *delta as u64;
+                    self.flush();
+                }
+                TracePacket::Overflow => {
+                    // Timestamps got lost, so anything pending is only roughly placed.
+                    self.flush();
+                }
+                _ => (),
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        let time = self.time;
+        self.events.extend(
+            self.pending
+                .drain(..)
+                .map(|(exception, action)| ExceptionEvent {
+                    time,
+                    exception,
+                    action,
+                }),
+        );
+    }
+
+    /// Writes the timeline in the Chrome Trace Event JSON format,
+    /// which can be opened with chrome://tracing or the Perfetto UI.
+    /// `clock` is the frequency of the timestamp clock in Hz.
+    pub fn write_chrome_trace<W: Write>(
+        &mut self,
+        names: &ExceptionNames,
+        clock: u32,
+        w: &mut W,
+    ) -> std::io::Result<()> {
+        self.flush();
+        let timestamp = |time: u64| time as f64 * 1_000_000.0 / clock as f64;
+
+        let mut entries = vec![];
+        let mut active: Vec<u16> = vec![];
+        for event in &self.events {
+            let ts = timestamp(event.time);
+            match event.action {
+                ExceptionAction::Entered => {
+                    active.push(event.exception);
+                    entries.push(Self::trace_event(&names.name(event.exception), "B", ts));
+                }
+                ExceptionAction::Exited => {
+                    // Close everything up to the exited exception so the events stay nested,
+                    // even if an exit got lost.
+                    if let Some(position) = active.iter().rposition(|&e| e == event.exception) {
+                        for exception in active.drain(position..).rev() {
+                            entries.push(Self::trace_event(&names.name(exception), "E", ts));
+                        }
+                    }
+                }
+                ExceptionAction::Returned => {
+                    entries.push(Self::trace_event(
+                        &format!("return to {}", names.name(event.exception)),
+                        "i",
+                        ts,
+                    ));
+                }
+            }
+        }
+        if let Some(last) = self.events.last() {
+            let ts = timestamp(last.time);
+            for exception in active.drain(..).rev() {
+                entries.push(Self::trace_event(&names.name(exception), "E", ts));
+            }
+        }
+
+        writeln!(w, "{{\"displayTimeUnit\":\"ns\",\"traceEvents\":[")?;
+        writeln!(
+            w,
+            "{{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":0,\"tid\":0,\"args\":{{\"name\":\"Exceptions\"}}}}{}",
+            if entries.is_empty() { "" } else { "," }
+        )?;
+        for (i, entry) in entries.iter().enumerate() {
+            let separator = if i + 1 < entries.len() { "," } else { "" };
+            writeln!(w, "{}{}", entry, separator)?;
+        }
+        writeln!(w, "]}}")
+    }
+
+    fn trace_event(name: &str, phase: &str, ts: f64) -> String {
+        let name = name.replace('\\', "\\\\").replace('"', "\\\"");
+        let scope = if phase == "i" { ",\"s\":\"t\"" } else { "" };
+        format!(
+            "{{\"name\":\"{}\",\"cat\":\"exception\",\"ph\":\"{}\",\"ts\":{:.3},\"pid\":0,\"tid\":0{}}}",
+            name, phase, ts, scope
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn assigns_following_timestamps() {
+        let mut timeline = Timeline::new();
+        timeline.add_packets(&[
+            TracePacket::ExceptionTrace {
+                exception: 15,
+                action: ExceptionAction::Entered,
+            },
+            TracePacket::LocalTimestamp {
+                delta: 100,
+                relation: crate::itm::TimestampRelation::Sync,
+            },
+            TracePacket::ExceptionTrace {
+                exception: 15,
+                action: ExceptionAction::Exited,
+            },
+            TracePacket::LocalTimestamp {
+                delta: 20,
+                relation: crate::itm::TimestampRelation::Sync,
+            },
+        ]);
+        assert_eq!(
+            timeline.events(),
+            &[
+                ExceptionEvent {
+                    time: 100,
+                    exception: 15,
+                    action: ExceptionAction::Entered
+                },
+                ExceptionEvent {
+                    time: 120,
+                    exception: 15,
+                    action: ExceptionAction::Exited
+                },
+            ]
+        );
+        assert_eq!(ExceptionNames::new().name(15), "SysTick");
+        assert_eq!(ExceptionNames::new().name(16 + 37), "IRQ37");
+    }
+}
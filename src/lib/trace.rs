@@ -0,0 +1,190 @@
+use crate::cortex_m::{DEMCR, DEMCR_TRCENA};
+use crate::memory::MemoryAccess;
+use crate::stlink::{AccessPort, STLinkError};
+use crate::swv::swo_divider;
+
+/// Configuration of the trace units of a Cortex-M target for SWO output.
+pub struct TraceConfig {
+    /// The clock feeding the TPIU (usually the core clock) in Hz.
+    pub trace_clk: u32,
+    /// The SWO baud rate. The closest rate `trace_clk` can be divided to is used.
+    pub baud: u32,
+    /// Bitmask of the ITM stimulus ports to enable.
+    pub stimulus_ports: u32,
+    /// Bitmask of the groups of 8 stimulus ports which only privileged code may write to.
+    pub privileged_ports: u32,
+    /// Emit local timestamps.
+    pub timestamps: bool,
+    /// Emit a PC sample about every given number of cycles.
+    /// The period is rounded to a multiple of 64 or 1024 cycles.
+    pub pc_sampling: Option<u32>,
+    /// Emit exception entry, exit and return packets.
+    pub exception_trace: bool,
+    /// Address of the STM32 DBGMCU_CR register to enable the trace pins with,
+    /// see `Stm32Family::dbgmcu_cr`.
+    pub dbgmcu_cr: Option<u32>,
+}
+
+impl TraceConfig {
+    /// Creates a configuration which only enables stimulus port 0 and leaves the
+    /// trace pins alone.
+    pub fn new(trace_clk: u32, baud: u32) -> Self {
+        Self {
+            trace_clk,
+            baud,
+            stimulus_ports: 0x0000_0001,
+            privileged_ports: 0x0000_0000,
+            timestamps: false,
+            pc_sampling: None,
+            exception_trace: false,
+            dbgmcu_cr: None,
+        }
+    }
+}
+
+const TPIU_ACPR: u32 = 0xe004_0010;
+const TPIU_SPPR: u32 = 0xe004_00f0;
+const TPIU_FFCR: u32 = 0xe004_0304;
+const SPPR_NRZ: u32 = 0x2;
+/// Bypasses the formatter, which is required for SWO output.
+const FFCR_TRIGIN: u32 = 0x100;
+
+const ITM_TER: u32 = 0xe000_0e00;
+const ITM_TPR: u32 = 0xe000_0e40;
+const ITM_TCR: u32 = 0xe000_0e80;
+const ITM_LAR: u32 = 0xe000_0fb0;
+const LAR_KEY: u32 = 0xc5ac_ce55;
+const TCR_ITMENA: u32 = 1 << 0;
+const TCR_TSENA: u32 = 1 << 1;
+const TCR_SYNCENA: u32 = 1 << 2;
+const TCR_TXENA: u32 = 1 << 3;
+const TCR_TRACE_BUS_ID: u32 = 1 << 16;
+
+const DWT_CTRL: u32 = 0xe000_1000;
+const DWT_CTRL_CYCCNTENA: u32 = 1 << 0;
+const DWT_CTRL_POSTPRESET_SHIFT: u32 = 1;
+const DWT_CTRL_CYCTAP: u32 = 1 << 9;
+const DWT_CTRL_SYNCTAP_CYCCNT24: u32 = 1 << 10;
+const DWT_CTRL_PCSAMPLENA: u32 = 1 << 12;
+const DWT_CTRL_EXCTRCENA: u32 = 1 << 16;
+
+const DBGMCU_CR_TRACE_IOEN: u32 = 1 << 5;
+const DBGMCU_CR_TRACE_MODE_MASK: u32 = 0x3 << 6;
+
+/// Configures TPIU, ITM and DWT of the target so it emits trace data over SWO
+/// in NRZ mode, which the ST-Link can receive with `start_swv`.
+pub fn configure_trace<M: MemoryAccess>(
+    mem: &mut M,
+    apsel: AccessPort,
+    config: &TraceConfig,
+) -> Result<(), STLinkError> {
+    // ACPR holds the divider minus one in 13 bits.
+    let prescaler = match swo_divider(config.trace_clk, config.baud) {
+        Some(divider) if divider <= 0x2000 => divider - 1,
+        _ => return Err(STLinkError::SwvBaudRateNotSupported),
+    };
+
+    let demcr = mem.read_word32(DEMCR, apsel)?;
+    mem.write_word32(DEMCR, demcr | DEMCR_TRCENA, apsel)?;
+
+    if let Some(dbgmcu_cr) = config.dbgmcu_cr {
+        // Asynchronous trace mode with the TRACESWO pin enabled.
+        let value = mem.read_word32(dbgmcu_cr, apsel)?;
+        mem.write_word32(
+            dbgmcu_cr,
+            (value & !DBGMCU_CR_TRACE_MODE_MASK) | DBGMCU_CR_TRACE_IOEN,
+            apsel,
+        )?;
+    }
+
+    mem.write_word32(TPIU_ACPR, prescaler, apsel)?;
+    mem.write_word32(TPIU_SPPR, SPPR_NRZ, apsel)?;
+    mem.write_word32(TPIU_FFCR, FFCR_TRIGIN, apsel)?;
+
+    let mut dwt_ctrl = mem.read_word32(DWT_CTRL, apsel)?;
+    dwt_ctrl &= !(DWT_CTRL_PCSAMPLENA | DWT_CTRL_EXCTRCENA);
+    if let Some(period) = config.pc_sampling {
+        // Samples are taken every POSTPRESET + 1 taps of CYCCNT bit 6 or bit 10.
+        let (tap, cycles) = if period > 16 * 64 {
+            (DWT_CTRL_CYCTAP, 1024)
+        } else {
+            (0, 64)
+        };
+        let preset = u32::min(u32::max(period / cycles, 1), 16) - 1;
+        dwt_ctrl &= !(DWT_CTRL_CYCTAP | (0xf << DWT_CTRL_POSTPRESET_SHIFT));
+        dwt_ctrl |= tap | (preset << DWT_CTRL_POSTPRESET_SHIFT) | DWT_CTRL_PCSAMPLENA;
+    }
+    if config.exception_trace {
+        dwt_ctrl |= DWT_CTRL_EXCTRCENA;
+    }
+    // CYCCNT drives PC sampling as well as the synchronization packets.
+    dwt_ctrl |= DWT_CTRL_CYCCNTENA | DWT_CTRL_SYNCTAP_CYCCNT24;
+    mem.write_word32(DWT_CTRL, dwt_ctrl, apsel)?;
+
+    mem.write_word32(ITM_LAR, LAR_KEY, apsel)?;
+    let mut tcr = TCR_ITMENA | TCR_SYNCENA | TCR_TRACE_BUS_ID;
+    if config.timestamps {
+        tcr |= TCR_TSENA;
+    }
+    if config.pc_sampling.is_some() || config.exception_trace {
+        tcr |= TCR_TXENA;
+    }
+    mem.write_word32(ITM_TCR, tcr, apsel)?;
+    mem.write_word32(ITM_TER, config.stimulus_ports, apsel)?;
+    mem.write_word32(ITM_TPR, config.privileged_ports, apsel)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::mock::Memory;
+
+    #[test]
+    fn configures_swo_output() {
+        let mut mem = Memory::new();
+        mem.set_word(0xe004_2004, 0x0000_00c7);
+        let mut config = TraceConfig::new(72_000_000, 2_250_000);
+        config.stimulus_ports = 0x0000_0003;
+        config.privileged_ports = 0x0000_0001;
+        config.timestamps = true;
+        config.dbgmcu_cr = Some(0xe004_2004);
+        configure_trace(&mut mem, 0, &config).unwrap();
+
+        assert_eq!(mem.word(DEMCR), DEMCR_TRCENA);
+        assert_eq!(mem.word(0xe004_2004), 0x0000_0027);
+        assert_eq!(mem.word(TPIU_ACPR), 31);
+        assert_eq!(mem.word(TPIU_SPPR), SPPR_NRZ);
+        assert_eq!(mem.word(TPIU_FFCR), FFCR_TRIGIN);
+        assert_eq!(mem.word(ITM_LAR), LAR_KEY);
+        assert_eq!(mem.word(ITM_TCR), 0x0001_0007);
+        assert_eq!(mem.word(ITM_TER), 0x0000_0003);
+        assert_eq!(mem.word(ITM_TPR), 0x0000_0001);
+        assert_eq!(mem.word(DWT_CTRL), 0x0000_0401);
+    }
+
+    #[test]
+    fn configures_hardware_packets() {
+        let mut mem = Memory::new();
+        mem.set_word(DWT_CTRL, 0x4000_0000);
+        let mut config = TraceConfig::new(16_000_000, 115_200);
+        config.pc_sampling = Some(4096);
+        config.exception_trace = true;
+        configure_trace(&mut mem, 0, &config).unwrap();
+
+        assert_eq!(mem.word(TPIU_ACPR), 138);
+        assert_eq!(mem.word(ITM_TCR), 0x0001_000d);
+        // Every 4 taps of CYCCNT bit 10.
+        assert_eq!(mem.word(DWT_CTRL), 0x4001_1607);
+        assert!(mem.writes.iter().all(|&(_, addr, _)| addr != 0xe004_2004));
+    }
+
+    #[test]
+    fn rejects_unreachable_baud_rates() {
+        let mut mem = Memory::new();
+        for &(trace_clk, baud) in &[(8_000_000, 0), (8_000_000, 20_000_000), (80_000_000, 9600)] {
+            let config = TraceConfig::new(trace_clk, baud);
+            assert!(configure_trace(&mut mem, 0, &config).is_err());
+        }
+        assert!(mem.writes.is_empty());
+    }
+}
@@ -216,7 +216,7 @@ impl<'a> STLinkUSBDevice<'a> {
 
     pub fn read_swv(&mut self, size: usize, timeout: Duration) -> Result<Vec<u8>, STLinkError> {
         let ep_swv = self.info.ep_swv;
-        let mut buf = Vec::with_capacity(size as usize);
+        let mut buf = vec![0; size];
         let read_bytes = self
             .device_handle
             .as_mut()